@@ -0,0 +1,176 @@
+//! Pre-shared key session authentication.
+//!
+//! When a PSK is configured, nothing but /auth is dispatched until the connection proves it knows
+//! the key: /auth/challenge returns a random nonce, /auth/respond takes HMAC-SHA256(psk, nonce).
+//! Nonce is single use, it is consumed by the first response whether it matches or not.
+//! Every link has its own session, bound to the connection id of the [Envelope] it was made over:
+//! events of another connection over the same link start a new one. Session of a link is also
+//! reset when its connection is closed. Bus links have a single connection for all the nodes.
+//!
+//! [Envelope]: ecbridge_net::envelope::Envelope
+
+use crate::link::LinkId;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const PSK_LEN: usize = 32;
+pub const NONCE_LEN: usize = 16;
+pub const MAC_LEN: usize = 32;
+/// One session per [LinkId]
+const LINKS: usize = 4;
+
+/// All zeroes PSK disables authentication
+pub fn is_enabled(psk: &[u8; PSK_LEN]) -> bool {
+    psk.iter().any(|b| *b != 0)
+}
+
+#[derive(Copy, Clone)]
+pub struct Session {
+    authenticated: bool,
+    /// Issued by the last challenge and not yet responded to
+    nonce: Option<[u8; NONCE_LEN]>,
+}
+
+impl Session {
+    pub const fn new() -> Self {
+        Session {
+            authenticated: false,
+            nonce: None,
+        }
+    }
+
+    /// Forget everything, called when the connection is closed
+    pub fn reset(&mut self) {
+        *self = Session::new();
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Remember a new nonce, replacing the previous one
+    pub fn challenge(&mut self, nonce: [u8; NONCE_LEN]) {
+        self.nonce = Some(nonce);
+    }
+
+    /// Check `mac` against the outstanding challenge, session becomes authenticated if it matches.
+    pub fn respond(&mut self, psk: &[u8; PSK_LEN], mac: &[u8; MAC_LEN]) -> bool {
+        let nonce = match self.nonce.take() {
+            Some(nonce) => nonce,
+            None => return false,
+        };
+        let mut hmac = match Hmac::<Sha256>::new_from_slice(psk) {
+            Ok(hmac) => hmac,
+            Err(_) => return false,
+        };
+        hmac.update(&nonce);
+        // constant time comparison
+        self.authenticated = hmac.verify_slice(mac).is_ok();
+        self.authenticated
+    }
+}
+
+/// Session of the current connection over every link
+pub struct Sessions {
+    links: [LinkSession; LINKS],
+}
+
+#[derive(Copy, Clone)]
+struct LinkSession {
+    connection: u16,
+    session: Session,
+}
+
+impl Sessions {
+    pub const fn new() -> Self {
+        Sessions {
+            links: [LinkSession { connection: 0, session: Session::new() }; LINKS],
+        }
+    }
+
+    /// Session of `connection` over `link`, previous connection's one is dropped
+    pub fn get(&mut self, link: LinkId, connection: u16) -> &mut Session {
+        let slot = &mut self.links[link as usize];
+        if slot.connection != connection {
+            *slot = LinkSession { connection, session: Session::new() };
+        }
+        &mut slot.session
+    }
+
+    pub fn is_authenticated(&self, link: LinkId, connection: u16) -> bool {
+        let slot = &self.links[link as usize];
+        slot.connection == connection && slot.session.is_authenticated()
+    }
+
+    /// Session of the latest connection over `link`
+    pub fn latest(&self, link: LinkId) -> &Session {
+        &self.links[link as usize].session
+    }
+
+    /// Forget the session of `link`, called when its connection is closed
+    pub fn reset(&mut self, link: LinkId) {
+        self.links[link as usize].session.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: [u8; PSK_LEN] = [7; PSK_LEN];
+    const NONCE: [u8; NONCE_LEN] = [1; NONCE_LEN];
+
+    fn mac(psk: &[u8; PSK_LEN], nonce: &[u8; NONCE_LEN]) -> [u8; MAC_LEN] {
+        let mut hmac = Hmac::<Sha256>::new_from_slice(psk).unwrap();
+        hmac.update(nonce);
+        let mut mac = [0u8; MAC_LEN];
+        mac.copy_from_slice(&hmac.finalize().into_bytes());
+        mac
+    }
+
+    fn authenticate(sessions: &mut Sessions, link: LinkId, connection: u16) -> bool {
+        sessions.get(link, connection).challenge(NONCE);
+        sessions.get(link, connection).respond(&PSK, &mac(&PSK, &NONCE))
+    }
+
+    #[test]
+    fn respond() {
+        let mut session = Session::new();
+        assert!(!session.respond(&PSK, &mac(&PSK, &NONCE)), "no challenge yet");
+        session.challenge(NONCE);
+        assert!(!session.respond(&PSK, &mac(&[8; PSK_LEN], &NONCE)));
+        // nonce was consumed by the wrong response
+        assert!(!session.respond(&PSK, &mac(&PSK, &NONCE)));
+        session.challenge(NONCE);
+        assert!(session.respond(&PSK, &mac(&PSK, &NONCE)));
+        assert!(session.is_authenticated());
+    }
+
+    #[test]
+    fn per_connection() {
+        let mut sessions = Sessions::new();
+        assert!(authenticate(&mut sessions, LinkId::Ethernet, 1));
+        assert!(sessions.is_authenticated(LinkId::Ethernet, 1));
+        assert!(!sessions.is_authenticated(LinkId::Ethernet, 2));
+        assert!(!sessions.is_authenticated(LinkId::Can, 0));
+        assert!(!sessions.is_authenticated(LinkId::Can, 1));
+
+        assert!(authenticate(&mut sessions, LinkId::Can, 0));
+        // next connection over Ethernet doesn't inherit the previous one's session
+        sessions.get(LinkId::Ethernet, 2);
+        assert!(!sessions.is_authenticated(LinkId::Ethernet, 1));
+        assert!(!sessions.is_authenticated(LinkId::Ethernet, 2));
+        assert!(sessions.is_authenticated(LinkId::Can, 0));
+    }
+
+    #[test]
+    fn reset() {
+        let mut sessions = Sessions::new();
+        assert!(authenticate(&mut sessions, LinkId::Ethernet, 1));
+        assert!(authenticate(&mut sessions, LinkId::Can, 0));
+        sessions.reset(LinkId::Ethernet);
+        assert!(!sessions.is_authenticated(LinkId::Ethernet, 1));
+        assert!(!sessions.latest(LinkId::Ethernet).is_authenticated());
+        assert!(sessions.is_authenticated(LinkId::Can, 0));
+    }
+}
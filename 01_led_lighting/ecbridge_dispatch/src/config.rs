@@ -0,0 +1,73 @@
+//! Network and node configuration served as /config.
+//!
+//! Config is read at boot only, changes made through xPI go into a pending copy, which is persisted
+//! by [crate::Node::apply_config] followed by a restart. Storage and factory defaults are up to the node.
+//! Fields must only ever be appended to `Config`, it is stored serialized by the firmware.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    pub ipv4: [u8; 4],
+    pub ipv4_prefix_len: u8,
+    pub mac: [u8; 6],
+    pub tcp_port: u16,
+    pub node_id: u8,
+    // v2
+    /// Static IPv6 address in addition to link-local and autoconfigured ones, all zeroes if none
+    pub ipv6: [u8; 16],
+    pub ipv6_prefix_len: u8,
+    // v3
+    /// Pre-shared key for xPI session authentication, all zeroes if disabled
+    pub psk: [u8; crate::auth::PSK_LEN],
+    // v4
+    /// TCP keep-alive and xPI heartbeat interval in seconds, 0 to disable
+    pub keepalive_s: u16,
+    /// Connection is dropped if the peer doesn't acknowledge data or keep-alives for that long, 0 to disable
+    pub timeout_s: u16,
+    // v5
+    /// Syslog collector, log-text-udp feature must be enabled for logs to be sent
+    pub syslog_ipv4: [u8; 4],
+    /// 0 to disable
+    pub syslog_port: u16,
+    // v6
+    /// SNTP server, all zeroes to disable wall clock sync
+    pub sntp_ipv4: [u8; 4],
+}
+
+/// Key is not printed to logs
+impl core::fmt::Debug for Config {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Config")
+            .field("ipv4", &self.ipv4)
+            .field("ipv4_prefix_len", &self.ipv4_prefix_len)
+            .field("mac", &self.mac)
+            .field("tcp_port", &self.tcp_port)
+            .field("node_id", &self.node_id)
+            .field("ipv6", &self.ipv6)
+            .field("ipv6_prefix_len", &self.ipv6_prefix_len)
+            .field("auth", &crate::auth::is_enabled(&self.psk))
+            .field("keepalive_s", &self.keepalive_s)
+            .field("timeout_s", &self.timeout_s)
+            .field("syslog_ipv4", &self.syslog_ipv4)
+            .field("syslog_port", &self.syslog_port)
+            .field("sntp_ipv4", &self.sntp_ipv4)
+            .finish()
+    }
+}
+
+/// Config used since boot and the one that will be used after the next restart
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigState {
+    pub active: Config,
+    pub pending: Config,
+}
+
+impl ConfigState {
+    pub fn new(active: Config) -> Self {
+        ConfigState {
+            active,
+            pending: active,
+        }
+    }
+}
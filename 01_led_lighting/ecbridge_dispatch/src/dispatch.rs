@@ -0,0 +1,1468 @@
+//! Dispatching of xPI requests addressed to the node and publishing of updates to subscribers.
+
+use log::{debug, error, info, trace, warn};
+use vhl_cg::point::Point;
+use vhl_stdlib::discrete::U4;
+use vhl_stdlib::serdes::buf::{Buf, BufMut};
+use vhl_stdlib::serdes::vlu4::{Vlu4Vec, Vlu4VecIter};
+use vhl_stdlib::serdes::{NibbleBuf, NibbleBufMut, SerDesSize, SerializeVlu4};
+use xpi::error::XpiError;
+use xpi::event_kind::{XpiEventDiscriminant, XpiGenericEventKind};
+use xpi::xwfd;
+use xpi::xwfd::event::EventBuilderKindState;
+use xpi::xwfd::{
+    EventBuilder, EventKind, MultiUriFlatIter, NodeId, NodeSet, ResourceSet, SerialUri, SerialUriIter,
+};
+use xpi::ReplySizeHint;
+use crate::auth;
+use crate::link::{LinkId, LinkTx, TxLinks, MTU_MAX};
+use crate::config::Config;
+use crate::log_record::{Backlog, Record, MODULE_MAX};
+use crate::node_table::{self, Allocation, NODE_TABLE_LEN};
+use crate::subscriptions::Subscription;
+use crate::{Node, CRASH_MESSAGE_MAX};
+use ecbridge_net::envelope::Envelope;
+use smoltcp::wire::{IpAddress, Ipv4Address};
+#[cfg(feature = "proto-ipv6")]
+use smoltcp::wire::Ipv6Address;
+
+/// /log : stream of log records, see publish_logs
+pub const LOG_RESOURCE: u32 = 3;
+/// Log record parts are sized so that a whole StreamUpdates event fits into MTU_MAX
+const LOG_UPDATE_MAX: usize = MTU_MAX - 16;
+// part header with the longest module name must leave space for the message
+const _: () = assert!(1 + 8 + 1 + MODULE_MAX + 1 < LOG_UPDATE_MAX);
+/// Set in the level byte of log record parts followed by more parts of the same message
+const LOG_CONTINUED: u8 = 0x80;
+/// /crash : report left by the previous run if it crashed, see crate::crash
+const CRASH_RESOURCE: u32 = 4;
+/// /crash/0 : kind (0 if there is no report), reset_flags, uptime_ms, message_len
+const CRASH_SUMMARY_NIBBLES: usize = 2 + 8 + 16 + 2;
+/// /crash/1 : r0, r1, r2, r3, r12, lr, pc, xpsr
+const CRASH_FRAME_NIBBLES: usize = 8 * 8;
+/// /crash/2/part : message bytes, zero padded
+const CRASH_MESSAGE_PART: usize = 32;
+const CRASH_MESSAGE_PARTS: usize = CRASH_MESSAGE_MAX / CRASH_MESSAGE_PART;
+/// /link : observable
+pub const LINK_RESOURCE: u32 = 9;
+/// up, speed, full_duplex, drops, symbol_errors
+const LINK_STATE_NIBBLES: usize = 2 + 2 + 2 + 8 + 8;
+/// /auth : the only resource accessible before authentication
+pub(crate) const AUTH_RESOURCE: u32 = 10;
+/// /heartbeat : observable uptime in seconds, published every keepalive_s
+pub const HEARTBEAT_RESOURCE: u32 = 11;
+/// /ping : starts the network self-test
+const PING_RESOURCE: u32 = 12;
+/// /ping_stats : observable results of the last self-test
+pub const PING_STATS_RESOURCE: u32 = 13;
+/// running, sent, received, rtt_min_ms, rtt_avg_ms, rtt_max_ms
+const PING_STATS_NIBBLES: usize = 2 + 2 + 2 + 4 + 4 + 4;
+/// /time : observable wall clock status, published after each SNTP sync
+pub const TIME_RESOURCE: u32 = 14;
+/// synced, stratum, unix_time_ms, correction_us, delay_us, since_sync_s
+const TIME_NIBBLES: usize = 2 + 2 + 16 + 8 + 8 + 8;
+/// /can_nodes : CAN node ids given out to modules, one child per table slot
+const CAN_NODES_RESOURCE: u32 = 15;
+/// node_id (0 if the slot is free), unique_id
+const CAN_NODE_NIBBLES: usize = 2 + 12;
+/// /log_filter : global log level 0 (off) to 5 (trace), one child per module of Node::log_modules,
+/// 0xFF for modules following the global level
+const LOG_FILTER_RESOURCE: u32 = 16;
+
+// dispatcher still runs in the protocol task
+// should be configurable by user what to do next with requests
+// dispatcher should have access to all the resources to answer for ex. Read requests for props
+// would be great to just put all the resources to rtic _resources_, so that different priority
+// task can run without waiting
+//
+/// How far dispatching an event got
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Dispatched {
+    /// Every reply was submitted
+    Done,
+    /// Tx queue had no space for the reply to batch `n`, earlier batches were executed and replied to.
+    /// The same event must be dispatched again with `skip_batches = n` once there is space.
+    Stalled(usize),
+}
+
+// Replies go to `tx`, the link the request arrived on. Each batch is replied to with one event,
+// tx is checked for space before executing a batch, so a stall never loses a reply.
+// `envelope` is the one the event was received with, its connection's session is checked.
+pub fn xpi_dispatch(
+    node: &mut impl Node,
+    tx: &mut dyn LinkTx,
+    envelope: &Envelope,
+    ev: &xwfd::Event,
+    skip_batches: usize,
+) -> Result<Dispatched, XpiError> {
+    trace!("xpi_dispatch: {}", ev);
+
+    let self_node_id = node.config(|c| c.active.node_id);
+    let self_node_id = NodeId::new(self_node_id).ok_or(XpiError::Internal)?;
+    let locked = !is_authenticated(node, envelope.link, envelope.connection);
+    let log_modules = node.log_modules();
+
+    // 1. scan over resources set
+    // 2. decide which calls to batch into one reply based on maximum reply len and max len of each call result
+    // 2a. async calls and reads will be replied later, need to remember original node id and request id.
+    // 3. create XpiReplyBuilder and serialize resource subset into it
+    // 4. advance builder to args_set state and dispatch every call
+    // 5. finish serializing, submit reply
+    // 6. repeat until all calls are processed or no more space for replies available
+
+    const MAX_REPLY_BATCH_LEN: usize = 16; // TODO: move to config file
+    const MAX_REPLY_BATCHES: usize = 8; // hard limit to not create an endless loop on erroneous requests
+
+    let mut resource_set_lookahead_uri_iter = ev.resource_set.flat_iter().peekable();
+    let mut resource_set_execute_uri_iter = ev.resource_set.flat_iter();
+    let ev_kind = ev.kind.discriminant();
+    for batch in 0..MAX_REPLY_BATCHES {
+        let mut reply_lookahead: [Option<ReplySizeHint>; MAX_REPLY_BATCH_LEN] =
+            [None; MAX_REPLY_BATCH_LEN];
+        let mut run_out_of_requests = false;
+        let mut batch_len = 0;
+        let mut immediate_replies = 0;
+        let mut reply_nibbles_left =
+            (tx.mtu() - /* frame sync overhead */5) * 2 - /*header*/10 - /*tail*/2 - /*spare*/10;
+        for idx in 0..MAX_REPLY_BATCH_LEN {
+            match resource_set_lookahead_uri_iter.peek() {
+                Some(uri) => {
+                    let hint = if locked && uri.clone().next() != Some(AUTH_RESOURCE) {
+                        not_authenticated()
+                    } else {
+                        reply_size_hint(uri.clone(), ev_kind, log_modules)
+                    };
+                    match hint {
+                        ReplySizeHint::Immediate { max_size, .. } => {
+                            let upper_bound = max_size.upper_bound(reply_nibbles_left);
+                            if reply_nibbles_left >= upper_bound {
+                                // reply will fit, take it
+                                let _ = resource_set_lookahead_uri_iter.next();
+                                reply_lookahead[idx] = Some(hint);
+                                batch_len += 1;
+                                immediate_replies += 1;
+                                reply_nibbles_left -= upper_bound;
+                            } else {
+                                // reply won't fit, stop and put it into next batch
+                                break;
+                            }
+                        }
+                        ReplySizeHint::Deferred => {
+                            // async replies do not consume any space from this batch
+                            let _ = resource_set_lookahead_uri_iter.next();
+                            reply_lookahead[idx] = Some(hint);
+                            batch_len += 1;
+                        }
+                    }
+                }
+                None => {
+                    run_out_of_requests = true;
+                    break;
+                }
+            }
+        }
+        if batch_len > 0 && batch < skip_batches {
+            // executed and replied to before the tx queue filled up
+            for _ in 0..batch_len {
+                let _ = resource_set_execute_uri_iter.next();
+            }
+        } else if batch_len > 0 {
+            if immediate_replies != 0 && !tx.ready() {
+                return Ok(Dispatched::Stalled(batch));
+            }
+            // serialized here first and then framed into the queue
+            let mut reply_buf = [0u8; MTU_MAX];
+            let reply_builder = EventBuilder::new(
+                NibbleBufMut::new_all(&mut reply_buf),
+                self_node_id,
+                ev.request_id,
+                ev.priority,
+                U4::new(15).unwrap(),
+            )?;
+            let reply_builder = reply_builder.build_node_set_with(|mut nwr| {
+                let node_set = NodeSet::Unicast(ev.source);
+                node_set.ser_vlu4(&mut nwr)?;
+                Ok((node_set.ser_header(), nwr))
+            })?;
+            let reply_builder = reply_builder.build_resource_set_with(|mut nwr| {
+                let resource_set = ev.resource_set.clone();
+                resource_set.ser_vlu4(&mut nwr)?;
+                Ok((resource_set.ser_header(), nwr))
+            })?;
+            let nwr = match &ev.kind {
+                EventKind::Call { args_set } => dispatch_call_set(
+                    &mut resource_set_execute_uri_iter,
+                    &reply_lookahead[..batch_len],
+                    reply_builder,
+                    args_set,
+                    envelope,
+                    node,
+                )?,
+                EventKind::Write { values } => dispatch_write_set(
+                    &mut resource_set_execute_uri_iter,
+                    &reply_lookahead[..batch_len],
+                    reply_builder,
+                    values,
+                    node,
+                )?,
+                EventKind::Read => dispatch_read_set(
+                    &mut resource_set_execute_uri_iter,
+                    &reply_lookahead[..batch_len],
+                    reply_builder,
+                    node,
+                )?,
+                EventKind::Subscribe { .. } => dispatch_subscribe_set(
+                    &mut resource_set_execute_uri_iter,
+                    &reply_lookahead[..batch_len],
+                    reply_builder,
+                    ev,
+                    envelope,
+                    node,
+                )?,
+                u => {
+                    warn!("Unsupported: {}", u);
+                    continue; // TODO: is it correct?
+                }
+            };
+            if immediate_replies == 0 {
+                trace!("Only async replies in a batch, not committing.");
+            } else {
+                trace!(
+                    "XpiReply {}, free space left={} expected:{}",
+                    nwr,
+                    nwr.nibbles_left(),
+                    reply_nibbles_left
+                );
+                let (_, len, _) = nwr.finish();
+                tx.submit(&reply_buf[..len])?;
+            }
+        }
+        if run_out_of_requests || batch_len == 0 {
+            break;
+        }
+    }
+    if resource_set_lookahead_uri_iter.next().is_some() {
+        error!(
+            "Maximum request count({}) is reached, some requests are skipped",
+            MAX_REPLY_BATCH_LEN * MAX_REPLY_BATCHES
+        );
+    }
+
+    Ok(Dispatched::Done)
+}
+
+/// Whether events from `connection` over `link` can be dispatched or routed.
+pub fn is_authenticated(node: &mut impl Node, link: LinkId, connection: u16) -> bool {
+    let auth_enabled = node.config(|c| auth::is_enabled(&c.active.psk));
+    !auth_enabled || node.sessions(|s| s.is_authenticated(link, connection))
+}
+
+/// Whether every resource of the event is under /auth
+pub(crate) fn is_auth_request(ev: &xwfd::Event) -> bool {
+    ev.resource_set.flat_iter().all(|mut uri| uri.next() == Some(AUTH_RESOURCE))
+}
+
+fn not_authenticated() -> ReplySizeHint {
+    // there is no dedicated error code for that yet
+    let r = Err(XpiError::OperationNotSupported);
+    ReplySizeHint::immediate(r.len_nibbles(), SerDesSize::Sized(0), r)
+}
+
+/// Send StreamUpdates with the current value of every changed resource to its subscribers,
+/// over the link each subscription was made on.
+///
+/// Returns false if a tx queue ran out of space, not sent updates are kept dirty
+/// and must be retried once there is space again.
+pub fn publish_updates(node: &mut impl Node, links: &mut impl TxLinks) -> bool {
+    let eth_up = node.link_state().up;
+    let self_node_id = node.config(|c| c.active.node_id);
+    let self_node_id = match NodeId::new(self_node_id) {
+        Some(id) => id,
+        None => return true,
+    };
+    let dirty = node.subscriptions(|s| s.take_dirty());
+    if dirty == 0 {
+        return true;
+    }
+    let subscriptions = node.subscriptions(|s| s.all());
+    let mut not_sent = 0u32;
+    for subscription in subscriptions.iter().flatten() {
+        if dirty & (1 << subscription.resource) == 0 || subscription.resource == LOG_RESOURCE {
+            // log records are sent by publish_logs
+            continue;
+        }
+        if subscription.link == LinkId::Ethernet && !eth_up {
+            // nobody would receive it, current value is sent once the link is back up
+            continue;
+        }
+        if !is_authenticated(node, subscription.link, subscription.connection) {
+            // made by a connection that is gone, its subscriptions are cleared when it is closed
+            continue;
+        }
+        let tx = match links.get(subscription.link) {
+            Some(tx) => tx,
+            None => continue,
+        };
+        if !tx.ready() {
+            not_sent |= 1 << subscription.resource;
+            continue;
+        }
+        let r = publish_update(tx, self_node_id, subscription, node);
+        if let Err(e) = r {
+            error!("publish /{} to {:?}: {:?}", subscription.resource, subscription.subscriber, e);
+        }
+    }
+    if not_sent != 0 {
+        // other subscribers of the same resource might get the same value twice, which is fine
+        node.subscriptions(|s| s.restore_dirty(not_sent));
+    }
+    not_sent == 0
+}
+
+/// Send queued log records to /log subscribers, each as one or more StreamUpdates.
+///
+/// Records stay queued while nobody is subscribed. Every subscriber gets the same part of a record,
+/// so a part is only sent when all their tx queues have space.
+/// Returns false if a tx queue ran out of space, the rest is sent on the next run.
+pub fn publish_logs<const N: usize>(node: &mut impl Node, links: &mut impl TxLinks, backlog: &mut Backlog<N>) -> bool {
+    let eth_up = node.link_state().up;
+    let mut subscribers = node.subscriptions(|s| s.all());
+    for slot in subscribers.iter_mut() {
+        // subscribers behind a link that is down miss records, they stay queued if nobody is reachable
+        let keep = matches!(slot, Some(s) if s.resource == LOG_RESOURCE
+            && (s.link != LinkId::Ethernet || eth_up)
+            && is_authenticated(node, s.link, s.connection));
+        if !keep {
+            *slot = None;
+        }
+    }
+    if subscribers.iter().all(|s| s.is_none()) {
+        return true;
+    }
+    let self_node_id = node.config(|c| c.active.node_id);
+    let self_node_id = match NodeId::new(self_node_id) {
+        Some(id) => id,
+        None => return true,
+    };
+    while let Some(rgr) = backlog.cons.read() {
+        let record = Record::parse(&rgr);
+        let part_max = match LOG_UPDATE_MAX.checked_sub(log_part_header_len(&record)) {
+            Some(part_max) if part_max > 0 => part_max,
+            _ => {
+                // not written by Record::header, which truncates module names
+                error!("dropping log record with {}B module name", record.module.len());
+                backlog.sent = 0;
+                rgr.release();
+                continue;
+            }
+        };
+        loop {
+            for subscription in subscribers.iter().flatten() {
+                if !links.get(subscription.link).map(|tx| tx.ready()).unwrap_or(true) {
+                    return false;
+                }
+            }
+            let part_len = (record.message.len() - backlog.sent).min(part_max);
+            let part = &record.message[backlog.sent..backlog.sent + part_len];
+            let continued = backlog.sent + part_len < record.message.len();
+            for subscription in subscribers.iter().flatten() {
+                let tx = match links.get(subscription.link) {
+                    Some(tx) => tx,
+                    None => continue,
+                };
+                if let Err(e) = publish_log_part(tx, self_node_id, subscription, &record, part, continued) {
+                    // must not be queued for /log by the node, it would never end
+                    error!("publish /log to {:?}: {:?}", subscription.subscriber, e);
+                }
+            }
+            backlog.sent += part_len;
+            if !continued {
+                break;
+            }
+        }
+        backlog.sent = 0;
+        rgr.release();
+    }
+    true
+}
+
+/// level (with LOG_CONTINUED), uptime_ms, module length and module, part length
+fn log_part_header_len(record: &Record) -> usize {
+    1 + 8 + 1 + record.module.len() + 1
+}
+
+fn publish_log_part(
+    tx: &mut dyn LinkTx,
+    self_node_id: NodeId,
+    subscription: &Subscription,
+    record: &Record,
+    part: &[u8],
+    continued: bool,
+) -> Result<(), XpiError> {
+    let mut event_buf = [0u8; MTU_MAX];
+    let builder = EventBuilder::new(
+        NibbleBufMut::new_all(&mut event_buf),
+        self_node_id,
+        subscription.request_id,
+        subscription.priority,
+        U4::new(15).unwrap(),
+    )?;
+    let builder = builder.build_node_set_with(|mut nwr| {
+        let node_set = NodeSet::Unicast(subscription.subscriber);
+        node_set.ser_vlu4(&mut nwr)?;
+        Ok((node_set.ser_header(), nwr))
+    })?;
+    let builder = builder.build_resource_set_with(|mut nwr| {
+        let resource = U4::new(LOG_RESOURCE as u8).ok_or(XpiError::Internal)?;
+        let resource_set = ResourceSet::Uri(SerialUri::OnePart4(resource));
+        resource_set.ser_vlu4(&mut nwr)?;
+        Ok((resource_set.ser_header(), nwr))
+    })?;
+    let nwr = builder.build_kind_with(|nwr| {
+        let mut vb = nwr.put_vec::<Result<NibbleBuf, XpiError>>();
+        let len_nibbles = (log_part_header_len(record) + part.len()) * 2;
+        vb.put_result_nib_slice_with(SerDesSize::Sized(len_nibbles), |value_nwr| {
+            let level = if continued { record.level | LOG_CONTINUED } else { record.level };
+            value_nwr.put(&level)?;
+            value_nwr.put_u32_be((record.uptime_ms >> 32) as u32)?;
+            value_nwr.put_u32_be(record.uptime_ms as u32)?;
+            value_nwr.put(&(record.module.len() as u8))?;
+            for b in record.module {
+                value_nwr.put(b)?;
+            }
+            value_nwr.put(&(part.len() as u8))?;
+            for b in part {
+                value_nwr.put(b)?;
+            }
+            Ok(())
+        })?;
+        let nwr = vb.finish()?;
+        Ok((XpiEventDiscriminant::StreamUpdates, nwr))
+    })?;
+    let (_, len, _) = nwr.finish();
+    tx.submit(&event_buf[..len])
+}
+
+fn publish_update(
+    tx: &mut dyn LinkTx,
+    self_node_id: NodeId,
+    subscription: &Subscription,
+    node: &mut impl Node,
+) -> Result<(), XpiError> {
+    let mut event_buf = [0u8; MTU_MAX];
+    let builder = EventBuilder::new(
+        NibbleBufMut::new_all(&mut event_buf),
+        self_node_id,
+        subscription.request_id,
+        subscription.priority,
+        U4::new(15).unwrap(),
+    )?;
+    let builder = builder.build_node_set_with(|mut nwr| {
+        let node_set = NodeSet::Unicast(subscription.subscriber);
+        node_set.ser_vlu4(&mut nwr)?;
+        Ok((node_set.ser_header(), nwr))
+    })?;
+    let builder = builder.build_resource_set_with(|mut nwr| {
+        let resource = U4::new(subscription.resource as u8).ok_or(XpiError::Internal)?;
+        let resource_set = ResourceSet::Uri(SerialUri::OnePart4(resource));
+        resource_set.ser_vlu4(&mut nwr)?;
+        Ok((resource_set.ser_header(), nwr))
+    })?;
+    let nwr = builder.build_kind_with(|nwr| {
+        let mut vb = nwr.put_vec::<Result<NibbleBuf, XpiError>>();
+        match subscription.resource {
+            LINK_RESOURCE => {
+                vb.put_result_nib_slice_with(SerDesSize::Sized(LINK_STATE_NIBBLES), |value_nwr| {
+                    read_link_state(value_nwr, node)
+                })?;
+            }
+            HEARTBEAT_RESOURCE => {
+                vb.put_result_nib_slice_with(SerDesSize::Sized(8), |value_nwr| {
+                    read_uptime(value_nwr, node)
+                })?;
+            }
+            PING_STATS_RESOURCE => {
+                vb.put_result_nib_slice_with(SerDesSize::Sized(PING_STATS_NIBBLES), |value_nwr| {
+                    read_ping_stats(value_nwr, node)
+                })?;
+            }
+            TIME_RESOURCE => {
+                vb.put_result_nib_slice_with(SerDesSize::Sized(TIME_NIBBLES), |value_nwr| {
+                    read_time(value_nwr, node)
+                })?;
+            }
+            _ => return Err(XpiError::Internal), // only observable resources can be subscribed to
+        }
+        let nwr = vb.finish()?;
+        Ok((XpiEventDiscriminant::StreamUpdates, nwr))
+    })?;
+    let (_, len, _) = nwr.finish();
+    tx.submit(&event_buf[..len])
+}
+
+fn read_link_state(value_nwr: &mut NibbleBufMut, node: &mut impl Node) -> Result<(), XpiError> {
+    let link = node.link_state();
+    value_nwr.put(&(link.up as u8))?;
+    value_nwr.put(&link.speed)?;
+    value_nwr.put(&(link.full_duplex as u8))?;
+    value_nwr.put_u32_be(link.drops)?;
+    value_nwr.put_u32_be(link.symbol_errors)?;
+    Ok(())
+}
+
+fn read_uptime(value_nwr: &mut NibbleBufMut, node: &mut impl Node) -> Result<(), XpiError> {
+    let uptime = node.uptime().secs();
+    value_nwr.put_u32_be(uptime as u32)?;
+    Ok(())
+}
+
+fn read_ping_stats(value_nwr: &mut NibbleBufMut, node: &mut impl Node) -> Result<(), XpiError> {
+    let stats = node.ping_stats();
+    value_nwr.put(&(stats.running as u8))?;
+    value_nwr.put(&stats.sent)?;
+    value_nwr.put(&stats.received)?;
+    value_nwr.put_u16_be(stats.rtt_min_ms)?;
+    value_nwr.put_u16_be(stats.rtt_avg_ms())?;
+    value_nwr.put_u16_be(stats.rtt_max_ms)?;
+    Ok(())
+}
+
+/// Unix time is 0 and the rest of the fields are meaningless until the first sync.
+fn read_time(value_nwr: &mut NibbleBufMut, node: &mut impl Node) -> Result<(), XpiError> {
+    let status = node.sntp_status();
+    let uptime = node.uptime();
+    let unix_time_ms = status.unix_time_us(uptime).map(|us| us / 1000).unwrap_or(0) as u64;
+    let since_sync_s = (uptime - status.synced_at).secs();
+    value_nwr.put(&(status.synced as u8))?;
+    value_nwr.put(&status.stratum)?;
+    value_nwr.put_u32_be((unix_time_ms >> 32) as u32)?;
+    value_nwr.put_u32_be(unix_time_ms as u32)?;
+    value_nwr.put_u32_be(status.correction_us.clamp(i32::MIN as i64, i32::MAX as i64) as i32 as u32)?;
+    value_nwr.put_u32_be(status.delay_us)?;
+    value_nwr.put_u32_be(since_sync_s as u32)?;
+    Ok(())
+}
+
+/// Target of /ping: 4 or 16 address bytes followed by the amount of echo requests.
+fn ping_args(args_nrd: &mut NibbleBuf) -> Result<(IpAddress, u8), XpiError> {
+    let target = match args_nrd.nibbles_left() {
+        10 => {
+            let mut addr = [0u8; 4];
+            for b in addr.iter_mut() {
+                *b = args_nrd.get_u8()?;
+            }
+            IpAddress::Ipv4(Ipv4Address(addr))
+        }
+        #[cfg(feature = "proto-ipv6")]
+        34 => {
+            let mut addr = [0u8; 16];
+            for b in addr.iter_mut() {
+                *b = args_nrd.get_u8()?;
+            }
+            IpAddress::Ipv6(Ipv6Address(addr))
+        }
+        len => {
+            error!("Bad /ping arguments length: {} nib", len);
+            return Err(XpiError::OperationNotSupported);
+        }
+    };
+    let count = args_nrd.get_u8()?;
+    Ok((target, count))
+}
+
+fn dispatch_call_set<'i>(
+    resource_set_execute_uri_iter: &mut MultiUriFlatIter,
+    reply_lookahead: &[Option<ReplySizeHint>],
+    reply_builder: EventBuilderKindState<'i>,
+    args_set: &Vlu4Vec<NibbleBuf>,
+    envelope: &Envelope,
+    node: &mut impl Node,
+) -> Result<NibbleBufMut<'i>, XpiError> {
+    let nwr = reply_builder.build_kind_with(|nwr| {
+        let mut vb = nwr.put_vec::<Result<NibbleBuf, XpiError>>();
+        let mut args_set_iter = args_set.iter();
+        for reply_size_hint in reply_lookahead {
+            let uri = resource_set_execute_uri_iter.next().expect("");
+            match reply_size_hint {
+                Some(ReplySizeHint::Immediate {
+                    raw_size,
+                    preliminary_result,
+                    ..
+                }) => match preliminary_result {
+                    Ok(_) => match args_set_iter.next() {
+                        Some(args_nrd) => {
+                            vb.put_result_nib_slice_with(*raw_size, |result_nwr| {
+                                dispatch_call(uri.clone(), args_nrd, result_nwr, envelope, node)
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        error!("dispatch error: {:?}", e);
+                                        e
+                                    })
+                            })?;
+                        }
+                        None => {
+                            error!("No args provided for {}", uri);
+                            vb.put(&Err(XpiError::NoArgumentsProvided))?;
+                        }
+                    },
+                    Err(e) => {
+                        vb.put(&Err(e.clone()))?;
+                    }
+                },
+                Some(ReplySizeHint::Deferred) => match args_set_iter.next() {
+                    Some(args_nrd) => {
+                        match dispatch_call(
+                            uri.clone(),
+                            args_nrd,
+                            &mut NibbleBufMut::new_all(&mut []),
+                            envelope,
+                            node,
+                        ) {
+                            Ok(_) => {
+                                trace!("async call spawned");
+                            }
+                            Err(e) => {
+                                error!("dispatch error: {:?}", e);
+                            }
+                        }
+                    }
+                    None => {
+                        error!("No args provided for {}", uri);
+                        vb.put(&Err(XpiError::NoArgumentsProvided))?;
+                    }
+                },
+                None => {
+                    return Err(XpiError::Internal); // shouldn't be reached, if batch_len is correct
+                }
+            }
+        }
+        let nwr = vb.finish()?;
+        Ok((XpiEventDiscriminant::CallResults, nwr))
+    })?;
+    Ok(nwr)
+}
+
+fn dispatch_write_set<'i>(
+    resource_set_execute_uri_iter: &mut MultiUriFlatIter,
+    reply_lookahead: &[Option<ReplySizeHint>],
+    reply_builder: EventBuilderKindState<'i>,
+    values: &Vlu4Vec<NibbleBuf>,
+    node: &mut impl Node,
+) -> Result<NibbleBufMut<'i>, XpiError> {
+    let nwr = reply_builder.build_kind_with(|nwr| {
+        let mut vb = nwr.put_vec::<Result<(), XpiError>>();
+        let mut args_set_iter = values.iter();
+        for reply_size_hint in reply_lookahead {
+            let uri = resource_set_execute_uri_iter.next().expect("");
+            match reply_size_hint {
+                Some(ReplySizeHint::Immediate {
+                    preliminary_result, ..
+                }) => match preliminary_result {
+                    Ok(_) => match args_set_iter.next() {
+                        Some(value_nrd) => {
+                            vb.put(&dispatch_write(uri.clone(), value_nrd, node))?;
+                        }
+                        None => {
+                            error!("No args provided for {}", uri);
+                            vb.put(&Err(XpiError::NoArgumentsProvided))?;
+                        }
+                    },
+                    Err(e) => {
+                        vb.put(&Err(e.clone()))?;
+                    }
+                },
+                Some(ReplySizeHint::Deferred) | None => {
+                    return Err(XpiError::Internal); // shouldn't be reached, writes are only sync
+                }
+            }
+        }
+        let nwr = vb.finish()?;
+        Ok((XpiEventDiscriminant::WriteResults, nwr))
+    })?;
+    Ok(nwr)
+}
+
+fn dispatch_read_set<'i>(
+    resource_set_execute_uri_iter: &mut MultiUriFlatIter,
+    reply_lookahead: &[Option<ReplySizeHint>],
+    reply_builder: EventBuilderKindState<'i>,
+    node: &mut impl Node,
+) -> Result<NibbleBufMut<'i>, XpiError> {
+    let nwr = reply_builder.build_kind_with(|nwr| {
+        let mut vb = nwr.put_vec::<Result<NibbleBuf, XpiError>>();
+        for reply_size_hint in reply_lookahead {
+            let uri = resource_set_execute_uri_iter.next().expect("");
+            match reply_size_hint {
+                Some(ReplySizeHint::Immediate {
+                    preliminary_result,
+                    raw_size,
+                    ..
+                }) => match preliminary_result {
+                    Ok(_) => {
+                        vb.put_result_nib_slice_with(*raw_size, |value_nwr| {
+                            dispatch_read(uri.clone(), value_nwr, node)
+                        })?;
+                    }
+                    Err(e) => {
+                        vb.put(&Err(e.clone()))?;
+                    }
+                },
+                Some(ReplySizeHint::Deferred) | None => {
+                    return Err(XpiError::Internal); // shouldn't be reached, writes are only sync
+                }
+            }
+        }
+        let nwr = vb.finish()?;
+        Ok((XpiEventDiscriminant::ReadResults, nwr))
+    })?;
+    Ok(nwr)
+}
+
+fn dispatch_subscribe_set<'i>(
+    resource_set_execute_uri_iter: &mut MultiUriFlatIter,
+    reply_lookahead: &[Option<ReplySizeHint>],
+    reply_builder: EventBuilderKindState<'i>,
+    ev: &xwfd::Event,
+    envelope: &Envelope,
+    node: &mut impl Node,
+) -> Result<NibbleBufMut<'i>, XpiError> {
+    let nwr = reply_builder.build_kind_with(|nwr| {
+        let mut vb = nwr.put_vec::<Result<(), XpiError>>();
+        for reply_size_hint in reply_lookahead {
+            let mut uri = resource_set_execute_uri_iter.next().expect("");
+            match reply_size_hint {
+                Some(ReplySizeHint::Immediate {
+                    preliminary_result, ..
+                }) => match preliminary_result {
+                    Ok(_) => {
+                        // only root level resources are observable, checked by reply_size_hint
+                        let resource = uri.next().ok_or(XpiError::Internal)?;
+                        let r = node.subscriptions(|s| s.subscribe(Subscription {
+                            resource,
+                            subscriber: ev.source,
+                            link: envelope.link,
+                            connection: envelope.connection,
+                            request_id: ev.request_id,
+                            priority: ev.priority,
+                        }));
+                        info!("subscribe /{} from {:?}: {:?}", resource, ev.source, r);
+                        vb.put(&r)?;
+                    }
+                    Err(e) => {
+                        vb.put(&Err(e.clone()))?;
+                    }
+                },
+                Some(ReplySizeHint::Deferred) | None => {
+                    return Err(XpiError::Internal); // shouldn't be reached, subscribes are only sync
+                }
+            }
+        }
+        let nwr = vb.finish()?;
+        Ok((XpiEventDiscriminant::SubscribeResults, nwr))
+    })?;
+    Ok(nwr)
+}
+
+/// Perform one method call on a resource.
+///
+/// if call_type == DryRun => return maximum length of the reply or an error if it is obvious right
+/// away that call would fails. In RealRun return the amount of bytes written into result slice.
+///
+/// Dispatcher will decide how many replies to batch together based on that information.
+/// It is ok to return less data than originally estimated.
+/// Returning an error in dry run allows to batch more replies, as some of them might be invalid,
+/// thus requiring space only for an error code.
+fn dispatch_call(
+    mut uri: SerialUriIter<Vlu4VecIter<u32>>,
+    mut args_nrd: NibbleBuf,
+    result_nwr: &mut NibbleBufMut,
+    envelope: &Envelope,
+    node: &mut impl Node,
+) -> Result<(), XpiError> {
+    debug!("dispatch_call({})", uri);
+    match uri.next() {
+        None => {
+            error!("Expected root level");
+            return Err(XpiError::BadUri);
+        }
+        id @ Some(0 | 1 | 7 | 9 | 11 | 13 | 14 | LOG_RESOURCE | CRASH_RESOURCE | CAN_NODES_RESOURCE | LOG_FILTER_RESOURCE) => {
+            error!("Resource /{:?} is not a method", id);
+            Err(XpiError::NotAMethod)
+        }
+        Some(2) => {
+            let a: u32 = args_nrd.des_vlu4()?;
+            node.set_digit(a as u8);
+            trace!("Called /set_digit({})", a);
+
+            Ok(())
+        }
+        Some(5) => {
+            let p1: Point = args_nrd.des_vlu4()?;
+            let p2: Point = args_nrd.des_vlu4()?;
+            if !args_nrd.is_at_end() {
+                // TODO: remove this as semver compatible newer versions can contain more data
+                warn!(
+                    "Unused {} nib left after deserializing arguments",
+                    args_nrd.nibbles_left()
+                );
+            }
+            let r = crate::sync(p1, p2);
+            trace!("Called /sync3({:?}, {:?}) = {:?}", p1, p2, r);
+
+            result_nwr.put(&r)?;
+            Ok(())
+        }
+        Some(6) => {
+            let p1: Point = args_nrd.des_vlu4()?;
+            let p2: Point = args_nrd.des_vlu4()?;
+            node.async_call(p1, p2);
+            trace!("Called /async({:?}, {:?})", p1, p2);
+            Ok(())
+        }
+        Some(8) => match uri.next() {
+            Some(5) => {
+                let pending = node.config(|c| c.pending);
+                let r = node.apply_config(pending);
+                info!("/config/apply: {:?}", r);
+                r
+            }
+            Some(6) => {
+                let r = node.factory_reset();
+                info!("/config/factory_reset: {:?}", r);
+                r
+            }
+            id @ (None | Some(0..=4 | 7..=14)) => {
+                error!("Resource /8/{:?} is not a method", id);
+                Err(XpiError::NotAMethod)
+            }
+            not_defined => {
+                error!("Resource /8/{:?} doesn't exist", not_defined);
+                Err(XpiError::BadUri)
+            }
+        },
+        Some(AUTH_RESOURCE) => match uri.next() {
+            Some(0) => {
+                let mut nonce = [0u8; auth::NONCE_LEN];
+                node.fill_random(&mut nonce[..]).map_err(|e| {
+                    error!("rng: {:?}", e);
+                    e
+                })?;
+                node.sessions(|s| s.get(envelope.link, envelope.connection).challenge(nonce));
+                for b in nonce {
+                    result_nwr.put(&b)?;
+                }
+                Ok(())
+            }
+            Some(1) => {
+                let mut mac = [0u8; auth::MAC_LEN];
+                for b in mac.iter_mut() {
+                    *b = args_nrd.get_u8()?;
+                }
+                let psk = node.config(|c| c.active.psk);
+                if node.sessions(|s| s.get(envelope.link, envelope.connection).respond(&psk, &mac)) {
+                    info!("Session authenticated: {:?} connection {}", envelope.link, envelope.connection);
+                    Ok(())
+                } else {
+                    warn!("Authentication failed");
+                    Err(XpiError::OperationNotSupported)
+                }
+            }
+            not_defined => {
+                error!("Resource /10/{:?} doesn't exist", not_defined);
+                Err(XpiError::BadUri)
+            }
+        },
+        Some(PING_RESOURCE) => {
+            let (target, count) = ping_args(&mut args_nrd)?;
+            if !node.start_ping(target, count) {
+                warn!("/ping: previous self-test is still running");
+                return Err(XpiError::OperationNotSupported);
+            }
+            info!("/ping {} x{}", target, count);
+            Ok(())
+        }
+        not_defined => {
+            error!("Resource /{:?} doesn't exist", not_defined);
+            Err(XpiError::BadUri)
+        }
+    }
+}
+
+fn dispatch_write(
+    mut uri: SerialUriIter<Vlu4VecIter<u32>>,
+    mut value_nrd: NibbleBuf,
+    node: &mut impl Node,
+) -> Result<(), XpiError> {
+    info!("dispatch_write({})", uri);
+    match uri.next() {
+        None => {
+            error!("Expected root level");
+            return Err(XpiError::BadUri);
+        }
+        Some(1) => {
+            let digit: u32 = value_nrd.des_vlu4()?;
+            node.write_digit(digit as u8);
+            info!("write {}", digit);
+            Ok(())
+        }
+        id @ Some(7 | 9 | 11 | 13 | 14) => {
+            error!("Resource /{:?} is read only", id);
+            Err(XpiError::OperationNotSupported)
+        }
+        Some(8) => {
+            let mut pending = node.config(|c| c.pending);
+            write_config_field(uri.next(), &mut value_nrd, &mut pending)?;
+            node.config(|c| c.pending = pending);
+            info!("config pending: {:?}", pending);
+            Ok(())
+        }
+        Some(CAN_NODES_RESOURCE) => write_can_node(uri.next(), &mut value_nrd, node),
+        Some(LOG_FILTER_RESOURCE) => write_log_filter(uri.next(), &mut value_nrd, node),
+        id @ Some(2 | LOG_RESOURCE | 5 | 6 | 10 | 12) => {
+            error!("Resource /{:?} is not a property", id);
+            Err(XpiError::NotAMethod)
+        }
+        not_defined => {
+            error!("Resource /{:?} doesn't exist", not_defined);
+            Err(XpiError::BadUri)
+        }
+    }
+}
+
+fn dispatch_read(
+    mut uri: SerialUriIter<Vlu4VecIter<u32>>,
+    value_nwr: &mut NibbleBufMut,
+    node: &mut impl Node,
+) -> Result<(), XpiError> {
+    info!("dispatch_read({})", uri);
+    match uri.next() {
+        None => {
+            error!("Expected root level");
+            return Err(XpiError::BadUri);
+        }
+        Some(1) => {
+            let digit = node.digit();
+            value_nwr.put(&digit)?;
+            Ok(())
+        }
+        Some(7) => {
+            let (rx_stalls, dispatch_stalls) = node.flow_stalls();
+            value_nwr.put_u32_be(rx_stalls)?;
+            value_nwr.put_u32_be(dispatch_stalls)?;
+            Ok(())
+        }
+        Some(8) => {
+            let pending = node.config(|c| c.pending);
+            read_config_field(uri.next(), value_nwr, &pending)
+        }
+        Some(9) => read_link_state(value_nwr, node),
+        Some(11) => read_uptime(value_nwr, node),
+        Some(PING_STATS_RESOURCE) => read_ping_stats(value_nwr, node),
+        Some(TIME_RESOURCE) => read_time(value_nwr, node),
+        Some(CAN_NODES_RESOURCE) => read_can_node(uri.next(), value_nwr, node),
+        Some(LOG_FILTER_RESOURCE) => read_log_filter(uri.next(), value_nwr, node),
+        Some(CRASH_RESOURCE) => read_crash(&mut uri, value_nwr, node),
+        id @ Some(2 | LOG_RESOURCE | 5 | 6 | 10 | 12) => {
+            error!("Resource /{:?} is not a property", id);
+            Err(XpiError::NotAMethod)
+        }
+        not_defined => {
+            error!("Resource /{:?} doesn't exist", not_defined);
+            Err(XpiError::BadUri)
+        }
+    }
+}
+
+
+/// Slot of /can_nodes child resource
+fn can_node_slot(id: Option<u32>) -> Result<usize, XpiError> {
+    match id {
+        Some(slot) if (slot as usize) < NODE_TABLE_LEN => Ok(slot as usize),
+        not_defined => {
+            error!("Resource /15/{:?} doesn't exist", not_defined);
+            Err(XpiError::BadUri)
+        }
+    }
+}
+
+fn read_can_node(id: Option<u32>, value_nwr: &mut NibbleBufMut, node: &mut impl Node) -> Result<(), XpiError> {
+    let slot = can_node_slot(id)?;
+    let allocation = node.node_table(|t| t.get(slot)).map_err(|_| XpiError::Internal)?;
+    let (node_id, unique_id) = allocation.map(|a| (a.node_id, a.unique_id)).unwrap_or((0, 0));
+    value_nwr.put(&node_id)?;
+    for b in &unique_id.to_be_bytes()[2..] {
+        value_nwr.put(b)?;
+    }
+    Ok(())
+}
+
+/// Pin a module to a node id or free the slot with node id 0, the table is persisted right away.
+/// The change is applied once the table accepts it, saving is up to the node.
+fn write_can_node(id: Option<u32>, value_nrd: &mut NibbleBuf, node: &mut impl Node) -> Result<(), XpiError> {
+    let slot = can_node_slot(id)?;
+    let node_id = value_nrd.get_u8()?;
+    let mut unique_id = [0u8; 8];
+    for b in unique_id[2..].iter_mut() {
+        *b = value_nrd.get_u8()?;
+    }
+    let allocation = match node_id {
+        0 => None,
+        node_id => Some(Allocation { unique_id: u64::from_be_bytes(unique_id), node_id }),
+    };
+    let self_node_id = node.config(|c| c.active.node_id);
+    node.node_table(|t| t.set(slot, allocation, self_node_id)).map_err(|e| {
+        error!("/can_nodes/{}: {:?}", slot, e);
+        match e {
+            node_table::Error::BadSlot => XpiError::BadUri,
+            _ => XpiError::OperationNotSupported,
+        }
+    })?;
+    info!("/can_nodes/{}: {:?}", slot, allocation);
+    node.save_node_table();
+    Ok(())
+}
+
+fn read_crash(
+    uri: &mut SerialUriIter<Vlu4VecIter<u32>>,
+    value_nwr: &mut NibbleBufMut,
+    node: &mut impl Node,
+) -> Result<(), XpiError> {
+    let report = node.crash_report();
+    match (uri.next(), uri.next()) {
+        (Some(0), None) => {
+            let (kind, uptime_ms, message_len) = report
+                .map(|r| (r.kind, r.uptime_ms, r.message_len))
+                .unwrap_or((0, 0, 0));
+            value_nwr.put(&kind)?;
+            value_nwr.put_u32_be(node.reset_flags())?;
+            value_nwr.put_u32_be((uptime_ms >> 32) as u32)?;
+            value_nwr.put_u32_be(uptime_ms as u32)?;
+            value_nwr.put(&message_len)?;
+        }
+        (Some(1), None) => {
+            for r in report.map(|r| r.frame).unwrap_or_default() {
+                value_nwr.put_u32_be(r)?;
+            }
+        }
+        (Some(2), Some(part)) if (part as usize) < CRASH_MESSAGE_PARTS => {
+            let start = part as usize * CRASH_MESSAGE_PART;
+            let message = report.map(|r| r.message).unwrap_or([0; CRASH_MESSAGE_MAX]);
+            for b in &message[start..start + CRASH_MESSAGE_PART] {
+                value_nwr.put(b)?;
+            }
+        }
+        not_defined => {
+            error!("Resource /4/{:?} doesn't exist", not_defined);
+            return Err(XpiError::BadUri);
+        }
+    }
+    Ok(())
+}
+
+fn read_log_filter(id: Option<u32>, value_nwr: &mut NibbleBufMut, node: &mut impl Node) -> Result<(), XpiError> {
+    let level = node.log_level(id.map(|module| module as usize))?;
+    value_nwr.put(&level)?;
+    Ok(())
+}
+
+/// Takes effect right away and is lost on reset
+fn write_log_filter(id: Option<u32>, value_nrd: &mut NibbleBuf, node: &mut impl Node) -> Result<(), XpiError> {
+    let level = value_nrd.get_u8()?;
+    node.set_log_level(id.map(|module| module as usize), level).map_err(|e| {
+        error!("/log_filter/{:?}: {:?}", id, e);
+        e
+    })?;
+    info!("/log_filter/{:?}: {}", id, level);
+    Ok(())
+}
+
+/// Write one of /config properties into the pending config, it is applied by /config/apply only.
+fn write_config_field(
+    id: Option<u32>,
+    value_nrd: &mut NibbleBuf,
+    pending: &mut Config,
+) -> Result<(), XpiError> {
+    match id {
+        Some(0) => {
+            for b in pending.ipv4.iter_mut() {
+                *b = value_nrd.get_u8()?;
+            }
+        }
+        Some(1) => {
+            let prefix_len = value_nrd.get_u8()?;
+            if prefix_len > 32 {
+                error!("Bad prefix length: {}", prefix_len);
+                return Err(XpiError::OperationNotSupported);
+            }
+            pending.ipv4_prefix_len = prefix_len;
+        }
+        Some(2) => {
+            let mut mac = [0u8; 6];
+            for b in mac.iter_mut() {
+                *b = value_nrd.get_u8()?;
+            }
+            if mac[0] & 0x01 != 0 {
+                error!("Multicast MAC address is not allowed");
+                return Err(XpiError::OperationNotSupported);
+            }
+            pending.mac = mac;
+        }
+        Some(3) => {
+            let port = value_nrd.get_u16_be()?;
+            if port == 0 {
+                error!("TCP port 0 is not allowed");
+                return Err(XpiError::OperationNotSupported);
+            }
+            pending.tcp_port = port;
+        }
+        Some(4) => {
+            let node_id = value_nrd.get_u8()?;
+            if NodeId::new(node_id).is_none() {
+                error!("Bad node id: {}", node_id);
+                return Err(XpiError::OperationNotSupported);
+            }
+            pending.node_id = node_id;
+        }
+        Some(7) => {
+            for b in pending.ipv6.iter_mut() {
+                *b = value_nrd.get_u8()?;
+            }
+        }
+        Some(8) => {
+            let prefix_len = value_nrd.get_u8()?;
+            if prefix_len > 128 {
+                error!("Bad prefix length: {}", prefix_len);
+                return Err(XpiError::OperationNotSupported);
+            }
+            pending.ipv6_prefix_len = prefix_len;
+        }
+        Some(9) => {
+            for b in pending.psk.iter_mut() {
+                *b = value_nrd.get_u8()?;
+            }
+        }
+        Some(10) => pending.keepalive_s = value_nrd.get_u16_be()?,
+        Some(11) => pending.timeout_s = value_nrd.get_u16_be()?,
+        Some(12) => {
+            for b in pending.syslog_ipv4.iter_mut() {
+                *b = value_nrd.get_u8()?;
+            }
+        }
+        Some(13) => pending.syslog_port = value_nrd.get_u16_be()?,
+        Some(14) => {
+            for b in pending.sntp_ipv4.iter_mut() {
+                *b = value_nrd.get_u8()?;
+            }
+        }
+        id @ Some(5 | 6) => {
+            error!("Resource /8/{:?} is not a property", id);
+            return Err(XpiError::NotAMethod);
+        }
+        not_defined => {
+            error!("Resource /8/{:?} doesn't exist", not_defined);
+            return Err(XpiError::BadUri);
+        }
+    }
+    Ok(())
+}
+
+fn read_config_field(
+    id: Option<u32>,
+    value_nwr: &mut NibbleBufMut,
+    pending: &Config,
+) -> Result<(), XpiError> {
+    match id {
+        Some(0) => {
+            for b in pending.ipv4 {
+                value_nwr.put(&b)?;
+            }
+        }
+        Some(1) => value_nwr.put(&pending.ipv4_prefix_len)?,
+        Some(2) => {
+            for b in pending.mac {
+                value_nwr.put(&b)?;
+            }
+        }
+        Some(3) => value_nwr.put_u16_be(pending.tcp_port)?,
+        Some(4) => value_nwr.put(&pending.node_id)?,
+        Some(7) => {
+            for b in pending.ipv6 {
+                value_nwr.put(&b)?;
+            }
+        }
+        Some(8) => value_nwr.put(&pending.ipv6_prefix_len)?,
+        Some(9) => {
+            error!("Resource /8/9 is write only");
+            return Err(XpiError::OperationNotSupported);
+        }
+        Some(10) => value_nwr.put_u16_be(pending.keepalive_s)?,
+        Some(11) => value_nwr.put_u16_be(pending.timeout_s)?,
+        Some(12) => {
+            for b in pending.syslog_ipv4 {
+                value_nwr.put(&b)?;
+            }
+        }
+        Some(13) => value_nwr.put_u16_be(pending.syslog_port)?,
+        Some(14) => {
+            for b in pending.sntp_ipv4 {
+                value_nwr.put(&b)?;
+            }
+        }
+        id @ Some(5 | 6) => {
+            error!("Resource /8/{:?} is not a property", id);
+            return Err(XpiError::NotAMethod);
+        }
+        not_defined => {
+            error!("Resource /8/{:?} doesn't exist", not_defined);
+            return Err(XpiError::BadUri);
+        }
+    }
+    Ok(())
+}
+
+///
+/// TODO: use proper max() or calculate in advance during code gen
+fn reply_size_hint(
+    mut uri: SerialUriIter<Vlu4VecIter<u32>>,
+    event_kind: XpiEventDiscriminant,
+    log_modules: usize,
+) -> ReplySizeHint {
+    trace!("reply_size_hint({})", uri);
+    use XpiEventDiscriminant::*;
+    let not_supported = Err(XpiError::OperationNotSupported);
+    let not_supported = ReplySizeHint::immediate(
+        not_supported.len_nibbles(),
+        SerDesSize::Sized(0),
+        not_supported,
+    );
+    let bad_uri = Err(XpiError::BadUri);
+    let bad_uri = ReplySizeHint::immediate(bad_uri.len_nibbles(), SerDesSize::Sized(0), bad_uri);
+    match uri.next() {
+        // /main
+        None => match event_kind {
+            _ => not_supported,
+        },
+        Some(1) => match uri.next() {
+            None => match event_kind {
+                Write => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                Read => ReplySizeHint::immediate(SerDesSize::Sized(2 + 3), SerDesSize::Sized(2), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(7) => match uri.next() {
+            // /main/flow_stats : rx_stalls, dispatch_stalls
+            None => match event_kind {
+                Read => ReplySizeHint::immediate(SerDesSize::Sized(16 + 3), SerDesSize::Sized(16), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(9) => match uri.next() {
+            // /main/link : up, speed, full_duplex, drops, symbol_errors
+            None => match event_kind {
+                Read => ReplySizeHint::immediate(
+                    SerDesSize::Sized(LINK_STATE_NIBBLES + 3),
+                    SerDesSize::Sized(LINK_STATE_NIBBLES),
+                    Ok(())
+                ),
+                Subscribe => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(8) => {
+            // /main/config : properties are read back as pending values
+            let property = |nibbles: usize| match event_kind {
+                Write => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                Read => ReplySizeHint::immediate(
+                    SerDesSize::Sized(nibbles + 3),
+                    SerDesSize::Sized(nibbles),
+                    Ok(())
+                ),
+                _ => not_supported,
+            };
+            let method = match event_kind {
+                Call => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            };
+            let hint = match uri.next() {
+                Some(0) => property(8), // ipv4
+                Some(1) => property(2), // ipv4_prefix_len
+                Some(2) => property(12), // mac
+                Some(3) => property(4), // tcp_port
+                Some(4) => property(2), // node_id
+                Some(7) => property(32), // ipv6, all zeroes if none
+                Some(8) => property(2), // ipv6_prefix_len
+                Some(9) => match event_kind {
+                    // psk, all zeroes to disable authentication
+                    Write => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                    _ => not_supported,
+                },
+                Some(10) => property(4), // keepalive_s
+                Some(11) => property(4), // timeout_s
+                Some(12) => property(8), // syslog_ipv4
+                Some(13) => property(4), // syslog_port, 0 to disable
+                Some(14) => property(8), // sntp_ipv4, all zeroes to disable
+                Some(5 | 6) => method, // apply, factory_reset
+                _ => return bad_uri,
+            };
+            match uri.next() {
+                None => hint,
+                Some(_) => bad_uri,
+            }
+        }
+        Some(5) => {
+            match uri.next() {
+                // dispatch /main/sync
+                None => match event_kind {
+                    Call => {
+                        ReplySizeHint::immediate(SerDesSize::Sized(8 + 3), SerDesSize::Sized(8), Ok(()))
+                    }
+                    _ => not_supported,
+                },
+                // /main/sync : has no child resources
+                Some(_) => bad_uri,
+            }
+        }
+        Some(6) => {
+            match uri.next() {
+                // dispatch /main/async
+                None => match event_kind {
+                    Call => ReplySizeHint::Deferred,
+                    _ => not_supported,
+                },
+                // /main/async : has no child resources
+                Some(_) => bad_uri,
+            }
+        }
+        Some(11) => match uri.next() {
+            // /main/heartbeat : uptime
+            None => match event_kind {
+                Read => ReplySizeHint::immediate(SerDesSize::Sized(8 + 3), SerDesSize::Sized(8), Ok(())),
+                Subscribe => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(LOG_RESOURCE) => match uri.next() {
+            // /main/log : level, uptime_ms, module, message, records are only published
+            None => match event_kind {
+                Subscribe => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(PING_RESOURCE) => match uri.next() {
+            // /main/ping : target address, count, results are published as /ping_stats
+            None => match event_kind {
+                Call => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(PING_STATS_RESOURCE) => match uri.next() {
+            // /main/ping_stats : running, sent, received, rtt_min_ms, rtt_avg_ms, rtt_max_ms
+            None => match event_kind {
+                Read => ReplySizeHint::immediate(
+                    SerDesSize::Sized(PING_STATS_NIBBLES + 3),
+                    SerDesSize::Sized(PING_STATS_NIBBLES),
+                    Ok(())
+                ),
+                Subscribe => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(TIME_RESOURCE) => match uri.next() {
+            // /main/time : synced, stratum, unix_time_ms, correction_us, delay_us, since_sync_s
+            None => match event_kind {
+                Read => ReplySizeHint::immediate(
+                    SerDesSize::Sized(TIME_NIBBLES + 3),
+                    SerDesSize::Sized(TIME_NIBBLES),
+                    Ok(())
+                ),
+                Subscribe => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                _ => not_supported,
+            },
+            Some(_) => bad_uri,
+        },
+        Some(CAN_NODES_RESOURCE) => {
+            // /main/can_nodes/slot : node_id, unique_id, node_id 0 frees the slot
+            let hint = match (uri.next(), event_kind) {
+                (Some(slot), Write) if (slot as usize) < NODE_TABLE_LEN => {
+                    ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(()))
+                }
+                (Some(slot), Read) if (slot as usize) < NODE_TABLE_LEN => ReplySizeHint::immediate(
+                    SerDesSize::Sized(CAN_NODE_NIBBLES + 3),
+                    SerDesSize::Sized(CAN_NODE_NIBBLES),
+                    Ok(())
+                ),
+                (Some(slot), _) if (slot as usize) < NODE_TABLE_LEN => return not_supported,
+                _ => return bad_uri,
+            };
+            match uri.next() {
+                None => hint,
+                Some(_) => bad_uri,
+            }
+        }
+        Some(CRASH_RESOURCE) => {
+            // /main/crash/0 : summary, /main/crash/1 : exception frame, /main/crash/2/part : message
+            let value_nibbles = match (uri.next(), uri.next()) {
+                (Some(0), None) => CRASH_SUMMARY_NIBBLES,
+                (Some(1), None) => CRASH_FRAME_NIBBLES,
+                (Some(2), Some(part)) if (part as usize) < CRASH_MESSAGE_PARTS => CRASH_MESSAGE_PART * 2,
+                _ => return bad_uri,
+            };
+            match (uri.next(), event_kind) {
+                (None, Read) => ReplySizeHint::immediate(
+                    SerDesSize::Sized(value_nibbles + 3),
+                    SerDesSize::Sized(value_nibbles),
+                    Ok(())
+                ),
+                (None, _) => not_supported,
+                (Some(_), _) => bad_uri,
+            }
+        }
+        Some(LOG_FILTER_RESOURCE) => {
+            // /main/log_filter and /main/log_filter/module : level
+            let is_module = |id: u32| (id as usize) < log_modules;
+            let hint = match (uri.next(), event_kind) {
+                (None, Write) => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                (None, Read) => ReplySizeHint::immediate(SerDesSize::Sized(2 + 3), SerDesSize::Sized(2), Ok(())),
+                (None, _) => return not_supported,
+                (Some(module), Write) if is_module(module) => {
+                    ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(()))
+                }
+                (Some(module), Read) if is_module(module) => {
+                    ReplySizeHint::immediate(SerDesSize::Sized(2 + 3), SerDesSize::Sized(2), Ok(()))
+                }
+                (Some(module), _) if is_module(module) => return not_supported,
+                _ => return bad_uri,
+            };
+            match uri.next() {
+                None => hint,
+                Some(_) => bad_uri,
+            }
+        }
+        Some(AUTH_RESOURCE) => {
+            // /main/auth : challenge returns a nonce, respond takes HMAC-SHA256(psk, nonce)
+            let hint = match (uri.next(), event_kind) {
+                (Some(0), Call) => ReplySizeHint::immediate(
+                    SerDesSize::Sized(auth::NONCE_LEN * 2 + 3),
+                    SerDesSize::Sized(auth::NONCE_LEN * 2),
+                    Ok(())
+                ),
+                (Some(1), Call) => ReplySizeHint::immediate(SerDesSize::Sized(3), SerDesSize::Sized(0), Ok(())),
+                (Some(0 | 1), _) => return not_supported,
+                _ => return bad_uri,
+            };
+            match uri.next() {
+                None => hint,
+                Some(_) => bad_uri,
+            }
+        }
+        // /main : all defined resources are handled
+        Some(_) => bad_uri,
+    }
+}
@@ -0,0 +1,111 @@
+#![no_std]
+
+//! ECBridge xPI dispatcher: answers requests addressed to the bridge itself and publishes updates
+//! of observable resources to their subscribers.
+//!
+//! Everything the dispatcher touches outside of the event being dispatched is behind the [Node]
+//! trait, implemented by the firmware over its RTIC shared resources and by ecbridge_host over a
+//! plain struct, so that the same request handling runs on the board and on Linux.
+//! Replies go to a [link::LinkTx], the tx side of the link the request arrived on. Events for other
+//! nodes are sent on by the [router::Router].
+
+pub mod auth;
+pub mod config;
+pub mod dispatch;
+pub mod link;
+pub mod log_record;
+pub mod node_table;
+pub mod router;
+pub mod subscriptions;
+
+pub use dispatch::{
+    is_authenticated, publish_logs, publish_updates, xpi_dispatch, Dispatched, HEARTBEAT_RESOURCE, LINK_RESOURCE, LOG_RESOURCE,
+    PING_STATS_RESOURCE, TIME_RESOURCE,
+};
+
+use auth::Sessions;
+use config::{Config, ConfigState};
+use ecbridge_net::ping::PingStats;
+use ecbridge_net::sntp::SntpStatus;
+use link::LinkState;
+use node_table::NodeTable;
+use smoltcp::time::Instant;
+use smoltcp::wire::IpAddress;
+use subscriptions::Subscriptions;
+use vhl_cg::point::Point;
+use xpi::error::XpiError;
+
+/// Longer crash messages are truncated
+pub const CRASH_MESSAGE_MAX: usize = 128;
+
+/// Resources and side effects of the node the dispatcher answers for.
+///
+/// Methods taking a closure hold the resource for the duration of the call, like `rtic::Mutex::lock`,
+/// they must not be nested.
+pub trait Node {
+    fn config<R>(&mut self, f: impl FnOnce(&mut ConfigState) -> R) -> R;
+    fn sessions<R>(&mut self, f: impl FnOnce(&mut Sessions) -> R) -> R;
+    fn subscriptions<R>(&mut self, f: impl FnOnce(&mut Subscriptions) -> R) -> R;
+    fn node_table<R>(&mut self, f: impl FnOnce(&mut NodeTable) -> R) -> R;
+
+    /// /digit
+    fn digit(&mut self) -> u8;
+    /// Write to /digit, shown right away
+    fn write_digit(&mut self, digit: u8);
+    /// Call to /set_digit, may be shown after the reply is sent
+    fn set_digit(&mut self, digit: u8);
+    /// Call to /async, replied to later by whoever executes it
+    fn async_call(&mut self, p1: Point, p2: Point);
+
+    fn link_state(&mut self) -> LinkState;
+    /// rx_stalls and dispatch_stalls, served as /flow_stats
+    fn flow_stalls(&mut self) -> (u32, u32);
+    /// Nonces for authentication challenges
+    fn fill_random(&mut self, buf: &mut [u8]) -> Result<(), XpiError>;
+    /// Time since boot, same clock as the network stack
+    fn uptime(&self) -> Instant;
+    fn sntp_status(&self) -> SntpStatus;
+
+    /// Start the ping self-test, false if the previous one is still running
+    fn start_ping(&mut self, target: IpAddress, count: u8) -> bool;
+    fn ping_stats(&mut self) -> PingStats;
+
+    /// Report left by the previous run if it crashed
+    fn crash_report(&self) -> Option<CrashReport>;
+    /// Reset cause flags read at boot
+    fn reset_flags(&self) -> u32;
+
+    /// Amount of modules with their own log level, children of /log_filter
+    fn log_modules(&self) -> usize;
+    /// Global level if `module` is None, 0 (off) to 5 (trace), 0xFF for modules following the global level
+    fn log_level(&self, module: Option<usize>) -> Result<u8, XpiError>;
+    /// Takes effect right away and is lost on reset
+    fn set_log_level(&mut self, module: Option<usize>, level: u8) -> Result<(), XpiError>;
+
+    /// Call to /config/apply: persist `config` and restart with it
+    fn apply_config(&mut self, config: Config) -> Result<(), XpiError>;
+    /// Call to /config/factory_reset: forget config and node table, restart with the defaults
+    fn factory_reset(&mut self) -> Result<(), XpiError>;
+    /// Persist the node table after it was changed over /can_nodes
+    fn save_node_table(&mut self);
+}
+
+/// Served as /crash
+#[derive(Copy, Clone)]
+pub struct CrashReport {
+    /// 1 panic, 2 HardFault, 3 watchdog
+    pub kind: u8,
+    pub uptime_ms: u64,
+    /// Stacked registers: r0, r1, r2, r3, r12, lr, pc, xpsr, all zeroes if not caused by a fault
+    pub frame: [u32; 8],
+    pub message_len: u8,
+    pub message: [u8; CRASH_MESSAGE_MAX],
+}
+
+/// Must be called directly from dispatcher on Call to /sync
+pub fn sync(p1: Point, p2: Point) -> Point {
+    Point {
+        x: p1.x + p2.x,
+        y: p1.y + p2.y
+    }
+}
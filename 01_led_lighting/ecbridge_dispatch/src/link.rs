@@ -0,0 +1,114 @@
+//! Sending side of the links the dispatcher replies and publishes over.
+
+pub use ecbridge_net::envelope::LinkId;
+use log::trace;
+use xpi::error::XpiError;
+
+/// Longest serialized xPI event sent over any link, dispatcher buffers are that large
+pub const MTU_MAX: usize = 64;
+
+/// Sending side of a link, all the dispatcher needs to reply and publish updates
+pub trait LinkTx {
+    fn id(&self) -> LinkId;
+
+    /// Longest serialized event the link can carry, not more than MTU_MAX
+    fn mtu(&self) -> usize;
+
+    /// Whether tx queue can take at least one more event of MTU size.
+    ///
+    /// Checked before dispatching each batch of requests, every batch is replied to with one event,
+    /// so that replies are not lost. Dispatching stops when it returns false and the rest of
+    /// batches are dispatched once there is space again.
+    fn ready(&mut self) -> bool;
+
+    /// Put serialized event into tx queue and wake the transport.
+    fn submit(&mut self, event: &[u8]) -> Result<(), XpiError>;
+
+    /// Wake the transport task, to send queued events or to receive again after rx queue was full
+    fn wake(&self);
+}
+
+/// Tx sides of all the links of a node, updates go over the link their subscription was made on
+pub trait TxLinks {
+    fn get(&mut self, id: LinkId) -> Option<&mut dyn LinkTx>;
+}
+
+/// Tx side of byte oriented links (TCP, UART, RTT): events are framed with xpi_framing into a bbqueue
+pub struct StreamTx<const N: usize> {
+    id: LinkId,
+    prod: bbqueue::Producer<'static, N>,
+    mtu: usize,
+    wake: fn(),
+}
+
+impl<const N: usize> StreamTx<N> {
+    pub fn new(id: LinkId, prod: bbqueue::Producer<'static, N>, mtu: usize, wake: fn()) -> Self {
+        StreamTx { id, prod, mtu: mtu.min(MTU_MAX), wake }
+    }
+}
+
+impl<const N: usize> LinkTx for StreamTx<N> {
+    fn id(&self) -> LinkId {
+        self.id
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn ready(&mut self) -> bool {
+        // dropped grant is not committed
+        self.prod.grant_exact(xpi_framing::max_encoded_len(self.mtu)).is_ok()
+    }
+
+    fn submit(&mut self, event: &[u8]) -> Result<(), XpiError> {
+        let mut wgr = self.prod
+            .grant_exact(xpi_framing::max_encoded_len(event.len()))
+            .map_err(|_| XpiError::InternalBbqueueError)?;
+        let frame_len = xpi_framing::encode(event, &mut wgr)
+            .map_err(|_| XpiError::Internal)?;
+        trace!("commit {} to {:?}", frame_len, self.id);
+        wgr.commit(frame_len);
+        (self.wake)();
+        Ok(())
+    }
+
+    fn wake(&self) {
+        (self.wake)()
+    }
+}
+
+/// Ethernet link state as seen by the PHY, published as /link
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LinkState {
+    pub up: bool,
+    /// Negotiated speed in Mbit/s, 0 if link is down or unknown
+    pub speed: u8,
+    pub full_duplex: bool,
+    /// Times the link went down since boot
+    pub drops: u32,
+    /// Symbol errors counted by the PHY since boot
+    pub symbol_errors: u32,
+}
+
+impl LinkState {
+    pub const fn new() -> Self {
+        LinkState {
+            up: false,
+            speed: 0,
+            full_duplex: false,
+            drops: 0,
+            symbol_errors: 0,
+        }
+    }
+
+    /// `speed` is the negotiated speed in Mbit/s and duplex, None if the link is down
+    pub fn update(&mut self, speed: Option<(u8, bool)>) {
+        let up = speed.is_some();
+        if self.up && !up {
+            self.drops += 1;
+        }
+        self.up = up;
+        (self.speed, self.full_duplex) = speed.unwrap_or((0, false));
+    }
+}
@@ -0,0 +1,79 @@
+//! Log records queued by the node and published as /log stream updates by [crate::publish_logs].
+//!
+//! Queued record: level, uptime_ms (u64 BE), module name length, module name, message.
+
+use bbqueue::framed::FrameConsumer;
+
+pub const RECORD_HEADER_LEN: usize = 1 + 8 + 1;
+/// Longer module names are truncated, so that the header of a /log part always fits an update
+pub const MODULE_MAX: usize = 16;
+
+/// Consumer side of the record queue, owned by whoever publishes
+pub struct Backlog<'a, const N: usize> {
+    pub cons: FrameConsumer<'a, N>,
+    /// Message bytes of the oldest record already published, long messages are sent in parts
+    pub sent: usize,
+}
+
+/// Queued record split into its fields
+pub struct Record<'a> {
+    pub level: u8,
+    pub uptime_ms: u64,
+    pub module: &'a [u8],
+    pub message: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// Header of a record with `module` name truncated to MODULE_MAX, followed by the message.
+    /// Returns the header length.
+    pub fn header(level: u8, uptime_ms: u64, module: &[u8], buf: &mut [u8]) -> usize {
+        let module = &module[..module.len().min(MODULE_MAX)];
+        buf[0] = level;
+        buf[1..9].copy_from_slice(&uptime_ms.to_be_bytes());
+        buf[9] = module.len() as u8;
+        buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + module.len()].copy_from_slice(module);
+        RECORD_HEADER_LEN + module.len()
+    }
+
+    /// Records are only written with [Record::header], so they are always well formed
+    pub fn parse(record: &'a [u8]) -> Self {
+        let mut uptime_ms = [0u8; 8];
+        uptime_ms.copy_from_slice(&record[1..9]);
+        let module_end = RECORD_HEADER_LEN + record[9] as usize;
+        Record {
+            level: record[0],
+            uptime_ms: u64::from_be_bytes(uptime_ms),
+            module: &record[RECORD_HEADER_LEN..module_end],
+            message: &record[module_end..],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0u8; 64];
+        let header_len = Record::header(3, 0x0102_0304_0506_0708, b"can", &mut buf);
+        assert_eq!(header_len, RECORD_HEADER_LEN + 3);
+        buf[header_len..header_len + 2].copy_from_slice(b"hi");
+        let record = Record::parse(&buf[..header_len + 2]);
+        assert_eq!(record.level, 3);
+        assert_eq!(record.uptime_ms, 0x0102_0304_0506_0708);
+        assert_eq!(record.module, b"can");
+        assert_eq!(record.message, b"hi");
+    }
+
+    #[test]
+    fn long_module_is_truncated() {
+        let mut buf = [0u8; 64];
+        let module = [b'm'; MODULE_MAX + 30];
+        let header_len = Record::header(1, 0, &module, &mut buf);
+        assert_eq!(header_len, RECORD_HEADER_LEN + MODULE_MAX);
+        let record = Record::parse(&buf[..header_len]);
+        assert_eq!(record.module, &module[..MODULE_MAX]);
+        assert!(record.message.is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+//! CAN node id allocator: LED modules without an id ask for one with their unique id hash, the
+//! bridge answers with the id remembered for that module or with the lowest free one.
+//!
+//! Requests arrive over UAVCAN plug-and-play (xpi_can::AllocationData) and are answered by
+//! can_event. The table is persisted in its own flash sector by config_store after every change
+//! and exposed as /can_nodes, so that a replacement module can be given the id of the old one
+//! without reflashing anything. Changes are applied in RAM right away, the table stays dirty until
+//! config_store manages to save it.
+
+use serde::{Deserialize, Serialize};
+
+/// Modules remembered, also the amount of /can_nodes children
+pub const NODE_TABLE_LEN: usize = 16;
+/// Lower ids are left for nodes with a static id, e.g. the bridge itself
+const DYNAMIC_ID_MIN: u8 = 16;
+/// 126 and 127 are reserved for diagnostic tools by UAVCAN
+const DYNAMIC_ID_MAX: u8 = 125;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Allocation {
+    /// 48 bit hash of the module's unique hardware id
+    pub unique_id: u64,
+    pub node_id: u8,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Every slot is taken, one has to be freed over xPI
+    Full,
+    BadSlot,
+    /// Outside of 1..=127 or the bridge's own id
+    BadNodeId,
+    /// Another slot already has this node id or unique id
+    Taken,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeTable {
+    slots: [Option<Allocation>; NODE_TABLE_LEN],
+    /// Changed since the last save, not persisted
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl NodeTable {
+    pub const fn new() -> Self {
+        NodeTable {
+            slots: [None; NODE_TABLE_LEN],
+            dirty: false,
+        }
+    }
+
+    /// Copy to be saved if the table changed since the last save, it is clean afterwards.
+    /// If saving fails, [NodeTable::mark_dirty] must be called.
+    pub fn take_dirty(&mut self) -> Option<NodeTable> {
+        if core::mem::replace(&mut self.dirty, false) {
+            Some(*self)
+        } else {
+            None
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Node id for a module: remembered one or the lowest free one from the dynamic range.
+    /// Returns the id and whether the table changed and has to be persisted.
+    pub fn allocate(&mut self, unique_id: u64, self_node_id: u8) -> Result<(u8, bool), Error> {
+        let unique_id = unique_id & 0xFFFF_FFFF_FFFF;
+        if let Some(a) = self.slots.iter().flatten().find(|a| a.unique_id == unique_id) {
+            return Ok((a.node_id, false));
+        }
+        let slot = self.slots.iter().position(|s| s.is_none()).ok_or(Error::Full)?;
+        let node_id = (DYNAMIC_ID_MIN..=DYNAMIC_ID_MAX)
+            .find(|id| *id != self_node_id && !self.is_taken(*id, None))
+            .ok_or(Error::Full)?;
+        self.slots[slot] = Some(Allocation { unique_id, node_id });
+        self.dirty = true;
+        Ok((node_id, true))
+    }
+
+    pub fn get(&self, slot: usize) -> Result<Option<Allocation>, Error> {
+        self.slots.get(slot).copied().ok_or(Error::BadSlot)
+    }
+
+    /// Pin a module to an id, e.g. to give a replacement module the id of the broken one,
+    /// any id except the bridge's own can be used. None frees the slot.
+    pub fn set(&mut self, slot: usize, allocation: Option<Allocation>, self_node_id: u8) -> Result<(), Error> {
+        if slot >= NODE_TABLE_LEN {
+            return Err(Error::BadSlot);
+        }
+        if let Some(a) = allocation {
+            if a.node_id == 0 || a.node_id > xpi_can::NODE_ID_MAX || a.node_id == self_node_id {
+                return Err(Error::BadNodeId);
+            }
+            let unique_id_taken = self.slots.iter().enumerate()
+                .any(|(i, s)| i != slot && matches!(s, Some(s) if s.unique_id == a.unique_id));
+            if unique_id_taken || self.is_taken(a.node_id, Some(slot)) {
+                return Err(Error::Taken);
+            }
+        }
+        if self.slots[slot] != allocation {
+            self.slots[slot] = allocation;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn is_taken(&self, node_id: u8, except_slot: Option<usize>) -> bool {
+        self.slots.iter().enumerate()
+            .any(|(i, s)| Some(i) != except_slot && matches!(s, Some(s) if s.node_id == node_id))
+    }
+}
@@ -0,0 +1,425 @@
+//! Routes xPI events between links, so that clients on Ethernet can reach nodes behind CAN.
+//!
+//! Routes are learned from the source of every received event, the same way a switch learns
+//! MAC addresses, and from whatever a link knows about its nodes (e.g. bus heartbeats).
+//! Only events for the bridge itself are dispatched locally. Unicast events for nodes behind
+//! another link are forwarded there as is, so their replies come back over the learned route to
+//! the original client. Requests for nodes without a route are sent over every other link, the
+//! route is learned from the reply. Broadcasts are dispatched locally and fan out to every other link.
+//!
+//! Loop prevention: nothing is sent back over the link it arrived on, events with bridge's own
+//! source are dropped and so are broadcasts already forwarded during the last DUPLICATE_WINDOW.
+//! A broadcast is recognized by its source, request id and frame CRC.
+//!
+//! When a PSK is configured, requests other than /auth from a connection that hasn't authenticated
+//! are dropped before anything is learned from them, wherever they are addressed to.
+
+use crate::dispatch::is_auth_request;
+use crate::link::LinkId;
+use ecbridge_net::envelope::Envelope;
+use smoltcp::time::{Duration, Instant};
+use xpi::xwfd::{Event, EventKind, NodeId, NodeSet, RequestId};
+
+pub const ROUTES: usize = 16;
+/// Broadcasts remembered for duplicate detection
+const BROADCAST_HISTORY: usize = 8;
+/// Same broadcast arriving again within that time is considered a loop
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Decision {
+    /// Addressed to the bridge
+    Local,
+    /// Send the frame as is over the link the destination is behind
+    Forward(LinkId),
+    /// Request for a node without a route, send the frame over every other link
+    Flood,
+    /// Dispatch locally and send the frame over every other link
+    Broadcast,
+    Drop(DropReason),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DropReason {
+    /// Destination is behind the link the event arrived on, it has already received it
+    SameLink,
+    /// Bridge's own event came back
+    OwnSource,
+    DuplicateBroadcast,
+    /// Not a request and there is nobody to forward it to, e.g. a reply to a closed connection
+    NoRoute,
+    /// Request other than /auth from a connection that hasn't authenticated yet
+    NotAuthenticated,
+}
+
+/// Broadcast remembered for duplicate detection
+#[derive(Copy, Clone, Debug)]
+struct SeenBroadcast {
+    source: NodeId,
+    request_id: RequestId,
+    crc: u16,
+    at: Instant,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Route {
+    node: NodeId,
+    link: LinkId,
+    seen_at: Instant,
+}
+
+pub struct Router {
+    routes: [Option<Route>; ROUTES],
+    broadcasts: [Option<SeenBroadcast>; BROADCAST_HISTORY],
+    next_broadcast: usize,
+}
+
+impl Router {
+    pub const fn new() -> Self {
+        Router {
+            routes: [None; ROUTES],
+            broadcasts: [None; BROADCAST_HISTORY],
+            next_broadcast: 0,
+        }
+    }
+
+    /// Remember that `node` is behind `link`, least recently seen route is replaced when the table is full.
+    pub fn learn(&mut self, node: NodeId, link: LinkId, now: Instant) {
+        let idx = self.routes.iter().position(|r| matches!(r, Some(r) if r.node == node))
+            .or_else(|| self.routes.iter().position(|r| r.is_none()))
+            .unwrap_or_else(|| {
+                let oldest = self.routes.iter().enumerate().min_by_key(|(_, r)| r.map(|r| r.seen_at));
+                oldest.map(|(idx, _)| idx).unwrap_or(0)
+            });
+        self.routes[idx] = Some(Route { node, link, seen_at: now });
+    }
+
+    pub fn lookup(&self, node: NodeId) -> Option<LinkId> {
+        self.routes.iter().flatten().find(|r| r.node == node).map(|r| r.link)
+    }
+
+    /// Drop all the routes over `link`, when its nodes are gone. Returns how many were removed.
+    pub fn forget(&mut self, link: LinkId) -> usize {
+        let mut count = 0;
+        for slot in self.routes.iter_mut() {
+            if matches!(slot, Some(r) if r.link == link) {
+                *slot = None;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Learn the source route and decide what to do with an event.
+    /// `frame` is the serialized event, used to recognize looped broadcasts. `authenticated` is
+    /// whether the session of the connection the event arrived on is, see [crate::is_authenticated].
+    pub fn route(
+        &mut self,
+        ev: &Event,
+        frame: &[u8],
+        envelope: &Envelope,
+        self_node_id: NodeId,
+        authenticated: bool,
+    ) -> Decision {
+        // replies and updates are let through, nodes on a bus never authenticate
+        if !authenticated && is_request(&ev.kind) && !is_auth_request(ev) {
+            return Decision::Drop(DropReason::NotAuthenticated);
+        }
+        if ev.source == self_node_id {
+            return Decision::Drop(DropReason::OwnSource);
+        }
+        self.learn(ev.source, envelope.link, envelope.received_at);
+        match ev.destination {
+            NodeSet::Unicast(id) if id == self_node_id => Decision::Local,
+            NodeSet::Unicast(id) => match self.lookup(id) {
+                Some(link) if link == envelope.link => Decision::Drop(DropReason::SameLink),
+                Some(link) => Decision::Forward(link),
+                None if is_request(&ev.kind) => Decision::Flood,
+                None => Decision::Drop(DropReason::NoRoute),
+            },
+            NodeSet::Broadcast { .. } => {
+                let seen = SeenBroadcast {
+                    source: ev.source,
+                    request_id: ev.request_id,
+                    crc: xpi_framing::crc16(frame),
+                    at: envelope.received_at,
+                };
+                if self.seen_broadcast(seen) {
+                    Decision::Drop(DropReason::DuplicateBroadcast)
+                } else {
+                    Decision::Broadcast
+                }
+            }
+            _ => Decision::Local,
+        }
+    }
+
+    /// Whether the same broadcast was seen recently, remembers it otherwise
+    fn seen_broadcast(&mut self, broadcast: SeenBroadcast) -> bool {
+        let seen = self.broadcasts.iter().flatten().any(|b| {
+            b.source == broadcast.source
+                && b.request_id == broadcast.request_id
+                && b.crc == broadcast.crc
+                && broadcast.at - b.at < DUPLICATE_WINDOW
+        });
+        if !seen {
+            self.broadcasts[self.next_broadcast] = Some(broadcast);
+            self.next_broadcast = (self.next_broadcast + 1) % BROADCAST_HISTORY;
+        }
+        seen
+    }
+}
+
+/// Events the local dispatcher answers
+fn is_request(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Call { .. } | EventKind::Read | EventKind::Write { .. } | EventKind::Subscribe { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vhl_stdlib::discrete::{U2Sp1, U4};
+    use vhl_stdlib::serdes::{NibbleBuf, NibbleBufMut, SerializeVlu4};
+    use xpi::error::XpiError;
+    use xpi::event_kind::XpiEventDiscriminant;
+    use xpi::xwfd::{EventBuilder, Priority, ResourceSet, SerialUri};
+    use crate::dispatch::AUTH_RESOURCE;
+    use ecbridge_net::envelope::Endpoint;
+
+    const SELF: u8 = 1;
+    const CLIENT: u8 = 33;
+    const LED: u8 = 5;
+
+    #[derive(Copy, Clone)]
+    enum To {
+        Node(u8),
+        /// From the original source
+        Broadcast(u8),
+    }
+
+    fn node(id: u8) -> NodeId {
+        NodeId::new(id).unwrap()
+    }
+
+    /// Read of root level resource or empty ReadResults
+    #[derive(Copy, Clone)]
+    enum Kind {
+        Read(u8),
+        Results,
+    }
+
+    /// Serialize event into `buf`, returns its length
+    fn event(buf: &mut [u8], source: u8, destination: To, request_id: u8, kind: Kind) -> usize {
+        let resource = match kind {
+            Kind::Read(resource) => resource,
+            Kind::Results => 1,
+        };
+        let builder = EventBuilder::new(
+            NibbleBufMut::new_all(buf),
+            node(source),
+            RequestId::new(request_id).unwrap(),
+            Priority::Lossy(U2Sp1::new(1).unwrap()),
+            U4::new(15).unwrap(),
+        )
+        .unwrap();
+        let builder = builder
+            .build_node_set_with(|mut nwr| {
+                let node_set = match destination {
+                    To::Node(id) => NodeSet::Unicast(node(id)),
+                    To::Broadcast(original_source) => NodeSet::Broadcast { original_source: node(original_source) },
+                };
+                node_set.ser_vlu4(&mut nwr)?;
+                Ok((node_set.ser_header(), nwr))
+            })
+            .unwrap();
+        let builder = builder
+            .build_resource_set_with(|mut nwr| {
+                let resource_set = ResourceSet::Uri(SerialUri::OnePart4(U4::new(resource).unwrap()));
+                resource_set.ser_vlu4(&mut nwr)?;
+                Ok((resource_set.ser_header(), nwr))
+            })
+            .unwrap();
+        let nwr = builder
+            .build_kind_with(|nwr| match kind {
+                Kind::Read(_) => Ok::<_, XpiError>((XpiEventDiscriminant::Read, nwr)),
+                Kind::Results => {
+                    let vb = nwr.put_vec::<Result<NibbleBuf, XpiError>>();
+                    Ok((XpiEventDiscriminant::ReadResults, vb.finish()?))
+                }
+            })
+            .unwrap();
+        let (_, len, _) = nwr.finish();
+        len
+    }
+
+    /// Route `frame` as if received over `link` at `at_ms`
+    fn route_frame(router: &mut Router, frame: &[u8], link: LinkId, at_ms: i64, authenticated: bool) -> Decision {
+        let ev: Event = NibbleBuf::new_all(frame).des_vlu4().unwrap();
+        let envelope = Envelope {
+            link,
+            connection: 0,
+            received_at: Instant::from_millis(at_ms),
+            endpoint: Endpoint::None,
+        };
+        router.route(&ev, frame, &envelope, node(SELF), authenticated)
+    }
+
+    /// Read of /1 if `request`, empty ReadResults otherwise, from an authenticated session
+    fn route(
+        router: &mut Router,
+        source: u8,
+        destination: To,
+        request_id: u8,
+        request: bool,
+        link: LinkId,
+        at_ms: i64,
+    ) -> Decision {
+        let mut buf = [0u8; 64];
+        let kind = if request { Kind::Read(1) } else { Kind::Results };
+        let len = event(&mut buf, source, destination, request_id, kind);
+        route_frame(router, &buf[..len], link, at_ms, true)
+    }
+
+    fn unicast(id: u8) -> To {
+        To::Node(id)
+    }
+
+    fn broadcast(original_source: u8) -> To {
+        To::Broadcast(original_source)
+    }
+
+    #[test]
+    fn local() {
+        let mut router = Router::new();
+        assert_eq!(route(&mut router, CLIENT, unicast(SELF), 1, true, LinkId::Ethernet, 0), Decision::Local);
+        assert_eq!(router.lookup(node(CLIENT)), Some(LinkId::Ethernet));
+    }
+
+    #[test]
+    fn learn_and_forward() {
+        let mut router = Router::new();
+        // reply from the LED node teaches the route back to it
+        assert_eq!(route(&mut router, CLIENT, unicast(LED), 1, true, LinkId::Ethernet, 0), Decision::Flood);
+        assert_eq!(
+            route(&mut router, LED, unicast(CLIENT), 1, false, LinkId::Can, 10),
+            Decision::Forward(LinkId::Ethernet)
+        );
+        assert_eq!(
+            route(&mut router, CLIENT, unicast(LED), 2, true, LinkId::Ethernet, 20),
+            Decision::Forward(LinkId::Can)
+        );
+        // node moved to another link
+        router.learn(node(LED), LinkId::Uart, Instant::from_millis(30));
+        assert_eq!(
+            route(&mut router, CLIENT, unicast(LED), 3, true, LinkId::Ethernet, 40),
+            Decision::Forward(LinkId::Uart)
+        );
+    }
+
+    #[test]
+    fn flood_on_unknown() {
+        let mut router = Router::new();
+        assert_eq!(route(&mut router, CLIENT, unicast(LED), 1, true, LinkId::Ethernet, 0), Decision::Flood);
+        // nobody asked for it
+        assert_eq!(
+            route(&mut router, CLIENT, unicast(LED), 1, false, LinkId::Ethernet, 0),
+            Decision::Drop(DropReason::NoRoute)
+        );
+    }
+
+    #[test]
+    fn same_link() {
+        let mut router = Router::new();
+        router.learn(node(LED), LinkId::Can, Instant::from_millis(0));
+        assert_eq!(
+            route(&mut router, LED + 1, unicast(LED), 1, true, LinkId::Can, 10),
+            Decision::Drop(DropReason::SameLink)
+        );
+    }
+
+    #[test]
+    fn own_source() {
+        let mut router = Router::new();
+        assert_eq!(
+            route(&mut router, SELF, unicast(CLIENT), 1, false, LinkId::Can, 0),
+            Decision::Drop(DropReason::OwnSource)
+        );
+        // not learned, replies to the bridge must not be sent to CAN
+        assert_eq!(router.lookup(node(SELF)), None);
+    }
+
+    #[test]
+    fn duplicate_broadcasts() {
+        let mut router = Router::new();
+        assert_eq!(route(&mut router, LED, broadcast(LED), 7, false, LinkId::Can, 0), Decision::Broadcast);
+        // came back over another link
+        assert_eq!(
+            route(&mut router, LED, broadcast(LED), 7, false, LinkId::Ethernet, 999),
+            Decision::Drop(DropReason::DuplicateBroadcast)
+        );
+        // another broadcast of the same node
+        assert_eq!(route(&mut router, LED, broadcast(LED), 8, false, LinkId::Can, 500), Decision::Broadcast);
+        // same one sent again later
+        assert_eq!(route(&mut router, LED, broadcast(LED), 7, false, LinkId::Can, 1000), Decision::Broadcast);
+        assert_eq!(
+            route(&mut router, LED, broadcast(LED), 7, false, LinkId::Ethernet, 1500),
+            Decision::Drop(DropReason::DuplicateBroadcast)
+        );
+    }
+
+    #[test]
+    fn least_recently_seen_route_is_replaced() {
+        let mut router = Router::new();
+        for i in 0..ROUTES as u8 {
+            router.learn(node(10 + i), LinkId::Can, Instant::from_millis(i as i64));
+        }
+        // first one seen again, second one is the oldest now
+        router.learn(node(10), LinkId::Can, Instant::from_millis(100));
+        router.learn(node(CLIENT), LinkId::Ethernet, Instant::from_millis(101));
+        assert_eq!(router.lookup(node(10)), Some(LinkId::Can));
+        assert_eq!(router.lookup(node(11)), None);
+        assert_eq!(router.lookup(node(CLIENT)), Some(LinkId::Ethernet));
+        for i in 2..ROUTES as u8 {
+            assert_eq!(router.lookup(node(10 + i)), Some(LinkId::Can));
+        }
+    }
+
+    #[test]
+    fn forget() {
+        let mut router = Router::new();
+        router.learn(node(CLIENT), LinkId::Ethernet, Instant::from_millis(0));
+        router.learn(node(LED), LinkId::Can, Instant::from_millis(0));
+        assert_eq!(router.forget(LinkId::Ethernet), 1);
+        assert_eq!(router.lookup(node(CLIENT)), None);
+        assert_eq!(router.lookup(node(LED)), Some(LinkId::Can));
+    }
+
+    #[test]
+    fn not_authenticated() {
+        let mut router = Router::new();
+        router.learn(node(LED), LinkId::Can, Instant::from_millis(0));
+        let mut buf = [0u8; 64];
+        let len = event(&mut buf, CLIENT, unicast(LED), 1, Kind::Read(1));
+        assert_eq!(
+            route_frame(&mut router, &buf[..len], LinkId::Ethernet, 10, false),
+            Decision::Drop(DropReason::NotAuthenticated)
+        );
+        // nor learned, replies must not go to a client that can't send requests
+        assert_eq!(router.lookup(node(CLIENT)), None);
+        assert_eq!(route_frame(&mut router, &buf[..len], LinkId::Ethernet, 20, true), Decision::Forward(LinkId::Can));
+
+        let len = event(&mut buf, CLIENT, unicast(SELF), 2, Kind::Read(AUTH_RESOURCE as u8));
+        assert_eq!(route_frame(&mut router, &buf[..len], LinkId::Ethernet, 30, false), Decision::Local);
+        let len = event(&mut buf, CLIENT, broadcast(CLIENT), 3, Kind::Read(1));
+        assert_eq!(
+            route_frame(&mut router, &buf[..len], LinkId::Ethernet, 40, false),
+            Decision::Drop(DropReason::NotAuthenticated)
+        );
+        // nodes on a bus never authenticate, their replies get through
+        let len = event(&mut buf, LED, unicast(CLIENT), 1, Kind::Results);
+        assert_eq!(route_frame(&mut router, &buf[..len], LinkId::Can, 50, false), Decision::Forward(LinkId::Ethernet));
+    }
+}
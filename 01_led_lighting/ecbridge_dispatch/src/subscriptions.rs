@@ -0,0 +1,89 @@
+//! Subscriptions to observable resources.
+//!
+//! Tasks changing an observable resource only mark it as dirty and spawn link_process,
+//! which owns the tx side of all links and emits StreamUpdates to every subscriber of dirty resources,
+//! over the link the subscription was made on.
+
+use ecbridge_net::envelope::LinkId;
+use xpi::error::XpiError;
+use xpi::xwfd::{NodeId, Priority, RequestId};
+
+pub const MAX_SUBSCRIPTIONS: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Subscription {
+    /// Root level resource id
+    pub resource: u32,
+    pub subscriber: NodeId,
+    /// Updates go back over the link the Subscribe request arrived on
+    pub link: LinkId,
+    /// Connection id of the Subscribe request, updates are sent while its session is authenticated
+    pub connection: u16,
+    /// Updates are sent with the request id and priority of the Subscribe request
+    pub request_id: RequestId,
+    pub priority: Priority,
+}
+
+pub struct Subscriptions {
+    slots: [Option<Subscription>; MAX_SUBSCRIPTIONS],
+    /// Bit per root level resource id
+    dirty: u32,
+}
+
+impl Subscriptions {
+    pub const fn new() -> Self {
+        Subscriptions {
+            slots: [None; MAX_SUBSCRIPTIONS],
+            dirty: 0,
+        }
+    }
+
+    /// Add a subscription or renew an existing one from the same subscriber on the same link.
+    /// Current value is sent right away.
+    pub fn subscribe(&mut self, subscription: Subscription) -> Result<(), XpiError> {
+        let existing = self.slots.iter().position(|s| matches!(s,
+            Some(s) if s.resource == subscription.resource
+                && s.subscriber == subscription.subscriber
+                && s.link == subscription.link
+        ));
+        let idx = existing
+            .or_else(|| self.slots.iter().position(|s| s.is_none()))
+            .ok_or(XpiError::OperationNotSupported)?;
+        self.slots[idx] = Some(subscription);
+        self.notify(subscription.resource);
+        Ok(())
+    }
+
+    /// Mark resource as changed, link_process must be spawned afterwards.
+    pub fn notify(&mut self, resource: u32) {
+        self.dirty |= 1 << resource;
+    }
+
+    /// Resources changed since the last call
+    pub fn take_dirty(&mut self) -> u32 {
+        core::mem::replace(&mut self.dirty, 0)
+    }
+
+    /// Mark resources as changed again, when not all the updates could be sent
+    pub fn restore_dirty(&mut self, resources: u32) {
+        self.dirty |= resources;
+    }
+
+    /// Remove all the subscriptions made over `link`, when their subscribers are gone.
+    /// Returns how many were removed.
+    pub fn clear(&mut self, link: LinkId) -> usize {
+        let mut count = 0;
+        for slot in self.slots.iter_mut() {
+            if matches!(slot, Some(s) if s.link == link) {
+                *slot = None;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Copy of all the subscriptions, so that the lock is not held while sending updates
+    pub fn all(&self) -> [Option<Subscription>; MAX_SUBSCRIPTIONS] {
+        self.slots
+    }
+}
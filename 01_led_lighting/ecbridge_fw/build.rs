@@ -28,4 +28,24 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // defmt symbols are placed by its own linker script, only linked in with a defmt logger
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    // Hash of the vhL schema, announced over mDNS so that clients can tell whether the
+    // node API matches theirs before connecting.
+    let vhl = std::fs::read("vhl/main.vhl").unwrap();
+    println!("cargo:rustc-env=VHL_SCHEMA_HASH={:08x}", fnv1a32(&vhl));
+    println!("cargo:rerun-if-changed=vhl/main.vhl");
+}
+
+fn fnv1a32(data: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in data {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
 }
\ No newline at end of file
@@ -7,6 +7,8 @@ MEMORY
   /* STM32H7A3xI/7B3xI             */
   //FLASH  : ORIGIN = 0x08000000, LENGTH = 2M
   FLASH  : ORIGIN = 0x08000000, LENGTH = 256K
+  /* Bank 2 sector 7 (0x081E0000, 128K) is reserved for config, see src/config.rs */
+  /* Bank 2 sector 6 (0x081C0000, 128K) is reserved for the CAN node table, see src/config.rs */
 
   /* STM32H742xG/743xG       */
   /* STM32H745xG/STM32H747xG */
@@ -57,6 +59,11 @@ SECTIONS {
     *(.sram3 .sram3.*);
     . = ALIGN(4);
     } > SRAM3
+  /* Survives resets other than power loss, crash report, see src/crash.rs */
+  .noinit (NOLOAD) : ALIGN(8) {
+    *(.noinit .noinit.*);
+    . = ALIGN(8);
+    } > SRAM4
   .sram4 (NOLOAD) : ALIGN(4) {
     *(.sram4 .sram4.*);
     . = ALIGN(4);
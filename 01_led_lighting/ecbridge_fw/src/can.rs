@@ -0,0 +1,384 @@
+//! xPI over CAN FD on FDCAN1, UAVCAN v1 (Cyphal/CAN) style transfers from xpi_can.
+//!
+//! can_event runs on FDCAN1 interrupts. Received frames are reassembled into transfers, which are
+//! put into can_rx queue behind an [Envelope] with the source node id for link_process.
+//! Events queued by link_process into can_tx queue (framed, one event per record) are split into
+//! frames and sent as soon as TX FIFO has space, the record is released after its last frame.
+//!
+//! All xPI events are messages on XPI_SUBJECT_ID, destination is inside the event.
+//! With log-text-can or log-defmt-can, records from [crate::can_log] are published on the log
+//! subjects after queued events.
+//! CAN node id of the bridge is its xPI node id, modules get theirs from the bridge over
+//! plug-and-play allocation, see [crate::node_table].
+
+use core::num::{NonZeroU16, NonZeroU8};
+use bbqueue::framed::{FrameConsumer, FrameProducer};
+use ecbridge_net::envelope::{self, Endpoint, Envelope, ENVELOPE_MAX};
+use fdcan::config::{DataBitTiming, FrameTransmissionConfig, NominalBitTiming, TxBufferMode};
+use fdcan::filter::{ExtendedFilter, ExtendedFilterSlot};
+use fdcan::frame::{FrameFormat, TxFrameHeader};
+use fdcan::id::{ExtendedId, Id};
+use fdcan::interrupt::{Interrupt, InterruptLine};
+use rtic::Mutex;
+use smoltcp::time::Instant;
+use stm32h7xx_hal::can::CanExt;
+use stm32h7xx_hal::gpio::{gpiod, Alternate};
+use stm32h7xx_hal::{rcc::rec, stm32};
+use xpi::error::XpiError;
+use xpi_can::{
+    AllocationData, CanId, Kind, Reassembler, Segmenter, TransferIdCounter, ALLOCATION_DATA_LEN_MAX, LOG_DEFMT_SUBJECT_ID,
+    LOG_PRIORITY, LOG_TEXT_SUBJECT_ID, MTU_FD, PNP_SUBJECT_ID, XPI_SUBJECT_ID,
+};
+use crate::config::StoreOp;
+use crate::vhlink::{LinkId, LinkTx, MTU_MAX};
+use crate::{debug, info, log_warn, trace};
+
+const T: u8 = 2;
+
+/// can_rx (enveloped transfers) and can_tx (events) queues between can_event and link_process
+pub const CAN_QUEUE_LEN: usize = 512;
+/// Longest transfer reassembled or sent, events forwarded from other links can be longer than MTU_MAX
+pub const TRANSFER_MAX: usize = 256;
+/// Nodes sending multi-frame transfers at the same time
+const SESSIONS: usize = 8;
+const PRIORITY: u8 = 4;
+
+pub type Can = fdcan::FdCan<stm32h7xx_hal::can::Can<stm32::FDCAN1>, fdcan::NormalOperationMode>;
+
+/// 1 Mbit/s arbitration phase from 80MHz PLL1_Q, sample point at 80%
+const fn nominal_bit_timing() -> NominalBitTiming {
+    NominalBitTiming {
+        prescaler: unsafe { NonZeroU16::new_unchecked(1) },
+        seg1: unsafe { NonZeroU8::new_unchecked(63) },
+        seg2: unsafe { NonZeroU8::new_unchecked(16) },
+        sync_jump_width: unsafe { NonZeroU8::new_unchecked(16) },
+    }
+}
+
+/// 2 Mbit/s data phase, sample point at 75%
+const fn data_bit_timing() -> DataBitTiming {
+    DataBitTiming {
+        transceiver_delay_compensation: true,
+        prescaler: unsafe { NonZeroU8::new_unchecked(1) },
+        seg1: unsafe { NonZeroU8::new_unchecked(29) },
+        seg2: unsafe { NonZeroU8::new_unchecked(10) },
+        sync_jump_width: unsafe { NonZeroU8::new_unchecked(10) },
+    }
+}
+
+pub fn init(
+    fdcan1: stm32::FDCAN1,
+    rx: gpiod::PD0<Alternate<9>>,
+    tx: gpiod::PD1<Alternate<9>>,
+    prec: rec::Fdcan,
+) -> Can {
+    let prec = prec.kernel_clk_mux(rec::FdcanClkSel::PLL1_Q);
+    let mut can = fdcan1.fdcan(tx, rx, prec);
+    can.set_protocol_exception_handling(false);
+    can.set_nominal_bit_timing(nominal_bit_timing());
+    can.set_data_bit_timing(data_bit_timing());
+    // only extended ids are used by UAVCAN v1
+    can.set_extended_filter(ExtendedFilterSlot::_0, ExtendedFilter::accept_all_into_fifo0());
+    let config = can.get_config()
+        .set_frame_transmit(FrameTransmissionConfig::AllowFdCanAndBRS)
+        // frames of a transfer share the same id and must go out in order
+        .set_tx_buffer_mode(TxBufferMode::Fifo);
+    can.apply_config(config);
+    can.enable_interrupt(Interrupt::RxFifo0NewMsg);
+    can.enable_interrupt(Interrupt::TxFifoEmpty);
+    can.enable_interrupt(Interrupt::BusOff);
+    can.enable_interrupt_line(InterruptLine::_0, true);
+    info!(=>T, "FDCAN1 1/2 Mbit/s");
+    can.into_normal()
+}
+
+/// can_event state that is not a queue
+pub struct CanState {
+    reassembler: Reassembler<TRANSFER_MAX, SESSIONS>,
+    transfer_id: TransferIdCounter,
+    pnp_transfer_id: TransferIdCounter,
+    /// Frames of the first event in can_tx queue that are already in TX FIFO
+    frames_sent: usize,
+    log_text_transfer_id: TransferIdCounter,
+    log_defmt_transfer_id: TransferIdCounter,
+    /// Same as frames_sent, for the first record in can_log queue
+    log_frames_sent: usize,
+}
+
+impl CanState {
+    pub const fn new() -> Self {
+        CanState {
+            reassembler: Reassembler::new(),
+            transfer_id: TransferIdCounter::new(),
+            pnp_transfer_id: TransferIdCounter::new(),
+            frames_sent: 0,
+            log_text_transfer_id: TransferIdCounter::new(),
+            log_defmt_transfer_id: TransferIdCounter::new(),
+            log_frames_sent: 0,
+        }
+    }
+}
+
+/// Tx side of the CAN link, link_process puts whole events, can_event splits them into frames
+pub struct CanTx {
+    prod: FrameProducer<'static, CAN_QUEUE_LEN>,
+}
+
+impl CanTx {
+    pub fn new(prod: FrameProducer<'static, CAN_QUEUE_LEN>) -> Self {
+        CanTx { prod }
+    }
+}
+
+impl LinkTx for CanTx {
+    fn id(&self) -> LinkId {
+        LinkId::Can
+    }
+
+    fn mtu(&self) -> usize {
+        MTU_MAX
+    }
+
+    fn ready(&mut self) -> bool {
+        // dropped grant is not committed
+        self.prod.grant(MTU_MAX).is_ok()
+    }
+
+    fn submit(&mut self, event: &[u8]) -> Result<(), XpiError> {
+        if event.len() > TRANSFER_MAX {
+            return Err(XpiError::Internal);
+        }
+        let mut wgr = self.prod.grant(event.len()).map_err(|_| XpiError::InternalBbqueueError)?;
+        wgr[..event.len()].copy_from_slice(event);
+        trace!("commit {} to {:?}", event.len(), LinkId::Can);
+        wgr.commit(event.len());
+        wake();
+        Ok(())
+    }
+
+    fn wake(&self) {
+        wake()
+    }
+}
+
+/// Wake-up hook of the CAN link: events were queued into can_tx or space was freed in can_rx
+pub fn wake() {
+    rtic::pend(stm32::Interrupt::FDCAN1_IT0);
+}
+
+pub fn can_event(mut ctx: crate::app::can_event::Context) {
+    let _busy = crate::supervisor::busy(crate::supervisor::Task::CanEvent);
+    let can: &mut Can = ctx.local.can;
+    let state: &mut CanState = ctx.local.can_state;
+    let now = Instant::from_micros(
+        crate::app::monotonics::now().duration_since_epoch().to_micros() as i64
+    );
+    let self_node_id = ctx.shared.config.lock(|c| c.active.node_id) & xpi_can::NODE_ID_MAX;
+
+    if can.has_interrupt(Interrupt::BusOff) {
+        can.clear_interrupt(Interrupt::BusOff);
+        log_warn!(=>T, "bus-off");
+        state.reassembler.reset();
+        // nodes will be learned again once the bus is back
+        ctx.shared.router.lock(|r| r.forget(LinkId::Can));
+    }
+    can.clear_interrupt(Interrupt::RxFifo0NewMsg);
+    can.clear_interrupt(Interrupt::TxFifoEmpty);
+
+    let (received, rx_stalled) = receive(can, state, ctx.local.can_rx_prod, &mut ctx.shared, self_node_id, now);
+    if received {
+        let _ = crate::app::link_process::spawn();
+    }
+    let tx_released = transmit(can, state, ctx.local.can_tx_cons, self_node_id);
+    // no logging from here on, it would pend can_event again
+    transmit_logs(can, state, ctx.local.can_log_cons, self_node_id);
+
+    let resume_dispatch = ctx.shared.flow_stats.lock(|s| {
+        if rx_stalled && !s.can_rx_stalled {
+            s.rx_stalls += 1;
+            log_warn!(=>T, "can_rx queue is full, pausing rx ({} stalls)", s.rx_stalls);
+        }
+        s.can_rx_stalled = rx_stalled;
+        s.dispatch_stalled && tx_released
+    });
+    if resume_dispatch {
+        let _ = crate::app::link_process::spawn();
+    }
+}
+
+/// Reassemble frames from RX FIFO 0 into can_rx queue.
+/// Returns whether any transfers were queued and whether stopped because the queue is full.
+fn receive(
+    can: &mut Can,
+    state: &mut CanState,
+    prod: &mut FrameProducer<'static, CAN_QUEUE_LEN>,
+    shared: &mut crate::app::can_event::SharedResources,
+    self_node_id: u8,
+    now: Instant,
+) -> (bool, bool) {
+    let mut received = false;
+    let mut frame = [0u8; MTU_FD];
+    loop {
+        // a frame can complete a transfer of any length, don't take it out of the FIFO before there is space
+        if prod.grant(ENVELOPE_MAX + TRANSFER_MAX).is_err() {
+            return (received, true);
+        }
+        let info = match can.receive0(&mut frame) {
+            Ok(overrun) => overrun.unwrap(),
+            Err(_) => return (received, false),
+        };
+        let raw_id = match info.id {
+            Id::Extended(id) => id.as_raw(),
+            Id::Standard(_) => continue,
+        };
+        let len = (info.len as usize).min(MTU_FD);
+        let transfer = match state.reassembler.accept(raw_id, &frame[..len], now.total_micros() as u64) {
+            Ok(Some(transfer)) => transfer,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!(=>T, "dropping frame {:08x}: {:?}", raw_id, e);
+                continue;
+            }
+        };
+        if transfer.id.anonymous && transfer.id.kind == (Kind::Message { subject_id: PNP_SUBJECT_ID }) {
+            match AllocationData::decode(transfer.payload) {
+                Ok(AllocationData { unique_id_hash, node_id: None }) => {
+                    allocate(can, shared, &mut state.pnp_transfer_id, unique_id_hash, self_node_id);
+                }
+                // response of another allocator
+                Ok(_) => {}
+                Err(e) => debug!(=>T, "bad allocation request: {:?}", e),
+            }
+            continue;
+        }
+        if transfer.id.kind != (Kind::Message { subject_id: XPI_SUBJECT_ID }) || transfer.id.source == self_node_id {
+            continue;
+        }
+        let envelope = Envelope {
+            link: LinkId::Can,
+            // no connections on a bus
+            connection: 0,
+            received_at: now,
+            endpoint: Endpoint::Node(transfer.id.source),
+        };
+        trace!(=>T, "{}B from node {}", transfer.payload.len(), transfer.id.source);
+        // space was checked above
+        received |= envelope::enqueue(prod, &envelope, transfer.payload);
+    }
+}
+
+/// Answer a plug-and-play allocation request, the table is persisted if it changed
+fn allocate(
+    can: &mut Can,
+    shared: &mut crate::app::can_event::SharedResources,
+    transfer_id: &mut TransferIdCounter,
+    unique_id_hash: u64,
+    self_node_id: u8,
+) {
+    let node_id = match shared.node_table.lock(|t| t.allocate(unique_id_hash, self_node_id)) {
+        Ok((node_id, changed)) => {
+            if changed {
+                info!(=>T, "node id {} allocated to {:012x}", node_id, unique_id_hash);
+                // fails only if config_store is already queued, that run saves the table too
+                let _ = crate::app::config_store::spawn(StoreOp::SaveNodeTable);
+            }
+            node_id
+        }
+        Err(e) => {
+            log_warn!(=>T, "no node id for {:012x}: {:?}", unique_id_hash, e);
+            return;
+        }
+    };
+    let response = AllocationData { unique_id_hash, node_id: Some(node_id as u16) };
+    let mut payload = [0u8; ALLOCATION_DATA_LEN_MAX];
+    let len = response.encode(&mut payload);
+    let id = CanId::message(PRIORITY, PNP_SUBJECT_ID, self_node_id).to_raw();
+    let mut segmenter = Segmenter::new(&payload[..len], transfer_id.advance(), MTU_FD);
+    let mut frame = [0u8; MTU_FD];
+    while let Some(len) = segmenter.next_frame(&mut frame) {
+        if !transmit_frame(can, id, &frame[..len]) {
+            // the module will ask again
+            debug!(=>T, "TX FIFO is full, allocation response dropped");
+            return;
+        }
+    }
+}
+
+/// Put one frame into TX FIFO, returns false if it is full
+fn transmit_frame(can: &mut Can, raw_id: u32, frame: &[u8]) -> bool {
+    let header = TxFrameHeader {
+        len: frame.len() as u8,
+        frame_format: if frame.len() > xpi_can::MTU_CLASSIC { FrameFormat::Fdcan } else { FrameFormat::Standard },
+        // ids always fit into 29 bits
+        id: ExtendedId::new(raw_id).unwrap().into(),
+        bit_rate_switching: true,
+        marker: None,
+    };
+    can.transmit(header, frame).is_ok()
+}
+
+/// Put frames of queued events into TX FIFO until it is full.
+/// Returns true if any space was freed in can_tx queue.
+fn transmit(
+    can: &mut Can,
+    state: &mut CanState,
+    cons: &mut FrameConsumer<'static, CAN_QUEUE_LEN>,
+    self_node_id: u8,
+) -> bool {
+    let id = CanId::message(PRIORITY, XPI_SUBJECT_ID, self_node_id).to_raw();
+    let mut released = false;
+    while let Some(rgr) = cons.read() {
+        if !transmit_transfer(can, id, &rgr, state.transfer_id.current(), &mut state.frames_sent) {
+            // continue on TxFifoEmpty
+            return released;
+        }
+        state.transfer_id.advance();
+        rgr.release();
+        released = true;
+    }
+    released
+}
+
+/// Put frames of queued log records into TX FIFO until it is full
+fn transmit_logs(
+    can: &mut Can,
+    state: &mut CanState,
+    cons: &mut FrameConsumer<'static, { crate::can_log::QUEUE_LEN }>,
+    self_node_id: u8,
+) {
+    while let Some(rgr) = cons.read() {
+        // level 0 is a defmt frame, see xpi_can::log_header
+        let (subject_id, transfer_id) = match rgr[0] {
+            0 => (LOG_DEFMT_SUBJECT_ID, &mut state.log_defmt_transfer_id),
+            _ => (LOG_TEXT_SUBJECT_ID, &mut state.log_text_transfer_id),
+        };
+        let id = CanId::message(LOG_PRIORITY, subject_id, self_node_id).to_raw();
+        if !transmit_transfer(can, id, &rgr, transfer_id.current(), &mut state.log_frames_sent) {
+            return;
+        }
+        transfer_id.advance();
+        rgr.release();
+    }
+}
+
+/// Put frames of one transfer into TX FIFO, `frames_sent` of them are already there.
+/// Returns true once the last frame is in, false if TX FIFO is full.
+fn transmit_transfer(can: &mut Can, raw_id: u32, payload: &[u8], transfer_id: u8, frames_sent: &mut usize) -> bool {
+    // frames already in TX FIFO are produced again and skipped, cheaper than keeping a segmenter around
+    let mut segmenter = Segmenter::new(payload, transfer_id, MTU_FD);
+    let mut frame = [0u8; MTU_FD];
+    let mut n = 0;
+    while let Some(len) = segmenter.next_frame(&mut frame) {
+        if n < *frames_sent {
+            n += 1;
+            continue;
+        }
+        if !transmit_frame(can, raw_id, &frame[..len]) {
+            return false;
+        }
+        n += 1;
+        *frames_sent = n;
+    }
+    *frames_sent = 0;
+    true
+}
@@ -0,0 +1,100 @@
+//! Log records waiting to be published on CAN by can_event, for bridges without a probe attached.
+//!
+//! Same as [crate::syslog]: logging macros run at any priority, so the producer is only used inside
+//! a short critical section, records are dropped when the queue is full and their count is
+//! reported with the next record that fits.
+//! Every record is a whole log transfer payload (xpi_can::log_header and body), text lines go to
+//! LOG_TEXT_SUBJECT_ID and defmt frames (level 0) to LOG_DEFMT_SUBJECT_ID.
+
+use bbqueue::BBBuffer;
+use bbqueue::framed::{FrameConsumer, FrameProducer};
+use core::cell::RefCell;
+use core::fmt::Write;
+use cortex_m::interrupt::Mutex;
+use ecbridge_net::SliceWriter;
+use xpi_can::{log_header, LogLevel, LOG_HEADER_LEN};
+
+pub const QUEUE_LEN: usize = 1024;
+/// Longer lines are truncated
+const TEXT_MAX: usize = 160;
+/// Longer defmt frames are dropped, they can't be truncated
+pub const DEFMT_FRAME_MAX: usize = 160;
+
+static QUEUE: BBBuffer<QUEUE_LEN> = BBBuffer::new();
+
+struct Producer {
+    prod: FrameProducer<'static, QUEUE_LEN>,
+    dropped: u32,
+}
+
+static PRODUCER: Mutex<RefCell<Option<Producer>>> = Mutex::new(RefCell::new(None));
+
+/// Must be called once during init, consumer goes to can_event.
+pub fn init() -> FrameConsumer<'static, QUEUE_LEN> {
+    let (prod, cons) = QUEUE.try_split_framed().unwrap();
+    cortex_m::interrupt::free(|cs| {
+        *PRODUCER.borrow(cs).borrow_mut() = Some(Producer { prod, dropped: 0 });
+    });
+    cons
+}
+
+/// Queue a text line, never waits for space.
+#[allow(dead_code)]
+pub fn log(level: LogLevel, args: core::fmt::Arguments) {
+    enqueue(|prod| enqueue_text(prod, level, args));
+}
+
+/// Queue a complete defmt frame, never waits for space.
+#[allow(dead_code)]
+pub fn log_defmt(frame: &[u8]) {
+    enqueue(|prod| {
+        let mut wgr = match prod.grant(LOG_HEADER_LEN + frame.len()) {
+            Ok(wgr) => wgr,
+            Err(_) => return false,
+        };
+        wgr[..LOG_HEADER_LEN].copy_from_slice(&log_header(None, frame.len()));
+        wgr[LOG_HEADER_LEN..].copy_from_slice(frame);
+        wgr.commit(LOG_HEADER_LEN + frame.len());
+        true
+    });
+}
+
+fn enqueue(f: impl FnOnce(&mut FrameProducer<'static, QUEUE_LEN>) -> bool) {
+    cortex_m::interrupt::free(|cs| {
+        // already borrowed if logging from a panic in the middle of enqueueing
+        let mut producer = match PRODUCER.borrow(cs).try_borrow_mut() {
+            Ok(producer) => producer,
+            Err(_) => return,
+        };
+        let producer = match producer.as_mut() {
+            Some(producer) => producer,
+            None => return, // not yet initialised
+        };
+        if producer.dropped != 0 {
+            let dropped = producer.dropped;
+            if !enqueue_text(&mut producer.prod, LogLevel::Warn, format_args!("{} log records dropped", dropped)) {
+                producer.dropped += 1;
+                return;
+            }
+            producer.dropped = 0;
+        }
+        if !f(&mut producer.prod) {
+            producer.dropped += 1;
+        }
+    });
+    crate::can::wake();
+}
+
+fn enqueue_text(prod: &mut FrameProducer<'static, QUEUE_LEN>, level: LogLevel, args: core::fmt::Arguments) -> bool {
+    let mut wgr = match prod.grant(LOG_HEADER_LEN + TEXT_MAX) {
+        Ok(wgr) => wgr,
+        Err(_) => return false,
+    };
+    let mut wr = SliceWriter { buf: &mut wgr[LOG_HEADER_LEN..], pos: 0 };
+    // truncated on overflow
+    let _ = wr.write_fmt(args);
+    let len = wr.pos;
+    wgr[..LOG_HEADER_LEN].copy_from_slice(&log_header(Some(level), len));
+    wgr.commit(LOG_HEADER_LEN + len);
+    true
+}
@@ -0,0 +1,290 @@
+//! Network and node configuration persisted in a reserved internal flash sector.
+//!
+//! Layout: magic, version, payload length, CRC32 of the payload, followed by ssmarshal serialized
+//! `Config` as the payload.
+//! Fields must only ever be appended to `Config` (bumping CONFIG_VERSION), then a block written by
+//! an older firmware is still loaded: missing tail is taken from defaults.
+//!
+//! Config is read at boot only, changes made through xPI go into a pending copy, which is written
+//! to flash on /config/apply followed by a restart.
+//!
+//! CAN node table lives in the previous sector in a block with the same header, it changes at
+//! runtime and is written without a restart.
+
+use stm32h7xx_hal::pac::FLASH;
+use crate::node_table::NodeTable;
+pub use ecbridge_dispatch::config::{Config, ConfigState};
+use crate::{error, info, log_warn};
+
+const T: u8 = 0;
+
+/// Last sector of bank 2, code lives in bank 1, so erasing does not stall the CPU
+pub const CONFIG_ADDR: usize = 0x081E_0000;
+const CONFIG_SECTOR: u8 = 7;
+
+const MAGIC: u32 = 0xEC_C0_4F_16;
+pub const CONFIG_VERSION: u16 = 6;
+const HEADER_LEN: usize = 12;
+/// Reserved for the serialized Config, plenty of space for new fields
+const PAYLOAD_MAX: usize = 116;
+/// Flash is programmed in 256 bit words
+const FLASH_WORD: usize = 32;
+const BLOCK_LEN: usize = HEADER_LEN + PAYLOAD_MAX;
+
+pub const NODE_TABLE_ADDR: usize = 0x081C_0000;
+const NODE_TABLE_SECTOR: u8 = 6;
+const NODE_TABLE_MAGIC: u32 = 0xEC_C0_4E_7B;
+const NODE_TABLE_VERSION: u16 = 1;
+/// Serialized NodeTable is 160 bytes
+const NODE_TABLE_BLOCK_LEN: usize = 192;
+/// Delay before saving the node table again after a flash error
+pub const NODE_TABLE_RETRY_MS: u64 = 1000;
+
+/// Factory defaults
+pub const fn factory_default() -> Config {
+    Config {
+        ipv4: [192, 168, 0, 199],
+        ipv4_prefix_len: 24,
+        mac: crate::ethernet::MAC_ADDRESS,
+        tcp_port: crate::ethernet::XPI_TCP_PORT,
+        node_id: crate::NODE_ID,
+        ipv6: [0; 16],
+        ipv6_prefix_len: 64,
+        psk: [0; crate::auth::PSK_LEN],
+        keepalive_s: 5,
+        timeout_s: 15,
+        syslog_ipv4: [0; 4],
+        syslog_port: 0,
+        sntp_ipv4: [0; 4],
+    }
+}
+
+/// Part used by the network stack
+pub fn net_config(config: &Config) -> ecbridge_net::NetConfig {
+    ecbridge_net::NetConfig {
+        ipv4: config.ipv4,
+        ipv4_prefix_len: config.ipv4_prefix_len,
+        ipv6: config.ipv6,
+        ipv6_prefix_len: config.ipv6_prefix_len,
+        tcp_port: config.tcp_port,
+        node_id: config.node_id,
+        schema_hash: env!("VHL_SCHEMA_HASH"),
+        keepalive_s: config.keepalive_s,
+        timeout_s: config.timeout_s,
+        syslog_ipv4: config.syslog_ipv4,
+        syslog_port: config.syslog_port,
+        sntp_ipv4: config.sntp_ipv4,
+    }
+}
+
+/// What config_store task should do with the config sectors
+#[derive(Copy, Clone, Debug)]
+pub enum StoreOp {
+    Save(Config),
+    /// Config and CAN node table
+    FactoryReset,
+    /// CAN node table if it changed, no restart afterwards
+    SaveNodeTable,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    BadMagic,
+    UnsupportedVersion(u16),
+    CrcMismatch,
+    Serdes,
+    Flash(u32),
+}
+
+/// Load config from flash, falling back to factory defaults if there is none or it is corrupted.
+pub fn load() -> Config {
+    // unsafe: sector is reserved for config and only written by save()
+    let block = unsafe { core::slice::from_raw_parts(CONFIG_ADDR as *const u8, BLOCK_LEN) };
+    match parse(block) {
+        Ok(config) => {
+            info!(=>T, "config loaded: {:?}", config);
+            config
+        }
+        Err(e) => {
+            log_warn!(=>T, "config: {:?}, using defaults", e);
+            factory_default()
+        }
+    }
+}
+
+/// Load CAN node table from flash, empty if there is none or it is corrupted.
+pub fn load_node_table() -> NodeTable {
+    // unsafe: sector is reserved for the node table and only written by save_node_table()
+    let block = unsafe { core::slice::from_raw_parts(NODE_TABLE_ADDR as *const u8, NODE_TABLE_BLOCK_LEN) };
+    let table = check_block(block, NODE_TABLE_MAGIC, NODE_TABLE_VERSION)
+        .and_then(|payload| ssmarshal::deserialize(payload).map_err(|_| Error::Serdes));
+    match table {
+        Ok((table, _)) => {
+            info!(=>T, "node table loaded: {:?}", table);
+            table
+        }
+        Err(e) => {
+            log_warn!(=>T, "node table: {:?}, starting empty", e);
+            NodeTable::new()
+        }
+    }
+}
+
+/// Check block header and CRC, returns the payload
+fn check_block(block: &[u8], magic: u32, version_max: u16) -> Result<&[u8], Error> {
+    if u32::from_le_bytes([block[0], block[1], block[2], block[3]]) != magic {
+        return Err(Error::BadMagic);
+    }
+    let version = u16::from_le_bytes([block[4], block[5]]);
+    if version == 0 || version > version_max {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let len = u16::from_le_bytes([block[6], block[7]]) as usize;
+    if HEADER_LEN + len > block.len() {
+        return Err(Error::Serdes);
+    }
+    let crc = u32::from_le_bytes([block[8], block[9], block[10], block[11]]);
+    let payload = &block[HEADER_LEN..HEADER_LEN + len];
+    if crc32(payload) != crc {
+        return Err(Error::CrcMismatch);
+    }
+    Ok(payload)
+}
+
+fn parse(block: &[u8]) -> Result<Config, Error> {
+    let payload = check_block(block, MAGIC, CONFIG_VERSION)?;
+    let len = payload.len();
+
+    // fields appended in newer versions are taken from defaults
+    let mut buf = [0u8; PAYLOAD_MAX];
+    let default_len = ssmarshal::serialize(&mut buf, &factory_default()).map_err(|_| Error::Serdes)?;
+    buf[..len].copy_from_slice(payload);
+    let (config, _) = ssmarshal::deserialize(&buf[..default_len.max(len)]).map_err(|_| Error::Serdes)?;
+    Ok(config)
+}
+
+/// Erase config and node table sectors, so that defaults are used after the next restart.
+pub fn factory_reset(flash: &mut FLASH) -> Result<(), Error> {
+    info!(=>T, "config: factory reset");
+    unlock(flash);
+    let r = erase_sector(flash, NODE_TABLE_SECTOR).and_then(|_| erase_sector(flash, CONFIG_SECTOR));
+    lock(flash);
+    r
+}
+
+/// Erase config sector and write `config` into it.
+pub fn save(flash: &mut FLASH, config: &Config) -> Result<(), Error> {
+    let mut block = [0xFFu8; (BLOCK_LEN + FLASH_WORD - 1) / FLASH_WORD * FLASH_WORD];
+    let len = ssmarshal::serialize(&mut block[HEADER_LEN..HEADER_LEN + PAYLOAD_MAX], config)
+        .map_err(|_| Error::Serdes)?;
+    let r = write_block(flash, CONFIG_ADDR, CONFIG_SECTOR, MAGIC, CONFIG_VERSION, &mut block, len);
+    match &r {
+        Ok(_) => info!(=>T, "config saved: {:?}", config),
+        Err(e) => error!(=>T, "config save failed: {:?}", e),
+    }
+    r
+}
+
+/// Erase node table sector and write `table` into it.
+pub fn save_node_table(flash: &mut FLASH, table: &NodeTable) -> Result<(), Error> {
+    let mut block = [0xFFu8; NODE_TABLE_BLOCK_LEN];
+    let len = ssmarshal::serialize(&mut block[HEADER_LEN..], table).map_err(|_| Error::Serdes)?;
+    let r = write_block(
+        flash,
+        NODE_TABLE_ADDR,
+        NODE_TABLE_SECTOR,
+        NODE_TABLE_MAGIC,
+        NODE_TABLE_VERSION,
+        &mut block,
+        len,
+    );
+    match &r {
+        Ok(_) => info!(=>T, "node table saved"),
+        Err(e) => error!(=>T, "node table save failed: {:?}", e),
+    }
+    r
+}
+
+/// Fill in the header for `len` bytes of payload already in `block`, erase `sector` and write the
+/// block to its start at `addr`. Block length must be a multiple of FLASH_WORD.
+fn write_block(
+    flash: &mut FLASH,
+    addr: usize,
+    sector: u8,
+    magic: u32,
+    version: u16,
+    block: &mut [u8],
+    len: usize,
+) -> Result<(), Error> {
+    let crc = crc32(&block[HEADER_LEN..HEADER_LEN + len]);
+    block[0..4].copy_from_slice(&magic.to_le_bytes());
+    block[4..6].copy_from_slice(&version.to_le_bytes());
+    block[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+    block[8..12].copy_from_slice(&crc.to_le_bytes());
+
+    unlock(flash);
+    let r = erase_sector(flash, sector).and_then(|_| program(flash, addr, block));
+    lock(flash);
+    r
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc32 = crc_any::CRCu32::crc32();
+    crc32.digest(data);
+    crc32.get_crc()
+}
+
+fn unlock(flash: &mut FLASH) {
+    let bank = flash.bank2();
+    if bank.cr.read().lock().bit_is_set() {
+        bank.keyr.write(|w| unsafe { w.keyr().bits(0x4567_0123) });
+        bank.keyr.write(|w| unsafe { w.keyr().bits(0xCDEF_89AB) });
+    }
+}
+
+fn lock(flash: &mut FLASH) {
+    flash.bank2().cr.modify(|_, w| w.lock().set_bit());
+}
+
+fn wait_and_check(flash: &mut FLASH) -> Result<(), Error> {
+    let bank = flash.bank2();
+    while bank.sr.read().qw().bit_is_set() {}
+    let sr = bank.sr.read().bits();
+    // WRPERR, PGSERR, STRBERR, INCERR, OPERR, RDPERR, RDSERR, SNECCERR, DBECCERR
+    const ERRORS: u32 = 0x0FEE_0000;
+    bank.ccr.write(|w| unsafe { w.bits(ERRORS | (1 << 16)) });
+    if sr & ERRORS != 0 {
+        return Err(Error::Flash(sr));
+    }
+    Ok(())
+}
+
+fn erase_sector(flash: &mut FLASH, sector: u8) -> Result<(), Error> {
+    flash.bank2().cr.modify(|_, w| unsafe {
+        w.ser().set_bit().snb().bits(sector).psize().bits(0b11)
+    });
+    flash.bank2().cr.modify(|_, w| w.start().set_bit());
+    let r = wait_and_check(flash);
+    flash.bank2().cr.modify(|_, w| w.ser().clear_bit());
+    r
+}
+
+fn program(flash: &mut FLASH, addr: usize, data: &[u8]) -> Result<(), Error> {
+    flash.bank2().cr.modify(|_, w| unsafe { w.pg().set_bit().psize().bits(0b11) });
+    let mut r = Ok(());
+    for (word_idx, word) in data.chunks(FLASH_WORD).enumerate() {
+        let dst = (addr + word_idx * FLASH_WORD) as *mut u32;
+        for (i, chunk) in word.chunks(4).enumerate() {
+            let value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            // unsafe: destination is inside of the erased config or node table sector
+            unsafe { core::ptr::write_volatile(dst.add(i), value) };
+        }
+        cortex_m::asm::dsb();
+        r = wait_and_check(flash);
+        if r.is_err() {
+            break;
+        }
+    }
+    flash.bank2().cr.modify(|_, w| w.pg().clear_bit());
+    r
+}
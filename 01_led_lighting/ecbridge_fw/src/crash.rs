@@ -0,0 +1,214 @@
+//! Crash report kept in RAM across the reset that follows a panic, a HardFault or a task missing its
+//! deadline (see crate::supervisor).
+//!
+//! The panic handler writes the message, and the exception frame saved by HardFault, into .noinit
+//! (SRAM4, not touched by the startup code), seals it with magic and CRC and lets the watchdog reset
+//! the chip. On the next boot init takes the report out, logs it and serves it as /crash until the
+//! following reset, together with the reset cause flags.
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use cortex_m::interrupt::Mutex;
+use stm32h7xx_hal::pac;
+
+const MAGIC: u32 = 0xC4A5_4ED0;
+/// IWDG1RSTF in reset_flags
+pub const RESET_IWDG1: u32 = 1 << 26;
+/// Longer panic messages are truncated
+pub const MESSAGE_MAX: usize = ecbridge_dispatch::CRASH_MESSAGE_MAX;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Kind {
+    Panic = 1,
+    HardFault = 2,
+    Watchdog = 3,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Kind::Panic),
+            2 => Some(Kind::HardFault),
+            3 => Some(Kind::Watchdog),
+            _ => None,
+        }
+    }
+}
+
+/// Stacked registers: r0, r1, r2, r3, r12, lr, pc, xpsr
+pub type Frame = [u32; 8];
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct CrashReport {
+    pub uptime_ms: u64,
+    /// All zeroes if the crash was not caused by a fault
+    pub frame: Frame,
+    pub kind: u8,
+    pub message_len: u8,
+    _reserved: [u8; 6],
+    pub message: [u8; MESSAGE_MAX],
+}
+
+#[repr(C)]
+struct Sealed {
+    magic: u32,
+    crc: u32,
+    report: CrashReport,
+}
+
+#[link_section = ".noinit.crash"]
+static mut NOINIT: MaybeUninit<Sealed> = MaybeUninit::uninit();
+
+/// Saved by HardFault for the panic handler
+static FRAME: Mutex<Cell<Option<Frame>>> = Mutex::new(Cell::new(None));
+static LAST: Mutex<Cell<Option<CrashReport>>> = Mutex::new(Cell::new(None));
+static RESET_FLAGS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Must be called once early in init with RCC_RSR, before its flags are cleared.
+/// Returns the report left by the previous run, if it crashed.
+pub fn init(reset_flags: u32) -> Option<CrashReport> {
+    // safe: nothing else touches .noinit before init is done, any bit pattern is a valid Sealed
+    let sealed = unsafe { &mut *(*addr_of_mut!(NOINIT)).as_mut_ptr() };
+    let report = if sealed.magic == MAGIC && sealed.crc == crc32(&sealed.report) {
+        Some(sealed.report)
+    } else {
+        None
+    };
+    // only reported on the boot right after the crash
+    sealed.magic = 0;
+    cortex_m::interrupt::free(|cs| {
+        LAST.borrow(cs).set(report);
+        RESET_FLAGS.borrow(cs).set(reset_flags);
+    });
+    report
+}
+
+/// Report of the previous run, None if it didn't crash
+pub fn last() -> Option<CrashReport> {
+    cortex_m::interrupt::free(|cs| LAST.borrow(cs).get())
+}
+
+/// RCC_RSR at boot: which reset it was (IWDG1RSTF, SFTRSTF, PORRSTF, PINRSTF, ...)
+pub fn reset_flags() -> u32 {
+    cortex_m::interrupt::free(|cs| RESET_FLAGS.borrow(cs).get())
+}
+
+/// Called by HardFault before panicking, so that the frame ends up in the report
+pub fn record_frame(ef: &cortex_m_rt::ExceptionFrame) {
+    let frame = [ef.r0(), ef.r1(), ef.r2(), ef.r3(), ef.r12(), ef.lr(), ef.pc(), ef.xpsr()];
+    cortex_m::interrupt::free(|cs| FRAME.borrow(cs).set(Some(frame)));
+}
+
+/// Write and seal the report, only the first crash is recorded if another one happens meanwhile.
+/// A panic after HardFault is recorded as HardFault with its frame.
+/// Must be called with interrupts disabled, returns false if a report is already being written.
+pub fn record(kind: Kind, args: core::fmt::Arguments) -> bool {
+    if CRASHING.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    let frame = cortex_m::interrupt::free(|cs| FRAME.borrow(cs).get());
+    let mut report = CrashReport {
+        uptime_ms: crate::wallclock::uptime().total_millis() as u64,
+        frame: frame.unwrap_or_default(),
+        kind: if frame.is_some() { Kind::HardFault } else { kind } as u8,
+        message_len: 0,
+        _reserved: [0; 6],
+        message: [0; MESSAGE_MAX],
+    };
+    let mut wr = MessageWriter { report: &mut report };
+    // truncated on overflow
+    let _ = wr.write_fmt(args);
+    // safe: interrupts are disabled and CRASHING keeps others out
+    unsafe {
+        addr_of_mut!(NOINIT).write(MaybeUninit::new(Sealed {
+            magic: MAGIC,
+            crc: crc32(&report),
+            report,
+        }));
+    }
+    compiler_fence(Ordering::SeqCst);
+    true
+}
+
+/// Let the independent watchdog reset the chip as soon as possible, whether it was started or not
+pub fn reset() -> ! {
+    // safe: nothing runs after this, the watchdog is not fed anymore
+    let iwdg = unsafe { &*pac::IWDG::ptr() };
+    iwdg.kr.write(|w| unsafe { w.bits(0xCCCC) }); // start, no-op if already running
+    iwdg.kr.write(|w| unsafe { w.bits(0x5555) }); // unlock PR and RLR
+    iwdg.pr.write(|w| unsafe { w.bits(0) }); // LSI / 4
+    iwdg.rlr.write(|w| unsafe { w.bits(0xFF) }); // ~32ms
+    iwdg.kr.write(|w| unsafe { w.bits(0xAAAA) });
+    loop {
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+fn crc32(report: &CrashReport) -> u32 {
+    // safe: repr(C) without padding
+    let bytes = unsafe {
+        core::slice::from_raw_parts(addr_of!(*report) as *const u8, core::mem::size_of::<CrashReport>())
+    };
+    let mut crc32 = crc_any::CRCu32::crc32();
+    crc32.digest(bytes);
+    crc32.get_crc()
+}
+
+impl CrashReport {
+    pub fn kind(&self) -> Option<Kind> {
+        Kind::from_u8(self.kind)
+    }
+
+    pub fn message(&self) -> &str {
+        let len = (self.message_len as usize).min(MESSAGE_MAX);
+        match core::str::from_utf8(&self.message[..len]) {
+            Ok(message) => message,
+            // cut in the middle of a character
+            Err(e) => core::str::from_utf8(&self.message[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl core::fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.kind() {
+            Some(kind) => write!(f, "{:?}", kind)?,
+            None => write!(f, "crash {}", self.kind)?,
+        }
+        write!(f, " {}.{:03}s after boot: {}", self.uptime_ms / 1000, self.uptime_ms % 1000, self.message())?;
+        if self.kind() == Some(Kind::HardFault) {
+            let [r0, r1, r2, r3, r12, lr, pc, xpsr] = self.frame;
+            write!(
+                f,
+                ", pc={:#010x} lr={:#010x} xpsr={:#010x} r0={:#010x} r1={:#010x} r2={:#010x} r3={:#010x} r12={:#010x}",
+                pc, lr, xpsr, r0, r1, r2, r3, r12
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats into the report message, silently truncating what doesn't fit
+struct MessageWriter<'a> {
+    report: &'a mut CrashReport,
+}
+
+impl<'a> Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let pos = self.report.message_len as usize;
+        let len = s.len().min(MESSAGE_MAX - pos);
+        self.report.message[pos..pos + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.report.message_len += len as u8;
+        if len < s.len() {
+            Err(core::fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! defmt global logger: frames are collected whole, then written to the "defmt" RTT channel
+//! (log-defmt-rtt) and queued for CAN by [crate::can_log] (log-defmt-can).
+//!
+//! Interrupts are disabled between acquire and release, so that frames from different
+//! priorities don't interleave. Frames longer than DEFMT_FRAME_MAX are dropped.
+//! Decoding needs the firmware ELF: `defmt-print -e target/thumbv7em-none-eabihf/release/ecbridge_fw`
+//! reading from probe-rs RTT output or from node<id>.defmt written by `ecbridge_host --can-log`.
+
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::register::primask;
+use crate::can_log::DEFMT_FRAME_MAX;
+
+#[defmt::global_logger]
+struct Logger;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut STATE: State = State {
+    encoder: defmt::Encoder::new(),
+    frame: [0; DEFMT_FRAME_MAX],
+    len: 0,
+    overflow: false,
+    interrupts_were_enabled: false,
+    #[cfg(feature = "log-defmt-rtt")]
+    channel: None,
+};
+
+struct State {
+    encoder: defmt::Encoder,
+    frame: [u8; DEFMT_FRAME_MAX],
+    len: usize,
+    overflow: bool,
+    interrupts_were_enabled: bool,
+    #[cfg(feature = "log-defmt-rtt")]
+    channel: Option<rtt_target::UpChannel>,
+}
+
+impl State {
+    fn push(frame: &mut [u8; DEFMT_FRAME_MAX], len: &mut usize, overflow: &mut bool, bytes: &[u8]) {
+        match frame.get_mut(*len..*len + bytes.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(bytes);
+                *len += bytes.len();
+            }
+            None => *overflow = true,
+        }
+    }
+
+    fn encode(&mut self, f: impl FnOnce(&mut defmt::Encoder, &mut dyn FnMut(&[u8]))) {
+        let State { encoder, frame, len, overflow, .. } = self;
+        f(encoder, &mut |bytes| Self::push(frame, len, overflow, bytes));
+    }
+}
+
+/// Must be called during init, before anything is logged with defmt
+#[cfg(feature = "log-defmt-rtt")]
+pub fn init_rtt(channel: rtt_target::UpChannel) {
+    cortex_m::interrupt::free(|_| {
+        // safe: the logger is not taken while interrupts are disabled
+        unsafe { (*addr_of_mut!(STATE)).channel = Some(channel) };
+    });
+}
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let interrupts_were_enabled = primask::read().is_active();
+        cortex_m::interrupt::disable();
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        // safe: interrupts are disabled and the logger is taken
+        let state = unsafe { &mut *addr_of_mut!(STATE) };
+        state.interrupts_were_enabled = interrupts_were_enabled;
+        state.len = 0;
+        state.overflow = false;
+        state.encode(|encoder, write| encoder.start_frame(write));
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let state = &mut *addr_of_mut!(STATE);
+        state.encode(|encoder, write| encoder.end_frame(write));
+        if !state.overflow {
+            let frame = &state.frame[..state.len];
+            #[cfg(feature = "log-defmt-rtt")]
+            if let Some(channel) = state.channel.as_mut() {
+                // NoBlockSkip: the whole frame or nothing
+                channel.write(frame);
+            }
+            #[cfg(feature = "log-defmt-can")]
+            crate::can_log::log_defmt(frame);
+        }
+        TAKEN.store(false, Ordering::Relaxed);
+        if state.interrupts_were_enabled {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        let state = &mut *addr_of_mut!(STATE);
+        state.encode(|encoder, write| encoder.write(bytes, write));
+    }
+}
+
+defmt::timestamp!("{=u64:us}", crate::wallclock::uptime().total_micros() as u64);
@@ -1,109 +1,139 @@
-use core::mem::size_of;
 use dwt_systick_monotonic::fugit;
-use smoltcp::iface::{
-    Interface, InterfaceBuilder, Neighbor, NeighborCache, Route, Routes,
-    SocketStorage,
-    SocketHandle,
-};
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, Ipv6Cidr};
-use smoltcp::socket::{TcpSocket, TcpSocketBuffer};
+use smoltcp::wire::{EthernetAddress, IpAddress};
 use stm32h7xx_hal::{ethernet as ethernet_h7, stm32};
 use stm32h7xx_hal::ethernet::PinsRMII;
 use stm32h7xx_hal::rcc::{CoreClocks, rec};
-use serde::{Serialize, Deserialize};
-use crate::{debug, error, info, trace, log_warn};
+use crate::{debug, info, trace, log_warn};
 use rtic::Mutex;
+use ecbridge_net::{http, NetStorage};
+use ecbridge_net::ping::PingStats;
+use crate::lan8742a::LinkSpeed;
+use crate::config::Config;
+use crate::vhlink::LinkId;
 
 const T: u8 = 0;
 
-/// Locally administered MAC address
+/// Locally administered MAC address, factory default
 pub const MAC_ADDRESS: [u8; 6] = [0x02, 0x00, 0x11, 0x22, 0x33, 0x44];
 
+/// xPI TCP listener port, also announced over mDNS, factory default
+pub const XPI_TCP_PORT: u16 = 7777;
+
 /// Ethernet descriptor rings are a global singleton
 #[link_section = ".sram3.eth"]
 pub static mut DES_RING: ethernet_h7::DesRing<4, 4> = ethernet_h7::DesRing::new();
 
+/// xPI TCP socket buffers, each direction
+#[cfg(not(feature = "large-buffers"))]
+pub const TCP_BUFFER_LEN: usize = ecbridge_net::TCP_BUFFER_LEN;
+#[cfg(feature = "large-buffers")]
+pub const TCP_BUFFER_LEN: usize = 1024;
+
+/// eth_out (enveloped requests, framed) and eth_in (replies) queues between ethernet_event and link_process
+#[cfg(not(feature = "large-buffers"))]
+pub const ETH_QUEUE_LEN: usize = 512;
+#[cfg(feature = "large-buffers")]
+pub const ETH_QUEUE_LEN: usize = 4096;
+
 /// Net storage with static initialisation - another global singleton
-pub struct NetStorageStatic<'a> {
-    ip_addrs: [IpCidr; 1],
-    socket_storage: [SocketStorage<'a>; 8],
-    neighbor_cache_storage: [Option<(IpAddress, Neighbor)>; 8],
-    routes_storage: [Option<(IpCidr, Route)>; 1],
+pub static mut STORE: NetStorage<'static, TCP_BUFFER_LEN, TCP_BUFFER_LEN> = NetStorage::new();
+
+pub type Lan8742A = crate::lan8742a::Lan8742A<ethernet_h7::EthernetMAC>;
+
+/// Updated from idle and published as /link
+pub use ecbridge_dispatch::link::LinkState;
+
+/// Negotiated speed in Mbit/s and duplex, as kept in LinkState
+pub fn speed_duplex(speed: LinkSpeed) -> (u8, bool) {
+    use LinkSpeed::*;
+    match speed {
+        BaseT10HalfDuplex => (10, false),
+        BaseT10FullDuplex => (10, true),
+        BaseT100HalfDuplex => (100, false),
+        BaseT100FullDuplex => (100, true),
+    }
 }
-pub static mut STORE: NetStorageStatic = NetStorageStatic {
-    // Garbage
-    ip_addrs: [IpCidr::Ipv6(Ipv6Cidr::SOLICITED_NODE_PREFIX)],
-    socket_storage: [SocketStorage::EMPTY; 8],
-    neighbor_cache_storage: [None; 8],
-    routes_storage: [None; 1],
-};
-
-pub type Lan8742A = ethernet_h7::phy::LAN8742A<ethernet_h7::EthernetMAC>;
-
-pub struct Net<'a> {
-    iface: Interface<'a, ethernet_h7::EthernetDMA<'a, 4, 4>>,
-    tcp_handle: SocketHandle,
+
+/// Ping self-test requested over xPI and its latest results, published as /ping_stats
+#[derive(Copy, Clone, Debug)]
+pub struct SelfTest {
+    /// Target and amount of echo requests, taken by ethernet_event
+    pub request: Option<(IpAddress, u8)>,
+    pub stats: PingStats,
 }
 
-impl<'a> Net<'a> {
-    pub fn new(
-        store: &'static mut NetStorageStatic<'a>,
-        ethdev: ethernet_h7::EthernetDMA<'a, 4, 4>,
-        ethernet_addr: HardwareAddress,
-    ) -> Self {
-        // Set IP address
-        store.ip_addrs =
-            [IpCidr::new(IpAddress::v4(192, 168, 0, 199).into(), 0)];
-
-        let neighbor_cache =
-            NeighborCache::new(&mut store.neighbor_cache_storage[..]);
-        let routes = Routes::new(&mut store.routes_storage[..]);
-
-        let mut iface =
-            InterfaceBuilder::new(ethdev, &mut store.socket_storage[..])
-                .hardware_addr(ethernet_addr)
-                .neighbor_cache(neighbor_cache)
-                .ip_addrs(&mut store.ip_addrs[..])
-                .routes(routes)
-                .finalize();
-
-        let tcp_socket = {
-            static mut TCP_SERVER_RX_DATA: [u8; 128] = [0; 128];
-            static mut TCP_SERVER_TX_DATA: [u8; 128] = [0; 128];
-            let tcp_rx_buffer = TcpSocketBuffer::new(unsafe { &mut TCP_SERVER_RX_DATA[..] });
-            let tcp_tx_buffer = TcpSocketBuffer::new(unsafe { &mut TCP_SERVER_TX_DATA[..] });
-            TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer)
-        };
-
-        let tcp_handle = iface.add_socket(tcp_socket);
-
-        return Net { iface, tcp_handle };
+impl SelfTest {
+    pub const fn new() -> Self {
+        SelfTest {
+            request: None,
+            stats: PingStats::new(),
+        }
     }
 
-    fn now() -> Instant {
-        let now: u64 = crate::app::monotonics::now().duration_since_epoch().to_millis();
-        // rprintln!("now(): {}ms", now);
-        Instant::from_millis(now as i64)
+    pub fn is_busy(&self) -> bool {
+        self.request.is_some() || self.stats.running
     }
+}
 
-    /// Polls on the ethernet interface.
-    pub fn poll(&mut self) -> bool {
-        self.iface
-            .poll(Self::now())
-            .unwrap_or_else(|e|  {
-                if e != smoltcp::Error::Unrecognized {
-                    log_warn!(=>T, "Poll err: {:?}", e);
-                }
-                false
-            })
+/// Firmware part of the HTTP status page, taken only when a request comes in
+struct StatusPage {
+    link: LinkState,
+    flow_stats: crate::vhlink::FlowStats,
+    auth_enabled: bool,
+    authenticated: bool,
+    subscriptions: usize,
+    digit: u8,
+    symbol: char,
+}
+
+impl StatusPage {
+    fn snapshot(shared: &mut crate::app::ethernet_event::SharedResources) -> Self {
+        StatusPage {
+            link: shared.link.lock(|l| *l),
+            flow_stats: shared.flow_stats.lock(|s| *s),
+            auth_enabled: shared.config.lock(|c| crate::auth::is_enabled(&c.active.psk)),
+            authenticated: shared.sessions.lock(|s| s.latest(LinkId::Ethernet).is_authenticated()),
+            subscriptions: shared.subscriptions.lock(|s| s.all().iter().flatten().count()),
+            digit: shared.digit.lock(|d| *d),
+            symbol: shared.symbol.lock(|s| *s),
+        }
     }
+}
 
-    pub fn poll_at(&mut self) -> Option<smoltcp::time::Instant> {
-        self.iface.poll_at(Self::now())
+impl http::Status for StatusPage {
+    fn fields(&self, f: &mut dyn FnMut(&str, http::Value)) {
+        use http::Value;
+        f("link_speed_mbps", Value::U32(self.link.speed as u32));
+        f("link_full_duplex", Value::Bool(self.link.full_duplex));
+        f("link_drops", Value::U32(self.link.drops));
+        f("symbol_errors", Value::U32(self.link.symbol_errors));
+        f("auth_enabled", Value::Bool(self.auth_enabled));
+        f("authenticated", Value::Bool(self.authenticated));
+        f("subscriptions", Value::U32(self.subscriptions as u32));
+        f("dispatched", Value::U32(self.flow_stats.dispatched));
+        f("dispatch_errors", Value::U32(self.flow_stats.dispatch_errors));
+        f("forwarded", Value::U32(self.flow_stats.forwarded));
+        f("route_drops", Value::U32(self.flow_stats.route_drops));
+        f("rx_stalls", Value::U32(self.flow_stats.rx_stalls));
+        f("dispatch_stalls", Value::U32(self.flow_stats.dispatch_stalls));
+        f("digit", Value::U32(self.digit as u32));
+        f("symbol", Value::Char(self.symbol));
     }
 }
 
+/// Time from RTIC monotonic
+pub struct MonoClock;
+
+impl ecbridge_net::Clock for MonoClock {
+    fn now(&self) -> Instant {
+        let now: u64 = crate::app::monotonics::now().duration_since_epoch().to_millis();
+        Instant::from_millis(now as i64)
+    }
+}
+
+pub type Net = ecbridge_net::Net<'static, ethernet_h7::EthernetDMA<'static, 4, 4>, MonoClock>;
+
 pub struct PollAtHandle {
     pub originally_scheduled_at: crate::Instant,
     pub handle: crate::app::smoltcp_poll_at::SpawnHandle
@@ -115,9 +145,10 @@ pub fn init(
     eth_dma: stm32::ETHERNET_DMA,
     pins: impl PinsRMII,
     prec: rec::Eth1Mac,
-    clocks: &CoreClocks
-) -> (Net<'static>, Lan8742A) {
-    let mac_addr = EthernetAddress::from_bytes(&MAC_ADDRESS);
+    clocks: &CoreClocks,
+    config: &Config,
+) -> (Net, Lan8742A) {
+    let mac_addr = EthernetAddress::from_bytes(&config.mac);
     let (eth_dma, eth_mac) = unsafe {
         ethernet_h7::new(
             eth_mac, eth_mtl, eth_dma,
@@ -131,7 +162,7 @@ pub fn init(
 
     // Initialise ethernet PHY...
     info!(=>T, "PHY init...");
-    let mut lan8742a = ethernet_h7::phy::LAN8742A::new(eth_mac);
+    let mut lan8742a = Lan8742A::new(eth_mac);
     use stm32h7xx_hal::ethernet::PHY;
     lan8742a.phy_reset();
     lan8742a.phy_init();
@@ -142,49 +173,12 @@ pub fn init(
 
     // unsafe: mutable reference to static storage, we only do this once
     let store = unsafe { &mut STORE };
-    let net = Net::new(store, eth_dma, mac_addr.into());
+    let net = Net::new(store, eth_dma, MonoClock, mac_addr, &crate::config::net_config(&config));
     (net, lan8742a)
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-pub struct IpEndpointL {
-    pub addr: IpAddressL,
-    pub port: u16,
-}
-
-impl TryFrom<IpEndpoint> for IpEndpointL {
-    type Error = ();
-
-    fn try_from(value: IpEndpoint) -> Result<Self, Self::Error> {
-        Ok(IpEndpointL {
-            addr: value.addr.try_into()?,
-            port: value.port
-        })
-    }
-}
-
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-pub enum IpAddressL {
-    Ipv4([u8; 4]),
-    #[cfg(feature = "proto-ipv6")]
-    Ipv6([u8; 16]),
-}
-
-impl TryFrom<smoltcp::wire::IpAddress> for IpAddressL {
-    type Error = ();
-
-    fn try_from(value: IpAddress) -> Result<Self, Self::Error> {
-        match value {
-            IpAddress::Unspecified => Err(()),
-            IpAddress::Ipv4(v4) => Ok(IpAddressL::Ipv4(v4.0)),
-            #[cfg(feature = "proto-ipv6")]
-            IpAddress::Ipv6(v6) => Ok(IpAddressL::Ipv6(v6.0)),
-            _ => Err(())
-        }
-    }
-}
-
 pub fn ethernet_event(mut ctx: crate::app::ethernet_event::Context) {
+    let _busy = crate::supervisor::busy(crate::supervisor::Task::EthernetEvent);
     let time = crate::app::monotonics::now().duration_since_epoch().to_micros();
     trace!(=>T, "\nethernet_event: {}us", time);
     // TODO: figure out why there are a bunch of ethernet_event: 0us at the start
@@ -192,32 +186,66 @@ pub fn ethernet_event(mut ctx: crate::app::ethernet_event::Context) {
     unsafe { ethernet_h7::interrupt_handler() }
     ctx.local.led_act.toggle();
 
-    let tcp_handle = ctx.local.net.tcp_handle;
-    let eth_out_prod: &mut bbqueue::Producer<512> = ctx.local.eth_out_prod;
-    let eth_in_cons: &mut bbqueue::Consumer<512> = ctx.local.eth_in_cons;
+    let eth_out_prod: &mut bbqueue::framed::FrameProducer<ETH_QUEUE_LEN> = ctx.local.eth_out_prod;
+    let eth_in_cons: &mut bbqueue::Consumer<ETH_QUEUE_LEN> = ctx.local.eth_in_cons;
     let net: &mut Net = ctx.local.net;
 
     let mut poll_at_advice: Option<crate::Instant> = None;
+    let mut rx_stalled = false;
+    let mut tx_released = false;
+
+    let link_up = ctx.shared.link.lock(|l| l.up);
+    if link_up != net.is_link_up() {
+        tx_released |= net.link_changed(link_up, eth_in_cons);
+    }
+    if let Some((target, count)) = ctx.shared.self_test.lock(|t| t.request.take()) {
+        if !net.start_ping(target, count) {
+            log_warn!(=>T, "ping to {} ignored, previous one is still running", target);
+        }
+    }
     const MAX_ITERATIONS: usize = 5;
     for i in 0..MAX_ITERATIONS {
         if i == MAX_ITERATIONS - 1 {
             log_warn!(=>T, "ethernet_event last iteration reached");
         }
 
-        let might_be_new_data = net.poll();
-        let tcp_socket: &mut TcpSocket = net.iface.get_socket(tcp_handle);
-        // rprintln!("{:?}", tcp_socket.state());
-        if might_be_new_data {
-            handle_tcp_rx(tcp_socket, eth_out_prod);
+        let _might_be_new_data = net.poll();
+        let events = net.process_tcp(eth_out_prod, eth_in_cons);
+        rx_stalled |= events.rx_stalled;
+        tx_released |= events.tx_released;
+        if events.frames_received {
+            let r = crate::app::link_process::spawn();
+            if r.is_err() {
+                // already spawned, will process all the frames in the queue
+                trace!(=>T, "link_process: spawn failed");
+            }
+        }
+        if events.closed {
+            // next connection has to authenticate again
+            ctx.shared.sessions.lock(|s| s.reset(LinkId::Ethernet));
+            // all the subscribers were behind the closed connection
+            let dropped = ctx.shared.subscriptions.lock(|s| s.clear(LinkId::Ethernet));
+            if dropped != 0 {
+                info!(=>T, "dropped {} subscriptions", dropped);
+            }
+            // replies to the old client must not go to the next one
+            ctx.shared.router.lock(|r| r.forget(LinkId::Ethernet));
         }
-        handle_tcp_tx(tcp_socket, eth_in_cons);
-        if tcp_socket.state() == smoltcp::socket::TcpState::CloseWait {
-            tcp_socket.close();
+        net.process_mdns();
+        #[cfg(feature = "proto-ipv6")]
+        net.process_slaac();
+        if let Some(stats) = net.process_ping() {
+            ctx.shared.self_test.lock(|t| t.stats = stats);
+            ctx.shared.subscriptions.lock(|s| s.notify(ecbridge_dispatch::PING_STATS_RESOURCE));
+            let _ = crate::app::link_process::spawn(); // publish to subscribers
         }
-        if !tcp_socket.is_open() {
-            let r = tcp_socket.listen(7777);
-            info!(=>T, "tcp_socket: listen(): {:?}", r);
+        if let Some(status) = net.process_sntp() {
+            crate::wallclock::set_status(status);
+            ctx.shared.subscriptions.lock(|s| s.notify(ecbridge_dispatch::TIME_RESOURCE));
+            let _ = crate::app::link_process::spawn(); // publish to subscribers
         }
+        net.process_syslog(ctx.local.syslog_cons);
+        net.process_http(|| StatusPage::snapshot(&mut ctx.shared));
 
         match net.poll_at() {
             Some(advised_instant) => {
@@ -241,6 +269,18 @@ pub fn ethernet_event(mut ctx: crate::app::ethernet_event::Context) {
         }
     }
 
+    let resume_dispatch = ctx.shared.flow_stats.lock(|s| {
+        if rx_stalled && !s.rx_stalled {
+            s.rx_stalls += 1;
+            log_warn!(=>T, "eth_out queue is full, pausing rx ({} stalls)", s.rx_stalls);
+        }
+        s.rx_stalled = rx_stalled;
+        s.dispatch_stalled && tx_released
+    });
+    if resume_dispatch {
+        let _ = crate::app::link_process::spawn();
+    }
+
     let poll_at_handle: Option<PollAtHandle> = ctx.shared.poll_at_handle.lock(|h| h.take());
     match poll_at_advice {
         Some(advised_instant) => {
@@ -294,66 +334,9 @@ pub fn ethernet_event(mut ctx: crate::app::ethernet_event::Context) {
     }
 }
 
-fn handle_tcp_rx(tcp_socket: &mut TcpSocket, eth_out_prod: &mut bbqueue::Producer<512>) {
-    let remote_endpoint =  tcp_socket.remote_endpoint();
-    if tcp_socket.can_recv() {
-        match tcp_socket.recv(|buffer| {
-            // dequeue the amount returned
-            (buffer.len(), buffer)
-        }) {
-            Ok(buf) => {
-                // rprintln!("tcp_socket: recv: {} {:02x?}", buf.len(), buf);
-                let endpoint: IpEndpointL = match remote_endpoint.try_into() {
-                    Ok(endpoint) => endpoint,
-                    Err(_) => {
-                        error!(=>T, "wrong endpoint address");
-                        return;
-                    }
-                };
-
-                // do not remove +1 from IpEndpointL size, because when ipv6 is disabled
-                // enum have only one variant and is optimized to be 0 size, but
-                // serializer still use 1 byte for the discriminant
-                match eth_out_prod.grant_exact(buf.len() + size_of::<IpEndpointL>() + 1) {
-                    Ok(mut wgr) => {
-                        let endpoint_ser_len = ssmarshal::serialize(&mut wgr, &endpoint).unwrap();
-                        wgr[endpoint_ser_len .. buf.len() + endpoint_ser_len].copy_from_slice(buf);
-                        wgr.commit(buf.len() + endpoint_ser_len);
-                        let r = crate::app::link_process::spawn();
-                        if r.is_err() {
-                            error!(=>T, "link_process: spawn failed");
-                        }
-                    }
-                    Err(_) => {
-                        error!(=>T, "grant failed");
-                    }
-                }
-            }
-            Err(e) => {
-                log_warn!(=>T, "tcp_socket: recv: {:?}", e);
-            }
-        }
-    }
-}
-
-fn handle_tcp_tx(tcp_socket: &mut TcpSocket, eth_in_cons: &mut bbqueue::Consumer<512>) {
-    if tcp_socket.can_send() {
-        match eth_in_cons.read() {
-            Ok(rgr) => {
-                match tcp_socket.send_slice(&rgr) {
-                    Ok(written) => {
-                        rgr.release(written);
-                        // done_smth_useful = true;
-                        // log_trace!("Written {} to tcp_socket", written);
-                    }
-                    Err(e) => {
-                        log_warn!(=>T, "tcp_socket write err: {:?}", e);
-                    }
-                }
-            }
-            Err(_) => {}
-        }
-    }
+/// Wake-up hook of the Ethernet link: replies were queued into eth_in or space was freed in eth_out
+pub fn wake() {
+    rtic::pend(stm32h7xx_hal::pac::Interrupt::ETH);
 }
 
 pub fn smoltcp_poll_at(mut cx: crate::app::smoltcp_poll_at::Context) {
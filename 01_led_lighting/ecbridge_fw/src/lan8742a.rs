@@ -0,0 +1,88 @@
+//! SMSC LAN8742A PHY.
+//!
+//! HAL driver only tells whether a 100BASE-TX full duplex link is up and keeps the MAC to itself,
+//! this one also reports negotiated speed, duplex and error counters.
+
+use stm32h7xx_hal::ethernet::{StationManagement, PHY};
+
+const REG_BCR: u8 = 0x00;
+const REG_BSR: u8 = 0x01;
+const REG_MMD_CTL: u8 = 0x0D;
+const REG_MMD_DATA: u8 = 0x0E;
+/// Symbol Error Counter Register, counts invalid code groups received at 100 Mbit/s
+const REG_SECR: u8 = 0x1A;
+/// Special Status Register
+const REG_SSR: u8 = 0x1F;
+
+const MMD_WUCSR: u16 = 0x8010;
+
+const BCR_AN: u16 = 1 << 12;
+const BCR_ANRST: u16 = 1 << 9;
+const BCR_100M: u16 = 1 << 13;
+const BCR_RESET: u16 = 1 << 15;
+
+const BSR_UP: u16 = 1 << 2;
+const BSR_ANDONE: u16 = 1 << 5;
+
+const SSR_ANDONE: u16 = 1 << 12;
+const SSR_SPEED_MASK: u16 = 0b111 << 2;
+const SSR_10BASE_HD: u16 = 0b001 << 2;
+const SSR_10BASE_FD: u16 = 0b101 << 2;
+const SSR_100BASE_HD: u16 = 0b010 << 2;
+const SSR_100BASE_FD: u16 = 0b110 << 2;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LinkSpeed {
+    BaseT10HalfDuplex,
+    BaseT10FullDuplex,
+    BaseT100HalfDuplex,
+    BaseT100FullDuplex,
+}
+
+pub struct Lan8742A<MAC: StationManagement> {
+    mac: MAC,
+}
+
+impl<MAC: StationManagement> PHY for Lan8742A<MAC> {
+    fn phy_reset(&mut self) {
+        self.mac.smi_write(REG_BCR, BCR_RESET);
+        while self.mac.smi_read(REG_BCR) & BCR_RESET == BCR_RESET {}
+    }
+
+    fn phy_init(&mut self) {
+        // clear wake-up control and status
+        self.mac.smi_write(REG_MMD_CTL, 0x0003);
+        self.mac.smi_write(REG_MMD_DATA, MMD_WUCSR);
+        self.mac.smi_write(REG_MMD_CTL, 0x4003);
+        self.mac.smi_write(REG_MMD_DATA, 0);
+
+        self.mac.smi_write(REG_BCR, BCR_AN | BCR_ANRST | BCR_100M);
+    }
+}
+
+impl<MAC: StationManagement> Lan8742A<MAC> {
+    pub fn new(mac: MAC) -> Self {
+        Lan8742A { mac }
+    }
+
+    /// Negotiated link speed and duplex, None if there is no link.
+    pub fn poll_link(&mut self) -> Option<LinkSpeed> {
+        let bsr = self.mac.smi_read(REG_BSR);
+        let ssr = self.mac.smi_read(REG_SSR);
+        if bsr & BSR_ANDONE == 0 || bsr & BSR_UP == 0 || ssr & SSR_ANDONE == 0 {
+            return None;
+        }
+        match ssr & SSR_SPEED_MASK {
+            SSR_10BASE_HD => Some(LinkSpeed::BaseT10HalfDuplex),
+            SSR_10BASE_FD => Some(LinkSpeed::BaseT10FullDuplex),
+            SSR_100BASE_HD => Some(LinkSpeed::BaseT100HalfDuplex),
+            SSR_100BASE_FD => Some(LinkSpeed::BaseT100FullDuplex),
+            _ => None,
+        }
+    }
+
+    /// Symbol errors since the previous call, counter is cleared on read.
+    pub fn symbol_errors(&mut self) -> u16 {
+        self.mac.smi_read(REG_SECR)
+    }
+}
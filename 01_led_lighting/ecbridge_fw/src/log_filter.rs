@@ -0,0 +1,192 @@
+//! Runtime log filter: a global level and overrides for single modules, applied on top of the
+//! log-level-* features, which decide what is compiled in at all.
+//!
+//! Kept in RAM only, every boot starts with everything that is compiled in enabled.
+//! Changed over xPI with /log_filter (global level) and /log_filter/<index in MODULES>,
+//! or with commands on RTT down channel 0: `log debug`, `log ecbridge_dispatch trace`,
+//! `log ecbridge_dispatch default` to follow the global level again, `log` to print the filter.
+
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use log::{Level, LevelFilter};
+use rtt_target::rprintln;
+
+const T: u8 = 0;
+
+/// Modules that can have their own level, records from main.rs are `app`,
+/// records from other crates are matched by the crate name. Listed as /log_filter children in main.vhl.
+pub const MODULES: [&str; 7] = ["app", "can", "config", "ethernet", "vhlink", "ecbridge_dispatch", "ecbridge_net"];
+/// Module level that follows the global one
+pub const DEFAULT: u8 = 0xFF;
+
+const COMPILED_MAX: u8 = crate::logging::max_level() as u8;
+const LEVEL_NAMES: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+#[allow(clippy::declare_interior_mutable_const)]
+const MODULE_DEFAULT: AtomicU8 = AtomicU8::new(DEFAULT);
+
+static GLOBAL: AtomicU8 = AtomicU8::new(COMPILED_MAX);
+static MODULE_LEVELS: [AtomicU8; MODULES.len()] = [MODULE_DEFAULT; MODULES.len()];
+/// Highest of the global and module levels, most records are rejected by it without a lookup
+static MAX: AtomicU8 = AtomicU8::new(COMPILED_MAX);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    BadLevel,
+    NoSuchModule,
+}
+
+/// Whether a record from `module_path` (module_path!() or log::Record::target()) passes
+pub fn enabled(level: Level, module_path: &str) -> bool {
+    let level = level as u8;
+    if level > MAX.load(Ordering::Relaxed) {
+        return false;
+    }
+    let filter = match module_index(module_name(module_path)) {
+        Some(i) => match MODULE_LEVELS[i].load(Ordering::Relaxed) {
+            DEFAULT => GLOBAL.load(Ordering::Relaxed),
+            module_level => module_level,
+        },
+        None => GLOBAL.load(Ordering::Relaxed),
+    };
+    level <= filter
+}
+
+pub fn global() -> u8 {
+    GLOBAL.load(Ordering::Relaxed)
+}
+
+/// 0 (off) to 5 (trace), levels that are not compiled in are lowered to the highest one that is
+pub fn set_global(level: u8) -> Result<(), Error> {
+    GLOBAL.store(check_level(level)?, Ordering::Relaxed);
+    update_max();
+    Ok(())
+}
+
+/// Level of MODULES[index] or DEFAULT
+pub fn module(index: usize) -> Result<u8, Error> {
+    MODULE_LEVELS.get(index).map(|l| l.load(Ordering::Relaxed)).ok_or(Error::NoSuchModule)
+}
+
+/// Same levels as for set_global or DEFAULT
+pub fn set_module(index: usize, level: u8) -> Result<(), Error> {
+    let slot = MODULE_LEVELS.get(index).ok_or(Error::NoSuchModule)?;
+    let level = match level {
+        DEFAULT => DEFAULT,
+        level => check_level(level)?,
+    };
+    slot.store(level, Ordering::Relaxed);
+    update_max();
+    Ok(())
+}
+
+fn check_level(level: u8) -> Result<u8, Error> {
+    if level > LevelFilter::Trace as u8 {
+        return Err(Error::BadLevel);
+    }
+    Ok(level.min(COMPILED_MAX))
+}
+
+fn update_max() {
+    let max = MODULE_LEVELS.iter()
+        .map(|l| l.load(Ordering::Relaxed))
+        .filter(|l| *l != DEFAULT)
+        .fold(GLOBAL.load(Ordering::Relaxed), |max, l| max.max(l));
+    MAX.store(max, Ordering::Relaxed);
+}
+
+/// `ecbridge_fw::vhlink::x` is `vhlink`, `ecbridge_fw` and `ecbridge_fw::app` are `app`,
+/// `ecbridge_dispatch::dispatch` is `ecbridge_dispatch`
+pub fn module_name(module_path: &str) -> &str {
+    let mut parts = module_path.split("::");
+    match (parts.next(), parts.next()) {
+        (Some("ecbridge_fw"), Some(module)) => module,
+        (Some("ecbridge_fw"), None) => "app",
+        (Some(krate), _) => krate,
+        (None, _) => module_path,
+    }
+}
+
+fn module_index(name: &str) -> Option<usize> {
+    MODULES.iter().position(|m| *m == name)
+}
+
+fn level_name(level: u8) -> &'static str {
+    match level {
+        DEFAULT => "default",
+        level => LEVEL_NAMES.get(level as usize).copied().unwrap_or("?"),
+    }
+}
+
+/// Collects commands from RTT down channel into lines, answers are printed on RTT regardless of
+/// the filter
+pub struct Console {
+    line: [u8; 48],
+    len: usize,
+}
+
+impl Console {
+    pub const fn new() -> Self {
+        Console { line: [0; 48], len: 0 }
+    }
+
+    /// Commands are executed on newline, too long ones are cut and most likely rejected
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if b == b'\n' || b == b'\r' {
+                if self.len != 0 {
+                    match core::str::from_utf8(&self.line[..self.len]) {
+                        Ok(line) => execute(line),
+                        Err(_) => rprintln!(=>T, "console: not UTF-8"),
+                    }
+                    self.len = 0;
+                }
+            } else if self.len < self.line.len() {
+                self.line[self.len] = b;
+                self.len += 1;
+            }
+        }
+    }
+}
+
+fn execute(line: &str) {
+    let mut words = line.split_ascii_whitespace();
+    let r = match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("log"), None, _, _) => {
+            print();
+            return;
+        }
+        (Some("log"), Some(level), None, _) => {
+            parse_level(level).and_then(set_global)
+        }
+        (Some("log"), Some(module), Some(level), None) => {
+            let level = match level {
+                "default" => Ok(DEFAULT),
+                level => parse_level(level),
+            };
+            module_index(module).ok_or(Error::NoSuchModule)
+                .and_then(|i| level.and_then(|level| set_module(i, level)))
+        }
+        _ => {
+            rprintln!(=>T, "console: unknown command '{}', try: log [module] [off|error|warn|info|debug|trace|default]", line);
+            return;
+        }
+    };
+    match r {
+        Ok(_) => print(),
+        Err(e) => rprintln!(=>T, "console: '{}': {:?}", line, e),
+    }
+}
+
+fn parse_level(level: &str) -> Result<u8, Error> {
+    LevelFilter::from_str(level).map(|l| l as u8).map_err(|_| Error::BadLevel)
+}
+
+fn print() {
+    rprintln!(=>T, "log level: {}", level_name(global()));
+    for (i, name) in MODULES.iter().enumerate() {
+        let level = MODULE_LEVELS[i].load(Ordering::Relaxed);
+        if level != DEFAULT {
+            rprintln!(=>T, "log level of {}: {}", name, level_name(level));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Log records waiting to be published as /log stream updates by link_process (log-text-xpi).
+//!
+//! Same as [crate::syslog]: logging macros run at any priority, so the producer is only used inside
+//! a short critical section, records are dropped when the queue is full and their count is
+//! reported with the next record that fits. Records stay queued until somebody subscribes to /log,
+//! so that the first subscriber gets the boot log too.
+//! Records logged by link_process itself don't spawn it again, they are published at the end of
+//! the same run, and records logged while publishing are dropped, otherwise every published record
+//! would queue a few more about being sent.
+//! Record layout is in ecbridge_dispatch::log_record, which publishes them.
+
+use bbqueue::BBBuffer;
+use bbqueue::framed::FrameProducer;
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use cortex_m::interrupt::Mutex;
+use ecbridge_dispatch::log_record::{Record, MODULE_MAX, RECORD_HEADER_LEN};
+use ecbridge_net::SliceWriter;
+use log::Level;
+
+pub const QUEUE_LEN: usize = 2048;
+/// Longer messages are truncated
+pub const MESSAGE_MAX: usize = 160;
+
+static QUEUE: BBBuffer<QUEUE_LEN> = BBBuffer::new();
+
+struct Producer {
+    prod: FrameProducer<'static, QUEUE_LEN>,
+    dropped: u32,
+}
+
+static PRODUCER: Mutex<RefCell<Option<Producer>>> = Mutex::new(RefCell::new(None));
+/// Active exception number of link_process while it runs, 0 otherwise
+static LINK_PROCESS: AtomicU16 = AtomicU16::new(0);
+/// link_process is publishing queued records
+static PUBLISHING: AtomicBool = AtomicBool::new(false);
+
+/// Consumer side, owned by link_process
+pub type Backlog = ecbridge_dispatch::log_record::Backlog<'static, QUEUE_LEN>;
+
+/// Must be called once during init, backlog goes to link_process.
+pub fn init() -> Backlog {
+    let (prod, cons) = QUEUE.try_split_framed().unwrap();
+    cortex_m::interrupt::free(|cs| {
+        *PRODUCER.borrow(cs).borrow_mut() = Some(Producer { prod, dropped: 0 });
+    });
+    Backlog { cons, sent: 0 }
+}
+
+/// Marks link_process as running until dropped
+pub struct LinkProcessGuard(());
+
+/// Called first thing in link_process
+pub fn link_process_running() -> LinkProcessGuard {
+    LINK_PROCESS.store(vect_active(), Ordering::Relaxed);
+    LinkProcessGuard(())
+}
+
+impl Drop for LinkProcessGuard {
+    fn drop(&mut self) {
+        LINK_PROCESS.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Drops records logged by link_process until dropped, records from higher priority tasks are
+/// still queued
+pub struct PublishingGuard(());
+
+/// Called by link_process around publish_logs
+pub fn publishing() -> PublishingGuard {
+    PUBLISHING.store(true, Ordering::Relaxed);
+    PublishingGuard(())
+}
+
+impl Drop for PublishingGuard {
+    fn drop(&mut self) {
+        PUBLISHING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// VECTACTIVE field of ICSR: exception number of the running handler, 0 in thread mode (idle)
+fn vect_active() -> u16 {
+    // read only access to a register that is always there
+    (unsafe { (*cortex_m::peripheral::SCB::PTR).icsr.read() } & 0x1FF) as u16
+}
+
+/// Queue a record, never waits for space.
+#[allow(dead_code)]
+pub fn log(level: Level, module_path: &str, args: core::fmt::Arguments) {
+    let link_process = LINK_PROCESS.load(Ordering::Relaxed);
+    let from_link_process = link_process != 0 && link_process == vect_active();
+    if from_link_process && PUBLISHING.load(Ordering::Relaxed) {
+        return;
+    }
+    let module = crate::log_filter::module_name(module_path);
+    let queued = cortex_m::interrupt::free(|cs| {
+        // already borrowed if logging from a panic in the middle of enqueueing
+        let mut producer = match PRODUCER.borrow(cs).try_borrow_mut() {
+            Ok(producer) => producer,
+            Err(_) => return false,
+        };
+        let producer = match producer.as_mut() {
+            Some(producer) => producer,
+            None => return false, // not yet initialised
+        };
+        let uptime_ms = crate::wallclock::uptime().total_millis() as u64;
+        if producer.dropped != 0 {
+            let dropped = producer.dropped;
+            let args = format_args!("{} log records dropped", dropped);
+            if !enqueue(&mut producer.prod, Level::Warn, uptime_ms, "log_stream", args) {
+                producer.dropped += 1;
+                return false;
+            }
+            producer.dropped = 0;
+        }
+        if !enqueue(&mut producer.prod, level, uptime_ms, module, args) {
+            producer.dropped += 1;
+            return false;
+        }
+        true
+    });
+    if queued && !from_link_process {
+        let _ = crate::app::link_process::spawn();
+    }
+}
+
+fn enqueue(
+    prod: &mut FrameProducer<'static, QUEUE_LEN>,
+    level: Level,
+    uptime_ms: u64,
+    module: &str,
+    args: core::fmt::Arguments,
+) -> bool {
+    let mut wgr = match prod.grant(RECORD_HEADER_LEN + module.len().min(MODULE_MAX) + MESSAGE_MAX) {
+        Ok(wgr) => wgr,
+        Err(_) => return false,
+    };
+    let header_len = Record::header(level as u8, uptime_ms, module.as_bytes(), &mut wgr);
+    let mut wr = SliceWriter { buf: &mut wgr[header_len..], pos: 0 };
+    // truncated on overflow
+    let _ = wr.write_fmt(args);
+    let len = wr.pos;
+    wgr.commit(header_len + len);
+    true
+}
@@ -7,112 +7,354 @@ macro_rules! _level_to_color {
     (error) => { crate::vt100::RED };
 }
 
+#[macro_export]
+macro_rules! _level_to_severity {
+    (trace) => { crate::syslog::Severity::Debug };
+    (debug) => { crate::syslog::Severity::Debug };
+    (info) => { crate::syslog::Severity::Info };
+    (warn) => { crate::syslog::Severity::Warning };
+    (error) => { crate::syslog::Severity::Error };
+}
+
+#[macro_export]
+macro_rules! _level_to_can {
+    (trace) => { xpi_can::LogLevel::Trace };
+    (debug) => { xpi_can::LogLevel::Debug };
+    (info) => { xpi_can::LogLevel::Info };
+    (warn) => { xpi_can::LogLevel::Warn };
+    (error) => { xpi_can::LogLevel::Error };
+}
+
+#[macro_export]
+macro_rules! _level_to_log {
+    (trace) => { log::Level::Trace };
+    (debug) => { log::Level::Debug };
+    (info) => { log::Level::Info };
+    (warn) => { log::Level::Warn };
+    (error) => { log::Level::Error };
+}
+
+/// Same format string as for rprintln, it is interned by defmt instead of being stored in flash.
+/// defmt has no terminals, lines for all of them end up in one channel, so the terminal is sent as
+/// the first argument of the frame, followed by the message written by the nested format string.
+/// The module path of the call site is kept in the frame. Arguments are encoded by defmt if they
+/// implement defmt::Format, otherwise formatted on target, see logging::defmt_arg.
+#[macro_export]
+macro_rules! _log_defmt {
+    ($level: ident, $terminal:expr, $fmt:literal) => {
+        defmt::$level!(
+            "{=u8}: {}",
+            $terminal,
+            crate::logging::defmt_message(|f: defmt::Formatter| defmt::write!(f, $fmt))
+        )
+    };
+    ($level: ident, $terminal:expr, $fmt:literal, $($arg:expr),+ $(,)?) => {{
+        #[allow(unused_imports)]
+        use crate::logging::defmt_arg::{ViaDebug as _, ViaDisplay as _, ViaFormat as _};
+        defmt::$level!(
+            "{=u8}: {}",
+            $terminal,
+            crate::logging::defmt_message(|f: defmt::Formatter| {
+                defmt::write!(f, $fmt, $((&&&crate::logging::defmt_arg::Arg(&$arg)).defmt_arg()),+)
+            })
+        )
+    }};
+}
+
 #[macro_export]
 macro_rules! _log_internal {
     ($level: ident, => $terminal:expr) => {
         #[cfg(feature = "log-text-rtt")]
         rtt_target::rprintln!(=> $terminal);
     };
-    ($level: ident, => $terminal:expr, $fmt:expr) => {
+    ($level: ident, => $terminal:expr, $fmt:literal) => {
+        #[cfg(not(any(feature = "log-text-rtt", feature = "log-defmt-rtt", feature = "log-defmt-can")))]
+        let _ = $terminal;
         #[cfg(feature = "log-text-rtt")] {
             rtt_target::rprint!(=> $terminal, "{}", crate::_level_to_color!($level));
             rtt_target::rprintln!(=> $terminal, $fmt);
             rtt_target::rprint!(=> $terminal, crate::vt100::DEFAULT);
         }
+        #[cfg(feature = "log-text-udp")]
+        crate::syslog::log(crate::_level_to_severity!($level), format_args!($fmt));
+        #[cfg(feature = "log-text-can")]
+        crate::can_log::log(crate::_level_to_can!($level), format_args!($fmt));
+        #[cfg(feature = "log-text-xpi")]
+        crate::log_stream::log(crate::_level_to_log!($level), module_path!(), format_args!($fmt));
+        #[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+        crate::_log_defmt!($level, $terminal, $fmt);
     };
-    ($level: ident, => $terminal:expr, $fmt:expr, $($arg:tt)*) => {
+    ($level: ident, => $terminal:expr, $fmt:literal, $($arg:tt)*) => {
+        #[cfg(not(any(feature = "log-text-rtt", feature = "log-defmt-rtt", feature = "log-defmt-can")))]
+        let _ = $terminal;
         #[cfg(feature = "log-text-rtt")] {
             rtt_target::rprint!(=> $terminal, "{}", crate::_level_to_color!($level));
             rtt_target::rprintln!(=> $terminal, $fmt, $($arg)*);
             rtt_target::rprint!(=> $terminal, crate::vt100::DEFAULT);
         }
+        #[cfg(feature = "log-text-udp")]
+        crate::syslog::log(crate::_level_to_severity!($level), format_args!($fmt, $($arg)*));
+        #[cfg(feature = "log-text-can")]
+        crate::can_log::log(crate::_level_to_can!($level), format_args!($fmt, $($arg)*));
+        #[cfg(feature = "log-text-xpi")]
+        crate::log_stream::log(crate::_level_to_log!($level), module_path!(), format_args!($fmt, $($arg)*));
+        #[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+        crate::_log_defmt!($level, $terminal, $fmt, $($arg)*);
     };
     ($level: ident) => {
         #[cfg(feature = "log-text-rtt")]
         rtt_target::rprintln!(=>T);
     };
-    ($level: ident, $fmt:expr) => {
+    ($level: ident, $fmt:literal) => {
         #[cfg(feature = "log-text-rtt")] {
             rtt_target::rprint!(=>T, "{}", crate::_level_to_color!($level));
             rtt_target::rprintln!(=>T, $fmt);
             rtt_target::rprint!(=>T, crate::vt100::DEFAULT);
         }
+        #[cfg(feature = "log-text-udp")]
+        crate::syslog::log(crate::_level_to_severity!($level), format_args!($fmt));
+        #[cfg(feature = "log-text-can")]
+        crate::can_log::log(crate::_level_to_can!($level), format_args!($fmt));
+        #[cfg(feature = "log-text-xpi")]
+        crate::log_stream::log(crate::_level_to_log!($level), module_path!(), format_args!($fmt));
+        #[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+        crate::_log_defmt!($level, T, $fmt);
     };
-    ($level: ident, $fmt:expr, $($arg:tt)*) => {
+    ($level: ident, $fmt:literal, $($arg:tt)*) => {
         #[cfg(feature = "log-text-rtt")] {
             rtt_target::rprint!(=>T, "{}", crate::_level_to_color!($level));
             rtt_target::rprintln!(=>T, $fmt, $($arg)*);
             rtt_target::rprint!(=>T, crate::vt100::DEFAULT);
         }
+        #[cfg(feature = "log-text-udp")]
+        crate::syslog::log(crate::_level_to_severity!($level), format_args!($fmt, $($arg)*));
+        #[cfg(feature = "log-text-can")]
+        crate::can_log::log(crate::_level_to_can!($level), format_args!($fmt, $($arg)*));
+        #[cfg(feature = "log-text-xpi")]
+        crate::log_stream::log(crate::_level_to_log!($level), module_path!(), format_args!($fmt, $($arg)*));
+        #[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+        crate::_log_defmt!($level, T, $fmt, $($arg)*);
     };
 }
 
+// Expand to a block, so that the macros can be used as match arms too.
+// log-level-* features decide what is compiled in, crate::log_filter what is logged at runtime.
 #[cfg(feature = "log-level-trace")]
 #[macro_export]
 macro_rules! trace {
-    ($($arg:tt)*) => {
-        crate::_log_internal!(trace, $($arg)*);
-    };
+    ($($arg:tt)*) => {{
+        if crate::log_filter::enabled(log::Level::Trace, module_path!()) {
+            crate::_log_internal!(trace, $($arg)*);
+        }
+    }};
 }
 #[cfg(not(feature = "log-level-trace"))]
 #[macro_export]
 macro_rules! log_trace {
-    ($($arg:tt)*) => {}
+    ($($arg:tt)*) => {{}}
 }
 pub use trace;
 
 #[cfg(feature = "log-level-debug")]
 #[macro_export]
 macro_rules! debug {
-    ($($arg:tt)*) => {
-        crate::_log_internal!(debug, $($arg)*);
-    };
+    ($($arg:tt)*) => {{
+        if crate::log_filter::enabled(log::Level::Debug, module_path!()) {
+            crate::_log_internal!(debug, $($arg)*);
+        }
+    }};
 }
 #[cfg(not(feature = "log-level-debug"))]
 #[macro_export]
 macro_rules! log_debug {
-    ($($arg:tt)*) => {}
+    ($($arg:tt)*) => {{}}
 }
 pub use debug;
 
 #[cfg(feature = "log-level-info")]
 #[macro_export]
 macro_rules! info {
-    ($($arg:tt)*) => {
-        crate::_log_internal!(info, $($arg)*);
-    };
+    ($($arg:tt)*) => {{
+        if crate::log_filter::enabled(log::Level::Info, module_path!()) {
+            crate::_log_internal!(info, $($arg)*);
+        }
+    }};
 }
 #[cfg(not(feature = "log-level-info"))]
 #[macro_export]
 macro_rules! log_info {
-    ($($arg:tt)*) => {}
+    ($($arg:tt)*) => {{}}
 }
 pub use info;
 
 #[cfg(feature = "log-level-warn")]
 #[macro_export]
 macro_rules! log_warn {
-    ($($arg:tt)*) => {
-        crate::_log_internal!(warn, $($arg)*);
-    };
+    ($($arg:tt)*) => {{
+        if crate::log_filter::enabled(log::Level::Warn, module_path!()) {
+            crate::_log_internal!(warn, $($arg)*);
+        }
+    }};
 }
 #[cfg(not(feature = "log-level-warn"))]
 #[macro_export]
 macro_rules! log_warn {
-    ($($arg:tt)*) => {}
+    ($($arg:tt)*) => {{}}
 }
 pub use log_warn;
 
 #[cfg(feature = "log-level-error")]
 #[macro_export]
 macro_rules! error {
-    ($($arg:tt)*) => {
-        crate::_log_internal!(error, $($arg)*);
-    };
+    ($($arg:tt)*) => {{
+        if crate::log_filter::enabled(log::Level::Error, module_path!()) {
+            crate::_log_internal!(error, $($arg)*);
+        }
+    }};
 }
 #[cfg(not(feature = "log-level-error"))]
 #[macro_export]
 macro_rules! log_error {
-    ($($arg:tt)*) => {}
+    ($($arg:tt)*) => {{}}
+}
+pub use error;
+
+/// Picks how a logging macro argument is sent with defmt: as is if the type implements
+/// defmt::Format, otherwise formatted on target with Debug or, failing that, Display.
+/// Resolved by method lookup on `&&&Arg`, the first trait that applies wins.
+#[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+pub mod defmt_arg {
+    use core::fmt::{Debug, Display};
+    use defmt::{Debug2Format, Display2Format, Format};
+
+    pub struct Arg<'a, T: ?Sized>(pub &'a T);
+
+    pub trait ViaFormat<'a, T: ?Sized> {
+        fn defmt_arg(&self) -> &'a T;
+    }
+
+    impl<'a, T: Format + ?Sized> ViaFormat<'a, T> for &&Arg<'a, T> {
+        fn defmt_arg(&self) -> &'a T {
+            self.0
+        }
+    }
+
+    pub trait ViaDebug<'a, T: ?Sized> {
+        fn defmt_arg(&self) -> Debug2Format<'a, T>;
+    }
+
+    impl<'a, T: Debug + ?Sized> ViaDebug<'a, T> for &Arg<'a, T> {
+        fn defmt_arg(&self) -> Debug2Format<'a, T> {
+            Debug2Format(self.0)
+        }
+    }
+
+    pub trait ViaDisplay<'a, T: ?Sized> {
+        fn defmt_arg(&self) -> Display2Format<'a, T>;
+    }
+
+    impl<'a, T: Display + ?Sized> ViaDisplay<'a, T> for Arg<'a, T> {
+        fn defmt_arg(&self) -> Display2Format<'a, T> {
+            Display2Format(self.0)
+        }
+    }
+}
+
+/// Message written by the nested format string of a defmt frame, see _log_defmt
+#[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+pub struct DefmtMessage<F>(F);
+
+#[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+pub fn defmt_message<F: Fn(defmt::Formatter<'_>)>(write: F) -> DefmtMessage<F> {
+    DefmtMessage(write)
+}
+
+#[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+impl<F: Fn(defmt::Formatter<'_>)> defmt::Format for DefmtMessage<F> {
+    fn format(&self, f: defmt::Formatter) {
+        (self.0)(f)
+    }
+}
+
+/// Forwards `log` crate records (from ecbridge_net) to RTT channel 0, syslog, CAN and /log, same as the macros above.
+pub struct RttLogger;
+
+impl log::Log for RttLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= max_level() && crate::log_filter::enabled(metadata.level(), metadata.target())
+    }
+
+    #[allow(unused_variables)]
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        #[cfg(feature = "log-text-rtt")] {
+            let color = match record.level() {
+                log::Level::Trace => crate::vt100::CYAN,
+                log::Level::Debug => crate::vt100::DEFAULT,
+                log::Level::Info => crate::vt100::GREEN,
+                log::Level::Warn => crate::vt100::YELLOW,
+                log::Level::Error => crate::vt100::RED,
+            };
+            rtt_target::rprint!(=>0, "{}", color);
+            rtt_target::rprintln!(=>0, "{}", record.args());
+            rtt_target::rprint!(=>0, crate::vt100::DEFAULT);
+        }
+        #[cfg(feature = "log-text-udp")]
+        crate::syslog::log(record.level().into(), *record.args());
+        #[cfg(feature = "log-text-can")]
+        crate::can_log::log(level_to_can(record.level()), *record.args());
+        #[cfg(feature = "log-text-xpi")]
+        crate::log_stream::log(record.level(), record.target(), *record.args());
+        #[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))] {
+            let args = defmt::Display2Format(record.args());
+            match record.level() {
+                log::Level::Trace => defmt::trace!("{=u8}: {}", 0, args),
+                log::Level::Debug => defmt::debug!("{=u8}: {}", 0, args),
+                log::Level::Info => defmt::info!("{=u8}: {}", 0, args),
+                log::Level::Warn => defmt::warn!("{=u8}: {}", 0, args),
+                log::Level::Error => defmt::error!("{=u8}: {}", 0, args),
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(feature = "log-text-can")]
+fn level_to_can(level: log::Level) -> xpi_can::LogLevel {
+    match level {
+        log::Level::Trace => xpi_can::LogLevel::Trace,
+        log::Level::Debug => xpi_can::LogLevel::Debug,
+        log::Level::Info => xpi_can::LogLevel::Info,
+        log::Level::Warn => xpi_can::LogLevel::Warn,
+        log::Level::Error => xpi_can::LogLevel::Error,
+    }
+}
+
+static LOGGER: RttLogger = RttLogger;
+
+/// Highest level enabled by log-level-* features
+pub const fn max_level() -> log::LevelFilter {
+    if cfg!(feature = "log-level-trace") {
+        log::LevelFilter::Trace
+    } else if cfg!(feature = "log-level-debug") {
+        log::LevelFilter::Debug
+    } else if cfg!(feature = "log-level-info") {
+        log::LevelFilter::Info
+    } else if cfg!(feature = "log-level-warn") {
+        log::LevelFilter::Warn
+    } else if cfg!(feature = "log-level-error") {
+        log::LevelFilter::Error
+    } else {
+        log::LevelFilter::Off
+    }
+}
+
+/// Must be called after rtt_init_print!()
+pub fn init_log() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(max_level());
+    }
 }
-pub use error;
\ No newline at end of file
@@ -7,16 +7,32 @@
 #![allow(unused_imports)]
 // #![allow(dead_code)]
 
+mod can;
+mod can_log;
+mod config;
+mod crash;
+#[cfg(any(feature = "log-defmt-rtt", feature = "log-defmt-can"))]
+mod defmt_log;
 mod ethernet;
 mod vhlink;
-mod xpi_dispatch;
 mod oled;
 mod vt100;
 mod logging;
+mod log_filter;
+mod log_stream;
+mod lan8742a;
+mod node;
+mod supervisor;
+mod syslog;
+mod wallclock;
 mod generated_goal;
 mod xpi_gen;
 
+use ecbridge_dispatch::{auth, node_table, router, subscriptions};
+
 pub const CORE_FREQ: u32 = 200_000_000;
+/// xPI node id of the ECBridge itself, factory default, actual one is in config
+pub const NODE_ID: u8 = 1;
 #[allow(dead_code)]
 type Instant = fugit::TimerInstantU64<CORE_FREQ>;
 #[allow(dead_code)]
@@ -29,10 +45,11 @@ mod app {
 
     use super::*;
     use bbqueue::BBBuffer;
-    use rtt_target::rtt_init_print;
 
     use ethernet::{ethernet_event, smoltcp_poll_at};
+    use can::can_event;
     use vhlink::link_process;
+    use supervisor::supervise;
     use oled::display_task;
 
     const T: u8 = 0;
@@ -52,32 +69,105 @@ mod app {
         /// Even better if possible to add notify_task to it
         symbol: char,
         digit: u8,
+
+        flow_stats: vhlink::FlowStats,
+
+        config: config::ConfigState,
+
+        link: ethernet::LinkState,
+        subscriptions: subscriptions::Subscriptions,
+
+        /// Authentication state of the current connection over each link
+        sessions: auth::Sessions,
+        /// Nonces for authentication challenges
+        rng: stm32h7xx_hal::rng::Rng,
+        /// Ping requested over xPI and its results
+        self_test: ethernet::SelfTest,
+        /// Which links nodes are behind, learned by link_process, forgotten by the transports
+        router: router::Router,
+        /// CAN node ids given out to modules, persisted by config_store
+        node_table: node_table::NodeTable,
     }
     #[local]
     struct LocalResources {
-        net: ethernet::Net<'static>,
-        eth_in_cons: bbqueue::Consumer<'static, 512>, // eth irq: take & tx
-        eth_out_prod: bbqueue::Producer<'static, 512>, // eth irq: rx & put
+        net: ethernet::Net,
+        eth_in_cons: bbqueue::Consumer<'static, { ethernet::ETH_QUEUE_LEN }>, // eth irq: take & tx
+        eth_out_prod: bbqueue::framed::FrameProducer<'static, { ethernet::ETH_QUEUE_LEN }>, // eth irq: rx & put
+        syslog_cons: bbqueue::framed::FrameConsumer<'static, { syslog::QUEUE_LEN }>, // eth irq: take & send
         lan8742a: ethernet::Lan8742A,
+        can: can::Can,
+        can_state: can::CanState,
+        can_rx_prod: bbqueue::framed::FrameProducer<'static, { can::CAN_QUEUE_LEN }>, // can irq: reassemble & put
+        can_tx_cons: bbqueue::framed::FrameConsumer<'static, { can::CAN_QUEUE_LEN }>, // can irq: take & segment
+        can_log_cons: bbqueue::framed::FrameConsumer<'static, { can_log::QUEUE_LEN }>, // can irq: take & segment
 
-        eth_out_cons: bbqueue::Consumer<'static, 512>, // dispatcher: take
-        eth_in_prod: bbqueue::Producer<'static, 512>, // dispatcher: put
+        links: vhlink::Links, // dispatcher: take requests & put replies
+        log_backlog: log_stream::Backlog, // dispatcher: publish to /log subscribers
 
         led_link: gpio::gpioe::PE10<gpio::Output<gpio::PushPull>>,
         led_act: gpio::gpioe::PE11<gpio::Output<gpio::PushPull>>,
 
         display: oled::DisplayTy,
+
+        flash: stm32h7xx_hal::pac::FLASH,
+
+        rtt_down: rtt_target::DownChannel, // idle: log_filter commands
+
+        watchdog: supervisor::Watchdog,
     }
 
     #[init(local = [
-        eth_out_bb: BBBuffer<512> = BBBuffer::new(),
-        eth_in_bb: BBBuffer<512> = BBBuffer::new(),
+        eth_out_bb: BBBuffer<{ ethernet::ETH_QUEUE_LEN }> = BBBuffer::new(),
+        eth_in_bb: BBBuffer<{ ethernet::ETH_QUEUE_LEN }> = BBBuffer::new(),
+        can_rx_bb: BBBuffer<{ can::CAN_QUEUE_LEN }> = BBBuffer::new(),
+        can_tx_bb: BBBuffer<{ can::CAN_QUEUE_LEN }> = BBBuffer::new(),
     ])]
     fn init(
         mut ctx: init::Context,
     ) -> (SharedResources, LocalResources, init::Monotonics) {
-        rtt_init_print!();
+        // down channel takes log_filter commands
+        #[cfg(not(feature = "log-defmt-rtt"))]
+        let rtt_down = {
+            let channels = rtt_target::rtt_init! {
+                up: {
+                    0: { size: 1024 mode: NoBlockSkip name: "Terminal" }
+                }
+                down: {
+                    0: { size: 64 name: "Terminal" }
+                }
+            };
+            rtt_target::set_print_channel(channels.up.0);
+            channels.down.0
+        };
+        #[cfg(feature = "log-defmt-rtt")]
+        let rtt_down = {
+            let channels = rtt_target::rtt_init! {
+                up: {
+                    0: { size: 1024 mode: NoBlockSkip name: "Terminal" }
+                    1: { size: 1024 mode: NoBlockSkip name: "defmt" }
+                }
+                down: {
+                    0: { size: 64 name: "Terminal" }
+                }
+            };
+            rtt_target::set_print_channel(channels.up.0);
+            defmt_log::init_rtt(channels.up.1);
+            channels.down.0
+        };
+        logging::init_log();
+        let syslog_cons = syslog::init();
+        let can_log_cons = can_log::init();
+        let log_backlog = log_stream::init();
         info!(=>T, "ecbridge_fw_hackathon");
+        let reset_flags = ctx.device.RCC.rsr.read().bits();
+        ctx.device.RCC.rsr.modify(|_, w| w.rmvf().set_bit());
+        match crash::init(reset_flags) {
+            Some(report) => error!(=>T, "previous run crashed: {}", report),
+            None if reset_flags & crash::RESET_IWDG1 != 0 => {
+                log_warn!(=>T, "watchdog reset without a crash report, supervisor didn't run")
+            }
+            None => info!(=>T, "reset flags: {:#010x}", reset_flags),
+        }
         // Initialise power...
         let pwr = ctx.device.PWR.constrain();
         let pwrcfg = pwr.freeze();
@@ -90,6 +180,7 @@ mod app {
         let ccdr = rcc
             .sys_ck(CORE_FREQ.Hz())
             .hclk(CORE_FREQ.Hz())
+            .pll1_q_ck(80.MHz()) // FDCAN kernel clock
             .freeze(pwrcfg, &ctx.device.SYSCFG);
 
         // Initialise system...
@@ -104,6 +195,9 @@ mod app {
 
         debug!(=>T, "Core init done");
 
+        let config = config::load();
+        let node_table = config::load_node_table();
+
         // Initialise IO...
         let gpioa = ctx.device.GPIOA.split(ccdr.peripheral.GPIOA);
         let gpiob = ctx.device.GPIOB.split(ccdr.peripheral.GPIOB);
@@ -150,8 +244,18 @@ mod app {
             ethernet_pins,
             eth_prec,
             &ccdr.clocks,
+            &config,
+        );
+
+        let can = can::init(
+            ctx.device.FDCAN1,
+            gpiod.pd0.into_alternate(),
+            gpiod.pd1.into_alternate(),
+            ccdr.peripheral.FDCAN,
         );
 
+        let rng = ctx.device.RNG.constrain(ccdr.peripheral.RNG, &ccdr.clocks);
+
         // Delay provider
         let timer2 = ctx.device
             .TIM2
@@ -182,13 +286,19 @@ mod app {
         display.init().unwrap();
 
         // Create queues
-        let (eth_out_prod, eth_out_cons) = ctx.local.eth_out_bb.try_split().unwrap();
+        let (eth_out_prod, eth_out_cons) = ctx.local.eth_out_bb.try_split_framed().unwrap();
         let (eth_in_prod, eth_in_cons) = ctx.local.eth_in_bb.try_split().unwrap();
+        let (can_rx_prod, can_rx_cons) = ctx.local.can_rx_bb.try_split_framed().unwrap();
+        let (can_tx_prod, can_tx_cons) = ctx.local.can_tx_bb.try_split_framed().unwrap();
 
         // Spawn tasks
         rtic::pend(stm32h7xx_hal::pac::Interrupt::ETH); // start listening on sockets, etc
         // blinky::spawn_after(1u64.secs()).unwrap();
         display_task::spawn().unwrap();
+        heartbeat::spawn().unwrap();
+        // last, init is long
+        let watchdog = supervisor::Watchdog::start(ctx.device.IWDG, &ctx.device.DBGMCU);
+        supervise::spawn().unwrap();
 
         debug!(=>T, "All init done");
         (
@@ -196,32 +306,98 @@ mod app {
                 symbol: '-',
                 digit: 0,
                 poll_at_handle: None,
+                flow_stats: vhlink::FlowStats::new(),
+                config: config::ConfigState::new(config),
+                link: ethernet::LinkState::new(),
+                subscriptions: subscriptions::Subscriptions::new(),
+                sessions: auth::Sessions::new(),
+                rng,
+                self_test: ethernet::SelfTest::new(),
+                router: router::Router::new(),
+                node_table,
             },
             LocalResources {
                 net,
                 eth_in_cons,
                 eth_out_prod,
+                syslog_cons,
                 lan8742a,
-
-                eth_out_cons,
-                eth_in_prod,
+                can,
+                can_state: can::CanState::new(),
+                can_rx_prod,
+                can_tx_cons,
+                can_log_cons,
+
+                links: vhlink::Links {
+                    eth_rx: eth_out_cons,
+                    can_rx: can_rx_cons,
+                    eth_stalled: None,
+                    can_stalled: None,
+                    tx: vhlink::LinksTx {
+                        eth: vhlink::StreamTx::new(
+                            vhlink::LinkId::Ethernet,
+                            eth_in_prod,
+                            vhlink::MTU_MAX,
+                            ethernet::wake,
+                        ),
+                        can: can::CanTx::new(can_tx_prod),
+                    },
+                },
+                log_backlog,
 
                 display,
                 led_link,
                 led_act,
 
+                flash: ctx.device.FLASH,
+
+                rtt_down,
+
+                watchdog,
             },
             init::Monotonics(mono),
         )
     }
 
-    #[idle(local = [lan8742a, led_link])]
-    fn idle(ctx: idle::Context) -> ! {
+    #[idle(local = [lan8742a, led_link, rtt_down, console: log_filter::Console = log_filter::Console::new()], shared = [link, subscriptions])]
+    fn idle(mut ctx: idle::Context) -> ! {
+        let mut link_speed = None;
+        let mut diagnostics_at = monotonics::now();
         loop {
+            supervisor::check_in(supervisor::Task::Idle);
+            let mut command = [0u8; 16];
+            let len = ctx.local.rtt_down.read(&mut command);
+            ctx.local.console.feed(&command[..len]);
+
             // Ethernet
-            match ctx.local.lan8742a.poll_link() {
-                true => ctx.local.led_link.set_high(),
-                _ => ctx.local.led_link.set_low(),
+            let speed = ctx.local.lan8742a.poll_link();
+            match speed {
+                Some(_) => ctx.local.led_link.set_high(),
+                None => ctx.local.led_link.set_low(),
+            }
+            let mut changed = false;
+            if speed != link_speed {
+                link_speed = speed;
+                let link = ctx.shared.link.lock(|l| {
+                    l.update(speed.map(ethernet::speed_duplex));
+                    *l
+                });
+                info!(=>T, "link: {:?}", link);
+                rtic::pend(stm32h7xx_hal::pac::Interrupt::ETH); // reset or restart the listener
+                changed = true;
+            }
+            let now = monotonics::now();
+            if now >= diagnostics_at {
+                diagnostics_at = now + 1000u64.millis();
+                let symbol_errors = ctx.local.lan8742a.symbol_errors() as u32;
+                if symbol_errors != 0 {
+                    ctx.shared.link.lock(|l| l.symbol_errors = l.symbol_errors.wrapping_add(symbol_errors));
+                    changed = true;
+                }
+            }
+            if changed {
+                ctx.shared.subscriptions.lock(|s| s.notify(ecbridge_dispatch::LINK_RESOURCE));
+                let _ = link_process::spawn(); // publish to subscribers
             }
         }
     }
@@ -246,20 +422,71 @@ mod app {
         info!(=>T, "async_task: {:?} {:?}", p1, p2);
     }
 
+    /// Spawned on Call to /config/apply or /config/factory_reset, restarts afterwards.
+    /// Also spawned when CAN node table changes, without a restart. A dirty node table is saved by
+    /// every run, failed saves are retried every NODE_TABLE_RETRY_MS until the table is clean.
+    #[task(local = [flash], shared = [node_table], capacity = 2)]
+    fn config_store(mut ctx: config_store::Context, op: config::StoreOp) {
+        if let Some(table) = ctx.shared.node_table.lock(|t| t.take_dirty()) {
+            if config::save_node_table(ctx.local.flash, &table).is_err() {
+                ctx.shared.node_table.lock(|t| t.mark_dirty());
+                // already queued if it fails, which saves the table as well
+                let _ = config_store::spawn_after(
+                    config::NODE_TABLE_RETRY_MS.millis(),
+                    config::StoreOp::SaveNodeTable,
+                );
+            }
+        }
+        let r = match op {
+            config::StoreOp::Save(config) => config::save(ctx.local.flash, &config),
+            config::StoreOp::FactoryReset => config::factory_reset(ctx.local.flash),
+            config::StoreOp::SaveNodeTable => return,
+        };
+        if r.is_ok() {
+            // give some time for the reply to go out
+            restart::spawn_after(200u64.millis()).unwrap();
+        }
+    }
+
+    /// xPI level heartbeat, lets subscribers tell a dead bridge from an idle one
+    #[task(shared = [config, subscriptions])]
+    fn heartbeat(mut ctx: heartbeat::Context) {
+        let keepalive_s = ctx.shared.config.lock(|c| c.active.keepalive_s);
+        if keepalive_s == 0 {
+            return;
+        }
+        ctx.shared.subscriptions.lock(|s| s.notify(ecbridge_dispatch::HEARTBEAT_RESOURCE));
+        let _ = link_process::spawn(); // publish to subscribers
+        heartbeat::spawn_after((keepalive_s as u64).secs()).unwrap();
+    }
+
+    #[task]
+    fn restart(_ctx: restart::Context) {
+        info!(=>T, "restarting");
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
     extern "Rust" {
         // Challenge - how to assemble all the resources names automatically?
-        #[task(binds = ETH, priority = 2, local = [net, eth_in_cons, eth_out_prod, led_act], shared = [poll_at_handle])]
+        #[task(binds = ETH, priority = 2, local = [net, eth_in_cons, eth_out_prod, syslog_cons, led_act], shared = [poll_at_handle, flow_stats, link, sessions, subscriptions, self_test, config, digit, symbol, router])]
         fn ethernet_event(_: ethernet_event::Context);
 
+        #[task(binds = FDCAN1_IT0, priority = 2, local = [can, can_state, can_rx_prod, can_tx_cons, can_log_cons], shared = [flow_stats, config, router, node_table])]
+        fn can_event(_: can_event::Context);
+
         // Priority <= ethernet_event make sense
         #[task(priority = 2, shared = [poll_at_handle])]
         fn smoltcp_poll_at(_: smoltcp_poll_at::Context);
 
-        #[task(shared = [digit], local = [eth_out_cons, eth_in_prod])]
+        #[task(shared = [digit, flow_stats, config, link, subscriptions, sessions, rng, self_test, router, node_table], local = [links, log_backlog])]
         fn link_process(_: link_process::Context);
 
         #[task(local = [display], shared = [symbol, digit])]
         fn display_task(_: display_task::Context);
+
+        // Above every task it supervises
+        #[task(priority = 3, local = [watchdog])]
+        fn supervise(_: supervise::Context);
     }
 }
 
@@ -268,7 +495,9 @@ use dwt_systick_monotonic::fugit;
 
 #[exception]
 unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
-    panic!("HF: {:#?}", ef);
+    // whole frame goes to the crash report
+    crash::record_frame(ef);
+    panic!("HF at pc={:#010x} lr={:#010x}", ef.pc(), ef.lr());
 }
 
 #[exception]
@@ -283,8 +512,6 @@ unsafe fn DefaultHandler(irqn: i16) {
 fn panic(info: &core::panic::PanicInfo) -> ! {
     use cortex_m::interrupt;
     // use rtt_target::{UpChannel, ChannelMode};
-    use core::sync::atomic::compiler_fence;
-    use core::sync::atomic::Ordering::SeqCst;
 
     interrupt::disable();
 
@@ -294,13 +521,14 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     //     writeln!(channel, "{}", info).ok();
     // }
 
-    for i in 0..8 {
-        error!(=>i, "{}", info);
+    // first, logging below might fault as well, nested panics go straight to reset
+    if crash::record(crash::Kind::Panic, format_args!("{}", info)) {
+        for i in 0..8 {
+            error!(=>i, "{}", info);
+        }
     }
 
-    loop {
-        compiler_fence(SeqCst);
-    }
+    crash::reset()
 }
 
 // /// Must be called directly from dispatcher on Call to /sync
@@ -316,10 +544,4 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 //     a - b
 // }
 
-use vhl_cg::point::Point;
-fn sync(p1: Point, p2: Point) -> Point {
-    Point {
-        x: p1.x + p2.x,
-        y: p1.y + p2.y
-    }
-}
\ No newline at end of file
+use vhl_cg::point::Point;
\ No newline at end of file
@@ -0,0 +1,153 @@
+//! The bridge as seen by ecbridge_dispatch: link_process shared resources, tasks spawned on calls
+//! and the globals served as /crash, /time and /log_filter.
+
+use crate::config::{Config, ConfigState, StoreOp};
+use crate::ethernet::LinkState;
+use crate::{crash, info, log_filter, trace};
+use ecbridge_dispatch::auth::Sessions;
+use ecbridge_dispatch::node_table::NodeTable;
+use ecbridge_dispatch::subscriptions::Subscriptions;
+use ecbridge_dispatch::{CrashReport, Node};
+use ecbridge_net::ping::PingStats;
+use ecbridge_net::sntp::SntpStatus;
+use rtic::Mutex;
+use smoltcp::time::Instant;
+use smoltcp::wire::IpAddress;
+use stm32h7xx_hal::rng::RngCore;
+use vhl_cg::point::Point;
+use xpi::error::XpiError;
+
+const T: u8 = 2;
+
+impl Node for crate::app::link_process::SharedResources<'_> {
+    fn config<R>(&mut self, f: impl FnOnce(&mut ConfigState) -> R) -> R {
+        self.config.lock(f)
+    }
+
+    fn sessions<R>(&mut self, f: impl FnOnce(&mut Sessions) -> R) -> R {
+        self.sessions.lock(f)
+    }
+
+    fn subscriptions<R>(&mut self, f: impl FnOnce(&mut Subscriptions) -> R) -> R {
+        self.subscriptions.lock(f)
+    }
+
+    fn node_table<R>(&mut self, f: impl FnOnce(&mut NodeTable) -> R) -> R {
+        self.node_table.lock(f)
+    }
+
+    fn digit(&mut self) -> u8 {
+        self.digit.lock(|d| *d)
+    }
+
+    fn write_digit(&mut self, digit: u8) {
+        self.digit.lock(|d| *d = digit);
+        let _ = crate::app::display_task::spawn();
+    }
+
+    fn set_digit(&mut self, digit: u8) {
+        let spawn_r = crate::app::set_digit::spawn(digit);
+        trace!(=>T, "Spawning /set_digit({}) {:?}", digit, spawn_r);
+    }
+
+    fn async_call(&mut self, p1: Point, p2: Point) {
+        let spawn_r = crate::app::async_task::spawn(p1, p2);
+        trace!(=>T, "Spawning /async: {:?}", spawn_r);
+    }
+
+    fn link_state(&mut self) -> LinkState {
+        self.link.lock(|l| *l)
+    }
+
+    fn flow_stalls(&mut self) -> (u32, u32) {
+        self.flow_stats.lock(|s| (s.rx_stalls, s.dispatch_stalls))
+    }
+
+    fn fill_random(&mut self, buf: &mut [u8]) -> Result<(), XpiError> {
+        self.rng.lock(|rng| rng.fill(buf)).map_err(|_| XpiError::Internal)
+    }
+
+    fn uptime(&self) -> Instant {
+        crate::wallclock::uptime()
+    }
+
+    fn sntp_status(&self) -> SntpStatus {
+        crate::wallclock::status()
+    }
+
+    fn start_ping(&mut self, target: IpAddress, count: u8) -> bool {
+        let started = self.self_test.lock(|t| {
+            if t.is_busy() {
+                false
+            } else {
+                t.request = Some((target, count));
+                true
+            }
+        });
+        if started {
+            rtic::pend(stm32h7xx_hal::pac::Interrupt::ETH);
+        }
+        started
+    }
+
+    fn ping_stats(&mut self) -> PingStats {
+        self.self_test.lock(|t| t.stats)
+    }
+
+    fn crash_report(&self) -> Option<CrashReport> {
+        crash::last().map(|r| CrashReport {
+            kind: r.kind,
+            uptime_ms: r.uptime_ms,
+            frame: r.frame,
+            message_len: r.message_len,
+            message: r.message,
+        })
+    }
+
+    fn reset_flags(&self) -> u32 {
+        crash::reset_flags()
+    }
+
+    fn log_modules(&self) -> usize {
+        log_filter::MODULES.len()
+    }
+
+    fn log_level(&self, module: Option<usize>) -> Result<u8, XpiError> {
+        match module {
+            None => Ok(log_filter::global()),
+            Some(module) => log_filter::module(module).map_err(log_filter_error),
+        }
+    }
+
+    fn set_log_level(&mut self, module: Option<usize>, level: u8) -> Result<(), XpiError> {
+        match module {
+            None => log_filter::set_global(level),
+            Some(module) => log_filter::set_module(module, level),
+        }
+        .map_err(log_filter_error)
+    }
+
+    fn apply_config(&mut self, config: Config) -> Result<(), XpiError> {
+        let spawn_r = crate::app::config_store::spawn(StoreOp::Save(config));
+        info!(=>T, "Spawning config_store: {:?}", spawn_r);
+        spawn_r.map_err(|_| XpiError::Internal)
+    }
+
+    fn factory_reset(&mut self) -> Result<(), XpiError> {
+        let spawn_r = crate::app::config_store::spawn(StoreOp::FactoryReset);
+        info!(=>T, "Spawning config_store: {:?}", spawn_r);
+        spawn_r.map_err(|_| XpiError::Internal)
+    }
+
+    fn save_node_table(&mut self) {
+        // fails only if config_store is already queued, that run saves the table too
+        let _ = crate::app::config_store::spawn(StoreOp::SaveNodeTable);
+    }
+}
+
+fn log_filter_error(e: log_filter::Error) -> XpiError {
+    match e {
+        log_filter::Error::NoSuchModule => XpiError::BadUri,
+        log_filter::Error::BadLevel => XpiError::OperationNotSupported,
+    }
+}
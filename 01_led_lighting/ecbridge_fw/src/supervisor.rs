@@ -0,0 +1,131 @@
+//! Task supervisor: the independent watchdog is fed only while every registered task is alive.
+//!
+//! idle loops forever and must check in at least once per deadline. Event driven tasks only hold
+//! a [Busy] guard while they run, they are stuck if it is held for longer than their deadline.
+//! supervise runs above all of them, so a stuck task can't keep it from noticing: the missed task
+//! is written to the crash report and the chip is reset, the report is served as /crash on the next
+//! boot. If supervise itself doesn't run (interrupts disabled for too long), the watchdog resets
+//! the chip without a report.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::crash;
+use crate::error;
+use dwt_systick_monotonic::ExtU64;
+use stm32h7xx_hal::pac;
+
+const T: u8 = 0;
+
+/// How often supervise checks the tasks and feeds the watchdog
+const PERIOD_MS: u64 = 250;
+/// LSI / 32 is ~1kHz, so RLR is in ms
+const WATCHDOG_TIMEOUT_MS: u32 = 2000;
+/// Not running, for event driven tasks
+const NOT_BUSY: u32 = u32::MAX;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum Task {
+    Idle,
+    EthernetEvent,
+    CanEvent,
+    LinkProcess,
+}
+
+/// Name and deadline in ms, indexed by Task. idle is starved while config_store erases flash.
+const TASKS: [(&str, u32); 4] = [
+    ("idle", 5000),
+    ("ethernet_event", 1000),
+    ("can_event", 1000),
+    ("link_process", 1000),
+];
+
+/// Last check-in of idle or start of the current run of other tasks, ms of uptime
+static CHECK_INS: [AtomicU32; TASKS.len()] = [
+    AtomicU32::new(0),
+    AtomicU32::new(NOT_BUSY),
+    AtomicU32::new(NOT_BUSY),
+    AtomicU32::new(NOT_BUSY),
+];
+
+fn now_ms() -> u32 {
+    crate::wallclock::uptime().total_millis() as u32
+}
+
+/// Called by looping tasks on every iteration
+pub fn check_in(task: Task) {
+    CHECK_INS[task as usize].store(now_ms(), Ordering::Relaxed);
+}
+
+/// Held by event driven tasks for the whole run: `let _busy = supervisor::busy(Task::CanEvent);`
+pub fn busy(task: Task) -> Busy {
+    // never NOT_BUSY, 49 days of uptime are 1ms short of it
+    CHECK_INS[task as usize].store(now_ms().min(NOT_BUSY - 1), Ordering::Relaxed);
+    Busy { task }
+}
+
+pub struct Busy {
+    task: Task,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        CHECK_INS[self.task as usize].store(NOT_BUSY, Ordering::Relaxed);
+    }
+}
+
+/// First task that missed its deadline and how late it is in ms
+fn missed() -> Option<(usize, u32)> {
+    let now = now_ms();
+    CHECK_INS.iter().zip(TASKS.iter()).enumerate().find_map(|(i, (check_in, (_, deadline)))| {
+        match check_in.load(Ordering::Relaxed) {
+            NOT_BUSY => None,
+            at => {
+                let since = now.wrapping_sub(at);
+                if since > *deadline {
+                    Some((i, since - deadline))
+                } else {
+                    None
+                }
+            }
+        }
+    })
+}
+
+pub struct Watchdog {
+    iwdg: pac::IWDG,
+}
+
+impl Watchdog {
+    /// Start the independent watchdog, it can't be stopped anymore until reset.
+    /// It is frozen while the core is halted by a debugger.
+    pub fn start(iwdg: pac::IWDG, dbgmcu: &pac::DBGMCU) -> Self {
+        dbgmcu.apb4fz1.modify(|_, w| w.dbg_iwdg1().set_bit());
+        iwdg.kr.write(|w| unsafe { w.bits(0xCCCC) }); // start
+        iwdg.kr.write(|w| unsafe { w.bits(0x5555) }); // unlock PR and RLR
+        iwdg.pr.write(|w| unsafe { w.bits(3) }); // LSI / 32
+        iwdg.rlr.write(|w| unsafe { w.bits(WATCHDOG_TIMEOUT_MS) });
+        while iwdg.sr.read().bits() != 0 {} // PR and RLR updated
+        iwdg.kr.write(|w| unsafe { w.bits(0xAAAA) });
+        Watchdog { iwdg }
+    }
+
+    fn feed(&mut self) {
+        self.iwdg.kr.write(|w| unsafe { w.bits(0xAAAA) });
+    }
+}
+
+pub fn supervise(ctx: crate::app::supervise::Context) {
+    match missed() {
+        None => ctx.local.watchdog.feed(),
+        Some((i, late_ms)) => {
+            let (name, deadline) = TASKS[i];
+            cortex_m::interrupt::disable();
+            let args = format_args!("{} missed its {}ms deadline by {}ms", name, deadline, late_ms);
+            if crash::record(crash::Kind::Watchdog, args) {
+                error!(=>T, "{} missed its {}ms deadline by {}ms", name, deadline, late_ms);
+            }
+            crash::reset();
+        }
+    }
+    crate::app::supervise::spawn_after(PERIOD_MS.millis()).unwrap();
+}
@@ -0,0 +1,60 @@
+//! Log records waiting to be sent to the syslog collector by ethernet_event.
+//!
+//! Logging macros run at any priority, so the producer is only used inside a short critical
+//! section. Records are dropped when the queue is full, their count is reported with the next
+//! record that fits. Records carry uptime, it is turned into UTC when sent.
+
+use bbqueue::BBBuffer;
+use bbqueue::framed::{FrameConsumer, FrameProducer};
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+use ecbridge_net::syslog::enqueue;
+pub use ecbridge_net::syslog::Severity;
+
+pub const QUEUE_LEN: usize = 1024;
+
+static QUEUE: BBBuffer<QUEUE_LEN> = BBBuffer::new();
+
+struct Producer {
+    prod: FrameProducer<'static, QUEUE_LEN>,
+    dropped: u32,
+}
+
+static PRODUCER: Mutex<RefCell<Option<Producer>>> = Mutex::new(RefCell::new(None));
+
+/// Must be called once during init, consumer goes to ethernet_event.
+pub fn init() -> FrameConsumer<'static, QUEUE_LEN> {
+    let (prod, cons) = QUEUE.try_split_framed().unwrap();
+    cortex_m::interrupt::free(|cs| {
+        *PRODUCER.borrow(cs).borrow_mut() = Some(Producer { prod, dropped: 0 });
+    });
+    cons
+}
+
+/// Queue a record, never waits for space.
+#[allow(dead_code)]
+pub fn log(severity: Severity, args: core::fmt::Arguments) {
+    cortex_m::interrupt::free(|cs| {
+        // already borrowed if logging from a panic in the middle of enqueueing
+        let mut producer = match PRODUCER.borrow(cs).try_borrow_mut() {
+            Ok(producer) => producer,
+            Err(_) => return,
+        };
+        let producer = match producer.as_mut() {
+            Some(producer) => producer,
+            None => return, // not yet initialised
+        };
+        let uptime_ms = crate::wallclock::uptime().total_millis() as u64;
+        if producer.dropped != 0 {
+            let dropped = producer.dropped;
+            if !enqueue(&mut producer.prod, Severity::Warning, uptime_ms, format_args!("{} log records dropped", dropped)) {
+                producer.dropped += 1;
+                return;
+            }
+            producer.dropped = 0;
+        }
+        if !enqueue(&mut producer.prod, severity, uptime_ms, args) {
+            producer.dropped += 1;
+        }
+    });
+}
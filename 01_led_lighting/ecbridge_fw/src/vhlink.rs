@@ -1,54 +1,286 @@
+//! Links carrying xPI events and the task dispatching requests received over them.
+//!
+//! Every transport (Ethernet and CAN now, UART and RTT later) puts received frames behind an
+//! [Envelope] into its framed rx queue, wakes link_process and sends whatever appears in its tx
+//! queue. link_process drains the rx queues one link at a time, the router decides whether each
+//! event is dispatched locally or forwarded to another link. Local replies always go back over the
+//! link their request arrived on.
+
 use rtt_target::rprintln;
 
-use crate::ethernet::IpEndpointL;
-use crate::xpi_dispatch::xpi_dispatch;
+use crate::can::{CanTx, CAN_QUEUE_LEN};
+use crate::ethernet::ETH_QUEUE_LEN;
+use bbqueue::framed::FrameConsumer;
+use ecbridge_net::envelope::Envelope;
+pub use ecbridge_net::envelope::LinkId;
+use crate::router::Decision;
+use ecbridge_dispatch::{is_authenticated, publish_logs, publish_updates, xpi_dispatch, Dispatched};
+pub use ecbridge_dispatch::link::{LinkTx, StreamTx, TxLinks, MTU_MAX};
 use vhl_stdlib::serdes::NibbleBuf;
 use xpi::error::XpiError;
-use xpi::xwfd::{Event};
-use crate::error;
+use xpi::xwfd::{Event, NodeId};
+use crate::{debug, error, log_warn, trace};
+use rtic::Mutex;
+
+/// All the links served by link_process.
+///
+/// Rx queues are kept apart from the tx sides, so that a frame can be forwarded to any link
+/// straight from the rx grant.
+pub struct Links {
+    /// Enveloped events put by ethernet_event
+    pub eth_rx: FrameConsumer<'static, ETH_QUEUE_LEN>,
+    /// Enveloped transfers put by can_event
+    pub can_rx: FrameConsumer<'static, CAN_QUEUE_LEN>,
+    /// Event at the head of eth_rx, routed and waiting for space in a tx queue
+    pub eth_stalled: Option<Stalled>,
+    pub can_stalled: Option<Stalled>,
+    pub tx: LinksTx,
+}
+
+/// Event that was routed but not sent on yet or whose first reply batches were sent before a tx
+/// queue filled up
+#[derive(Copy, Clone, Debug)]
+pub struct Stalled {
+    decision: Decision,
+    replied_batches: usize,
+}
+
+pub struct LinksTx {
+    pub eth: StreamTx<ETH_QUEUE_LEN>,
+    pub can: CanTx,
+}
+
+impl TxLinks for LinksTx {
+    fn get(&mut self, id: LinkId) -> Option<&mut dyn LinkTx> {
+        match id {
+            LinkId::Ethernet => Some(&mut self.eth),
+            LinkId::Can => Some(&mut self.can),
+            _ => None,
+        }
+    }
+}
+
+impl LinksTx {
+    /// Tx sides of all the links, except `but`
+    fn others(&mut self, but: LinkId) -> impl Iterator<Item = &mut dyn LinkTx> {
+        let eth: &mut dyn LinkTx = &mut self.eth;
+        let can: &mut dyn LinkTx = &mut self.can;
+        [eth, can].into_iter().filter(move |tx| tx.id() != but)
+    }
+}
+
+/// Flow control state and counters, stalls are counted once per occurrence, not per retry.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FlowStats {
+    /// A transport paused rx because its rx queue had no space for a received frame
+    pub rx_stalls: u32,
+    /// Dispatching was postponed because a tx queue had no space for a reply
+    pub dispatch_stalls: u32,
+    /// xPI events handed to the dispatcher
+    pub dispatched: u32,
+    /// Malformed envelopes, frames that were not an xPI event or that the dispatcher failed on
+    pub dispatch_errors: u32,
+    /// Events sent as is to another link, broadcasts and floods are counted once per link
+    pub forwarded: u32,
+    /// Events dropped by the router
+    pub route_drops: u32,
+    /// Data is left in the socket, ETH must be pended once eth_out queue is drained
+    pub rx_stalled: bool,
+    /// Frames are left in FDCAN RX FIFO, FDCAN1_IT0 must be pended once can_rx queue is drained
+    pub can_rx_stalled: bool,
+    /// Requests are left in rx queues, link_process must be spawned once tx queues are drained
+    pub dispatch_stalled: bool,
+}
+
+impl FlowStats {
+    pub const fn new() -> Self {
+        FlowStats {
+            rx_stalls: 0,
+            dispatch_stalls: 0,
+            dispatched: 0,
+            dispatch_errors: 0,
+            forwarded: 0,
+            route_drops: 0,
+            rx_stalled: false,
+            can_rx_stalled: false,
+            dispatch_stalled: false,
+        }
+    }
+}
 
-// ethernet / can irq task -> put data onto bbqueue?
-// protocol processing task: data slices comes in from bbq -> uavcan/webscoket -> packets arrive
-// XpiRequest is deserialized from the packet -> goes to dispatcher
+/// Dispatch or forward events from every link, then publish updates to subscribers.
 pub fn link_process(mut ctx: crate::app::link_process::Context) {
+    let _busy = crate::supervisor::busy(crate::supervisor::Task::LinkProcess);
+    let _running = crate::log_stream::link_process_running();
     rprintln!(=>1, "link_process");
 
-    let eth_out_cons: &mut bbqueue::Consumer<512> = ctx.local.eth_out_cons;
-    match eth_out_cons.read() {
-        Ok(rgr) => {
-            let rgr_len = rgr.len();
-            // let endpoint = IpEndpoint::des(&rgr).expect("endpoint is wrong");
-            // rprintln!(=>1, "{:?}", rgr);
-            let endpoint: (IpEndpointL, usize) = ssmarshal::deserialize(&rgr).unwrap();
-            let (endpoint, endpoint_size) = (endpoint.0, endpoint.1);
-            let buf = &rgr[endpoint_size..];
-
-            rprintln!(=>1, "link_process got: {}B from {:?} {:02x?}", rgr_len, endpoint, buf);
-
-            let mut rdr = NibbleBuf::new_all(&buf);
-
-            let xpi_event: Result<Event, _> = rdr.des_vlu4();
-            match xpi_event {
-                Ok(ev) => {
-                    match xpi_dispatch(&mut ctx, &ev) {
-                        Ok(_) => {}
+    let links: &mut Links = ctx.local.links;
+    let self_node_id = ctx.shared.config.lock(|c| c.active.node_id);
+    let self_node_id = match NodeId::new(self_node_id) {
+        Some(id) => id,
+        None => {
+            error!(=>1, "bad node id: {}", self_node_id);
+            return;
+        }
+    };
+    let mut counts = LinkCounts::default();
+    let mut dispatch_stalled = process_link(
+        LinkId::Ethernet, &mut links.eth_rx, &mut links.eth_stalled, &mut links.tx, self_node_id, &mut ctx.shared, &mut counts
+    );
+    dispatch_stalled |= process_link(
+        LinkId::Can, &mut links.can_rx, &mut links.can_stalled, &mut links.tx, self_node_id, &mut ctx.shared, &mut counts
+    );
+    if !dispatch_stalled && !publish_updates(&mut ctx.shared, &mut links.tx) {
+        dispatch_stalled = true;
+    }
+    // last, so that records logged while dispatching are sent right away
+    if !dispatch_stalled {
+        let _publishing = crate::log_stream::publishing();
+        if !publish_logs(&mut ctx.shared, &mut links.tx, ctx.local.log_backlog) {
+            dispatch_stalled = true;
+        }
+    }
+
+    let (rx_stalled, can_rx_stalled) = ctx.shared.flow_stats.lock(|s| {
+        s.dispatched = s.dispatched.wrapping_add(counts.dispatched);
+        s.dispatch_errors = s.dispatch_errors.wrapping_add(counts.errors);
+        s.forwarded = s.forwarded.wrapping_add(counts.forwarded);
+        s.route_drops = s.route_drops.wrapping_add(counts.route_drops);
+        if dispatch_stalled && !s.dispatch_stalled {
+            s.dispatch_stalls += 1;
+            log_warn!(=>1, "tx queue is full, pausing dispatch ({} stalls)", s.dispatch_stalls);
+        }
+        s.dispatch_stalled = dispatch_stalled;
+        (s.rx_stalled, s.can_rx_stalled)
+    });
+    // space was freed in rx queues, resume receiving
+    if rx_stalled {
+        links.tx.eth.wake();
+    }
+    if can_rx_stalled {
+        links.tx.can.wake();
+    }
+}
+
+#[derive(Default)]
+struct LinkCounts {
+    dispatched: u32,
+    errors: u32,
+    forwarded: u32,
+    route_drops: u32,
+}
+
+/// Route events received over link `id`: dispatch locally replying over the same link, or forward.
+/// Returns true if stopped because a tx queue is full, the event is left in `rx` then, with
+/// `stalled` set to its decision and the amount of replies already sent.
+fn process_link<const RX: usize>(
+    id: LinkId,
+    rx: &mut FrameConsumer<'static, RX>,
+    stalled: &mut Option<Stalled>,
+    tx: &mut LinksTx,
+    self_node_id: NodeId,
+    shared: &mut crate::app::link_process::SharedResources,
+    counts: &mut LinkCounts,
+) -> bool {
+    // one grant per frame, more can arrive while dispatching
+    while let Some(rgr) = rx.read() {
+        let (envelope, buf) = match Envelope::decode(&rgr) {
+            Ok(record) => record,
+            Err(e) => {
+                counts.errors += 1;
+                error!(=>1, "dropping malformed {:?} record: {:?}", id, e);
+                rgr.release();
+                continue;
+            }
+        };
+
+        rprintln!(=>1, "link_process got: {}B from {:?} {:02x?}", buf.len(), envelope.endpoint, buf);
+
+        let mut rdr = NibbleBuf::new_all(buf);
+
+        let xpi_event: Result<Event, _> = rdr.des_vlu4();
+        let ev = match xpi_event {
+            Ok(ev) => ev,
+            Err(e) => {
+                counts.errors += 1;
+                rprintln!(=>1, "{:?}", e);
+                rgr.release();
+                continue;
+            }
+        };
+        let (decision, skip_batches) = match stalled.take() {
+            // routed in a previous run, not again, a broadcast would be taken for a looped one
+            Some(s) => (s.decision, s.replied_batches),
+            None => {
+                let authenticated = is_authenticated(shared, envelope.link, envelope.connection);
+                let decision = shared.router.lock(|r| r.route(&ev, buf, &envelope, self_node_id, authenticated));
+                trace!("route {:?} -> {:?}", id, decision);
+                (decision, 0)
+            }
+        };
+        // only the links the event goes to, a full reply queue doesn't hold up forwarding
+        let ready = match decision {
+            // other links get broadcasts only if they are ready
+            Decision::Local | Decision::Broadcast => matches!(tx.get(id), Some(reply_tx) if reply_tx.ready()),
+            Decision::Forward(to) => matches!(tx.get(to), Some(to_tx) if to_tx.ready()),
+            Decision::Flood => tx.others(id).all(|other| other.ready()),
+            Decision::Drop(_) => true,
+        };
+        if !ready {
+            // leave the rest of events in the queue, the transport will re-spawn us
+            *stalled = Some(Stalled { decision, replied_batches: skip_batches });
+            return true;
+        }
+        match decision {
+            Decision::Local | Decision::Broadcast => {
+                if let Some(reply_tx) = tx.get(id) {
+                    match xpi_dispatch(shared, reply_tx, &envelope, &ev, skip_batches) {
+                        Ok(Dispatched::Done) => counts.dispatched += 1,
+                        Ok(Dispatched::Stalled(replied_batches)) => {
+                            // the rest is dispatched once the transport drained the tx queue
+                            *stalled = Some(Stalled { decision, replied_batches });
+                            return true;
+                        }
                         Err(e) => {
+                            counts.dispatched += 1;
+                            counts.errors += 1;
                             error!(=>1, "xpi_dispatch err: {:?}", e);
                         }
                     }
-                },
-                Err(e) => {
-                    rprintln!(=>1, "{:?}", e);
                 }
-            };
-
-            rgr.release(rgr_len);
+            }
+            Decision::Forward(to) => {
+                if let Some(to_tx) = tx.get(to) {
+                    match to_tx.submit(buf) {
+                        Ok(()) => counts.forwarded += 1,
+                        Err(e) => error!(=>1, "forward to {:?}: {:?}", to, e),
+                    }
+                }
+            }
+            Decision::Flood => {
+                for other in tx.others(id) {
+                    match other.submit(buf) {
+                        Ok(()) => counts.forwarded += 1,
+                        Err(e) => error!(=>1, "flood to {:?}: {:?}", other.id(), e),
+                    }
+                }
+            }
+            Decision::Drop(reason) => {
+                counts.route_drops += 1;
+                debug!(=>1, "dropping event from {:?}: {:?}", id, reason);
+            }
         }
-        Err(_) => {
-            return;
+        if decision == Decision::Broadcast {
+            for other in tx.others(id) {
+                // broadcasts are lossy, a busy link doesn't hold up the others
+                if other.ready() && other.submit(buf).is_ok() {
+                    counts.forwarded += 1;
+                } else {
+                    counts.route_drops += 1;
+                }
+            }
         }
+        rgr.release();
     }
-
-
+    false
 }
-
@@ -0,0 +1,30 @@
+//! UTC wall clock: monotonic uptime plus the offset measured by the SNTP client in ethernet_event.
+//!
+//! Kept outside of RTIC resources, so that it can be read from any priority without locking.
+
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use ecbridge_net::Clock;
+use ecbridge_net::sntp::SntpStatus;
+use smoltcp::time::Instant;
+
+static STATUS: Mutex<Cell<SntpStatus>> = Mutex::new(Cell::new(SntpStatus::new()));
+
+/// Called by ethernet_event after each successful sync
+pub fn set_status(status: SntpStatus) {
+    cortex_m::interrupt::free(|cs| STATUS.borrow(cs).set(status));
+}
+
+pub fn status() -> SntpStatus {
+    cortex_m::interrupt::free(|cs| STATUS.borrow(cs).get())
+}
+
+/// Monotonic uptime, same as the network stack sees it
+pub fn uptime() -> Instant {
+    crate::ethernet::MonoClock.now()
+}
+
+/// Milliseconds since Unix epoch, None until the first sync
+pub fn unix_time_ms() -> Option<i64> {
+    status().unix_time_us(uptime()).map(|us| us / 1000)
+}
@@ -0,0 +1,175 @@
+//! `ecbridge_host --bench`: requests per second through the xPI TCP receive and reply path.
+//!
+//! A client over the loopback device keeps WINDOW requests in flight. With [Responder::Dispatcher]
+//! each one is a read of /digit answered by the firmware dispatcher from a [HostNode], with
+//! [Responder::Echo] (`--bench-echo`) each frame is sent back as is, leaving only the network
+//! stack, framing and queues.
+
+use anyhow::{bail, Result};
+use ecbridge_net::{Clock, Net};
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::Device;
+use smoltcp::socket::TcpSocket;
+use std::time::{Duration, Instant};
+use vhl_stdlib::discrete::{U2Sp1, U4};
+use vhl_stdlib::serdes::{NibbleBuf, NibbleBufMut, SerializeVlu4};
+use xpi::error::XpiError;
+use xpi::event_kind::{XpiEventDiscriminant, XpiGenericEventKind};
+use xpi::xwfd::{Event, EventBuilder, NodeId, NodeSet, Priority, RequestId, ResourceSet, SerialUri};
+use xpi_framing::FrameDecoder;
+
+use crate::node::{HostLink, HostNode};
+
+/// Typical size of an xPI request, echoed frames are this long
+pub const PAYLOAD_LEN: usize = 24;
+/// Frames sent without waiting for replies
+const WINDOW: usize = 16;
+const DURATION: Duration = Duration::from_secs(2);
+/// Node id of the bench client, the bridge is node 1
+const CLIENT_NODE_ID: u8 = 33;
+/// Resource read by every request
+const DIGIT_RESOURCE: u8 = 1;
+
+/// What answers the requests
+pub enum Responder<const N_IN: usize> {
+    /// Every frame is sent back as is by [crate::echo]
+    Echo(bbqueue::Producer<'static, N_IN>),
+    /// Requests are answered by [crate::node::process]
+    Dispatcher(Box<HostNode>, HostLink<N_IN>),
+}
+
+impl<const N_IN: usize> Responder<N_IN> {
+    fn process<const N_OUT: usize>(&mut self, eth_out_cons: &mut bbqueue::framed::FrameConsumer<N_OUT>) {
+        match self {
+            Responder::Echo(eth_in_prod) => {
+                crate::echo::process(eth_out_cons, eth_in_prod);
+            }
+            Responder::Dispatcher(node, link) => {
+                crate::node::process(node, eth_out_cons, link);
+            }
+        }
+    }
+
+    /// Frame sent over and over
+    pub fn request(&self) -> Result<Vec<u8>> {
+        match self {
+            Responder::Echo(_) => Ok((1..=PAYLOAD_LEN).map(|b| b as u8).collect()),
+            Responder::Dispatcher(..) => read_request(DIGIT_RESOURCE),
+        }
+    }
+
+    fn is_reply(&self, request: &[u8], reply: &[u8]) -> bool {
+        match self {
+            Responder::Echo(_) => reply == request,
+            Responder::Dispatcher(..) => {
+                let reply: Result<Event, _> = NibbleBuf::new_all(reply).des_vlu4();
+                matches!(reply, Ok(ev) if ev.kind.discriminant() == XpiEventDiscriminant::ReadResults)
+            }
+        }
+    }
+}
+
+/// Read of root level `resource` from CLIENT_NODE_ID to the bridge
+fn read_request(resource: u8) -> Result<Vec<u8>> {
+    let mut buf = [0u8; 64];
+    let builder = EventBuilder::new(
+        NibbleBufMut::new_all(&mut buf),
+        NodeId::new(CLIENT_NODE_ID).unwrap(),
+        RequestId::new(1).unwrap(),
+        Priority::Lossy(U2Sp1::new(1).unwrap()),
+        U4::new(15).unwrap(),
+    )
+    .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let builder = builder
+        .build_node_set_with(|mut nwr| {
+            let node_set = NodeSet::Unicast(NodeId::new(1).unwrap());
+            node_set.ser_vlu4(&mut nwr)?;
+            Ok((node_set.ser_header(), nwr))
+        })
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let builder = builder
+        .build_resource_set_with(|mut nwr| {
+            let resource_set = ResourceSet::Uri(SerialUri::OnePart4(U4::new(resource).unwrap()));
+            resource_set.ser_vlu4(&mut nwr)?;
+            Ok((resource_set.ser_header(), nwr))
+        })
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let nwr = builder
+        .build_kind_with(|nwr| Ok::<_, XpiError>((XpiEventDiscriminant::Read, nwr)))
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let (_, len, _) = nwr.finish();
+    Ok(buf[..len].to_vec())
+}
+
+pub struct Queues<'q, const N_OUT: usize, const N_IN: usize> {
+    pub eth_out_prod: bbqueue::framed::FrameProducer<'q, N_OUT>,
+    pub eth_out_cons: bbqueue::framed::FrameConsumer<'q, N_OUT>,
+    pub responder: Responder<N_IN>,
+    pub eth_in_cons: bbqueue::Consumer<'q, N_IN>,
+}
+
+/// Run for DURATION against an already connected `client`, returns requests per second.
+pub fn run<'a, D: for<'d> Device<'d>, C: Clock, const N_OUT: usize, const N_IN: usize>(
+    net: &mut Net<'a, D, C>,
+    queues: &mut Queues<N_OUT, N_IN>,
+    client: SocketHandle,
+) -> Result<f64> {
+    let request = queues.responder.request()?;
+    let mut frame = vec![0; xpi_framing::max_encoded_len(request.len())];
+    let frame_len = xpi_framing::encode(&request, &mut frame).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let frame = &frame[..frame_len];
+
+    // client side must not be the bottleneck
+    let socket: &mut TcpSocket = net.iface().get_socket(client);
+    socket.set_nagle_enabled(false);
+    socket.set_ack_delay(None);
+
+    let mut decoder: FrameDecoder<{ ecbridge_net::TCP_RX_FRAME_MAX }> = FrameDecoder::new();
+    let mut sent = 0usize;
+    let mut received = 0usize;
+    let mut tx_pos = frame_len;
+    let started_at = Instant::now();
+    while started_at.elapsed() < DURATION {
+        net.poll();
+        net.process_tcp(&mut queues.eth_out_prod, &mut queues.eth_in_cons);
+        queues.responder.process(&mut queues.eth_out_cons);
+
+        let socket: &mut TcpSocket = net.iface().get_socket(client);
+        if !socket.may_send() {
+            bail!("bench: connection closed");
+        }
+        while socket.can_send() {
+            if tx_pos == frame_len {
+                if sent - received >= WINDOW {
+                    break;
+                }
+                tx_pos = 0;
+                sent += 1;
+            }
+            match socket.send_slice(&frame[tx_pos..])? {
+                0 => break,
+                written => tx_pos += written,
+            }
+        }
+        let mut bad = false;
+        while socket.can_recv() {
+            socket.recv(|buf| {
+                for b in buf.iter() {
+                    match decoder.feed(*b) {
+                        Some(Ok(reply)) => {
+                            bad |= !queues.responder.is_reply(&request, reply);
+                            received += 1;
+                        }
+                        Some(Err(_)) => bad = true,
+                        None => {}
+                    }
+                }
+                (buf.len(), ())
+            })?;
+        }
+        if bad {
+            bail!("bench: corrupted reply after {} requests", received);
+        }
+    }
+    Ok(received as f64 / started_at.elapsed().as_secs_f64())
+}
@@ -0,0 +1,278 @@
+//! xPI over CAN on Linux SocketCAN, using the same transfer logic as the firmware.
+//!
+//! `ecbridge_host --can <iface> [node id|auto]` is a CAN node echoing every xPI transfer back
+//! as its own, works on a virtual bus too:
+//! `sudo ip link add dev vcan0 type vcan mtu 72 && sudo ip link set vcan0 up`
+//! With `auto` it asks the bridge for a node id first, the same way LED modules do, using
+//! /etc/machine-id as the unique id.
+//!
+//! `ecbridge_host --can-log <iface>` prints text logs of every node on the bus and appends their
+//! defmt frames to node<id>.defmt in the current directory, for
+//! `defmt-print -e <firmware elf> < node<id>.defmt`.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Write;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{info, trace, warn};
+use xpi_can::{
+    parse_log, AllocationData, CanId, Kind, Reassembler, Segmenter, TransferIdCounter, ALLOCATION_DATA_LEN_MAX,
+    LOG_DEFMT_SUBJECT_ID, LOG_TEXT_SUBJECT_ID, MTU_FD, PNP_SUBJECT_ID, XPI_SUBJECT_ID,
+};
+
+/// Same as in the firmware
+pub const TRANSFER_MAX: usize = 512;
+pub const SESSIONS: usize = 8;
+pub const PRIORITY: u8 = 4;
+/// Allocation request is repeated until answered
+const ALLOCATION_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct CanSocket {
+    fd: libc::c_int,
+}
+
+impl CanSocket {
+    /// Raw CAN socket with FD frames enabled, bound to `iface`
+    pub fn open(iface: &str) -> Result<Self> {
+        let name = CString::new(iface)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            bail!("no interface {}: {}", iface, std::io::Error::last_os_error());
+        }
+        let fd = unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW) };
+        if fd < 0 {
+            bail!("CAN socket: {}", std::io::Error::last_os_error());
+        }
+        let socket = CanSocket { fd };
+        let enable: libc::c_int = 1;
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_CAN_RAW,
+                libc::CAN_RAW_FD_FRAMES,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            bail!("enabling CAN FD frames: {}", std::io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_can = unsafe { mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = ifindex as libc::c_int;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            bail!("binding to {}: {}", iface, std::io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Send one frame with extended id, FD frame if it doesn't fit into a classic one
+    pub fn send(&self, raw_id: u32, data: &[u8]) -> Result<()> {
+        let mut frame: libc::canfd_frame = unsafe { mem::zeroed() };
+        frame.can_id = raw_id | libc::CAN_EFF_FLAG;
+        frame.len = data.len() as u8;
+        frame.data[..data.len()].copy_from_slice(data);
+        let size = if data.len() > xpi_can::MTU_CLASSIC { libc::CANFD_MTU } else { libc::CAN_MTU };
+        let written = unsafe { libc::write(self.fd, &frame as *const _ as *const libc::c_void, size) };
+        if written != size as isize {
+            bail!("CAN send: {}", std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// recv() returns None after that long without frames, None to block forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        let timeout = timeout.unwrap_or(Duration::ZERO);
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        let res = unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &tv as *const _ as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            bail!("setting CAN read timeout: {}", std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait for an extended id frame, standard id frames are skipped.
+    /// Returns the id and the data length, None on read timeout.
+    pub fn recv(&self, data: &mut [u8; MTU_FD]) -> Result<Option<(u32, usize)>> {
+        loop {
+            let mut frame: libc::canfd_frame = unsafe { mem::zeroed() };
+            let read = unsafe {
+                libc::read(self.fd, &mut frame as *mut _ as *mut libc::c_void, libc::CANFD_MTU)
+            };
+            if read < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(None);
+                }
+                bail!("CAN recv: {}", e);
+            }
+            if frame.can_id & libc::CAN_EFF_FLAG == 0 || frame.can_id & libc::CAN_ERR_FLAG != 0 {
+                continue;
+            }
+            let len = (frame.len as usize).min(MTU_FD);
+            data[..len].copy_from_slice(&frame.data[..len]);
+            return Ok(Some((frame.can_id & libc::CAN_EFF_MASK, len)));
+        }
+    }
+}
+
+impl Drop for CanSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Send `payload` as one transfer
+fn send_transfer(socket: &CanSocket, id: &CanId, transfer_id: u8, payload: &[u8]) -> Result<()> {
+    let mut segmenter = Segmenter::new(payload, transfer_id, MTU_FD);
+    let mut frame = [0u8; MTU_FD];
+    while let Some(len) = segmenter.next_frame(&mut frame) {
+        socket.send(id.to_raw(), &frame[..len])?;
+    }
+    Ok(())
+}
+
+/// Node id is asked from the allocator if None
+pub fn serve(iface: &str, node_id: Option<u8>) -> Result<()> {
+    let socket = CanSocket::open(iface).context(format!("opening {}", iface))?;
+    let mut reassembler: Reassembler<TRANSFER_MAX, SESSIONS> = Reassembler::new();
+    let node_id = match node_id {
+        Some(id) => id,
+        None => allocate_node_id(&socket, &mut reassembler)?,
+    };
+    let mut transfer_id = TransferIdCounter::new();
+    let tx_id = CanId::message(PRIORITY, XPI_SUBJECT_ID, node_id);
+    let started = std::time::Instant::now();
+    info!("echoing xPI transfers on {} as node {}", iface, node_id);
+    let mut frame = [0u8; MTU_FD];
+    loop {
+        let Some((raw_id, len)) = socket.recv(&mut frame)? else { continue };
+        let now_us = started.elapsed().as_micros() as u64;
+        let transfer = match reassembler.accept(raw_id, &frame[..len], now_us) {
+            Ok(Some(transfer)) => transfer,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("dropping frame {:08x}: {:?}", raw_id, e);
+                continue;
+            }
+        };
+        if transfer.id.kind != tx_id.kind || transfer.id.source == node_id {
+            continue;
+        }
+        trace!("echo {}B from node {}", transfer.payload.len(), transfer.id.source);
+        let payload = transfer.payload.to_vec();
+        send_transfer(&socket, &tx_id, transfer_id.advance(), &payload)?;
+    }
+}
+
+/// Plug-and-play allocation: repeat anonymous requests until the allocator answers with our hash
+fn allocate_node_id(socket: &CanSocket, reassembler: &mut Reassembler<TRANSFER_MAX, SESSIONS>) -> Result<u8> {
+    let machine_id = std::fs::read_to_string("/etc/machine-id").context("reading /etc/machine-id")?;
+    let unique_id_hash = u64::from_str_radix(machine_id.get(..12).unwrap_or_default(), 16)
+        .context("parsing /etc/machine-id")?;
+    let request = AllocationData { unique_id_hash, node_id: None };
+    let mut payload = [0u8; ALLOCATION_DATA_LEN_MAX];
+    let len = request.encode(&mut payload);
+    let id = CanId {
+        anonymous: true,
+        // pseudo id, so that two modules asking at once are unlikely to collide
+        ..CanId::message(PRIORITY, PNP_SUBJECT_ID, (unique_id_hash & xpi_can::NODE_ID_MAX as u64) as u8)
+    };
+    let mut transfer_id = TransferIdCounter::new();
+    let started = Instant::now();
+    let mut frame = [0u8; MTU_FD];
+    info!("asking for a node id, unique id {:012x}", unique_id_hash);
+    loop {
+        send_transfer(socket, &id, transfer_id.advance(), &payload[..len])?;
+        let asked_at = Instant::now();
+        socket.set_read_timeout(Some(ALLOCATION_INTERVAL))?;
+        while asked_at.elapsed() < ALLOCATION_INTERVAL {
+            let Some((raw_id, len)) = socket.recv(&mut frame)? else { break };
+            let now_us = started.elapsed().as_micros() as u64;
+            let Ok(Some(transfer)) = reassembler.accept(raw_id, &frame[..len], now_us) else { continue };
+            if transfer.id.kind != (Kind::Message { subject_id: PNP_SUBJECT_ID }) || transfer.id.anonymous {
+                continue;
+            }
+            match AllocationData::decode(transfer.payload) {
+                Ok(AllocationData { unique_id_hash: hash, node_id: Some(node_id) })
+                    if hash == unique_id_hash && node_id <= xpi_can::NODE_ID_MAX as u16 =>
+                {
+                    info!("node id {} allocated by node {}", node_id, transfer.id.source);
+                    socket.set_read_timeout(None)?;
+                    return Ok(node_id as u8);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Print log lines of all nodes, defmt frames go to a file per node
+pub fn print_logs(iface: &str) -> Result<()> {
+    let socket = CanSocket::open(iface).context(format!("opening {}", iface))?;
+    let mut reassembler: Reassembler<TRANSFER_MAX, SESSIONS> = Reassembler::new();
+    let mut defmt_files: HashMap<u8, File> = HashMap::new();
+    let started = Instant::now();
+    info!("printing logs from {}", iface);
+    let mut frame = [0u8; MTU_FD];
+    loop {
+        let Some((raw_id, len)) = socket.recv(&mut frame)? else { continue };
+        let now_us = started.elapsed().as_micros() as u64;
+        let transfer = match reassembler.accept(raw_id, &frame[..len], now_us) {
+            Ok(Some(transfer)) => transfer,
+            Ok(None) => continue,
+            Err(e) => {
+                trace!("dropping frame {:08x}: {:?}", raw_id, e);
+                continue;
+            }
+        };
+        let Kind::Message { subject_id } = transfer.id.kind else { continue };
+        if subject_id != LOG_TEXT_SUBJECT_ID && subject_id != LOG_DEFMT_SUBJECT_ID {
+            continue;
+        }
+        let node_id = transfer.id.source;
+        match parse_log(transfer.payload) {
+            Ok((Some(level), text)) => {
+                println!("node {:3} {:5} {}", node_id, level.as_str(), String::from_utf8_lossy(text));
+            }
+            Ok((None, defmt_frame)) => {
+                let file = match defmt_files.entry(node_id) {
+                    std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        let path = format!("node{}.defmt", node_id);
+                        info!("node {}: defmt frames go to {}", node_id, path);
+                        let file = File::options().create(true).append(true).open(&path)
+                            .context(format!("opening {}", path))?;
+                        e.insert(file)
+                    }
+                };
+                file.write_all(defmt_frame)?;
+            }
+            Err(e) => warn!("node {}: bad log record: {:?}", node_id, e),
+        }
+    }
+}
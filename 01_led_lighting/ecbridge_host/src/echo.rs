@@ -0,0 +1,37 @@
+//! Stand-in for the firmware dispatcher: every received xPI frame is sent back as is.
+
+use ecbridge_net::envelope::Envelope;
+use log::{trace, warn};
+
+/// Move all the frames from eth_out queue back into eth_in queue, returns the amount of frames echoed.
+///
+/// Frames are left in eth_out queue if there is no space for them in eth_in queue,
+/// same as the firmware dispatcher does.
+pub fn process<const N_OUT: usize, const N_IN: usize>(
+    eth_out_cons: &mut bbqueue::framed::FrameConsumer<N_OUT>,
+    eth_in_prod: &mut bbqueue::Producer<N_IN>,
+) -> usize {
+    let mut echoed = 0;
+    while let Some(rgr) = eth_out_cons.read() {
+        let (envelope, frame) = match Envelope::decode(&rgr) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("dropping malformed eth_out record: {:?}", e);
+                rgr.release();
+                continue;
+            }
+        };
+        let mut wgr = match eth_in_prod.grant_exact(xpi_framing::max_encoded_len(frame.len())) {
+            Ok(wgr) => wgr,
+            Err(_) => break,
+        };
+        trace!("echo {}B to {:?}", frame.len(), envelope.endpoint);
+        match xpi_framing::encode(frame, &mut wgr) {
+            Ok(len) => wgr.commit(len),
+            Err(e) => warn!("encode: {:?}", e),
+        }
+        rgr.release();
+        echoed += 1;
+    }
+    echoed
+}
@@ -0,0 +1,81 @@
+//! ECBridge networking running on Linux, for testing clients without the hardware.
+//!
+//! The binary serves xPI on a TAP interface with the firmware dispatcher or on SocketCAN,
+//! integration tests in tests/ run a client against the same stack over an in-memory loopback device.
+
+pub mod bench;
+pub mod can;
+pub mod echo;
+pub mod node;
+
+use anyhow::Result;
+use ecbridge_net::{http, Clock, Net, NetConfig, NetStorage};
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::Device;
+use smoltcp::socket::{TcpSocket, TcpSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, Ipv4Address};
+
+/// Locally administered, different from the firmware default so that both can be on the same network
+pub const MAC_ADDRESS: [u8; 6] = [0x02, 0x00, 0x11, 0x22, 0x33, 0x55];
+pub const XPI_TCP_PORT: u16 = 7777;
+/// Local port of the client made by [connect_client]
+pub const CLIENT_PORT: u16 = 49152;
+
+pub struct StdClock;
+
+impl Clock for StdClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Status page fields of the host binary
+pub struct HostStatus {
+    pub events_dispatched: usize,
+}
+
+impl http::Status for HostStatus {
+    fn fields(&self, f: &mut dyn FnMut(&str, http::Value)) {
+        f("events_dispatched", http::Value::U64(self.events_dispatched as u64));
+    }
+}
+
+pub fn net_config(ipv4: [u8; 4], ipv4_prefix_len: u8) -> NetConfig {
+    NetConfig {
+        ipv4,
+        ipv4_prefix_len,
+        ipv6: [0; 16],
+        ipv6_prefix_len: 64,
+        tcp_port: XPI_TCP_PORT,
+        node_id: 1,
+        schema_hash: "host",
+        keepalive_s: 5,
+        timeout_s: 15,
+        syslog_ipv4: [0; 4],
+        syslog_port: 0,
+        sntp_ipv4: [0; 4],
+    }
+}
+
+pub fn new_net<D: for<'d> Device<'d>>(device: D, config: &NetConfig) -> Net<'static, D, StdClock> {
+    new_net_with::<D, { ecbridge_net::TCP_BUFFER_LEN }>(device, config)
+}
+
+pub fn new_net_with<D: for<'d> Device<'d>, const TCP_BUFFER_LEN: usize>(
+    device: D,
+    config: &NetConfig,
+) -> Net<'static, D, StdClock> {
+    let store = Box::leak(Box::new(NetStorage::<TCP_BUFFER_LEN, TCP_BUFFER_LEN>::new()));
+    Net::new(store, device, StdClock, EthernetAddress(MAC_ADDRESS), config)
+}
+
+/// TCP client connecting to the xPI listener from CLIENT_PORT, the listener must already be listening
+pub fn connect_client<D: for<'d> Device<'d>>(net: &mut Net<'static, D, StdClock>, config: &NetConfig) -> Result<SocketHandle> {
+    let rx = Box::leak(vec![0; 256].into_boxed_slice());
+    let tx = Box::leak(vec![0; 256].into_boxed_slice());
+    let handle = net.iface().add_socket(TcpSocket::new(TcpSocketBuffer::new(&mut rx[..]), TcpSocketBuffer::new(&mut tx[..])));
+    let (socket, cx) = net.iface().get_socket_and_context::<TcpSocket>(handle);
+    socket.connect(cx, (IpAddress::Ipv4(Ipv4Address(config.ipv4)), config.tcp_port), CLIENT_PORT)?;
+    Ok(handle)
+}
@@ -0,0 +1,150 @@
+//! ECBridge networking running on Linux, for testing clients without the hardware.
+//!
+//! `ecbridge_host <tap> [ipv4/prefix]` serves xPI on a TAP interface, requests are answered by the
+//! firmware dispatcher, see [ecbridge_host::node]. Create the interface first, e.g.:
+//! `sudo ip tuntap add name tap0 mode tap user $USER && sudo ip addr add 192.168.69.100/24 dev tap0 && sudo ip link set tap0 up`
+//!
+//! `ecbridge_host --can <iface> [node id|auto]` echoes xPI transfers on a SocketCAN interface.
+//! `ecbridge_host --can-log <iface>` prints logs published by nodes on a SocketCAN interface.
+//!
+//! `ecbridge_host --bench` measures xPI requests per second over the loopback device, answered by
+//! the firmware dispatcher, `ecbridge_host --bench-echo` the same with frames echoed back as is.
+//!
+//! `cargo test` runs a client against the listener over an in-memory device, see tests/.
+
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bbqueue::BBBuffer;
+use ecbridge_host::node::{HostLink, HostNode};
+use ecbridge_host::{bench, can, connect_client, net_config, new_net, new_net_with, HostStatus, XPI_TCP_PORT};
+use ecbridge_net::http;
+use log::{info, LevelFilter};
+use smoltcp::phy::{Loopback, Medium, TunTapInterface};
+use smoltcp::time::Duration;
+use smoltcp::wire::Ipv4Address;
+
+/// Bridge itself is usually node 1
+const DEFAULT_CAN_NODE_ID: u8 = 2;
+
+static ETH_OUT_BB: BBBuffer<512> = BBBuffer::new();
+static ETH_IN_BB: BBBuffer<512> = BBBuffer::new();
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|a| a.as_str()) {
+        Some("--bench") => run_bench(false),
+        Some("--bench-echo") => run_bench(true),
+        Some("--can") => {
+            let iface = args.get(1).ok_or_else(|| anyhow!("--can needs an interface, e.g. vcan0"))?;
+            let node_id = match args.get(2).map(|a| a.as_str()) {
+                Some("auto") => None,
+                Some(id) => Some(id.parse().context("node id")?),
+                None => Some(DEFAULT_CAN_NODE_ID),
+            };
+            can::serve(iface, node_id)
+        }
+        Some("--can-log") => {
+            let iface = args.get(1).ok_or_else(|| anyhow!("--can-log needs an interface, e.g. can0"))?;
+            can::print_logs(iface)
+        }
+        Some(tap) => {
+            let (ipv4, prefix_len) = match args.get(1) {
+                Some(cidr) => parse_cidr(cidr)?,
+                None => ([192, 168, 69, 1], 24),
+            };
+            serve_tap(tap, ipv4, prefix_len)
+        }
+        None => bail!("usage: ecbridge_host <tap> [ipv4/prefix] | ecbridge_host --can <iface> [node id|auto] | ecbridge_host --can-log <iface> | ecbridge_host --bench | ecbridge_host --bench-echo"),
+    }
+}
+
+fn serve_tap(name: &str, ipv4: [u8; 4], prefix_len: u8) -> Result<()> {
+    let device = TunTapInterface::new(name, Medium::Ethernet).context(format!("opening {}", name))?;
+    let fd = device.as_raw_fd();
+    let mut net = new_net(device, &net_config(ipv4, prefix_len));
+    let (mut eth_out_prod, mut eth_out_cons) = ETH_OUT_BB.try_split_framed().map_err(|e| anyhow!("{:?}", e))?;
+    let (eth_in_prod, mut eth_in_cons) = ETH_IN_BB.try_split().map_err(|e| anyhow!("{:?}", e))?;
+    let mut node = HostNode::new(ipv4, prefix_len);
+    let mut link = HostLink::new(eth_in_prod);
+    info!("serving xPI on {}:{} and status on port {} via {}", Ipv4Address(ipv4), XPI_TCP_PORT, http::PORT, name);
+    let mut events_dispatched = 0;
+
+    // TAP is always up, there is no PHY to ask
+    net.link_changed(true, &mut eth_in_cons);
+    loop {
+        net.poll();
+        if net.process_tcp(&mut eth_out_prod, &mut eth_in_cons).closed {
+            node.connection_closed();
+        }
+        events_dispatched += ecbridge_host::node::process(&mut node, &mut eth_out_cons, &mut link);
+        net.process_mdns();
+        net.process_slaac();
+        net.process_ping();
+        net.process_sntp();
+        net.process_http(|| HostStatus { events_dispatched });
+        // replies are not sent until the next poll, don't wait for a packet to arrive
+        if eth_in_cons.read().is_ok() {
+            continue;
+        }
+        let now = net.now();
+        // instant in the past, including 0, means right away
+        let delay = net.poll_at().map(|at| if at > now { at - now } else { Duration::ZERO });
+        smoltcp::phy::wait(fd, delay).context("waiting for the TAP interface")?;
+    }
+}
+
+/// Default buffer sizes, then the ones of the firmware large-buffers feature,
+/// each with frames reassembled byte by byte (before) and decoded in the socket buffer (after)
+fn run_bench(echo: bool) -> Result<()> {
+    for rx_in_place in [false, true] {
+        bench_with::<{ ecbridge_net::TCP_BUFFER_LEN }, 512>(rx_in_place, echo)?;
+        bench_with::<1024, 4096>(rx_in_place, echo)?;
+    }
+    Ok(())
+}
+
+fn bench_with<const TCP_BUFFER_LEN: usize, const QUEUE_LEN: usize>(rx_in_place: bool, echo: bool) -> Result<()> {
+    let config = net_config([127, 0, 0, 1], 8);
+    let mut net = new_net_with::<_, TCP_BUFFER_LEN>(Loopback::new(Medium::Ethernet), &config);
+    net.set_rx_in_place(rx_in_place);
+    let eth_out_bb: &'static BBBuffer<QUEUE_LEN> = Box::leak(Box::new(BBBuffer::new()));
+    let eth_in_bb: &'static BBBuffer<QUEUE_LEN> = Box::leak(Box::new(BBBuffer::new()));
+    let (eth_out_prod, eth_out_cons) = eth_out_bb.try_split_framed().map_err(|e| anyhow!("{:?}", e))?;
+    let (eth_in_prod, eth_in_cons) = eth_in_bb.try_split().map_err(|e| anyhow!("{:?}", e))?;
+    let responder = if echo {
+        bench::Responder::Echo(eth_in_prod)
+    } else {
+        bench::Responder::Dispatcher(Box::new(HostNode::new([127, 0, 0, 1], 8)), HostLink::new(eth_in_prod))
+    };
+    let request_len = responder.request()?.len();
+    let mut queues = bench::Queues { eth_out_prod, eth_out_cons, responder, eth_in_cons };
+    net.link_changed(true, &mut queues.eth_in_cons);
+    net.process_tcp(&mut queues.eth_out_prod, &mut queues.eth_in_cons);
+    let client = connect_client(&mut net, &config)?;
+    // the dispatcher logs every request at info level, that would be measured instead
+    let level = log::max_level();
+    log::set_max_level(LevelFilter::Warn);
+    let rps = bench::run(&mut net, &mut queues, client);
+    log::set_max_level(level);
+    let rps = rps?;
+    info!(
+        "bench: {:.0} requests/s, {}, {}B requests, {}B TCP buffers, {}B queues, {}",
+        rps,
+        if echo { "echoed" } else { "dispatched" },
+        request_len,
+        TCP_BUFFER_LEN,
+        QUEUE_LEN,
+        if rx_in_place { "decoded in place" } else { "reassembled byte by byte" }
+    );
+    Ok(())
+}
+
+fn parse_cidr(cidr: &str) -> Result<([u8; 4], u8)> {
+    let (addr, prefix_len) = cidr.split_once('/').ok_or_else(|| anyhow!("expected ipv4/prefix, got {}", cidr))?;
+    let addr: std::net::Ipv4Addr = addr.parse().context("ipv4")?;
+    let prefix_len: u8 = prefix_len.parse().context("prefix")?;
+    Ok((addr.octets(), prefix_len))
+}
@@ -0,0 +1,291 @@
+//! The firmware dispatcher on Linux: requests received over TCP are answered by ecbridge_dispatch
+//! from a [HostNode], the same way link_process does it on the board, without the router and CAN.
+//!
+//! Calls with side effects on the hardware are answered but do nothing: /config/apply copies the
+//! pending config into the active one without a restart, the node table is not persisted, /ping
+//! sends nothing and /log is not published.
+
+use std::fs::File;
+use std::io::Read;
+
+use bbqueue::framed::FrameConsumer;
+use ecbridge_dispatch::auth::Sessions;
+use ecbridge_dispatch::config::{Config, ConfigState};
+use ecbridge_dispatch::link::{LinkId, LinkState, LinkTx, StreamTx, TxLinks};
+use ecbridge_dispatch::node_table::NodeTable;
+use ecbridge_dispatch::subscriptions::Subscriptions;
+use ecbridge_dispatch::{publish_updates, xpi_dispatch, CrashReport, Dispatched, Node};
+use ecbridge_net::envelope::Envelope;
+use ecbridge_net::ping::PingStats;
+use ecbridge_net::sntp::SntpStatus;
+use ecbridge_net::Clock;
+use log::{info, trace, warn, LevelFilter};
+use smoltcp::time::Instant;
+use smoltcp::wire::IpAddress;
+use vhl_cg::point::Point;
+use vhl_stdlib::serdes::NibbleBuf;
+use xpi::error::XpiError;
+use xpi::xwfd::Event;
+
+use crate::{StdClock, MAC_ADDRESS, XPI_TCP_PORT};
+
+/// Same amount of /log_filter children as the firmware has, levels are only remembered
+const LOG_MODULES: usize = 7;
+/// Level of modules following the global one
+const LOG_LEVEL_DEFAULT: u8 = 0xFF;
+
+pub struct HostNode {
+    pub config: ConfigState,
+    pub sessions: Sessions,
+    pub subscriptions: Subscriptions,
+    pub node_table: NodeTable,
+    pub digit: u8,
+    pub link: LinkState,
+    pub ping_stats: PingStats,
+    pub dispatch_stalls: u32,
+    log_global: u8,
+    log_modules: [u8; LOG_MODULES],
+}
+
+impl HostNode {
+    pub fn new(ipv4: [u8; 4], ipv4_prefix_len: u8) -> Self {
+        let mut link = LinkState::new();
+        // TAP and loopback are always up, there is no PHY to ask
+        link.update(Some((100, true)));
+        HostNode {
+            config: ConfigState::new(defaults(ipv4, ipv4_prefix_len)),
+            sessions: Sessions::new(),
+            subscriptions: Subscriptions::new(),
+            node_table: NodeTable::new(),
+            digit: 0,
+            link,
+            ping_stats: PingStats::new(),
+            dispatch_stalls: 0,
+            log_global: LevelFilter::Info as u8,
+            log_modules: [LOG_LEVEL_DEFAULT; LOG_MODULES],
+        }
+    }
+
+    /// TCP connection was closed: the next one has to authenticate again and all the subscribers
+    /// were behind the closed one
+    pub fn connection_closed(&mut self) {
+        self.sessions.reset(LinkId::Ethernet);
+        let dropped = self.subscriptions.clear(LinkId::Ethernet);
+        if dropped != 0 {
+            info!("dropped {} subscriptions", dropped);
+        }
+    }
+}
+
+/// Same as the firmware factory defaults, except for the addresses
+fn defaults(ipv4: [u8; 4], ipv4_prefix_len: u8) -> Config {
+    Config {
+        ipv4,
+        ipv4_prefix_len,
+        mac: MAC_ADDRESS,
+        tcp_port: XPI_TCP_PORT,
+        node_id: 1,
+        ipv6: [0; 16],
+        ipv6_prefix_len: 64,
+        psk: [0; ecbridge_dispatch::auth::PSK_LEN],
+        keepalive_s: 5,
+        timeout_s: 15,
+        syslog_ipv4: [0; 4],
+        syslog_port: 0,
+        sntp_ipv4: [0; 4],
+    }
+}
+
+impl Node for HostNode {
+    fn config<R>(&mut self, f: impl FnOnce(&mut ConfigState) -> R) -> R {
+        f(&mut self.config)
+    }
+
+    fn sessions<R>(&mut self, f: impl FnOnce(&mut Sessions) -> R) -> R {
+        f(&mut self.sessions)
+    }
+
+    fn subscriptions<R>(&mut self, f: impl FnOnce(&mut Subscriptions) -> R) -> R {
+        f(&mut self.subscriptions)
+    }
+
+    fn node_table<R>(&mut self, f: impl FnOnce(&mut NodeTable) -> R) -> R {
+        f(&mut self.node_table)
+    }
+
+    fn digit(&mut self) -> u8 {
+        self.digit
+    }
+
+    fn write_digit(&mut self, digit: u8) {
+        self.digit = digit;
+    }
+
+    fn set_digit(&mut self, digit: u8) {
+        info!("set_digit: {}", digit);
+        self.digit = digit;
+    }
+
+    fn async_call(&mut self, p1: Point, p2: Point) {
+        info!("async: {:?} {:?}", p1, p2);
+    }
+
+    fn link_state(&mut self) -> LinkState {
+        self.link
+    }
+
+    fn flow_stalls(&mut self) -> (u32, u32) {
+        (0, self.dispatch_stalls)
+    }
+
+    fn fill_random(&mut self, buf: &mut [u8]) -> Result<(), XpiError> {
+        File::open("/dev/urandom").and_then(|mut f| f.read_exact(buf)).map_err(|e| {
+            warn!("/dev/urandom: {}", e);
+            XpiError::Internal
+        })
+    }
+
+    fn uptime(&self) -> Instant {
+        StdClock.now()
+    }
+
+    fn sntp_status(&self) -> SntpStatus {
+        SntpStatus::new()
+    }
+
+    fn start_ping(&mut self, target: IpAddress, count: u8) -> bool {
+        info!("ping {} x{} is not sent from the host", target, count);
+        true
+    }
+
+    fn ping_stats(&mut self) -> PingStats {
+        self.ping_stats
+    }
+
+    fn crash_report(&self) -> Option<CrashReport> {
+        None
+    }
+
+    fn reset_flags(&self) -> u32 {
+        0
+    }
+
+    fn log_modules(&self) -> usize {
+        LOG_MODULES
+    }
+
+    fn log_level(&self, module: Option<usize>) -> Result<u8, XpiError> {
+        match module {
+            None => Ok(self.log_global),
+            Some(module) => self.log_modules.get(module).copied().ok_or(XpiError::BadUri),
+        }
+    }
+
+    fn set_log_level(&mut self, module: Option<usize>, level: u8) -> Result<(), XpiError> {
+        let slot = match module {
+            None if level <= LevelFilter::Trace as u8 => &mut self.log_global,
+            Some(module) if level <= LevelFilter::Trace as u8 || level == LOG_LEVEL_DEFAULT => {
+                self.log_modules.get_mut(module).ok_or(XpiError::BadUri)?
+            }
+            _ => return Err(XpiError::OperationNotSupported),
+        };
+        *slot = level;
+        Ok(())
+    }
+
+    fn apply_config(&mut self, config: Config) -> Result<(), XpiError> {
+        info!("config applied without a restart: {:?}", config);
+        self.config = ConfigState::new(config);
+        Ok(())
+    }
+
+    fn factory_reset(&mut self) -> Result<(), XpiError> {
+        let active = self.config.active;
+        self.config = ConfigState::new(defaults(active.ipv4, active.ipv4_prefix_len));
+        self.node_table = NodeTable::new();
+        Ok(())
+    }
+
+    fn save_node_table(&mut self) {
+        let _ = self.node_table.take_dirty();
+    }
+}
+
+/// Tx side of the TCP link and the event at the head of eth_out queue, partly replied to when
+/// the tx queue filled up
+pub struct HostLink<const N: usize> {
+    pub tx: StreamTx<N>,
+    stalled: Option<usize>,
+}
+
+impl<const N: usize> HostLink<N> {
+    pub fn new(eth_in_prod: bbqueue::Producer<'static, N>) -> Self {
+        // replies are sent by the next poll, nothing to wake
+        HostLink { tx: StreamTx::new(LinkId::Ethernet, eth_in_prod, ecbridge_dispatch::link::MTU_MAX, || {}), stalled: None }
+    }
+}
+
+impl<const N: usize> TxLinks for HostLink<N> {
+    fn get(&mut self, id: LinkId) -> Option<&mut dyn LinkTx> {
+        match id {
+            LinkId::Ethernet => Some(&mut self.tx),
+            _ => None,
+        }
+    }
+}
+
+/// Dispatch every request in eth_out queue replying over `link`, then publish updates to
+/// subscribers. Returns the amount of events dispatched.
+///
+/// Requests are left in eth_out queue if there is no space for their replies, the one that was
+/// partly replied to is resumed from the first batch without a reply on the next call.
+pub fn process<const N_OUT: usize, const N_IN: usize>(
+    node: &mut HostNode,
+    eth_out_cons: &mut FrameConsumer<N_OUT>,
+    link: &mut HostLink<N_IN>,
+) -> usize {
+    let mut dispatched = 0;
+    while let Some(rgr) = eth_out_cons.read() {
+        let (envelope, buf) = match Envelope::decode(&rgr) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("dropping malformed eth_out record: {:?}", e);
+                rgr.release();
+                continue;
+            }
+        };
+        trace!("dispatch {}B from {:?}", buf.len(), envelope.endpoint);
+        let mut rdr = NibbleBuf::new_all(buf);
+        let xpi_event: Result<Event, _> = rdr.des_vlu4();
+        let ev = match xpi_event {
+            Ok(ev) => ev,
+            Err(e) => {
+                warn!("not an xPI event: {:?}", e);
+                rgr.release();
+                continue;
+            }
+        };
+        let skip_batches = match link.stalled.take() {
+            Some(replied_batches) => replied_batches,
+            None if !link.tx.ready() => return dispatched,
+            None => 0,
+        };
+        match xpi_dispatch(node, &mut link.tx, &envelope, &ev, skip_batches) {
+            Ok(Dispatched::Done) => dispatched += 1,
+            Ok(Dispatched::Stalled(replied_batches)) => {
+                node.dispatch_stalls += 1;
+                link.stalled = Some(replied_batches);
+                return dispatched;
+            }
+            Err(e) => {
+                dispatched += 1;
+                warn!("xpi_dispatch: {:?}", e);
+            }
+        }
+        rgr.release();
+    }
+    if !publish_updates(node, link) {
+        node.dispatch_stalls += 1;
+    }
+    dispatched
+}
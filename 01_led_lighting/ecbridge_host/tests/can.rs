@@ -0,0 +1,125 @@
+//! xPI over CAN transfers through the same segmenter and reassembler as the firmware, in memory.
+
+use ecbridge_host::can::{PRIORITY, SESSIONS, TRANSFER_MAX};
+use xpi_can::{
+    log_header, parse_log, AllocationData, CanId, Error, LogLevel, Reassembler, Segmenter, ALLOCATION_DATA_LEN_MAX,
+    LOG_DEFMT_SUBJECT_ID, LOG_PRIORITY, LOG_TEXT_SUBJECT_ID, MTU_CLASSIC, MTU_FD, XPI_SUBJECT_ID,
+};
+
+type HostReassembler = Reassembler<TRANSFER_MAX, SESSIONS>;
+
+/// Transfers from two sources with interleaved frames must be reassembled intact
+#[test]
+fn interleaved_transfers_are_reassembled() {
+    let payloads: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0x10, 0x20, 0x30],
+        (0..7).collect(),
+        (0..8).collect(),
+        (0..63).collect(),
+        (0..64).collect(),
+        (0..200).map(|b| b as u8).collect(),
+    ];
+    for mtu in [MTU_CLASSIC, MTU_FD] {
+        for (i, payload) in payloads.iter().enumerate() {
+            let a = CanId::message(PRIORITY, XPI_SUBJECT_ID, 10);
+            let b = CanId::message(PRIORITY + 1, XPI_SUBJECT_ID, 11);
+            let reversed: Vec<u8> = payload.iter().rev().copied().collect();
+            let frames_a = segment(payload, i as u8, mtu);
+            let frames_b = segment(&reversed, i as u8 + 30, mtu);
+
+            let mut reassembler = HostReassembler::new();
+            let mut received = Vec::new();
+            for n in 0..frames_a.len().max(frames_b.len()) {
+                for (id, frames) in [(a, &frames_a), (b, &frames_b)] {
+                    let Some(frame) = frames.get(n) else { continue };
+                    assert!(
+                        frame.len() <= mtu && xpi_can::frame_len(frame.len()) == frame.len(),
+                        "invalid frame length {} for MTU {}",
+                        frame.len(),
+                        mtu
+                    );
+                    match reassembler.accept(id.to_raw(), frame, 0) {
+                        Ok(Some(t)) => received.push((t.id.source, trim_padding(t.payload, payload.len()).to_vec())),
+                        Ok(None) => {}
+                        Err(e) => panic!("{}B transfer, MTU {}: {:?}", payload.len(), mtu, e),
+                    }
+                }
+            }
+            received.sort();
+            assert_eq!(received, vec![(10, payload.clone()), (11, reversed)], "MTU {}", mtu);
+        }
+    }
+}
+
+/// Corrupted and incomplete transfers must be dropped
+#[test]
+fn broken_transfers_are_dropped() {
+    let id = CanId::message(PRIORITY, XPI_SUBJECT_ID, 10);
+    let payload: Vec<u8> = (0..100).collect();
+    let mut reassembler = HostReassembler::new();
+    let mut corrupted = segment(&payload, 1, MTU_FD);
+    corrupted[1][0] ^= 0x01;
+    assert_eq!(feed(&mut reassembler, id.to_raw(), &corrupted), [Error::CrcMismatch]);
+    let mut incomplete = segment(&payload, 2, MTU_CLASSIC);
+    incomplete.remove(3);
+    let errors = feed(&mut reassembler, id.to_raw(), &incomplete);
+    assert_eq!(errors.first(), Some(&Error::ToggleMismatch), "{:?}", errors);
+}
+
+#[test]
+fn ids_and_allocations_round_trip() {
+    let id = CanId::message(PRIORITY, XPI_SUBJECT_ID, 10);
+    assert_eq!(CanId::from_raw(id.to_raw()), Ok(id));
+    let mut buf = [0u8; ALLOCATION_DATA_LEN_MAX];
+    for data in [
+        AllocationData { unique_id_hash: 0x1234_5678_9abc, node_id: None },
+        AllocationData { unique_id_hash: 0x1234_5678_9abc, node_id: Some(16) },
+    ] {
+        let len = data.encode(&mut buf);
+        assert_eq!(AllocationData::decode(&buf[..len]), Ok(data), "{:02x?}", &buf[..len]);
+    }
+}
+
+/// Log records must survive CAN FD padding
+#[test]
+fn log_records_survive_padding() {
+    // longer than one frame
+    let text = "can_rx queue is full, pausing rx (3 stalls); node id 16 allocated to 123456789abc";
+    let defmt_frame = [0x03, 0x01, 0x00, 0x2a, 0x00];
+    let records = [(Some(LogLevel::Info), text.as_bytes()), (None, &defmt_frame[..])];
+    for (transfer_id, (level, body)) in records.into_iter().enumerate() {
+        let mut payload = log_header(level, body.len()).to_vec();
+        payload.extend_from_slice(body);
+        let subject_id = if level.is_some() { LOG_TEXT_SUBJECT_ID } else { LOG_DEFMT_SUBJECT_ID };
+        let id = CanId::message(LOG_PRIORITY, subject_id, 10);
+        let mut reassembler = HostReassembler::new();
+        let frames = segment(&payload, transfer_id as u8, MTU_FD);
+        let (last, first) = frames.split_last().unwrap();
+        let errors = feed(&mut reassembler, id.to_raw(), first);
+        let Ok(Some(transfer)) = reassembler.accept(id.to_raw(), last, 0) else {
+            panic!("log record {:02x?} is not reassembled: {:?}", payload, errors);
+        };
+        assert_eq!(parse_log(transfer.payload), Ok((level, body)), "{:02x?}", transfer.payload);
+    }
+}
+
+fn segment(payload: &[u8], transfer_id: u8, mtu: usize) -> Vec<Vec<u8>> {
+    let mut segmenter = Segmenter::new(payload, transfer_id, mtu);
+    let mut frames = Vec::new();
+    let mut frame = [0u8; MTU_FD];
+    while let Some(len) = segmenter.next_frame(&mut frame) {
+        frames.push(frame[..len].to_vec());
+    }
+    frames
+}
+
+/// Feed all the frames, returns the errors
+fn feed(reassembler: &mut HostReassembler, raw_id: u32, frames: &[Vec<u8>]) -> Vec<Error> {
+    frames.iter().filter_map(|frame| reassembler.accept(raw_id, frame, 0).err()).collect()
+}
+
+/// CAN FD transfers keep their padding, xPI events know their length
+fn trim_padding(payload: &[u8], len: usize) -> &[u8] {
+    &payload[..len.min(payload.len())]
+}
@@ -0,0 +1,199 @@
+//! Requests through the firmware dispatcher running on a HostNode, straight through the queues
+//! that ecbridge_net fills and drains.
+
+use bbqueue::framed::{FrameConsumer, FrameProducer};
+use bbqueue::{BBBuffer, Consumer};
+use ecbridge_dispatch::link::LinkTx;
+use ecbridge_dispatch::subscriptions::Subscription;
+use ecbridge_dispatch::HEARTBEAT_RESOURCE;
+use ecbridge_host::node::{self, HostLink, HostNode};
+use ecbridge_net::envelope::{self, Endpoint, Envelope, LinkId};
+use smoltcp::time::Instant;
+use vhl_stdlib::discrete::{U2Sp1, U4};
+use vhl_stdlib::serdes::{NibbleBuf, NibbleBufMut, SerializeVlu4};
+use xpi::error::XpiError;
+use xpi::event_kind::{XpiEventDiscriminant, XpiGenericEventKind};
+use xpi::xwfd::{Event, EventBuilder, NodeId, NodeSet, Priority, RequestId, ResourceSet, SerialUri};
+use xpi_framing::FrameDecoder;
+
+const QUEUE_LEN: usize = 512;
+const CLIENT_NODE_ID: u8 = 33;
+/// A property, a method that can't be read and a resource that doesn't exist
+const DIGIT: u8 = 1;
+const SET_DIGIT: u8 = 2;
+const UNDEFINED: u8 = 0;
+
+struct Bridge {
+    node: HostNode,
+    link: HostLink<QUEUE_LEN>,
+    eth_out_prod: FrameProducer<'static, QUEUE_LEN>,
+    eth_out_cons: FrameConsumer<'static, QUEUE_LEN>,
+    eth_in_cons: Consumer<'static, QUEUE_LEN>,
+}
+
+impl Bridge {
+    fn new() -> Self {
+        let eth_out_bb: &'static BBBuffer<QUEUE_LEN> = Box::leak(Box::new(BBBuffer::new()));
+        let eth_in_bb: &'static BBBuffer<QUEUE_LEN> = Box::leak(Box::new(BBBuffer::new()));
+        let (eth_out_prod, eth_out_cons) = eth_out_bb.try_split_framed().unwrap();
+        let (eth_in_prod, eth_in_cons) = eth_in_bb.try_split().unwrap();
+        Bridge {
+            node: HostNode::new([127, 0, 0, 1], 8),
+            link: HostLink::new(eth_in_prod),
+            eth_out_prod,
+            eth_out_cons,
+            eth_in_cons,
+        }
+    }
+
+    /// Put `frame` into eth_out queue as if it was received over TCP
+    fn receive(&mut self, frame: &[u8]) {
+        let envelope = Envelope {
+            link: LinkId::Ethernet,
+            connection: 1,
+            received_at: Instant::from_millis(0),
+            endpoint: Endpoint::Ipv4 { addr: [127, 0, 0, 1], port: ecbridge_host::CLIENT_PORT },
+        };
+        assert!(envelope::enqueue(&mut self.eth_out_prod, &envelope, frame));
+    }
+
+    fn process(&mut self) -> usize {
+        node::process(&mut self.node, &mut self.eth_out_cons, &mut self.link)
+    }
+
+    /// Every event in eth_in queue
+    fn sent(&mut self) -> Vec<Vec<u8>> {
+        let mut decoder: FrameDecoder<QUEUE_LEN> = FrameDecoder::new();
+        let mut events = Vec::new();
+        if let Ok(rgr) = self.eth_in_cons.read() {
+            for b in rgr.iter() {
+                match decoder.feed(*b) {
+                    Some(Ok(event)) => events.push(event.to_vec()),
+                    Some(Err(e)) => panic!("bad frame in eth_in: {:?}", e),
+                    None => {}
+                }
+            }
+            let len = rgr.len();
+            rgr.release(len);
+        }
+        events
+    }
+}
+
+/// Read request from CLIENT_NODE_ID to the bridge for root level `resource`
+fn read_request(request_id: u8, resource: u8) -> Vec<u8> {
+    let mut buf = [0u8; 64];
+    let builder = EventBuilder::new(
+        NibbleBufMut::new_all(&mut buf),
+        NodeId::new(CLIENT_NODE_ID).unwrap(),
+        RequestId::new(request_id).unwrap(),
+        Priority::Lossy(U2Sp1::new(1).unwrap()),
+        U4::new(15).unwrap(),
+    )
+    .unwrap();
+    let builder = builder
+        .build_node_set_with(|mut nwr| {
+            let node_set = NodeSet::Unicast(NodeId::new(1).unwrap());
+            node_set.ser_vlu4(&mut nwr)?;
+            Ok((node_set.ser_header(), nwr))
+        })
+        .unwrap();
+    let builder = builder
+        .build_resource_set_with(|mut nwr| {
+            let resource_set = ResourceSet::Uri(SerialUri::OnePart4(U4::new(resource).unwrap()));
+            resource_set.ser_vlu4(&mut nwr)?;
+            Ok((resource_set.ser_header(), nwr))
+        })
+        .unwrap();
+    let nwr = builder
+        .build_kind_with(|nwr| Ok::<_, XpiError>((XpiEventDiscriminant::Read, nwr)))
+        .unwrap();
+    let (_, len, _) = nwr.finish();
+    buf[..len].to_vec()
+}
+
+fn parse(event: &[u8]) -> Event {
+    NibbleBuf::new_all(event).des_vlu4().expect("not an xPI event")
+}
+
+/// Every read is replied to once, back to the client with its request id, whatever the resource
+#[test]
+fn reads_are_replied_to() {
+    let mut bridge = Bridge::new();
+    bridge.node.digit = 7;
+    let requests = [(1, DIGIT), (2, SET_DIGIT), (3, UNDEFINED)];
+    for (request_id, resource) in requests {
+        bridge.receive(&read_request(request_id, resource));
+    }
+    assert_eq!(bridge.process(), requests.len());
+    let replies = bridge.sent();
+    assert_eq!(replies.len(), requests.len());
+    for ((request_id, _), reply) in requests.iter().zip(replies.iter()) {
+        let reply = parse(reply);
+        assert_eq!(reply.kind.discriminant(), XpiEventDiscriminant::ReadResults);
+        assert_eq!(reply.source, NodeId::new(1).unwrap());
+        assert_eq!(reply.destination, NodeSet::Unicast(NodeId::new(CLIENT_NODE_ID).unwrap()));
+        assert_eq!(reply.request_id, RequestId::new(*request_id).unwrap());
+    }
+}
+
+/// Requests stay queued while there is no space for their replies
+#[test]
+fn requests_wait_for_reply_space() {
+    let mut bridge = Bridge::new();
+    let (eth_in_prod, mut eth_in_cons) = Box::leak(Box::new(BBBuffer::<128>::new())).try_split().unwrap();
+    let mut link = HostLink::new(eth_in_prod);
+    // 80 of 128 bytes taken, not enough for a reply of MTU size
+    link.tx.submit(&[0u8; 80]).unwrap();
+    bridge.receive(&read_request(1, DIGIT));
+    assert_eq!(node::process(&mut bridge.node, &mut bridge.eth_out_cons, &mut link), 0);
+    assert!(bridge.eth_out_cons.read().is_some(), "request must be left in eth_out");
+
+    let rgr = eth_in_cons.read().unwrap();
+    let len = rgr.len();
+    rgr.release(len);
+    assert_eq!(node::process(&mut bridge.node, &mut bridge.eth_out_cons, &mut link), 1);
+    assert!(bridge.eth_out_cons.read().is_none());
+}
+
+/// Changed observable resources are published to their subscribers
+#[test]
+fn updates_are_published() {
+    let mut bridge = Bridge::new();
+    let subscription = Subscription {
+        resource: HEARTBEAT_RESOURCE,
+        subscriber: NodeId::new(CLIENT_NODE_ID).unwrap(),
+        link: LinkId::Ethernet,
+        connection: 1,
+        request_id: RequestId::new(5).unwrap(),
+        priority: Priority::Lossy(U2Sp1::new(1).unwrap()),
+    };
+    bridge.node.subscriptions.subscribe(subscription).unwrap();
+    assert_eq!(bridge.process(), 0);
+    assert!(bridge.sent().is_empty(), "nothing changed yet");
+
+    bridge.node.subscriptions.notify(HEARTBEAT_RESOURCE);
+    bridge.process();
+    let updates = bridge.sent();
+    assert_eq!(updates.len(), 1);
+    let update = parse(&updates[0]);
+    assert_eq!(update.kind.discriminant(), XpiEventDiscriminant::StreamUpdates);
+    assert_eq!(update.request_id, RequestId::new(5).unwrap());
+
+    // the next connection doesn't get updates for the closed one
+    bridge.node.connection_closed();
+    bridge.node.subscriptions.notify(HEARTBEAT_RESOURCE);
+    bridge.process();
+    assert!(bridge.sent().is_empty());
+}
+
+/// Frames that are not xPI events are dropped without a reply
+#[test]
+fn garbage_is_dropped() {
+    let mut bridge = Bridge::new();
+    bridge.receive(&[0xff; 3]);
+    bridge.receive(&read_request(1, DIGIT));
+    assert_eq!(bridge.process(), 1);
+    assert_eq!(bridge.sent().len(), 1);
+    assert!(bridge.eth_out_cons.read().is_none());
+}
@@ -0,0 +1,287 @@
+//! Client against the listener and the other services over an in-memory loopback device,
+//! every test with its own network stack.
+
+use anyhow::{anyhow, bail, Result};
+use bbqueue::BBBuffer;
+use ecbridge_host::{connect_client, echo, net_config, new_net, HostStatus, StdClock, CLIENT_PORT};
+use ecbridge_net::{http, sntp, syslog, Net, NetConfig};
+use smoltcp::phy::{Loopback, Medium};
+use smoltcp::socket::{TcpSocket, TcpSocketBuffer, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpAddress, Ipv4Address};
+use xpi_framing::FrameDecoder;
+
+type LoopbackNet = Net<'static, Loopback, StdClock>;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const QUEUE_LEN: usize = 512;
+const SYSLOG_COLLECTOR_PORT: u16 = 5514;
+/// Time the loopback SNTP server reports, 2022-06-01T00:00:00.5Z, so that rounding doesn't change the second
+const SNTP_SERVER_UNIX_S: u64 = 1_654_041_600;
+const SNTP_SERVER_FRACTION: u64 = 0x8000_0000;
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+
+fn config() -> NetConfig {
+    NetConfig {
+        syslog_ipv4: [127, 0, 0, 1],
+        syslog_port: SYSLOG_COLLECTOR_PORT,
+        sntp_ipv4: [127, 0, 0, 1],
+        ..net_config([127, 0, 0, 1], 8)
+    }
+}
+
+fn queue() -> &'static BBBuffer<QUEUE_LEN> {
+    Box::leak(Box::new(BBBuffer::new()))
+}
+
+/// Frames must come back intact, including a delimiter inside and a frame longer than the TCP buffer
+/// to exercise reassembly and flow control
+#[test]
+fn frames_are_echoed() -> Result<()> {
+    let config = config();
+    let mut net = new_net(Loopback::new(Medium::Ethernet), &config);
+    let (mut eth_out_prod, mut eth_out_cons) = queue().try_split_framed().map_err(|e| anyhow!("{:?}", e))?;
+    let (mut eth_in_prod, mut eth_in_cons) = queue().try_split().map_err(|e| anyhow!("{:?}", e))?;
+    net.link_changed(true, &mut eth_in_cons);
+    // start listening, otherwise the client SYN is answered with RST
+    net.process_tcp(&mut eth_out_prod, &mut eth_in_cons);
+
+    let client = connect_client(&mut net, &config)?;
+    let frames: Vec<Vec<u8>> = vec![
+        vec![0x10, 0x20, 0x30],
+        vec![0x00, 0x01, 0x00, 0x02],
+        (0..200).map(|b| b as u8).collect(),
+        vec![0xff; 1],
+    ];
+    let mut tx = Vec::new();
+    for frame in &frames {
+        let mut encoded = vec![0; xpi_framing::max_encoded_len(frame.len())];
+        let len = xpi_framing::encode(frame, &mut encoded).map_err(|e| anyhow!("{:?}", e))?;
+        tx.extend_from_slice(&encoded[..len]);
+    }
+    let mut tx_pos = 0;
+    let mut decoder: FrameDecoder<{ ecbridge_net::TCP_RX_FRAME_MAX }> = FrameDecoder::new();
+    let mut received = Vec::new();
+
+    let deadline = net.now() + TIMEOUT;
+    while received.len() < frames.len() {
+        if net.now() > deadline {
+            bail!("timed out, {} out of {} frames echoed", received.len(), frames.len());
+        }
+        net.poll();
+        net.process_tcp(&mut eth_out_prod, &mut eth_in_cons);
+        echo::process(&mut eth_out_cons, &mut eth_in_prod);
+
+        let socket: &mut TcpSocket = net.iface().get_socket(client);
+        if socket.can_send() && tx_pos < tx.len() {
+            tx_pos += socket.send_slice(&tx[tx_pos..])?;
+        }
+        if socket.can_recv() {
+            socket.recv(|buf| {
+                for b in buf.iter() {
+                    match decoder.feed(*b) {
+                        Some(Ok(frame)) => received.push(frame.to_vec()),
+                        Some(Err(e)) => log::warn!("client: bad frame: {:?}", e),
+                        None => {}
+                    }
+                }
+                (buf.len(), ())
+            })?;
+        }
+    }
+    assert_eq!(received, frames);
+    Ok(())
+}
+
+#[test]
+fn answers_pings() -> Result<()> {
+    const PING_COUNT: u8 = 3;
+    let config = config();
+    let mut net = new_net(Loopback::new(Medium::Ethernet), &config);
+    link_up(&mut net)?;
+    assert!(net.start_ping(IpAddress::Ipv4(Ipv4Address(config.ipv4)), PING_COUNT));
+    let deadline = net.now() + TIMEOUT;
+    let stats = loop {
+        if net.now() > deadline {
+            bail!("ping timed out");
+        }
+        net.poll();
+        match net.process_ping() {
+            Some(stats) if !stats.running => break stats,
+            _ => {}
+        }
+        wait(&mut net);
+    };
+    assert_eq!(stats.received, PING_COUNT, "{:?}", stats);
+    Ok(())
+}
+
+/// Wall clock must follow a server answering with a fixed time
+#[test]
+fn syncs_wall_clock() -> Result<()> {
+    let mut net = new_net(Loopback::new(Medium::Ethernet), &config());
+    link_up(&mut net)?;
+    let status = sync_wall_clock(&mut net)?;
+    let unix_s = net.unix_time_us().ok_or_else(|| anyhow!("SNTP: not synced"))? as u64 / 1_000_000;
+    assert!(status.synced, "{:?}", status);
+    assert!(unix_s.abs_diff(SNTP_SERVER_UNIX_S) <= 1, "expected {}s, got {}s", SNTP_SERVER_UNIX_S, unix_s);
+    Ok(())
+}
+
+/// Log records must arrive at the collector as RFC 5424 messages
+#[test]
+fn sends_syslog_messages() -> Result<()> {
+    let mut net = new_net(Loopback::new(Medium::Ethernet), &config());
+    link_up(&mut net)?;
+    sync_wall_clock(&mut net)?;
+    let rx = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 1024]);
+    let tx = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 0]);
+    let mut collector = UdpSocket::new(rx, tx);
+    collector.bind(SYSLOG_COLLECTOR_PORT)?;
+    let collector = net.iface().add_socket(collector);
+
+    let syslog_bb: &'static BBBuffer<QUEUE_LEN> = queue();
+    let (mut prod, mut cons) = syslog_bb.try_split_framed().map_err(|e| anyhow!("{:?}", e))?;
+    let uptime_ms = net.now().total_millis() as u64;
+    syslog::enqueue(&mut prod, syslog::Severity::Warning, uptime_ms, format_args!("test {}", 42));
+    // milliseconds depend on how long the test took so far
+    let expected_start = "<132>1 2022-06-01T00:00:00.";
+    let expected_end = "Z ecbridge-1 ecbridge_fw - - - test 42";
+
+    let deadline = net.now() + TIMEOUT;
+    loop {
+        if net.now() > deadline {
+            bail!("syslog message not received");
+        }
+        net.poll();
+        net.process_syslog(&mut cons);
+        let socket: &mut UdpSocket = net.iface().get_socket(collector);
+        if let Ok((message, _)) = socket.recv() {
+            let message = String::from_utf8_lossy(message);
+            assert!(
+                message.starts_with(expected_start) && message.ends_with(expected_end),
+                "expected '{}...{}', got '{}'",
+                expected_start,
+                expected_end,
+                message
+            );
+            return Ok(());
+        }
+    }
+}
+
+/// Status page must be served as JSON with both network and application fields
+#[test]
+fn serves_status_page() -> Result<()> {
+    let config = config();
+    let mut net = new_net(Loopback::new(Medium::Ethernet), &config);
+    let (mut eth_out_prod, _) = queue().try_split_framed().map_err(|e| anyhow!("{:?}", e))?;
+    let (_, mut eth_in_cons) = queue().try_split().map_err(|e| anyhow!("{:?}", e))?;
+    net.link_changed(true, &mut eth_in_cons);
+    sync_wall_clock(&mut net)?;
+    // xPI client shows up on the page
+    net.process_tcp(&mut eth_out_prod, &mut eth_in_cons);
+    connect_client(&mut net, &config)?;
+
+    let rx = Box::leak(vec![0; 2048].into_boxed_slice());
+    let tx = Box::leak(vec![0; 256].into_boxed_slice());
+    let client = net.iface().add_socket(TcpSocket::new(TcpSocketBuffer::new(&mut rx[..]), TcpSocketBuffer::new(&mut tx[..])));
+    let status = || HostStatus { events_dispatched: 4 };
+    // start listening, otherwise the client SYN is answered with RST
+    net.process_http(status);
+    let (socket, cx) = net.iface().get_socket_and_context::<TcpSocket>(client);
+    socket.connect(cx, (IpAddress::Ipv4(Ipv4Address(config.ipv4)), http::PORT), CLIENT_PORT + 1)?;
+
+    let request = b"GET /status.json HTTP/1.1\r\nHost: ecbridge\r\nAccept: */*\r\n\r\n";
+    let mut request_sent = false;
+    let mut response = Vec::new();
+    let deadline = net.now() + TIMEOUT;
+    loop {
+        if net.now() > deadline {
+            bail!("HTTP: timed out, got '{}'", String::from_utf8_lossy(&response));
+        }
+        net.poll();
+        net.process_tcp(&mut eth_out_prod, &mut eth_in_cons);
+        net.process_http(status);
+        let socket: &mut TcpSocket = net.iface().get_socket(client);
+        if socket.can_send() && !request_sent {
+            socket.send_slice(request)?;
+            request_sent = true;
+        }
+        if socket.can_recv() {
+            socket.recv(|buf| {
+                response.extend_from_slice(buf);
+                (buf.len(), ())
+            })?;
+        }
+        if request_sent && !socket.may_recv() {
+            // server closed the connection after the response
+            break;
+        }
+    }
+    let response = String::from_utf8(response)?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| anyhow!("HTTP: no head in '{}'", response))?;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(head.contains(&format!("Content-Length: {}", body.len())), "{}", response);
+    for field in [
+        "\"link_up\":true",
+        "\"ip_addrs\":[\"127.0.0.1/8\"",
+        "\"xpi_client\":\"127.0.0.1:49152\"",
+        "\"utc\":\"2022-06-01T00:00:",
+        "\"events_dispatched\":4}",
+    ] {
+        assert!(body.contains(field), "{} not in {}", field, body);
+    }
+    Ok(())
+}
+
+/// Not needed by the rest of the test, nothing is replied over xPI
+fn link_up(net: &mut LoopbackNet) -> Result<()> {
+    let (_, mut eth_in_cons) = queue().try_split().map_err(|e| anyhow!("{:?}", e))?;
+    net.link_changed(true, &mut eth_in_cons);
+    Ok(())
+}
+
+/// Answer SNTP requests with the fixed time until the client syncs
+fn sync_wall_clock(net: &mut LoopbackNet) -> Result<sntp::SntpStatus> {
+    let rx = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 2], vec![0; 2 * sntp::PACKET_LEN]);
+    let tx = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 2], vec![0; 2 * sntp::PACKET_LEN]);
+    let mut server = UdpSocket::new(rx, tx);
+    server.bind(sntp::PORT)?;
+    let server = net.iface().add_socket(server);
+
+    let deadline = net.now() + TIMEOUT;
+    let status = loop {
+        if net.now() > deadline {
+            bail!("SNTP sync timed out");
+        }
+        net.poll();
+        if let Some(status) = net.process_sntp() {
+            break status;
+        }
+        let socket: &mut UdpSocket = net.iface().get_socket(server);
+        if let Ok((request, client)) = socket.recv() {
+            let mut reply = [0u8; sntp::PACKET_LEN];
+            // LI = 0, VN = 4, Mode = 4 (server), stratum 1
+            reply[0] = 0b00_100_100;
+            reply[1] = 1;
+            reply[24..32].copy_from_slice(&request[40..48]);
+            let timestamp = ((SNTP_SERVER_UNIX_S + NTP_UNIX_OFFSET_S) << 32) | SNTP_SERVER_FRACTION;
+            reply[32..40].copy_from_slice(&timestamp.to_be_bytes());
+            reply[40..48].copy_from_slice(&timestamp.to_be_bytes());
+            socket.send_slice(&reply, client)?;
+        }
+        wait(net);
+    };
+    // frees the slot for other sockets
+    net.iface().remove_socket(server);
+    Ok(status)
+}
+
+/// Sleep until the stack asks to be polled again
+fn wait(net: &mut LoopbackNet) {
+    match net.poll_at() {
+        Some(at) if at > net.now() => std::thread::sleep((at - net.now()).into()),
+        _ => {}
+    }
+}
@@ -0,0 +1,302 @@
+//! Envelope in front of every received xPI frame in the framed request queue.
+//!
+//! Same format for all the transports, so that the dispatcher doesn't care where a request came
+//! from and can send the reply back the same way. Layout, multi-byte fields are little endian:
+//!
+//! | link id | u16 connection id | i64 received at, us | endpoint kind | endpoint | frame
+//!
+//! Endpoint is `[u8; 4] address, u16 port` for IPv4, `[u8; 16] address, u16 port` for IPv6,
+//! `u8 node id` for bus links and nothing for point-to-point ones.
+//! bbqueue framed mode keeps the record length, so the frame is just the rest of the grant.
+//! Records are only ever written by this module, still decoding never panics on a malformed one.
+
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+/// Link id, connection id, timestamp and endpoint kind
+const HEADER_LEN: usize = 1 + 2 + 8 + 1;
+/// Longest envelope, IPv6 endpoint
+pub const ENVELOPE_MAX: usize = HEADER_LEN + 16 + 2;
+
+const ENDPOINT_NONE: u8 = 0;
+const ENDPOINT_NODE: u8 = 1;
+const ENDPOINT_IPV4: u8 = 4;
+const ENDPOINT_IPV6: u8 = 6;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Record is shorter than the envelope it claims to have
+    TooShort,
+    UnknownLink(u8),
+    UnknownEndpoint(u8),
+    /// Grant is too small for the envelope and the frame
+    OutOfSpace,
+}
+
+/// Transport a request arrived over
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum LinkId {
+    Ethernet = 0,
+    Can = 1,
+    Uart = 2,
+    Rtt = 3,
+}
+
+impl TryFrom<u8> for LinkId {
+    type Error = Error;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0 => Ok(LinkId::Ethernet),
+            1 => Ok(LinkId::Can),
+            2 => Ok(LinkId::Uart),
+            3 => Ok(LinkId::Rtt),
+            id => Err(Error::UnknownLink(id)),
+        }
+    }
+}
+
+/// Remote side of the link
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Endpoint {
+    /// Point-to-point links
+    None,
+    /// Node id on a bus
+    Node(u8),
+    Ipv4 { addr: [u8; 4], port: u16 },
+    Ipv6 { addr: [u8; 16], port: u16 },
+}
+
+impl Endpoint {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Endpoint::None => 0,
+            Endpoint::Node(_) => 1,
+            Endpoint::Ipv4 { .. } => 4 + 2,
+            Endpoint::Ipv6 { .. } => 16 + 2,
+        }
+    }
+}
+
+impl TryFrom<IpEndpoint> for Endpoint {
+    type Error = ();
+
+    fn try_from(value: IpEndpoint) -> Result<Self, Self::Error> {
+        match value.addr {
+            IpAddress::Ipv4(v4) => Ok(Endpoint::Ipv4 { addr: v4.0, port: value.port }),
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(v6) => Ok(Endpoint::Ipv6 { addr: v6.0, port: value.port }),
+            #[allow(unreachable_patterns)]
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Envelope {
+    pub link: LinkId,
+    /// Changes every time a connection oriented link accepts a new connection,
+    /// so that replies to requests from a closed connection can be told apart
+    pub connection: u16,
+    /// Uptime when the frame was taken out of the transport
+    pub received_at: Instant,
+    pub endpoint: Endpoint,
+}
+
+impl Envelope {
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.endpoint.encoded_len()
+    }
+
+    /// Write the envelope followed by `frame` into `buf`, returns the record length.
+    pub fn encode(&self, frame: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+        let frame_start = self.encoded_len();
+        let len = frame_start + frame.len();
+        if buf.len() < len {
+            return Err(Error::OutOfSpace);
+        }
+        buf[0] = self.link as u8;
+        buf[1..3].copy_from_slice(&self.connection.to_le_bytes());
+        buf[3..11].copy_from_slice(&self.received_at.total_micros().to_le_bytes());
+        let endpoint = &mut buf[HEADER_LEN..frame_start];
+        buf[HEADER_LEN - 1] = match self.endpoint {
+            Endpoint::None => ENDPOINT_NONE,
+            Endpoint::Node(id) => {
+                endpoint[0] = id;
+                ENDPOINT_NODE
+            }
+            Endpoint::Ipv4 { addr, port } => {
+                endpoint[..4].copy_from_slice(&addr);
+                endpoint[4..].copy_from_slice(&port.to_le_bytes());
+                ENDPOINT_IPV4
+            }
+            Endpoint::Ipv6 { addr, port } => {
+                endpoint[..16].copy_from_slice(&addr);
+                endpoint[16..].copy_from_slice(&port.to_le_bytes());
+                ENDPOINT_IPV6
+            }
+        };
+        buf[frame_start..len].copy_from_slice(frame);
+        Ok(len)
+    }
+
+    /// Split a record into the envelope and the frame.
+    pub fn decode(record: &[u8]) -> Result<(Envelope, &[u8]), Error> {
+        let header = record.get(..HEADER_LEN).ok_or(Error::TooShort)?;
+        let link = LinkId::try_from(header[0])?;
+        let connection = u16::from_le_bytes([header[1], header[2]]);
+        let mut micros = [0u8; 8];
+        micros.copy_from_slice(&header[3..11]);
+        let received_at = Instant::from_micros(i64::from_le_bytes(micros));
+        let rest = &record[HEADER_LEN..];
+        let (endpoint, frame) = match header[HEADER_LEN - 1] {
+            ENDPOINT_NONE => (Endpoint::None, rest),
+            ENDPOINT_NODE => {
+                let (id, frame) = rest.split_first().ok_or(Error::TooShort)?;
+                (Endpoint::Node(*id), frame)
+            }
+            ENDPOINT_IPV4 => {
+                let endpoint = rest.get(..6).ok_or(Error::TooShort)?;
+                let mut addr = [0u8; 4];
+                addr.copy_from_slice(&endpoint[..4]);
+                let port = u16::from_le_bytes([endpoint[4], endpoint[5]]);
+                (Endpoint::Ipv4 { addr, port }, &rest[6..])
+            }
+            ENDPOINT_IPV6 => {
+                let endpoint = rest.get(..18).ok_or(Error::TooShort)?;
+                let mut addr = [0u8; 16];
+                addr.copy_from_slice(&endpoint[..16]);
+                let port = u16::from_le_bytes([endpoint[16], endpoint[17]]);
+                (Endpoint::Ipv6 { addr, port }, &rest[18..])
+            }
+            kind => return Err(Error::UnknownEndpoint(kind)),
+        };
+        let envelope = Envelope { link, connection, received_at, endpoint };
+        Ok((envelope, frame))
+    }
+}
+
+/// Put one received frame into the framed request queue, returns false if there is no space for it.
+pub fn enqueue<const N: usize>(
+    prod: &mut bbqueue::framed::FrameProducer<N>,
+    envelope: &Envelope,
+    frame: &[u8],
+) -> bool {
+    let mut wgr = match prod.grant(envelope.encoded_len() + frame.len()) {
+        Ok(wgr) => wgr,
+        Err(_) => return false,
+    };
+    match envelope.encode(frame, &mut wgr) {
+        Ok(len) => {
+            wgr.commit(len);
+            true
+        }
+        // granted exactly that much
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    const FRAME: [u8; 5] = [0x10, 0x00, 0xFF, 0x7E, 0x01];
+
+    fn envelopes() -> [Envelope; 4] {
+        let envelope = |link, endpoint| Envelope {
+            link,
+            connection: 0xBEEF,
+            received_at: Instant::from_micros(-1_234_567_890_123i64),
+            endpoint,
+        };
+        [
+            envelope(LinkId::Rtt, Endpoint::None),
+            envelope(LinkId::Can, Endpoint::Node(127)),
+            envelope(LinkId::Ethernet, Endpoint::Ipv4 { addr: [192, 168, 0, 10], port: 49152 }),
+            envelope(LinkId::Uart, Endpoint::Ipv6 { addr: [0xFE; 16], port: 7777 }),
+        ]
+    }
+
+    fn encode_vec(envelope: &Envelope, frame: &[u8]) -> Vec<u8> {
+        let mut buf = [0u8; ENVELOPE_MAX + 16];
+        let len = envelope.encode(frame, &mut buf).unwrap();
+        assert_eq!(len, envelope.encoded_len() + frame.len());
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn round_trip() {
+        for envelope in envelopes() {
+            for frame in [&FRAME[..], &[]] {
+                let record = encode_vec(&envelope, frame);
+                assert!(envelope.encoded_len() <= ENVELOPE_MAX);
+                assert_eq!(Envelope::decode(&record), Ok((envelope, frame)));
+            }
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let envelope = Envelope {
+            link: LinkId::Ethernet,
+            connection: 0x0102,
+            received_at: Instant::from_micros(0x0A0B),
+            endpoint: Endpoint::Ipv4 { addr: [10, 0, 0, 1], port: 0x1E61 },
+        };
+        let record = encode_vec(&envelope, &[0xAA]);
+        assert_eq!(
+            record,
+            vec![0, 0x02, 0x01, 0x0B, 0x0A, 0, 0, 0, 0, 0, 0, ENDPOINT_IPV4, 10, 0, 0, 1, 0x61, 0x1E, 0xAA]
+        );
+    }
+
+    #[test]
+    fn truncated_records() {
+        for envelope in envelopes() {
+            // frame is whatever follows the envelope, so only cuts inside it are detectable
+            let record = encode_vec(&envelope, &[]);
+            for len in 0..record.len() {
+                assert_eq!(Envelope::decode(&record[..len]), Err(Error::TooShort), "{:?} cut at {}", envelope, len);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_link_and_endpoint() {
+        let mut record = encode_vec(&envelopes()[2], &FRAME);
+        record[0] = 4;
+        assert_eq!(Envelope::decode(&record), Err(Error::UnknownLink(4)));
+        record[0] = 0xFF;
+        assert_eq!(Envelope::decode(&record), Err(Error::UnknownLink(0xFF)));
+
+        let mut record = encode_vec(&envelopes()[2], &FRAME);
+        for kind in [2, 3, 5, 7, 0xFF] {
+            record[HEADER_LEN - 1] = kind;
+            assert_eq!(Envelope::decode(&record), Err(Error::UnknownEndpoint(kind)));
+        }
+    }
+
+    #[test]
+    fn out_of_space() {
+        let envelope = envelopes()[3];
+        let mut buf = [0u8; ENVELOPE_MAX + FRAME.len()];
+        assert_eq!(envelope.encode(&FRAME, &mut buf[..ENVELOPE_MAX + FRAME.len() - 1]), Err(Error::OutOfSpace));
+        assert_eq!(envelope.encode(&FRAME, &mut buf), Ok(ENVELOPE_MAX + FRAME.len()));
+    }
+
+    #[test]
+    fn enqueue_into_framed_queue() {
+        let bb: bbqueue::BBBuffer<64> = bbqueue::BBBuffer::new();
+        let (mut prod, mut cons) = bb.try_split_framed().unwrap();
+        let envelope = envelopes()[1];
+        assert!(enqueue(&mut prod, &envelope, &FRAME));
+        // 64 bytes is not enough for another IPv6 envelope with a long frame
+        assert!(!enqueue(&mut prod, &envelopes()[3], &[0; 40]));
+        let rgr = cons.read().unwrap();
+        assert_eq!(Envelope::decode(&rgr), Ok((envelope, &FRAME[..])));
+    }
+}
@@ -0,0 +1,333 @@
+//! Minimal HTTP/1.1 server for the status page.
+//!
+//! One connection at a time and one request per connection, responses are sent with
+//! `Connection: close`. Only the request line is kept, the rest of the head is skipped.
+//! Response is rendered into a fixed buffer and sent over as many polls as the socket needs.
+//!
+//! `/` is an HTML table, `/status.json` is the same fields as a JSON object.
+
+use core::fmt::{self, Write};
+use log::{debug, warn};
+use smoltcp::socket::TcpSocket;
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpCidr, IpEndpoint};
+use crate::sntp;
+use crate::SliceWriter;
+
+pub const PORT: u16 = 80;
+/// Longest request line handled, longer ones are answered with 414
+pub const REQUEST_LINE_MAX: usize = 128;
+/// Rendered response, head included
+pub const RESPONSE_MAX: usize = 2048;
+/// Socket buffer sizes
+pub const RX_BUFFER_LEN: usize = 256;
+pub const TX_BUFFER_LEN: usize = 1024;
+/// Connection is dropped if the client stops sending or acknowledging for that long
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+/// Space left in front of the body for the response head
+const HEAD_MAX: usize = 128;
+
+/// One field of the status page
+#[derive(Copy, Clone, Debug)]
+pub enum Value<'a> {
+    Bool(bool),
+    U32(u32),
+    U64(u64),
+    Str(&'a str),
+    Char(char),
+    /// Rendered as a list of addresses with prefix lengths
+    Cidrs(&'a [IpCidr]),
+    /// None is rendered as null
+    Endpoint(Option<IpEndpoint>),
+    /// Milliseconds since Unix epoch as RFC 3339, None is rendered as null
+    UnixTime(Option<i64>),
+}
+
+/// Application specific part of the status page
+pub trait Status {
+    /// Called for each field, they are shown after the ones known to the network stack.
+    fn fields(&self, f: &mut dyn FnMut(&str, Value));
+}
+
+impl Status for () {
+    fn fields(&self, _f: &mut dyn FnMut(&str, Value)) {}
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Route {
+    Html,
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Request {
+    Get { route: Route, head_only: bool },
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    UriTooLong,
+}
+
+enum State {
+    /// Collecting the request head, `tail` holds the last 4 bytes to find its end
+    Receiving { line_len: usize, line_done: bool, tail: u32 },
+    /// Head received, waiting for the response to be rendered
+    Pending(Request),
+    Sending { len: usize, sent: usize },
+    /// Response sent, waiting for the connection to close
+    Done,
+}
+
+pub struct Server<'a> {
+    request_line: [u8; REQUEST_LINE_MAX],
+    response: &'a mut [u8],
+    state: State,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(response: &'a mut [u8]) -> Self {
+        Server {
+            request_line: [0; REQUEST_LINE_MAX],
+            response,
+            state: State::Receiving { line_len: 0, line_done: false, tail: 0 },
+        }
+    }
+
+    /// Listen again once the previous connection is closed, collect the request head.
+    /// Returns the request once a response has to be rendered for it.
+    pub fn receive(&mut self, socket: &mut TcpSocket) -> Option<Request> {
+        if !socket.is_open() {
+            if let Err(e) = socket.listen(PORT) {
+                warn!("http: listen: {:?}", e);
+                return None;
+            }
+            socket.set_timeout(Some(TIMEOUT));
+            self.state = State::Receiving { line_len: 0, line_done: false, tail: 0 };
+        }
+        let request_line = &mut self.request_line;
+        let r = socket.recv(|buffer| match &mut self.state {
+            State::Receiving { line_len, line_done, tail } => {
+                let mut consumed = 0;
+                for &b in buffer.iter() {
+                    consumed += 1;
+                    *tail = (*tail << 8) | b as u32;
+                    if !*line_done {
+                        if b == b'\r' || b == b'\n' {
+                            *line_done = true;
+                        } else if *line_len < REQUEST_LINE_MAX {
+                            request_line[*line_len] = b;
+                            *line_len += 1;
+                        } else {
+                            // keep counting, so that the request is rejected as too long
+                            *line_len = REQUEST_LINE_MAX + 1;
+                        }
+                    }
+                    // bare LF line endings are accepted as well
+                    let end_of_head = *tail == u32::from_be_bytes(*b"\r\n\r\n") || *tail & 0xFFFF == 0x0A0A;
+                    if end_of_head {
+                        let request = parse(&request_line[..(*line_len).min(REQUEST_LINE_MAX)], *line_len);
+                        self.state = State::Pending(request);
+                        break;
+                    }
+                }
+                (consumed, ())
+            }
+            // request body and pipelined requests are ignored
+            _ => (buffer.len(), ()),
+        });
+        if let Err(e) = r {
+            debug!("http: recv: {:?}", e);
+        }
+        match self.state {
+            State::Pending(request) => Some(request),
+            _ => None,
+        }
+    }
+
+    /// Render the response to the request returned by receive().
+    pub fn respond(&mut self, request: Request, net: &dyn Status, app: &dyn Status) {
+        let (status_line, content_type, route, head_only) = match request {
+            Request::Get { route: Route::Html, head_only } => ("200 OK", "text/html; charset=utf-8", Some(Route::Html), head_only),
+            Request::Get { route: Route::Json, head_only } => ("200 OK", "application/json", Some(Route::Json), head_only),
+            Request::NotFound => ("404 Not Found", "text/plain", None, false),
+            Request::MethodNotAllowed => ("405 Method Not Allowed", "text/plain", None, false),
+            Request::BadRequest => ("400 Bad Request", "text/plain", None, false),
+            Request::UriTooLong => ("414 URI Too Long", "text/plain", None, false),
+        };
+        let mut wr = SliceWriter { buf: &mut self.response[HEAD_MAX..], pos: 0 };
+        let r = match route {
+            Some(Route::Html) => write_html(&mut wr, net, app),
+            Some(Route::Json) => write_json(&mut wr, net, app),
+            None => wr.write_str(status_line),
+        };
+        let (status_line, content_type, body_len) = match r {
+            Ok(()) => (status_line, content_type, wr.pos),
+            Err(_) => {
+                warn!("http: response doesn't fit into {}B", RESPONSE_MAX);
+                let mut wr = SliceWriter { buf: &mut self.response[HEAD_MAX..], pos: 0 };
+                let _ = wr.write_str("status is too long");
+                ("500 Internal Server Error", "text/plain", wr.pos)
+            }
+        };
+
+        let mut head = [0u8; HEAD_MAX];
+        let mut wr = SliceWriter { buf: &mut head, pos: 0 };
+        let _ = write!(
+            wr,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status_line,
+            content_type,
+            body_len
+        );
+        let head_len = wr.pos;
+        let len = if head_only {
+            head_len
+        } else {
+            self.response.copy_within(HEAD_MAX..HEAD_MAX + body_len, head_len);
+            head_len + body_len
+        };
+        self.response[..head_len].copy_from_slice(&head[..head_len]);
+        debug!("http: {:?}: {}", request, status_line);
+        self.state = State::Sending { len, sent: 0 };
+    }
+
+    /// Send as much of the response as the socket takes, close once all of it is queued.
+    pub fn send(&mut self, socket: &mut TcpSocket) {
+        if let State::Sending { len, sent } = &mut self.state {
+            if socket.can_send() {
+                match socket.send_slice(&self.response[*sent..*len]) {
+                    Ok(written) => *sent += written,
+                    Err(e) => warn!("http: send: {:?}", e),
+                }
+            }
+            if *sent >= *len {
+                socket.close();
+                self.state = State::Done;
+            }
+        }
+    }
+}
+
+/// `METHOD /path HTTP/1.x`, query string is ignored
+fn parse(line: &[u8], line_len: usize) -> Request {
+    if line_len > REQUEST_LINE_MAX {
+        return Request::UriTooLong;
+    }
+    let line = match core::str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return Request::BadRequest,
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Request::BadRequest,
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Request::BadRequest;
+    }
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return Request::MethodNotAllowed,
+    };
+    let path = target.split('?').next().unwrap_or("");
+    match path {
+        "/" | "/index.html" => Request::Get { route: Route::Html, head_only },
+        "/status.json" => Request::Get { route: Route::Json, head_only },
+        _ => Request::NotFound,
+    }
+}
+
+fn write_html(wr: &mut SliceWriter, net: &dyn Status, app: &dyn Status) -> fmt::Result {
+    wr.write_str(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>ECBridge</title></head>\
+         <body><h1>ECBridge</h1><table>\n",
+    )?;
+    let mut r = Ok(());
+    let mut row = |name: &str, value: Value| {
+        if r.is_ok() {
+            r = write!(wr, "<tr><th align=\"left\">{}</th><td>", name)
+                .and_then(|_| write_value(wr, value, false))
+                .and_then(|_| wr.write_str("</td></tr>\n"));
+        }
+    };
+    net.fields(&mut row);
+    app.fields(&mut row);
+    r?;
+    wr.write_str("</table><p><a href=\"/status.json\">status.json</a></p></body></html>\n")
+}
+
+fn write_json(wr: &mut SliceWriter, net: &dyn Status, app: &dyn Status) -> fmt::Result {
+    wr.write_char('{')?;
+    let mut r = Ok(());
+    let mut first = true;
+    let mut member = |name: &str, value: Value| {
+        if r.is_ok() {
+            let separator = if first { "" } else { "," };
+            first = false;
+            r = write!(wr, "{}\"{}\":", separator, name).and_then(|_| write_value(wr, value, true));
+        }
+    };
+    net.fields(&mut member);
+    app.fields(&mut member);
+    r?;
+    wr.write_str("}\n")
+}
+
+fn write_value(wr: &mut SliceWriter, value: Value, json: bool) -> fmt::Result {
+    let null = if json { "null" } else { "-" };
+    match value {
+        Value::Bool(b) => write!(wr, "{}", b),
+        Value::U32(n) => write!(wr, "{}", n),
+        Value::U64(n) => write!(wr, "{}", n),
+        Value::Str(s) => write_str_escaped(wr, s, json),
+        Value::Char(c) => {
+            let mut buf = [0u8; 4];
+            write_str_escaped(wr, c.encode_utf8(&mut buf), json)
+        }
+        Value::Cidrs(cidrs) => {
+            wr.write_str(if json { "[" } else { "" })?;
+            for (i, cidr) in cidrs.iter().enumerate() {
+                let separator = match (i, json) {
+                    (0, _) => "",
+                    (_, true) => ",",
+                    (_, false) => "<br>",
+                };
+                let quote = if json { "\"" } else { "" };
+                write!(wr, "{}{}{}{}", separator, quote, cidr, quote)?;
+            }
+            wr.write_str(if json { "]" } else { "" })
+        }
+        Value::Endpoint(Some(endpoint)) if json => write!(wr, "\"{}\"", endpoint),
+        Value::Endpoint(Some(endpoint)) => write!(wr, "{}", endpoint),
+        Value::UnixTime(Some(unix_ms)) => {
+            let quote = if json { "\"" } else { "" };
+            wr.write_str(quote)?;
+            sntp::write_rfc3339(wr, unix_ms)?;
+            wr.write_str(quote)
+        }
+        Value::Endpoint(None) | Value::UnixTime(None) => wr.write_str(null),
+    }
+}
+
+/// Quoted JSON string or HTML text
+fn write_str_escaped(wr: &mut SliceWriter, s: &str, json: bool) -> fmt::Result {
+    if json {
+        wr.write_char('"')?;
+    }
+    for c in s.chars() {
+        match (c, json) {
+            ('"', true) => wr.write_str("\\\"")?,
+            ('\\', true) => wr.write_str("\\\\")?,
+            (c, true) if (c as u32) < 0x20 => write!(wr, "\\u{:04x}", c as u32)?,
+            ('<', false) => wr.write_str("&lt;")?,
+            ('>', false) => wr.write_str("&gt;")?,
+            ('&', false) => wr.write_str("&amp;")?,
+            (c, _) => wr.write_char(c)?,
+        }
+    }
+    if json {
+        wr.write_char('"')?;
+    }
+    Ok(())
+}
@@ -0,0 +1,788 @@
+#![no_std]
+
+//! ECBridge networking: smoltcp interface with the xPI TCP listener, mDNS responder, SLAAC, ping,
+//! syslog sender, SNTP client and HTTP status page,
+//! moving xPI frames between the TCP socket and bbqueue queues.
+//!
+//! Generic over the smoltcp `Device` and a `Clock`, so that the same code runs on the board with
+//! the STM32H7 Ethernet MAC and RTIC monotonic, and on Linux with a TAP or loopback device.
+//!
+//! Frames received over TCP are put into framed eth_out queue behind an [envelope::Envelope],
+//! replies are taken from eth_in queue already framed with xpi_framing.
+//! Frames that are whole in the socket buffer are decoded right there into the queue, only the ones
+//! split across TCP segments or the socket ring buffer wrap go through the reassembly buffer.
+//!
+//! TCP socket buffer sizes are const generic parameters of NetStorage, queue sizes are chosen by
+//! whoever creates the queues.
+
+pub mod envelope;
+pub mod http;
+pub mod mdns;
+pub mod ping;
+pub mod sntp;
+pub mod syslog;
+#[cfg(feature = "proto-ipv6")]
+pub mod slaac;
+
+use log::{debug, error, info, trace, warn};
+use smoltcp::iface::{
+    Interface, InterfaceBuilder, Neighbor, NeighborCache, Route, Routes, SocketHandle, SocketStorage,
+};
+use smoltcp::phy::Device;
+#[cfg(feature = "proto-ipv6")]
+use smoltcp::socket::{RawPacketMetadata, RawSocket, RawSocketBuffer};
+use smoltcp::socket::{
+    IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer, TcpSocket, TcpSocketBuffer, UdpPacketMetadata,
+    UdpSocket, UdpSocketBuffer,
+};
+use smoltcp::time::{Duration, Instant};
+#[cfg(feature = "proto-ipv6")]
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv6Address};
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr};
+use xpi_framing::FrameDecoder;
+use envelope::{Endpoint, Envelope, LinkId};
+
+/// Longest encoded xPI frame that can be received, longer ones are dropped
+pub const TCP_RX_FRAME_MAX: usize = 256;
+
+/// Slots in the interface address list, unused IPv6 slots hold a copy of the link-local address
+const IP_SLOT_IPV4: usize = 0;
+#[cfg(feature = "proto-ipv6")]
+const IP_SLOT_LINK_LOCAL: usize = 1;
+#[cfg(feature = "proto-ipv6")]
+const IP_SLOT_STATIC_IPV6: usize = 2;
+#[cfg(feature = "proto-ipv6")]
+const IP_SLOT_SLAAC: usize = 3;
+#[cfg(feature = "proto-ipv6")]
+const IP_ADDRS: usize = 4;
+#[cfg(not(feature = "proto-ipv6"))]
+const IP_ADDRS: usize = 1;
+
+/// Default TCP socket buffer sizes, enough for a client waiting for each reply before the next request
+pub const TCP_BUFFER_LEN: usize = 128;
+/// Own sockets and a few spare ones for iface() users
+const SOCKETS: usize = 12;
+
+/// Time source for smoltcp
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Part of the node config used by the network stack
+#[derive(Copy, Clone, Debug)]
+pub struct NetConfig {
+    pub ipv4: [u8; 4],
+    pub ipv4_prefix_len: u8,
+    /// Static IPv6 address in addition to link-local and autoconfigured ones, all zeroes if none
+    pub ipv6: [u8; 16],
+    pub ipv6_prefix_len: u8,
+    pub tcp_port: u16,
+    /// Announced over mDNS
+    pub node_id: u8,
+    pub schema_hash: &'static str,
+    /// TCP keep-alive interval in seconds, 0 to disable
+    pub keepalive_s: u16,
+    /// Connection is dropped if the peer doesn't acknowledge data or keep-alives for that long, 0 to disable
+    pub timeout_s: u16,
+    /// Syslog collector, messages are not sent if port is 0
+    pub syslog_ipv4: [u8; 4],
+    pub syslog_port: u16,
+    /// SNTP server, wall clock is not synced if all zeroes
+    pub sntp_ipv4: [u8; 4],
+}
+
+/// Interface and socket buffers, can be placed into a static.
+///
+/// Larger xPI TCP buffers let pipelining clients keep more requests in flight.
+pub struct NetStorage<'a, const TCP_RX_LEN: usize = TCP_BUFFER_LEN, const TCP_TX_LEN: usize = TCP_BUFFER_LEN> {
+    ip_addrs: [IpCidr; IP_ADDRS],
+    socket_storage: [SocketStorage<'a>; SOCKETS],
+    neighbor_cache_storage: [Option<(IpAddress, Neighbor)>; 8],
+    routes_storage: [Option<(IpCidr, Route)>; 2],
+    ipv4_multicast_storage: [Option<(Ipv4Address, ())>; 2],
+    tcp_rx: [u8; TCP_RX_LEN],
+    tcp_tx: [u8; TCP_TX_LEN],
+    mdns_rx_metadata: [UdpPacketMetadata; 4],
+    mdns_rx: [u8; mdns::MESSAGE_MAX],
+    mdns_tx_metadata: [UdpPacketMetadata; 4],
+    mdns_tx: [u8; mdns::MESSAGE_MAX],
+    icmp_rx_metadata: [IcmpPacketMetadata; 4],
+    icmp_rx: [u8; ping::PACKET_MAX * 4],
+    icmp_tx_metadata: [IcmpPacketMetadata; 1],
+    icmp_tx: [u8; ping::PACKET_MAX],
+    syslog_rx_metadata: [UdpPacketMetadata; 1],
+    syslog_rx: [u8; 0],
+    syslog_tx_metadata: [UdpPacketMetadata; 4],
+    syslog_tx: [u8; syslog::MESSAGE_MAX * 4],
+    sntp_rx_metadata: [UdpPacketMetadata; 2],
+    sntp_rx: [u8; sntp::PACKET_LEN * 2],
+    sntp_tx_metadata: [UdpPacketMetadata; 1],
+    sntp_tx: [u8; sntp::PACKET_LEN],
+    http_rx: [u8; http::RX_BUFFER_LEN],
+    http_tx: [u8; http::TX_BUFFER_LEN],
+    http_response: [u8; http::RESPONSE_MAX],
+    #[cfg(feature = "proto-ipv6")]
+    slaac_rx_metadata: [RawPacketMetadata; 4],
+    #[cfg(feature = "proto-ipv6")]
+    slaac_rx: [u8; slaac::PACKET_MAX * 2],
+    #[cfg(feature = "proto-ipv6")]
+    slaac_tx_metadata: [RawPacketMetadata; 1],
+    #[cfg(feature = "proto-ipv6")]
+    slaac_tx: [u8; slaac::PACKET_MAX],
+}
+
+impl<'a, const TCP_RX_LEN: usize, const TCP_TX_LEN: usize> NetStorage<'a, TCP_RX_LEN, TCP_TX_LEN> {
+    pub const fn new() -> Self {
+        NetStorage {
+            // Garbage
+            ip_addrs: [IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)); IP_ADDRS],
+            socket_storage: [SocketStorage::EMPTY; SOCKETS],
+            neighbor_cache_storage: [None; 8],
+            routes_storage: [None; 2],
+            ipv4_multicast_storage: [None; 2],
+            tcp_rx: [0; TCP_RX_LEN],
+            tcp_tx: [0; TCP_TX_LEN],
+            mdns_rx_metadata: [UdpPacketMetadata::EMPTY; 4],
+            mdns_rx: [0; mdns::MESSAGE_MAX],
+            mdns_tx_metadata: [UdpPacketMetadata::EMPTY; 4],
+            mdns_tx: [0; mdns::MESSAGE_MAX],
+            icmp_rx_metadata: [IcmpPacketMetadata::EMPTY; 4],
+            icmp_rx: [0; ping::PACKET_MAX * 4],
+            icmp_tx_metadata: [IcmpPacketMetadata::EMPTY; 1],
+            icmp_tx: [0; ping::PACKET_MAX],
+            syslog_rx_metadata: [UdpPacketMetadata::EMPTY; 1],
+            syslog_rx: [0; 0],
+            syslog_tx_metadata: [UdpPacketMetadata::EMPTY; 4],
+            syslog_tx: [0; syslog::MESSAGE_MAX * 4],
+            sntp_rx_metadata: [UdpPacketMetadata::EMPTY; 2],
+            sntp_rx: [0; sntp::PACKET_LEN * 2],
+            sntp_tx_metadata: [UdpPacketMetadata::EMPTY; 1],
+            sntp_tx: [0; sntp::PACKET_LEN],
+            http_rx: [0; http::RX_BUFFER_LEN],
+            http_tx: [0; http::TX_BUFFER_LEN],
+            http_response: [0; http::RESPONSE_MAX],
+            #[cfg(feature = "proto-ipv6")]
+            slaac_rx_metadata: [RawPacketMetadata::EMPTY; 4],
+            #[cfg(feature = "proto-ipv6")]
+            slaac_rx: [0; slaac::PACKET_MAX * 2],
+            #[cfg(feature = "proto-ipv6")]
+            slaac_tx_metadata: [RawPacketMetadata::EMPTY; 1],
+            #[cfg(feature = "proto-ipv6")]
+            slaac_tx: [0; slaac::PACKET_MAX],
+        }
+    }
+}
+
+impl<'a, const TCP_RX_LEN: usize, const TCP_TX_LEN: usize> Default for NetStorage<'a, TCP_RX_LEN, TCP_TX_LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What happened during one process_tcp() call
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TcpEvents {
+    /// At least one frame was put into eth_out queue, dispatcher must be run
+    pub frames_received: bool,
+    /// Data is left in the socket, because eth_out queue had no space for it
+    pub rx_stalled: bool,
+    /// Some space was freed in eth_in queue
+    pub tx_released: bool,
+    /// Connection was closed and the listener restarted, per connection state must be dropped
+    pub closed: bool,
+}
+
+pub struct Net<'a, D: for<'d> Device<'d>, C: Clock> {
+    iface: Interface<'a, D>,
+    clock: C,
+    tcp_handle: SocketHandle,
+    tcp_port: u16,
+    /// Put into envelopes, incremented on every listen()
+    tcp_connection: u16,
+    node_id: u8,
+    /// Applied to every new connection, so that the socket is freed if the peer vanishes
+    tcp_keep_alive: Option<Duration>,
+    tcp_timeout: Option<Duration>,
+    /// Link state last seen by link_changed()
+    link_up: bool,
+    /// Reassembles xPI frames split across or coalesced in TCP segments
+    tcp_rx_decoder: FrameDecoder<TCP_RX_FRAME_MAX>,
+    /// Decode whole frames in the socket buffer, see [Net::set_rx_in_place]
+    tcp_rx_in_place: bool,
+    mdns_handle: SocketHandle,
+    mdns: mdns::Responder,
+    icmp_handle: SocketHandle,
+    pinger: ping::Pinger,
+    syslog_handle: SocketHandle,
+    syslog: syslog::Sender,
+    sntp_handle: SocketHandle,
+    sntp: sntp::SntpClient,
+    http_handle: SocketHandle,
+    http: http::Server<'a>,
+    #[cfg(feature = "proto-ipv6")]
+    slaac_handle: SocketHandle,
+    #[cfg(feature = "proto-ipv6")]
+    slaac: slaac::Slaac,
+}
+
+impl<'a, D: for<'d> Device<'d>, C: Clock> Net<'a, D, C> {
+    pub fn new<const TCP_RX_LEN: usize, const TCP_TX_LEN: usize>(
+        store: &'a mut NetStorage<'a, TCP_RX_LEN, TCP_TX_LEN>,
+        device: D,
+        clock: C,
+        ethernet_addr: EthernetAddress,
+        config: &NetConfig,
+    ) -> Self {
+        // Set IP addresses
+        store.ip_addrs[IP_SLOT_IPV4] =
+            IpCidr::new(Ipv4Address(config.ipv4).into(), config.ipv4_prefix_len);
+        #[cfg(feature = "proto-ipv6")]
+        {
+            let link_local = IpCidr::new(slaac::link_local(&ethernet_addr).into(), 64);
+            store.ip_addrs[IP_SLOT_LINK_LOCAL] = link_local;
+            store.ip_addrs[IP_SLOT_STATIC_IPV6] = if config.ipv6 != [0; 16] {
+                IpCidr::new(Ipv6Address(config.ipv6).into(), config.ipv6_prefix_len)
+            } else {
+                link_local
+            };
+            // filled in when a router advertises a prefix
+            store.ip_addrs[IP_SLOT_SLAAC] = link_local;
+        }
+
+        let neighbor_cache =
+            NeighborCache::new(&mut store.neighbor_cache_storage[..]);
+        let routes = Routes::new(&mut store.routes_storage[..]);
+
+        let mut iface =
+            InterfaceBuilder::new(device, &mut store.socket_storage[..])
+                .hardware_addr(HardwareAddress::Ethernet(ethernet_addr))
+                .neighbor_cache(neighbor_cache)
+                .ip_addrs(&mut store.ip_addrs[..])
+                .routes(routes)
+                .ipv4_multicast_groups(&mut store.ipv4_multicast_storage[..])
+                .finalize();
+
+        let tcp_socket = TcpSocket::new(
+            TcpSocketBuffer::new(&mut store.tcp_rx[..]),
+            TcpSocketBuffer::new(&mut store.tcp_tx[..]),
+        );
+        let tcp_handle = iface.add_socket(tcp_socket);
+
+        let mdns_socket = UdpSocket::new(
+            UdpSocketBuffer::new(&mut store.mdns_rx_metadata[..], &mut store.mdns_rx[..]),
+            UdpSocketBuffer::new(&mut store.mdns_tx_metadata[..], &mut store.mdns_tx[..]),
+        );
+        let mdns_handle = iface.add_socket(mdns_socket);
+        // clock might not be running yet, IGMP report will be sent again on query anyway
+        let r = iface.join_multicast_group(mdns::MDNS_GROUP, Instant::from_millis(0));
+        debug!("join mDNS group: {:?}", r);
+        let mdns = mdns::Responder::new(config.node_id, config.tcp_port, config.schema_hash);
+
+        // echo requests are answered by the interface itself, the socket only sees replies to ours
+        let mut icmp_socket = IcmpSocket::new(
+            IcmpSocketBuffer::new(&mut store.icmp_rx_metadata[..], &mut store.icmp_rx[..]),
+            IcmpSocketBuffer::new(&mut store.icmp_tx_metadata[..], &mut store.icmp_tx[..]),
+        );
+        let r = icmp_socket.bind(IcmpEndpoint::Ident(ping::IDENT));
+        debug!("bind ICMP socket: {:?}", r);
+        let icmp_handle = iface.add_socket(icmp_socket);
+
+        // only sends, bound on first use
+        let syslog_socket = UdpSocket::new(
+            UdpSocketBuffer::new(&mut store.syslog_rx_metadata[..], &mut store.syslog_rx[..]),
+            UdpSocketBuffer::new(&mut store.syslog_tx_metadata[..], &mut store.syslog_tx[..]),
+        );
+        let syslog_handle = iface.add_socket(syslog_socket);
+        let syslog = syslog::Sender::new(config.syslog_ipv4, config.syslog_port, config.node_id);
+
+        let sntp_socket = UdpSocket::new(
+            UdpSocketBuffer::new(&mut store.sntp_rx_metadata[..], &mut store.sntp_rx[..]),
+            UdpSocketBuffer::new(&mut store.sntp_tx_metadata[..], &mut store.sntp_tx[..]),
+        );
+        let sntp_handle = iface.add_socket(sntp_socket);
+
+        // listens on first process_http()
+        let http_socket = TcpSocket::new(
+            TcpSocketBuffer::new(&mut store.http_rx[..]),
+            TcpSocketBuffer::new(&mut store.http_tx[..]),
+        );
+        let http_handle = iface.add_socket(http_socket);
+
+        #[cfg(feature = "proto-ipv6")]
+        let slaac_handle = {
+            let rx_buffer = RawSocketBuffer::new(&mut store.slaac_rx_metadata[..], &mut store.slaac_rx[..]);
+            let tx_buffer = RawSocketBuffer::new(&mut store.slaac_tx_metadata[..], &mut store.slaac_tx[..]);
+            iface.add_socket(RawSocket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, rx_buffer, tx_buffer))
+        };
+
+        Net {
+            iface,
+            clock,
+            tcp_handle,
+            tcp_port: config.tcp_port,
+            tcp_connection: 0,
+            node_id: config.node_id,
+            tcp_keep_alive: seconds(config.keepalive_s),
+            tcp_timeout: seconds(config.timeout_s),
+            link_up: false,
+            tcp_rx_decoder: FrameDecoder::new(),
+            tcp_rx_in_place: true,
+            mdns_handle,
+            mdns,
+            icmp_handle,
+            pinger: ping::Pinger::new(),
+            syslog_handle,
+            syslog,
+            sntp_handle,
+            sntp: sntp::SntpClient::new(config.sntp_ipv4),
+            http_handle,
+            http: http::Server::new(&mut store.http_response[..]),
+            #[cfg(feature = "proto-ipv6")]
+            slaac_handle,
+            #[cfg(feature = "proto-ipv6")]
+            slaac: slaac::Slaac::new(ethernet_addr),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Underlying interface, e.g. to add more sockets
+    pub fn iface(&mut self) -> &mut Interface<'a, D> {
+        &mut self.iface
+    }
+
+    pub fn is_link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// Polls on the ethernet interface.
+    pub fn poll(&mut self) -> bool {
+        self.iface
+            .poll(self.clock.now())
+            .unwrap_or_else(|e|  {
+                if e != smoltcp::Error::Unrecognized {
+                    warn!("Poll err: {:?}", e);
+                }
+                false
+            })
+    }
+
+    pub fn poll_at(&mut self) -> Option<Instant> {
+        #[cfg(feature = "proto-ipv6")]
+        let slaac_at = self.slaac.poll_at();
+        #[cfg(not(feature = "proto-ipv6"))]
+        let slaac_at = None;
+        [
+            self.iface.poll_at(self.clock.now()),
+            self.mdns.poll_at(),
+            self.pinger.poll_at(),
+            self.syslog.poll_at(),
+            self.sntp.poll_at(),
+            slaac_at,
+        ]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Drop the connection when the link goes down, the peer won't see our RST anyway,
+    /// so the listener is ready for a new one right away. Announce again when it comes back up.
+    /// Returns true if some space was freed in eth_in queue.
+    pub fn link_changed<const N: usize>(&mut self, up: bool, eth_in_cons: &mut bbqueue::Consumer<N>) -> bool {
+        self.link_up = up;
+        let now = self.clock.now();
+        if up {
+            info!("link up");
+            self.mdns.announce(now);
+            #[cfg(feature = "proto-ipv6")]
+            self.slaac.solicit(now);
+            false
+        } else {
+            info!("link down, closing connection");
+            let tcp_socket: &mut TcpSocket = self.iface.get_socket(self.tcp_handle);
+            tcp_socket.abort();
+            self.tcp_rx_decoder.reset();
+            // replies to the old connection must not go to the next one
+            let mut released = false;
+            while let Ok(rgr) = eth_in_cons.read() {
+                let len = rgr.len();
+                rgr.release(len);
+                released = true;
+            }
+            released
+        }
+    }
+
+    /// With false every received byte goes through the reassembly buffer, as before frames were
+    /// decoded in the socket buffer. Only there for ecbridge_host --bench to compare both.
+    pub fn set_rx_in_place(&mut self, in_place: bool) {
+        self.tcp_rx_in_place = in_place;
+    }
+
+    /// Move received frames into eth_out queue and replies from eth_in queue into the socket,
+    /// listen again once the connection is closed.
+    pub fn process_tcp<const N_OUT: usize, const N_IN: usize>(
+        &mut self,
+        eth_out_prod: &mut bbqueue::framed::FrameProducer<N_OUT>,
+        eth_in_cons: &mut bbqueue::Consumer<N_IN>,
+    ) -> TcpEvents {
+        let mut events = TcpEvents::default();
+        let now = self.clock.now();
+        let tcp_socket: &mut TcpSocket = self.iface.get_socket(self.tcp_handle);
+        // not only on new data, data left in the socket after a stall must be picked up as well
+        let (frames_received, rx_stalled) = handle_tcp_rx(
+            tcp_socket,
+            self.tcp_connection,
+            now,
+            &mut self.tcp_rx_decoder,
+            self.tcp_rx_in_place,
+            eth_out_prod,
+        );
+        events.frames_received = frames_received;
+        events.rx_stalled = rx_stalled;
+        events.tx_released = handle_tcp_tx(tcp_socket, eth_in_cons);
+        if tcp_socket.state() == smoltcp::socket::TcpState::CloseWait {
+            tcp_socket.close();
+        }
+        if !tcp_socket.is_open() {
+            // partial frame from the previous connection must not be glued to the next one
+            self.tcp_rx_decoder.reset();
+            let r = tcp_socket.listen(self.tcp_port);
+            info!("tcp_socket: listen(): {:?}", r);
+            self.tcp_connection = self.tcp_connection.wrapping_add(1);
+            // listen() resets them
+            tcp_socket.set_keep_alive(self.tcp_keep_alive);
+            tcp_socket.set_timeout(self.tcp_timeout);
+            events.closed = true;
+        }
+        events
+    }
+
+    /// Answer mDNS queries and send pending announcements.
+    pub fn process_mdns(&mut self) {
+        let ipv4 = self.iface.ip_addrs().iter().find_map(|cidr| match cidr.address() {
+            IpAddress::Ipv4(addr) => Some(addr),
+            _ => None,
+        });
+        let now = self.clock.now();
+        let mdns_socket: &mut UdpSocket = self.iface.get_socket(self.mdns_handle);
+        self.mdns.process(mdns_socket, now, ipv4);
+    }
+
+    /// Start pinging `target`, returns false if the previous self-test is still running.
+    pub fn start_ping(&mut self, target: IpAddress, count: u8) -> bool {
+        let now = self.clock.now();
+        self.pinger.start(target, count, now)
+    }
+
+    /// Send echo requests and collect replies, returns new stats if they changed.
+    pub fn process_ping(&mut self) -> Option<ping::PingStats> {
+        let now = self.clock.now();
+        #[cfg(feature = "proto-ipv6")]
+        let src = self.ipv6_source(self.pinger.target());
+        #[cfg(not(feature = "proto-ipv6"))]
+        let src = None;
+        let icmp_socket: &mut IcmpSocket = self.iface.get_socket(self.icmp_handle);
+        if self.pinger.process(icmp_socket, src, now) {
+            Some(self.pinger.stats())
+        } else {
+            None
+        }
+    }
+
+    /// Send queued log records to the syslog collector.
+    pub fn process_syslog<const N: usize>(&mut self, cons: &mut bbqueue::framed::FrameConsumer<N>) {
+        let now = self.clock.now();
+        let syslog_socket: &mut UdpSocket = self.iface.get_socket(self.syslog_handle);
+        self.syslog.process(syslog_socket, cons, now, self.sntp.status());
+    }
+
+    /// Sync with the SNTP server when it is time to, returns new status if a sync completed.
+    pub fn process_sntp(&mut self) -> Option<sntp::SntpStatus> {
+        let now = self.clock.now();
+        let sntp_socket: &mut UdpSocket = self.iface.get_socket(self.sntp_handle);
+        if self.sntp.process(sntp_socket, now) {
+            Some(self.sntp.status())
+        } else {
+            None
+        }
+    }
+
+    /// Current wall clock time in microseconds since Unix epoch, None until synced
+    pub fn unix_time_us(&self) -> Option<i64> {
+        self.sntp.status().unix_time_us(self.clock.now())
+    }
+
+    /// Serve the status page, `app` is only called when a response has to be rendered.
+    pub fn process_http<S: http::Status>(&mut self, app: impl FnOnce() -> S) {
+        let http_socket: &mut TcpSocket = self.iface.get_socket(self.http_handle);
+        if let Some(request) = self.http.receive(http_socket) {
+            let tcp_socket: &TcpSocket = self.iface.get_socket(self.tcp_handle);
+            let xpi_client = match tcp_socket.state() {
+                smoltcp::socket::TcpState::Established => Some(tcp_socket.remote_endpoint()),
+                _ => None,
+            };
+            // unused IPv6 slots hold copies of the link-local address
+            let mut ip_addrs = [IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)); IP_ADDRS];
+            let mut ip_addrs_len = 0;
+            for cidr in self.iface.ip_addrs() {
+                if !ip_addrs[..ip_addrs_len].contains(cidr) {
+                    ip_addrs[ip_addrs_len] = *cidr;
+                    ip_addrs_len += 1;
+                }
+            }
+            let now = self.clock.now();
+            let status = NetStatus {
+                node_id: self.node_id,
+                uptime_s: now.secs() as u64,
+                utc: self.sntp.status().unix_time_us(now).map(|us| us / 1000),
+                link_up: self.link_up,
+                ip_addrs: &ip_addrs[..ip_addrs_len],
+                xpi_port: self.tcp_port,
+                xpi_client,
+            };
+            self.http.respond(request, &status, &app());
+        }
+        let http_socket: &mut TcpSocket = self.iface.get_socket(self.http_handle);
+        self.http.send(http_socket);
+    }
+
+    /// Own address echo replies will come to: link-local for link-local targets,
+    /// autoconfigured or static one otherwise.
+    #[cfg(feature = "proto-ipv6")]
+    fn ipv6_source(&self, target: Option<IpAddress>) -> Option<IpAddress> {
+        let target = match target {
+            Some(IpAddress::Ipv6(target)) => target,
+            _ => return None,
+        };
+        let addrs = self.iface.ip_addrs();
+        let link_local = addrs[IP_SLOT_LINK_LOCAL];
+        let slot = if target.is_link_local() {
+            IP_SLOT_LINK_LOCAL
+        } else if addrs[IP_SLOT_SLAAC] != link_local {
+            IP_SLOT_SLAAC
+        } else {
+            IP_SLOT_STATIC_IPV6
+        };
+        Some(addrs[slot].address())
+    }
+
+    /// Pick up router advertisements and update autoconfigured address and default route.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn process_slaac(&mut self) {
+        let now = self.clock.now();
+        let raw_socket: &mut RawSocket = self.iface.get_socket(self.slaac_handle);
+        if !self.slaac.process(raw_socket, now) {
+            return;
+        }
+        let address = match self.slaac.address() {
+            Some(cidr) => IpCidr::Ipv6(cidr),
+            None => self.iface.ip_addrs()[IP_SLOT_LINK_LOCAL],
+        };
+        self.iface.update_ip_addrs(|addrs| addrs[IP_SLOT_SLAAC] = address);
+        let routes = self.iface.routes_mut();
+        match self.slaac.router() {
+            Some(router) => {
+                if let Err(e) = routes.add_default_ipv6_route(router) {
+                    warn!("add default route: {:?}", e);
+                }
+            }
+            None => {
+                routes.remove_default_ipv6_route();
+            }
+        }
+    }
+}
+
+/// Status page fields known to the network stack
+struct NetStatus<'s> {
+    node_id: u8,
+    uptime_s: u64,
+    utc: Option<i64>,
+    link_up: bool,
+    ip_addrs: &'s [IpCidr],
+    xpi_port: u16,
+    xpi_client: Option<IpEndpoint>,
+}
+
+impl<'s> http::Status for NetStatus<'s> {
+    fn fields(&self, f: &mut dyn FnMut(&str, http::Value)) {
+        use http::Value;
+        f("node_id", Value::U32(self.node_id as u32));
+        f("uptime_s", Value::U64(self.uptime_s));
+        f("utc", Value::UnixTime(self.utc));
+        f("link_up", Value::Bool(self.link_up));
+        f("ip_addrs", Value::Cidrs(self.ip_addrs));
+        f("xpi_port", Value::U32(self.xpi_port as u32));
+        f("xpi_client", Value::Endpoint(self.xpi_client));
+    }
+}
+
+fn seconds(s: u16) -> Option<Duration> {
+    match s {
+        0 => None,
+        s => Some(Duration::from_secs(s as u64)),
+    }
+}
+
+/// Decode frames from the socket into eth_out queue.
+///
+/// Bytes are only dequeued from the socket when there is space for the frame they complete, otherwise
+/// they are left there and the receive window shrinks, so that the remote stops sending.
+/// Returns whether any frames were enqueued and whether rx stalled.
+fn handle_tcp_rx<const N: usize>(
+    tcp_socket: &mut TcpSocket,
+    connection: u16,
+    now: Instant,
+    rx_decoder: &mut FrameDecoder<TCP_RX_FRAME_MAX>,
+    in_place: bool,
+    eth_out_prod: &mut bbqueue::framed::FrameProducer<N>
+) -> (bool, bool) {
+    if !tcp_socket.can_recv() {
+        return (false, false);
+    }
+    let endpoint: Endpoint = match tcp_socket.remote_endpoint().try_into() {
+        Ok(endpoint) => endpoint,
+        Err(_) => {
+            error!("wrong endpoint address");
+            return (false, false);
+        }
+    };
+    let envelope = Envelope { link: LinkId::Ethernet, connection, received_at: now, endpoint };
+    let mut stalled = false;
+    let mut received = false;
+    // recv() only gives the contiguous part of the ring buffer, second call picks up the rest after the wrap
+    for _ in 0..2 {
+        if stalled || !tcp_socket.can_recv() {
+            break;
+        }
+        let r = tcp_socket.recv(|buffer| {
+            let (consumed, frames_received, rx_stalled) = decode_tcp_rx(buffer, &envelope, rx_decoder, in_place, eth_out_prod);
+            received |= frames_received;
+            stalled |= rx_stalled;
+            // dequeue the amount returned
+            (consumed, ())
+        });
+        if let Err(e) = r {
+            warn!("tcp_socket: recv: {:?}", e);
+            break;
+        }
+    }
+    (received, stalled)
+}
+
+/// Decode frames from a chunk of the socket buffer, returns the amount of bytes consumed,
+/// whether any frames were enqueued and whether it stopped because eth_out queue is full.
+///
+/// Frames that are whole in the chunk are decoded in place, the socket buffer is dequeued anyway.
+/// Only the ones split across chunks, or all of them if not `in_place`, are reassembled byte by byte
+/// in the decoder.
+fn decode_tcp_rx<const N: usize>(
+    buffer: &mut [u8],
+    envelope: &Envelope,
+    rx_decoder: &mut FrameDecoder<TCP_RX_FRAME_MAX>,
+    in_place: bool,
+    eth_out_prod: &mut bbqueue::framed::FrameProducer<N>,
+) -> (usize, bool, bool) {
+    let mut pos = 0;
+    let mut received = false;
+    while pos < buffer.len() {
+        if in_place && rx_decoder.is_idle() {
+            if let Some(len) = buffer[pos..].iter().position(|b| *b == xpi_framing::DELIMITER) {
+                if len > TCP_RX_FRAME_MAX {
+                    warn!("dropping bad frame: {:?}", xpi_framing::Error::FrameTooLong);
+                } else if len > 0 {
+                    // decoded frame is always shorter than the encoded one
+                    let wgr = match eth_out_prod.grant(envelope.encoded_len() + len) {
+                        Ok(wgr) => wgr,
+                        Err(_) => return (pos, received, true),
+                    };
+                    match xpi_framing::decode_in_place(&mut buffer[pos..pos + len]) {
+                        Ok(frame) => {
+                            enqueue_frame(envelope, frame, wgr);
+                            received = true;
+                        }
+                        Err(e) => {
+                            warn!("dropping bad frame: {:?}", e);
+                        }
+                    }
+                }
+                pos += len + 1;
+                continue;
+            }
+        }
+        let b = buffer[pos];
+        if b == xpi_framing::DELIMITER && rx_decoder.pending_len() > 0 {
+            let wgr = match eth_out_prod.grant(envelope.encoded_len() + rx_decoder.pending_len()) {
+                Ok(wgr) => wgr,
+                Err(_) => return (pos, received, true),
+            };
+            match rx_decoder.feed(b) {
+                Some(Ok(frame)) => {
+                    enqueue_frame(envelope, frame, wgr);
+                    received = true;
+                }
+                Some(Err(e)) => {
+                    warn!("dropping bad frame: {:?}", e);
+                }
+                None => {}
+            }
+        } else if let Some(Err(e)) = rx_decoder.feed(b) {
+            warn!("dropping bad frame: {:?}", e);
+        }
+        pos += 1;
+    }
+    (pos, received, false)
+}
+
+/// Put one complete xPI frame into the queue behind the envelope, `wgr` has space for both.
+fn enqueue_frame<const N: usize>(envelope: &Envelope, frame: &[u8], mut wgr: bbqueue::framed::FrameGrantW<N>) {
+    match envelope.encode(frame, &mut wgr) {
+        Ok(len) => {
+            wgr.commit(len);
+            trace!("enqueued {}B frame from {:?}", frame.len(), envelope.endpoint);
+        }
+        Err(e) => error!("envelope: {:?}", e),
+    }
+}
+
+/// Returns true if some space was freed in eth_in queue.
+fn handle_tcp_tx<const N: usize>(tcp_socket: &mut TcpSocket, eth_in_cons: &mut bbqueue::Consumer<N>) -> bool {
+    if tcp_socket.can_send() {
+        if let Ok(rgr) = eth_in_cons.read() {
+            match tcp_socket.send_slice(&rgr) {
+                Ok(written) => {
+                    rgr.release(written);
+                    return written > 0;
+                }
+                Err(e) => {
+                    warn!("tcp_socket write err: {:?}", e);
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Formats into a fixed buffer, silently truncating what doesn't fit.
+/// Also used by the firmware to format log records into bbqueue grants.
+pub struct SliceWriter<'a> {
+    pub buf: &'a mut [u8],
+    /// Bytes written so far
+    pub pos: usize,
+}
+
+impl<'a> core::fmt::Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(self.buf.len() - self.pos);
+        self.buf[self.pos..self.pos + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.pos += len;
+        if len < s.len() {
+            Err(core::fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Minimal mDNS / DNS-SD responder (RFC 6762, RFC 6763) announcing the xPI TCP listener
+//! as `ecbridge-<node id>._xpi._tcp.local`, so that clients do not need to know the IP address.
+//!
+//! Only the records of this node are answered, no probing or conflict resolution is done.
+
+use smoltcp::socket::UdpSocket;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use log::{debug, trace, warn};
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address([224, 0, 0, 251]);
+
+const SERVICE: &[u8] = b"\x04_xpi\x04_tcp\x05local\x00";
+const SERVICES_META: &[u8] = b"\x09_services\x07_dns-sd\x04_udp\x05local\x00";
+const LOCAL: &[u8] = b"\x05local\x00";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+const QU_BIT: u16 = 0x8000;
+
+const TTL: u32 = 120;
+/// RFC 6762 8.3: at least two announcements, one second apart
+const ANNOUNCE_COUNT: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(1000);
+/// PHY auto negotiation is usually not done before that
+const STARTUP_DELAY: Duration = Duration::from_millis(3000);
+
+const RECORD_PTR: u8 = 1 << 0;
+const RECORD_SRV: u8 = 1 << 1;
+const RECORD_TXT: u8 = 1 << 2;
+const RECORD_A: u8 = 1 << 3;
+const RECORD_META: u8 = 1 << 4;
+
+pub const MESSAGE_MAX: usize = 512;
+
+/// Domain name in wire format (length prefixed labels, zero terminated)
+struct Name {
+    buf: [u8; 48],
+    len: usize,
+}
+
+impl Name {
+    fn new(label: &[u8], suffix: &[u8]) -> Self {
+        let mut buf = [0u8; 48];
+        buf[0] = label.len() as u8;
+        buf[1..1 + label.len()].copy_from_slice(label);
+        buf[1 + label.len()..1 + label.len() + suffix.len()].copy_from_slice(suffix);
+        Name { buf, len: 1 + label.len() + suffix.len() }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Records of this node
+struct Identity {
+    instance: Name,
+    host: Name,
+    txt: [u8; 64],
+    txt_len: usize,
+    port: u16,
+}
+
+pub struct Responder {
+    identity: Identity,
+    announcements_left: u8,
+    next_announcement: Instant,
+    rx_buf: [u8; MESSAGE_MAX],
+    tx_buf: [u8; MESSAGE_MAX],
+}
+
+impl Responder {
+    pub fn new(node_id: u8, port: u16, schema_hash: &str) -> Self {
+        // "ecbridge-" + up to 3 digits
+        let mut label = [0u8; 12];
+        label[..9].copy_from_slice(b"ecbridge-");
+        let label_len = 9 + write_decimal(node_id as u32, &mut label[9..]);
+        let label = &label[..label_len];
+
+        let mut txt = [0u8; 64];
+        let mut txt_len = 0;
+        let mut node_id_str = [0u8; 3];
+        let node_id_len = write_decimal(node_id as u32, &mut node_id_str);
+        for (key, value) in [(&b"node_id="[..], &node_id_str[..node_id_len]), (b"schema=", schema_hash.as_bytes())] {
+            txt[txt_len] = (key.len() + value.len()) as u8;
+            txt[txt_len + 1..txt_len + 1 + key.len()].copy_from_slice(key);
+            txt[txt_len + 1 + key.len()..txt_len + 1 + key.len() + value.len()].copy_from_slice(value);
+            txt_len += 1 + key.len() + value.len();
+        }
+
+        Responder {
+            identity: Identity {
+                instance: Name::new(label, SERVICE),
+                host: Name::new(label, LOCAL),
+                txt,
+                txt_len,
+                port,
+            },
+            announcements_left: ANNOUNCE_COUNT,
+            next_announcement: Instant::from_millis(0) + STARTUP_DELAY,
+            rx_buf: [0u8; MESSAGE_MAX],
+            tx_buf: [0u8; MESSAGE_MAX],
+        }
+    }
+
+    /// Start announcing again, e.g. after a link up or an address change.
+    pub fn announce(&mut self, now: Instant) {
+        self.announcements_left = ANNOUNCE_COUNT;
+        self.next_announcement = now;
+    }
+
+    /// When process() has to be called next for announcements.
+    pub fn poll_at(&self) -> Option<Instant> {
+        if self.announcements_left > 0 {
+            Some(self.next_announcement)
+        } else {
+            None
+        }
+    }
+
+    /// Answer queries received on the socket and send pending announcements.
+    pub fn process(&mut self, socket: &mut UdpSocket, now: Instant, ipv4: Option<Ipv4Address>) {
+        if !socket.is_open() {
+            if let Err(e) = socket.bind(MDNS_PORT) {
+                warn!("mdns: bind: {:?}", e);
+                return;
+            }
+        }
+        while socket.can_recv() {
+            let (len, source) = match socket.recv_slice(&mut self.rx_buf) {
+                Ok(r) => r,
+                Err(_) => break,
+            };
+            trace!("mdns: {}B from {}", len, source);
+            let query = &self.rx_buf[..len];
+            let (reply_len, unicast) = match self.identity.answer(query, source.port, ipv4, &mut self.tx_buf) {
+                Some(r) => r,
+                None => continue,
+            };
+            let destination = if unicast {
+                source
+            } else {
+                IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT)
+            };
+            if let Err(e) = socket.send_slice(&self.tx_buf[..reply_len], destination) {
+                warn!("mdns: send: {:?}", e);
+            }
+        }
+        if self.announcements_left > 0 && now >= self.next_announcement && socket.can_send() {
+            let mut wr = Writer::new(&mut self.tx_buf);
+            wr.header(0, 0);
+            let records = RECORD_PTR | RECORD_SRV | RECORD_TXT | RECORD_A;
+            match self.identity.write_records(&mut wr, records, ipv4, true) {
+                Some(len) => {
+                    let destination = IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT);
+                    if socket.send_slice(&self.tx_buf[..len], destination).is_ok() {
+                        debug!("mdns: announced");
+                        self.announcements_left -= 1;
+                        self.next_announcement = now + ANNOUNCE_INTERVAL;
+                    }
+                }
+                None => {
+                    warn!("mdns: announcement does not fit");
+                    self.announcements_left = 0;
+                }
+            }
+        }
+    }
+}
+
+impl Identity {
+    /// Parse a query and serialize a response into `tx_buf`.
+    /// Returns response length and whether it must be sent directly to the querier.
+    fn answer(
+        &self,
+        msg: &[u8],
+        source_port: u16,
+        ipv4: Option<Ipv4Address>,
+        tx_buf: &mut [u8],
+    ) -> Option<(usize, bool)> {
+        let len = msg.len();
+        if len < 12 {
+            return None;
+        }
+        let id = u16::from_be_bytes([msg[0], msg[1]]);
+        let flags = u16::from_be_bytes([msg[2], msg[3]]);
+        if flags & 0x8000 != 0 {
+            return None; // response from some other responder
+        }
+        let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+
+        let mut records = 0u8;
+        let mut unicast = false;
+        let mut pos = 12;
+        let mut name = [0u8; 128];
+        for _ in 0..qdcount {
+            let (name_len, next) = read_name(msg, pos, &mut name)?;
+            if next + 4 > len {
+                return None;
+            }
+            let qtype = u16::from_be_bytes([msg[next], msg[next + 1]]);
+            let qclass = u16::from_be_bytes([msg[next + 2], msg[next + 3]]);
+            pos = next + 4;
+            if qclass & !QU_BIT != CLASS_IN {
+                continue;
+            }
+            let name = &name[..name_len];
+            let any = qtype == TYPE_ANY;
+            let matched = if name_eq(name, SERVICE) && (qtype == TYPE_PTR || any) {
+                RECORD_PTR
+            } else if name_eq(name, SERVICES_META) && (qtype == TYPE_PTR || any) {
+                RECORD_META
+            } else if name_eq(name, self.instance.as_bytes()) {
+                match qtype {
+                    TYPE_SRV => RECORD_SRV,
+                    TYPE_TXT => RECORD_TXT,
+                    TYPE_ANY => RECORD_SRV | RECORD_TXT,
+                    _ => 0,
+                }
+            } else if name_eq(name, self.host.as_bytes()) && (qtype == TYPE_A || any) {
+                RECORD_A
+            } else {
+                0
+            };
+            if matched != 0 {
+                records |= matched;
+                unicast |= qclass & QU_BIT != 0;
+            }
+        }
+        if records == 0 {
+            return None;
+        }
+
+        let mut wr = Writer::new(tx_buf);
+        // RFC 6762 6.7: legacy unicast queries (not from port 5353) are answered directly,
+        // with the same id, repeated questions and without cache flush bits
+        let legacy = source_port != MDNS_PORT;
+        if legacy {
+            wr.header(id, qdcount);
+            // verbatim copy keeps compression pointers valid, questions start at the same offset
+            wr.bytes(&msg[12..pos])?;
+        } else {
+            wr.header(0, 0);
+        }
+        let len = self.write_records(&mut wr, records, ipv4, !legacy)?;
+        Some((len, unicast || legacy))
+    }
+
+    /// Write answers for `records`, and additional records that the querier will most likely
+    /// need next. Returns the message length.
+    fn write_records(
+        &self,
+        wr: &mut Writer,
+        records: u8,
+        ipv4: Option<Ipv4Address>,
+        cache_flush: bool,
+    ) -> Option<usize> {
+        let mut additional = 0u8;
+        if records & RECORD_PTR != 0 {
+            additional |= RECORD_SRV | RECORD_TXT | RECORD_A;
+        }
+        if records & RECORD_SRV != 0 {
+            additional |= RECORD_A;
+        }
+        additional &= !records;
+        let flush = if cache_flush { CACHE_FLUSH } else { 0 };
+
+        let mut answers = 0;
+        let mut additionals = 0;
+        for (set, count) in [(records, &mut answers), (additional, &mut additionals)] {
+            if set & RECORD_META != 0 {
+                wr.record(SERVICES_META, TYPE_PTR, CLASS_IN, TTL)?;
+                wr.rdata(|wr| wr.bytes(SERVICE))?;
+                *count += 1;
+            }
+            if set & RECORD_PTR != 0 {
+                wr.record(SERVICE, TYPE_PTR, CLASS_IN, TTL)?;
+                wr.rdata(|wr| wr.bytes(self.instance.as_bytes()))?;
+                *count += 1;
+            }
+            if set & RECORD_SRV != 0 {
+                wr.record(self.instance.as_bytes(), TYPE_SRV, CLASS_IN | flush, TTL)?;
+                wr.rdata(|wr| {
+                    wr.u16(0)?; // priority
+                    wr.u16(0)?; // weight
+                    wr.u16(self.port)?;
+                    wr.bytes(self.host.as_bytes())
+                })?;
+                *count += 1;
+            }
+            if set & RECORD_TXT != 0 {
+                wr.record(self.instance.as_bytes(), TYPE_TXT, CLASS_IN | flush, TTL)?;
+                wr.rdata(|wr| wr.bytes(&self.txt[..self.txt_len]))?;
+                *count += 1;
+            }
+            if set & RECORD_A != 0 {
+                if let Some(ipv4) = ipv4 {
+                    wr.record(self.host.as_bytes(), TYPE_A, CLASS_IN | flush, TTL)?;
+                    wr.rdata(|wr| wr.bytes(ipv4.as_bytes()))?;
+                    *count += 1;
+                }
+            }
+        }
+        wr.set_counts(answers, additionals);
+        Some(wr.pos)
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, pos: 0 }
+    }
+
+    fn header(&mut self, id: u16, qdcount: u16) {
+        self.buf[0..2].copy_from_slice(&id.to_be_bytes());
+        // QR = 1, AA = 1
+        self.buf[2..4].copy_from_slice(&0x8400u16.to_be_bytes());
+        self.buf[4..6].copy_from_slice(&qdcount.to_be_bytes());
+        self.buf[6..12].fill(0);
+        self.pos = 12;
+    }
+
+    fn set_counts(&mut self, ancount: u16, arcount: u16) {
+        self.buf[6..8].copy_from_slice(&ancount.to_be_bytes());
+        self.buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.pos + bytes.len();
+        if end > self.buf.len() {
+            return None;
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn record(&mut self, name: &[u8], rtype: u16, class: u16, ttl: u32) -> Option<()> {
+        self.bytes(name)?;
+        self.u16(rtype)?;
+        self.u16(class)?;
+        self.bytes(&ttl.to_be_bytes())
+    }
+
+    fn rdata<F: FnOnce(&mut Self) -> Option<()>>(&mut self, f: F) -> Option<()> {
+        let len_pos = self.pos;
+        self.u16(0)?;
+        f(self)?;
+        let rdlen = (self.pos - len_pos - 2) as u16;
+        self.buf[len_pos..len_pos + 2].copy_from_slice(&rdlen.to_be_bytes());
+        Some(())
+    }
+}
+
+/// Decompress name at `pos` into `out` in wire format.
+/// Returns name length and position right after the name in the message.
+fn read_name(msg: &[u8], mut pos: usize, out: &mut [u8]) -> Option<(usize, usize)> {
+    let mut out_len = 0;
+    let mut end = None;
+    // bounds amount of pointers followed, so that malicious loops terminate
+    for _ in 0..32 {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let offset = ((len & 0x3F) << 8) | *msg.get(pos + 1)? as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pos = offset;
+            continue;
+        }
+        if len > 63 || out_len + 1 + len > out.len() || pos + 1 + len > msg.len() {
+            return None;
+        }
+        out[out_len] = len as u8;
+        out[out_len + 1..out_len + 1 + len].copy_from_slice(&msg[pos + 1..pos + 1 + len]);
+        out_len += 1 + len;
+        pos += 1 + len;
+        if len == 0 {
+            return Some((out_len, end.unwrap_or(pos)));
+        }
+    }
+    None
+}
+
+fn name_eq(a: &[u8], b: &[u8]) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn write_decimal(mut value: u32, out: &mut [u8]) -> usize {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (value % 10) as u8;
+        len += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    for i in 0..len {
+        out[i] = digits[len - 1 - i];
+    }
+    len
+}
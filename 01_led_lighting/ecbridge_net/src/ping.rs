@@ -0,0 +1,242 @@
+//! ICMP echo requests for the network self-test.
+//!
+//! Sends `count` echo requests to the target one INTERVAL apart and collects round-trip times,
+//! replies arriving later than TIMEOUT after the last request are not counted.
+//! Send time is carried in the request data, so nothing has to be remembered per request.
+
+use log::{debug, info, warn};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::IcmpSocket;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, Icmpv4Message, Icmpv4Packet, Icmpv4Repr};
+#[cfg(feature = "proto-ipv6")]
+use smoltcp::wire::{Icmpv6Message, Icmpv6Packet, Icmpv6Repr};
+
+/// Identifier of all the echo requests sent, socket is bound to it
+pub const IDENT: u16 = 0x4543;
+/// Send timestamp in milliseconds
+const DATA_LEN: usize = 8;
+/// Largest echo request or reply handled, ICMPv4 and ICMPv6 echo headers are of the same size
+pub const PACKET_MAX: usize = 8 + DATA_LEN;
+pub const INTERVAL: Duration = Duration::from_millis(250);
+pub const TIMEOUT: Duration = Duration::from_millis(1000);
+/// Upper bound on requests per self-test, so that it doesn't block the next one for long
+pub const COUNT_MAX: u8 = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PingStats {
+    /// Requests are still being sent or replies awaited
+    pub running: bool,
+    pub sent: u8,
+    pub received: u8,
+    /// Round-trip times of the received replies, 0 if there were none
+    pub rtt_min_ms: u16,
+    pub rtt_max_ms: u16,
+    rtt_sum_ms: u32,
+}
+
+impl PingStats {
+    pub const fn new() -> Self {
+        PingStats {
+            running: false,
+            sent: 0,
+            received: 0,
+            rtt_min_ms: 0,
+            rtt_max_ms: 0,
+            rtt_sum_ms: 0,
+        }
+    }
+
+    pub fn rtt_avg_ms(&self) -> u16 {
+        match self.received {
+            0 => 0,
+            received => (self.rtt_sum_ms / received as u32) as u16,
+        }
+    }
+
+    fn add(&mut self, rtt_ms: u16) {
+        if self.received == 0 || rtt_ms < self.rtt_min_ms {
+            self.rtt_min_ms = rtt_ms;
+        }
+        if rtt_ms > self.rtt_max_ms {
+            self.rtt_max_ms = rtt_ms;
+        }
+        self.rtt_sum_ms += rtt_ms as u32;
+        self.received += 1;
+    }
+}
+
+impl Default for PingStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Pinger {
+    target: Option<IpAddress>,
+    count: u8,
+    stats: PingStats,
+    /// Next request or the end of the test
+    next_at: Option<Instant>,
+}
+
+impl Pinger {
+    pub const fn new() -> Self {
+        Pinger {
+            target: None,
+            count: 0,
+            stats: PingStats::new(),
+            next_at: None,
+        }
+    }
+
+    /// Start a new self-test, returns false if the previous one is still running.
+    pub fn start(&mut self, target: IpAddress, count: u8, now: Instant) -> bool {
+        if self.stats.running {
+            return false;
+        }
+        info!("ping {} x{}", target, count);
+        self.target = Some(target);
+        self.count = count.clamp(1, COUNT_MAX);
+        self.stats = PingStats {
+            running: true,
+            ..PingStats::new()
+        };
+        self.next_at = Some(now);
+        true
+    }
+
+    pub fn target(&self) -> Option<IpAddress> {
+        self.target
+    }
+
+    pub fn stats(&self) -> PingStats {
+        self.stats
+    }
+
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.next_at
+    }
+
+    /// Collect replies and send the next request when it is time to.
+    ///
+    /// `src` is the address replies will come to, only needed for the ICMPv6 checksum.
+    /// Returns true if the stats changed.
+    pub fn process(&mut self, socket: &mut IcmpSocket, src: Option<IpAddress>, now: Instant) -> bool {
+        let mut changed = false;
+        while let Ok((payload, from)) = socket.recv() {
+            if !self.stats.running || Some(from) != self.target {
+                continue;
+            }
+            if let Some(sent_at) = echo_reply_timestamp(from, payload) {
+                let rtt_ms = (now - sent_at).total_millis();
+                debug!("echo reply from {}: {}ms", from, rtt_ms);
+                self.stats.add(rtt_ms.min(u16::MAX as u64) as u16);
+                changed = true;
+            }
+        }
+
+        let (target, next_at) = match (self.target, self.next_at) {
+            (Some(target), Some(next_at)) => (target, next_at),
+            _ => return changed,
+        };
+        if self.stats.received >= self.count {
+            self.finish();
+            return true;
+        }
+        if now < next_at {
+            return changed;
+        }
+        if self.stats.sent >= self.count {
+            // last reply is overdue
+            self.finish();
+            return true;
+        }
+        if !socket.can_send() {
+            return changed;
+        }
+        let seq_no = self.stats.sent as u16;
+        if let Err(e) = send_echo_request(socket, target, src, seq_no, now) {
+            warn!("ping {}: {:?}", target, e);
+        }
+        // counted even if not sent, otherwise a bad target would keep the test running forever
+        self.stats.sent += 1;
+        self.next_at = Some(if self.stats.sent >= self.count {
+            now + TIMEOUT
+        } else {
+            now + INTERVAL
+        });
+        true
+    }
+
+    fn finish(&mut self) {
+        self.stats.running = false;
+        self.next_at = None;
+        info!("ping done: {:?}", self.stats);
+    }
+}
+
+impl Default for Pinger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn send_echo_request(
+    socket: &mut IcmpSocket,
+    target: IpAddress,
+    _src: Option<IpAddress>,
+    seq_no: u16,
+    now: Instant,
+) -> smoltcp::Result<()> {
+    let data = now.total_millis().to_be_bytes();
+    match target {
+        IpAddress::Ipv4(_) => {
+            let repr = Icmpv4Repr::EchoRequest {
+                ident: IDENT,
+                seq_no,
+                data: &data,
+            };
+            let buf = socket.send(repr.buffer_len(), target)?;
+            repr.emit(&mut Icmpv4Packet::new_unchecked(buf), &ChecksumCapabilities::default());
+            Ok(())
+        }
+        #[cfg(feature = "proto-ipv6")]
+        IpAddress::Ipv6(_) => {
+            let src = _src.ok_or(smoltcp::Error::Unaddressable)?;
+            let repr = Icmpv6Repr::EchoRequest {
+                ident: IDENT,
+                seq_no,
+                data: &data,
+            };
+            let buf = socket.send(repr.buffer_len(), target)?;
+            repr.emit(&src, &target, &mut Icmpv6Packet::new_unchecked(buf), &ChecksumCapabilities::default());
+            Ok(())
+        }
+        _ => Err(smoltcp::Error::Unaddressable),
+    }
+}
+
+/// Send time of our own echo request the reply is for, checksum is already verified by the interface.
+fn echo_reply_timestamp(from: IpAddress, payload: &[u8]) -> Option<Instant> {
+    let data = match from {
+        IpAddress::Ipv4(_) => {
+            let packet = Icmpv4Packet::new_checked(payload).ok()?;
+            if packet.msg_type() != Icmpv4Message::EchoReply || packet.echo_ident() != IDENT {
+                return None;
+            }
+            packet.data()
+        }
+        #[cfg(feature = "proto-ipv6")]
+        IpAddress::Ipv6(_) => {
+            let packet = Icmpv6Packet::new_checked(payload).ok()?;
+            if packet.msg_type() != Icmpv6Message::EchoReply || packet.echo_ident() != IDENT {
+                return None;
+            }
+            packet.payload()
+        }
+        _ => return None,
+    };
+    let timestamp: [u8; DATA_LEN] = data.get(..DATA_LEN)?.try_into().ok()?;
+    Some(Instant::from_millis(i64::from_be_bytes(timestamp)))
+}
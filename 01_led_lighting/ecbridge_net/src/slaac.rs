@@ -0,0 +1,225 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862).
+//!
+//! smoltcp answers neighbor solicitations on its own, but ignores router advertisements,
+//! so they are picked up from a raw ICMPv6 socket here. One global address is formed from the
+//! first autonomous /64 prefix advertised, together with a default route through the advertiser.
+//! Duplicate address detection is not performed, EUI-64 interface identifier is assumed unique.
+
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::RawSocket;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetAddress, Icmpv6Packet, Icmpv6Repr, IpAddress, IpProtocol, Ipv6Address, Ipv6Cidr,
+    Ipv6Packet, Ipv6Repr, NdiscPrefixInfoFlags, NdiscRepr,
+};
+use log::{debug, info, trace, warn};
+
+/// RFC 4861 10: MAX_RTR_SOLICITATIONS, RTR_SOLICITATION_INTERVAL
+const SOLICITATION_COUNT: u8 = 3;
+const SOLICITATION_INTERVAL: Duration = Duration::from_millis(4000);
+/// PHY auto negotiation is usually not done before that
+const STARTUP_DELAY: Duration = Duration::from_millis(3000);
+
+pub const PACKET_MAX: usize = 256;
+
+/// fe80::/64 address with the EUI-64 interface identifier derived from `mac`
+pub fn link_local(mac: &EthernetAddress) -> Ipv6Address {
+    with_interface_id(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0], mac)
+}
+
+fn with_interface_id(prefix: &[u8], mac: &EthernetAddress) -> Ipv6Address {
+    let mac = mac.as_bytes();
+    let mut addr = [0u8; 16];
+    addr[..8].copy_from_slice(&prefix[..8]);
+    addr[8..].copy_from_slice(&[mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]);
+    Ipv6Address(addr)
+}
+
+struct RouterAdvert {
+    router: Ipv6Address,
+    router_lifetime: Duration,
+    /// Autonomous /64 prefix and its valid lifetime
+    prefix: Option<(Ipv6Address, Duration)>,
+}
+
+pub struct Slaac {
+    mac: EthernetAddress,
+    solicitations_left: u8,
+    next_solicitation: Instant,
+    address: Option<(Ipv6Cidr, Instant)>,
+    router: Option<(Ipv6Address, Instant)>,
+    rx_buf: [u8; PACKET_MAX],
+}
+
+impl Slaac {
+    pub fn new(mac: EthernetAddress) -> Self {
+        Slaac {
+            mac,
+            solicitations_left: SOLICITATION_COUNT,
+            next_solicitation: Instant::from_millis(0) + STARTUP_DELAY,
+            address: None,
+            router: None,
+            rx_buf: [0u8; PACKET_MAX],
+        }
+    }
+
+    /// Autoconfigured address, if any router advertised a suitable prefix
+    pub fn address(&self) -> Option<Ipv6Cidr> {
+        self.address.map(|(cidr, _)| cidr)
+    }
+
+    /// Default router, if any
+    pub fn router(&self) -> Option<Ipv6Address> {
+        self.router.map(|(router, _)| router)
+    }
+
+    /// Solicit routers again, e.g. after a link up.
+    pub fn solicit(&mut self, now: Instant) {
+        self.solicitations_left = SOLICITATION_COUNT;
+        self.next_solicitation = now;
+    }
+
+    /// When process() has to be called next for solicitations or lifetimes expiration.
+    pub fn poll_at(&self) -> Option<Instant> {
+        let solicitation = if self.solicitations_left > 0 {
+            Some(self.next_solicitation)
+        } else {
+            None
+        };
+        [solicitation, self.address.map(|(_, t)| t), self.router.map(|(_, t)| t)]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Handle router advertisements received on the socket, expire old state and send
+    /// pending solicitations. Returns true if address or router have changed.
+    pub fn process(&mut self, socket: &mut RawSocket, now: Instant) -> bool {
+        let address_before = self.address();
+        let router_before = self.router();
+
+        while socket.can_recv() {
+            let len = match socket.recv_slice(&mut self.rx_buf) {
+                Ok(len) => len,
+                Err(_) => break,
+            };
+            if let Some(ra) = parse_router_advert(&self.rx_buf[..len]) {
+                self.update(ra, now);
+            }
+        }
+
+        if matches!(self.address, Some((_, valid_until)) if now >= valid_until) {
+            self.address = None;
+        }
+        if matches!(self.router, Some((_, valid_until)) if now >= valid_until) {
+            self.router = None;
+        }
+
+        if self.solicitations_left > 0 && now >= self.next_solicitation && socket.can_send() {
+            match self.send_solicitation(socket) {
+                Some(_) => {
+                    debug!("slaac: router solicitation sent");
+                    self.solicitations_left -= 1;
+                    self.next_solicitation = now + SOLICITATION_INTERVAL;
+                }
+                None => {
+                    warn!("slaac: router solicitation failed");
+                    self.solicitations_left = 0;
+                }
+            }
+        }
+
+        let changed = self.address() != address_before || self.router() != router_before;
+        if changed {
+            info!("slaac: address: {:?} router: {:?}", self.address(), self.router());
+        }
+        changed
+    }
+
+    fn update(&mut self, ra: RouterAdvert, now: Instant) {
+        trace!("slaac: RA from {}", ra.router);
+        // stop soliciting once any router answered
+        self.solicitations_left = 0;
+        if ra.router_lifetime == Duration::ZERO {
+            if self.router() == Some(ra.router) {
+                self.router = None;
+            }
+        } else {
+            self.router = Some((ra.router, now + ra.router_lifetime));
+        }
+        if let Some((prefix, valid_lifetime)) = ra.prefix {
+            let addr = with_interface_id(prefix.as_bytes(), &self.mac);
+            let cidr = Ipv6Cidr::new(addr, 64);
+            let same_prefix = self.address() == Some(cidr);
+            if valid_lifetime == Duration::ZERO {
+                if same_prefix {
+                    self.address = None;
+                }
+            } else if same_prefix || self.address.is_none() {
+                self.address = Some((cidr, now + valid_lifetime));
+            }
+        }
+    }
+
+    fn send_solicitation(&mut self, socket: &mut RawSocket) -> Option<()> {
+        let src_addr = link_local(&self.mac);
+        let dst_addr = Ipv6Address::LINK_LOCAL_ALL_ROUTERS;
+        let icmp_repr = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit {
+            lladdr: Some(self.mac.into()),
+        });
+        let ip_repr = Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: 255,
+        };
+        let buf = socket.send(ip_repr.buffer_len() + icmp_repr.buffer_len()).ok()?;
+        let mut ip_packet = Ipv6Packet::new_unchecked(buf);
+        ip_repr.emit(&mut ip_packet);
+        let mut icmp_packet = Icmpv6Packet::new_unchecked(ip_packet.payload_mut());
+        icmp_repr.emit(
+            &IpAddress::Ipv6(src_addr),
+            &IpAddress::Ipv6(dst_addr),
+            &mut icmp_packet,
+            &ChecksumCapabilities::default(),
+        );
+        Some(())
+    }
+}
+
+fn parse_router_advert(packet: &[u8]) -> Option<RouterAdvert> {
+    let ip_packet = Ipv6Packet::new_checked(packet).ok()?;
+    let ip_repr = Ipv6Repr::parse(&ip_packet).ok()?;
+    // RFC 4861 6.1.2: must come from a link-local address and not be forwarded
+    if !ip_repr.src_addr.is_link_local() || ip_repr.hop_limit != 255 {
+        return None;
+    }
+    let icmp_packet = Icmpv6Packet::new_checked(ip_packet.payload()).ok()?;
+    let icmp_repr = Icmpv6Repr::parse(
+        &IpAddress::Ipv6(ip_repr.src_addr),
+        &IpAddress::Ipv6(ip_repr.dst_addr),
+        &icmp_packet,
+        &ChecksumCapabilities::default(),
+    ).ok()?;
+    match icmp_repr {
+        Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert { router_lifetime, prefix_info, .. }) => {
+            let prefix = prefix_info.and_then(|info| {
+                let usable = info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+                    && info.prefix_len == 64
+                    && !info.prefix.is_link_local();
+                if usable {
+                    Some((info.prefix, info.valid_lifetime))
+                } else {
+                    None
+                }
+            });
+            Some(RouterAdvert {
+                router: ip_repr.src_addr,
+                router_lifetime,
+                prefix,
+            })
+        }
+        _ => None,
+    }
+}
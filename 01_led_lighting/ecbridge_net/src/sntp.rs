@@ -0,0 +1,226 @@
+//! SNTP client (RFC 4330), keeps the offset between the local uptime and UTC.
+//!
+//! Unix time is `uptime + offset`, so it keeps running between syncs and never jumps backwards
+//! by more than the correction of one sync.
+
+use log::{debug, info, warn};
+use smoltcp::socket::UdpSocket;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+/// Server port
+pub const PORT: u16 = 123;
+/// Requests are sent from it, any port is fine for SNTP clients
+pub const LOCAL_PORT: u16 = 49123;
+pub const PACKET_LEN: usize = 48;
+/// Time between syncs
+pub const POLL_INTERVAL: Duration = Duration::from_secs(64);
+/// Request is sent again if there is no reply in that time
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+/// Seconds between 1900 (NTP era 0) and 1970 (Unix epoch)
+const NTP_UNIX_OFFSET_S: i64 = 2_208_988_800;
+/// LI = 0, VN = 4, Mode = 3 (client)
+const CLIENT_HEADER: u8 = 0b00_100_011;
+const MODE_SERVER: u8 = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SntpStatus {
+    pub synced: bool,
+    /// Unix time in us is `uptime_us + offset_us`
+    pub offset_us: i64,
+    /// Change of the offset made by the last sync, 0 after the first one
+    pub correction_us: i64,
+    /// Round-trip delay of the last sync
+    pub delay_us: u32,
+    pub stratum: u8,
+    /// Local uptime of the last successful sync
+    pub synced_at: Instant,
+}
+
+impl SntpStatus {
+    pub const fn new() -> Self {
+        SntpStatus {
+            synced: false,
+            offset_us: 0,
+            correction_us: 0,
+            delay_us: 0,
+            stratum: 0,
+            synced_at: Instant::from_micros_const(0),
+        }
+    }
+
+    /// Microseconds since Unix epoch at local `now`, None if not yet synced
+    pub fn unix_time_us(&self, now: Instant) -> Option<i64> {
+        if self.synced {
+            Some(now.total_micros() + self.offset_us)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for SntpStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SntpClient {
+    server: Option<IpEndpoint>,
+    status: SntpStatus,
+    /// Local time put into the request transmit timestamp, echoed back by the server as originate
+    request_sent_at: Option<Instant>,
+    next_at: Option<Instant>,
+}
+
+impl SntpClient {
+    /// Doesn't sync if `server` is all zeroes
+    pub fn new(server: [u8; 4]) -> Self {
+        let server = if server != [0; 4] {
+            Some(IpEndpoint::new(IpAddress::Ipv4(Ipv4Address(server)), PORT))
+        } else {
+            None
+        };
+        SntpClient {
+            server,
+            status: SntpStatus::new(),
+            request_sent_at: None,
+            next_at: server.map(|_| Instant::from_millis(0)),
+        }
+    }
+
+    pub fn status(&self) -> SntpStatus {
+        self.status
+    }
+
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.next_at
+    }
+
+    /// Send a request when it is time to and handle the reply, returns true if the status changed.
+    pub fn process(&mut self, socket: &mut UdpSocket, now: Instant) -> bool {
+        let server = match self.server {
+            Some(server) => server,
+            None => return false,
+        };
+        if !socket.is_open() {
+            if let Err(e) = socket.bind(LOCAL_PORT) {
+                warn!("sntp: bind: {:?}", e);
+                return false;
+            }
+        }
+        let mut changed = false;
+        while let Ok((packet, from)) = socket.recv() {
+            if from != server {
+                continue;
+            }
+            if let (Some(sent_at), Ok(packet)) = (self.request_sent_at, <&[u8; PACKET_LEN]>::try_from(packet)) {
+                changed |= self.handle_reply(packet, sent_at, now);
+            }
+        }
+        match self.next_at {
+            Some(next_at) if now >= next_at => {}
+            _ => return changed,
+        }
+        if !socket.can_send() {
+            return changed;
+        }
+        let mut request = [0u8; PACKET_LEN];
+        request[0] = CLIENT_HEADER;
+        request[40..48].copy_from_slice(&ntp_timestamp(now.total_micros()).to_be_bytes());
+        match socket.send_slice(&request, server) {
+            Ok(()) => debug!("sntp: request to {}", server),
+            Err(e) => warn!("sntp: send: {:?}", e),
+        }
+        self.request_sent_at = Some(now);
+        self.next_at = Some(now + TIMEOUT);
+        changed
+    }
+
+    fn handle_reply(&mut self, packet: &[u8; PACKET_LEN], sent_at: Instant, now: Instant) -> bool {
+        let originate = u64::from_be_bytes(packet[24..32].try_into().unwrap());
+        if packet[0] & 0b111 != MODE_SERVER || originate != ntp_timestamp(sent_at.total_micros()) {
+            debug!("sntp: unexpected reply");
+            return false;
+        }
+        self.request_sent_at = None;
+        let stratum = packet[1];
+        if stratum == 0 {
+            // kiss-o'-death, try again later
+            warn!("sntp: kiss code {:?}", core::str::from_utf8(&packet[12..16]));
+            self.next_at = Some(now + POLL_INTERVAL);
+            return false;
+        }
+        let t1 = sent_at.total_micros();
+        let t2 = unix_us(u64::from_be_bytes(packet[32..40].try_into().unwrap()));
+        let t3 = unix_us(u64::from_be_bytes(packet[40..48].try_into().unwrap()));
+        let t4 = now.total_micros();
+        let offset_us = ((t2 - t1) + (t3 - t4)) / 2;
+        let delay_us = ((t4 - t1) - (t3 - t2)).max(0);
+        let previous = self.status;
+        let correction_us = if previous.synced { offset_us - previous.offset_us } else { 0 };
+        self.status = SntpStatus {
+            synced: true,
+            offset_us,
+            correction_us,
+            delay_us: delay_us.min(u32::MAX as i64) as u32,
+            stratum,
+            synced_at: now,
+        };
+        if previous.synced {
+            debug!("sntp: synced, correction {}us, delay {}us", correction_us, delay_us);
+        } else {
+            info!("sntp: synced, stratum {}, delay {}us", stratum, delay_us);
+        }
+        self.next_at = Some(now + POLL_INTERVAL);
+        true
+    }
+}
+
+/// Local time in the NTP timestamp format, only used to match replies to requests
+fn ntp_timestamp(us: i64) -> u64 {
+    let secs = (us / 1_000_000) as u64;
+    let frac = (((us % 1_000_000) as u64) << 32) / 1_000_000;
+    (secs << 32) | frac
+}
+
+/// NTP timestamp (seconds since 1900 and 2^-32 fraction) to microseconds since Unix epoch
+fn unix_us(ntp: u64) -> i64 {
+    let secs = (ntp >> 32) as i64 - NTP_UNIX_OFFSET_S;
+    let frac_us = ((ntp & 0xFFFF_FFFF) * 1_000_000) >> 32;
+    secs * 1_000_000 + frac_us as i64
+}
+
+/// Unix time in milliseconds as RFC 3339 `YYYY-MM-DDTHH:MM:SS.sssZ`
+pub fn write_rfc3339(wr: &mut impl core::fmt::Write, unix_ms: i64) -> core::fmt::Result {
+    let secs = unix_ms.div_euclid(1000);
+    let ms = unix_ms.rem_euclid(1000);
+    let days = secs.div_euclid(86_400);
+    let secs_of_day = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    write!(
+        wr,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        ms
+    )
+}
+
+/// Days since 1970-01-01 to a proleptic Gregorian date, Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,191 @@
+//! RFC 5424 syslog messages over UDP.
+//!
+//! Log records are put into a framed bbqueue as `severity, u64 be uptime in ms, text` by whoever
+//! logs, without blocking: if there is no space, the record is dropped. Sender takes them out from
+//! the network task and sends at most RATE messages per second, the rest waits in the queue.
+//! Uptime is turned into the wall clock time when sending, so records logged before the first
+//! SNTP sync are timestamped correctly as long as they are still in the queue by then.
+
+use core::fmt::{self, Write};
+use log::warn;
+use smoltcp::socket::UdpSocket;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use crate::sntp::{self, SntpStatus};
+use crate::SliceWriter;
+
+/// Local port messages are sent from
+pub const LOCAL_PORT: u16 = 514;
+/// Longest message sent, header included, longer text is truncated
+pub const MESSAGE_MAX: usize = 192;
+/// Longest record text kept in the queue
+pub const TEXT_MAX: usize = 160;
+/// Sustained messages per second
+const RATE: u32 = 20;
+/// Messages that can be sent at once after a quiet period
+const BURST: u32 = 20;
+/// Queued records are picked up at least that often, logging itself doesn't wake the network task
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+/// local0
+const FACILITY: u8 = 16;
+/// Severity and uptime in front of the text
+const RECORD_HEADER_LEN: usize = 1 + 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+impl From<log::Level> for Severity {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Severity::Error,
+            log::Level::Warn => Severity::Warning,
+            log::Level::Info => Severity::Info,
+            log::Level::Debug | log::Level::Trace => Severity::Debug,
+        }
+    }
+}
+
+impl Severity {
+    fn from_u8(severity: u8) -> Self {
+        match severity {
+            3 => Severity::Error,
+            4 => Severity::Warning,
+            6 => Severity::Info,
+            _ => Severity::Debug,
+        }
+    }
+}
+
+/// Format a record logged at `uptime_ms` into the queue, returns false if there was no space for it.
+pub fn enqueue<const N: usize>(
+    prod: &mut bbqueue::framed::FrameProducer<N>,
+    severity: Severity,
+    uptime_ms: u64,
+    args: fmt::Arguments,
+) -> bool {
+    let mut wgr = match prod.grant(RECORD_HEADER_LEN + TEXT_MAX) {
+        Ok(wgr) => wgr,
+        Err(_) => return false,
+    };
+    wgr[0] = severity as u8;
+    wgr[1..RECORD_HEADER_LEN].copy_from_slice(&uptime_ms.to_be_bytes());
+    let mut wr = SliceWriter { buf: &mut wgr[RECORD_HEADER_LEN..], pos: 0 };
+    // truncated on overflow
+    let _ = wr.write_fmt(args);
+    let len = wr.pos;
+    wgr.commit(RECORD_HEADER_LEN + len);
+    true
+}
+
+pub struct Sender {
+    collector: Option<IpEndpoint>,
+    node_id: u8,
+    tokens: u32,
+    refilled_at: Instant,
+    flush_at: Option<Instant>,
+}
+
+impl Sender {
+    /// Messages are not sent if `port` is 0
+    pub fn new(collector: [u8; 4], port: u16, node_id: u8) -> Self {
+        let collector = if port != 0 {
+            Some(IpEndpoint::new(IpAddress::Ipv4(Ipv4Address(collector)), port))
+        } else {
+            None
+        };
+        Sender {
+            collector,
+            node_id,
+            tokens: BURST,
+            refilled_at: Instant::from_millis(0),
+            flush_at: collector.map(|_| Instant::from_millis(0)),
+        }
+    }
+
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.flush_at
+    }
+
+    /// Send queued records as long as the rate allows and there is space in the socket.
+    pub fn process<const N: usize>(
+        &mut self,
+        socket: &mut UdpSocket,
+        cons: &mut bbqueue::framed::FrameConsumer<N>,
+        now: Instant,
+        time: SntpStatus,
+    ) {
+        let collector = match self.collector {
+            Some(collector) => collector,
+            None => {
+                // nowhere to send, don't let records pile up
+                while let Some(rgr) = cons.read() {
+                    rgr.release();
+                }
+                return;
+            }
+        };
+        if !socket.is_open() {
+            if let Err(e) = socket.bind(LOCAL_PORT) {
+                warn!("syslog: bind: {:?}", e);
+                return;
+            }
+        }
+        self.refill(now);
+        while self.tokens > 0 && socket.can_send() {
+            let rgr = match cons.read() {
+                Some(rgr) => rgr,
+                None => break,
+            };
+            if rgr.len() >= RECORD_HEADER_LEN {
+                let (header, text) = rgr.split_at(RECORD_HEADER_LEN);
+                let uptime_ms = u64::from_be_bytes(header[1..].try_into().unwrap());
+                let logged_at = Instant::from_millis(uptime_ms as i64);
+                let unix_ms = time.unix_time_us(logged_at).map(|us| us / 1000);
+                let mut message = [0u8; MESSAGE_MAX];
+                let len = self.format(&mut message, Severity::from_u8(header[0]), unix_ms, text);
+                if let Err(e) = socket.send_slice(&message[..len], collector) {
+                    warn!("syslog: send: {:?}", e);
+                }
+            }
+            rgr.release();
+            self.tokens -= 1;
+        }
+        self.flush_at = Some(if self.tokens == 0 {
+            // rest is sent when tokens are refilled
+            now + Duration::from_millis(1000 / RATE as u64)
+        } else {
+            now + FLUSH_INTERVAL
+        });
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed_ms = (now - self.refilled_at).total_millis();
+        let new_tokens = (elapsed_ms * RATE as u64 / 1000) as u32;
+        if new_tokens > 0 || self.tokens == BURST {
+            self.tokens = (self.tokens + new_tokens).min(BURST);
+            self.refilled_at = now;
+        }
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG`,
+    /// timestamp is NILVALUE until the wall clock is synced.
+    fn format(&self, message: &mut [u8], severity: Severity, unix_ms: Option<i64>, text: &[u8]) -> usize {
+        let mut wr = SliceWriter { buf: message, pos: 0 };
+        let _ = write!(wr, "<{}>1 ", FACILITY * 8 + severity as u8);
+        let _ = match unix_ms {
+            Some(unix_ms) => sntp::write_rfc3339(&mut wr, unix_ms),
+            None => wr.write_str("-"),
+        };
+        let _ = write!(wr, " ecbridge-{} ecbridge_fw - - - ", self.node_id);
+        let header_len = wr.pos;
+        let text_len = text.len().min(message.len() - header_len);
+        message[header_len..header_len + text_len].copy_from_slice(&text[..text_len]);
+        header_len + text_len
+    }
+}
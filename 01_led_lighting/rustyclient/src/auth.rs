@@ -0,0 +1,38 @@
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Must match ecbridge_fw auth::PSK_LEN
+pub const PSK_LEN: usize = 32;
+
+/// Environment variable holding the pre-shared key as 64 hex digits
+pub const PSK_ENV: &str = "XPI_PSK";
+
+/// Key from XPI_PSK environment variable, None if it is not set.
+pub fn psk_from_env() -> Result<Option<[u8; PSK_LEN]>> {
+    match std::env::var(PSK_ENV) {
+        Ok(hex) => parse_psk(&hex).map(Some),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).context(PSK_ENV),
+    }
+}
+
+fn parse_psk(hex: &str) -> Result<[u8; PSK_LEN]> {
+    let hex = hex.trim();
+    if !hex.is_ascii() || hex.len() != PSK_LEN * 2 {
+        return Err(anyhow!("{} must be {} hex digits, got {}", PSK_ENV, PSK_LEN * 2, hex.len()));
+    }
+    let mut psk = [0u8; PSK_LEN];
+    for (i, b) in psk.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .context(format!("{} is not a hex string", PSK_ENV))?;
+    }
+    Ok(psk)
+}
+
+/// Answer to the /auth/challenge nonce
+pub fn response(psk: &[u8; PSK_LEN], nonce: &[u8]) -> [u8; 32] {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(psk).expect("HMAC accepts keys of any length");
+    hmac.update(nonce);
+    hmac.finalize().into_bytes().into()
+}
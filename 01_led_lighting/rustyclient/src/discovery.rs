@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_xpi._tcp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+/// ECBridge (or any other xPI node) announcing itself as `_xpi._tcp` over mDNS
+#[derive(Debug, Clone)]
+pub struct DiscoveredBridge {
+    pub instance: String,
+    pub addr: SocketAddr,
+    pub node_id: Option<u32>,
+    pub schema_hash: Option<String>,
+}
+
+impl DiscoveredBridge {
+    /// Address in the form accepted on the command line
+    pub fn remote_addr(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+}
+
+#[derive(Default)]
+struct Instance {
+    port: Option<u16>,
+    host: Option<String>,
+    txt: HashMap<String, String>,
+    responder: Option<IpAddr>,
+}
+
+/// List xPI nodes on the local network, waiting `timeout` for the answers.
+///
+/// Query is sent from an ephemeral port, so responders answer directly to us (legacy unicast),
+/// no need to bind to 5353 which is most likely taken by the OS responder.
+pub async fn discover(timeout: Duration) -> Result<Vec<DiscoveredBridge>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(&ptr_query(SERVICE), (MDNS_GROUP, MDNS_PORT)).await?;
+
+    let mut instances: HashMap<String, Instance> = HashMap::new();
+    let mut hosts: HashMap<String, IpAddr> = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, from) = match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(r) => r?,
+            Err(_) => break,
+        };
+        trace!("mdns: {}B from {}", len, from);
+        if let Err(e) = parse_response(&buf[..len], from.ip(), &mut instances, &mut hosts) {
+            debug!("mdns: ignoring response from {}: {}", from, e);
+        }
+    }
+
+    let mut bridges = Vec::new();
+    for (name, instance) in instances {
+        let port = match instance.port {
+            Some(port) => port,
+            None => continue,
+        };
+        let ip = instance.host.as_ref()
+            .and_then(|host| hosts.get(host).cloned())
+            .or(instance.responder);
+        let ip = match ip {
+            Some(ip) => ip,
+            None => continue,
+        };
+        bridges.push(DiscoveredBridge {
+            instance: name,
+            addr: SocketAddr::new(ip, port),
+            node_id: instance.txt.get("node_id").and_then(|id| id.parse().ok()),
+            schema_hash: instance.txt.get("schema").cloned(),
+        });
+    }
+    bridges.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(bridges)
+}
+
+fn ptr_query(name: &str) -> Vec<u8> {
+    let mut msg = vec![0u8; 12];
+    msg[5] = 1; // qdcount
+    for label in name.split('.') {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    msg.extend_from_slice(&1u16.to_be_bytes()); // IN
+    msg
+}
+
+fn parse_response(
+    msg: &[u8],
+    responder: IpAddr,
+    instances: &mut HashMap<String, Instance>,
+    hosts: &mut HashMap<String, IpAddr>,
+) -> Result<()> {
+    if msg.len() < 12 {
+        return Err(anyhow!("too short"));
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let rrcount = u16::from_be_bytes([msg[6], msg[7]]) as usize
+        + u16::from_be_bytes([msg[8], msg[9]]) as usize
+        + u16::from_be_bytes([msg[10], msg[11]]) as usize;
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        let (_, next) = read_name(msg, pos)?;
+        pos = next + 4;
+    }
+    for _ in 0..rrcount {
+        let (name, next) = read_name(msg, pos)?;
+        let header = msg.get(next..next + 10).ok_or_else(|| anyhow!("truncated record"))?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata_pos = next + 10;
+        let rdata = msg.get(rdata_pos..rdata_pos + rdlen).ok_or_else(|| anyhow!("truncated rdata"))?;
+        pos = rdata_pos + rdlen;
+        match rtype {
+            TYPE_PTR if name.eq_ignore_ascii_case(SERVICE) => {
+                let (instance, _) = read_name(msg, rdata_pos)?;
+                instances.entry(instance).or_default().responder = Some(responder);
+            }
+            TYPE_SRV if rdlen > 6 => {
+                let instance = instances.entry(name).or_default();
+                instance.port = Some(u16::from_be_bytes([rdata[4], rdata[5]]));
+                instance.host = Some(read_name(msg, rdata_pos + 6)?.0);
+                instance.responder = Some(responder);
+            }
+            TYPE_TXT => {
+                let instance = instances.entry(name).or_default();
+                let mut txt = rdata;
+                while let Some((len, rest)) = txt.split_first() {
+                    let len = (*len as usize).min(rest.len());
+                    let entry = String::from_utf8_lossy(&rest[..len]);
+                    if let Some((key, value)) = entry.split_once('=') {
+                        instance.txt.insert(key.to_owned(), value.to_owned());
+                    }
+                    txt = &rest[len..];
+                }
+            }
+            TYPE_A if rdlen == 4 => {
+                hosts.insert(name, IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]]));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Read possibly compressed name at `pos`, returns dotted name and position right after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..64 {
+        let len = *msg.get(pos).ok_or_else(|| anyhow!("truncated name"))? as usize;
+        if len & 0xC0 == 0xC0 {
+            let low = *msg.get(pos + 1).ok_or_else(|| anyhow!("truncated pointer"))? as usize;
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3F) << 8) | low;
+            continue;
+        }
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(|| anyhow!("truncated label"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Err(anyhow!("name compression loop"))
+}
@@ -0,0 +1,142 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bytes::{Buf, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{debug, warn};
+use xpi_framing::FrameDecoder;
+
+/// Must be the same or bigger than what ECBridge can send in one frame
+pub const MAX_FRAME_LEN: usize = 1024;
+
+/// COBS + CRC framing of xwfd events over a byte stream, see xpi_framing.
+#[derive(Default)]
+pub struct XpiFrameCodec {
+    decoder: FrameDecoder<MAX_FRAME_LEN>,
+}
+
+impl XpiFrameCodec {
+    pub fn new() -> Self {
+        XpiFrameCodec {
+            decoder: FrameDecoder::new(),
+        }
+    }
+}
+
+impl Decoder for XpiFrameCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut consumed = 0;
+        let mut frame = None;
+        for b in src.iter() {
+            consumed += 1;
+            match self.decoder.feed(*b) {
+                Some(Ok(payload)) => {
+                    frame = Some(payload.to_vec());
+                    break;
+                }
+                Some(Err(e)) => {
+                    warn!("dropping bad frame: {:?}", e);
+                }
+                None => {}
+            }
+        }
+        src.advance(consumed);
+        Ok(frame)
+    }
+}
+
+impl Encoder<&[u8]> for XpiFrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, payload: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+        dst.resize(start + xpi_framing::max_encoded_len(payload.len()), 0);
+        let len = xpi_framing::encode(payload, &mut dst[start..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        dst.truncate(start + len);
+        Ok(())
+    }
+}
+
+/// Raw framed TCP connection to a node, carries serialized xwfd events.
+pub struct FramedLink {
+    framed: Framed<TcpStream, XpiFrameCodec>,
+}
+
+impl FramedLink {
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(FramedLink {
+            framed: Framed::new(stream, XpiFrameCodec::new()),
+        })
+    }
+
+    pub async fn send(&mut self, event: &[u8]) -> io::Result<()> {
+        self.framed.send(event).await
+    }
+
+    /// Returns None when the remote closed the connection.
+    pub async fn recv(&mut self) -> Option<io::Result<Vec<u8>>> {
+        self.framed.next().await
+    }
+}
+
+/// Connect to a bridge and return a loopback address for VhNode to connect to instead.
+///
+/// VhNode writes and reads unframed events over TCP, one event per write and per read. Every read
+/// from its connection is sent to the bridge as one frame and every frame from the bridge is
+/// written back to it as one event. Both connections are closed when either of them is.
+pub async fn spawn_proxy(remote: SocketAddr) -> io::Result<SocketAddr> {
+    let mut link = FramedLink::connect(remote).await?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        let mut node = match listener.accept().await {
+            Ok((node, _)) => node,
+            Err(e) => {
+                warn!("proxy: accept failed: {}", e);
+                return;
+            }
+        };
+        let _ = node.set_nodelay(true);
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        loop {
+            tokio::select! {
+                len = node.read(&mut buf) => match len {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if let Err(e) = link.send(&buf[..len]).await {
+                            warn!("proxy: send to {} failed: {}", remote, e);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("proxy: node connection: {}", e);
+                        break;
+                    }
+                },
+                event = link.recv() => match event {
+                    Some(Ok(event)) => {
+                        if node.write_all(&event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("proxy: {}: {}", remote, e);
+                        break;
+                    }
+                    None => break,
+                },
+            }
+        }
+        debug!("proxy to {} closed", remote);
+    });
+    Ok(local_addr)
+}
@@ -1,6 +1,10 @@
 #![allow(unused_imports)]
 // #![allow(unused_variables)]
 
+mod auth;
+mod discovery;
+mod framing;
+
 use std::collections::HashMap;
 use std::env;
 use std::net::{AddrParseError, SocketAddr};
@@ -11,7 +15,7 @@ use anyhow::{Context, Result};
 use futures::channel::mpsc;
 use futures::channel::mpsc::Receiver;
 use futures::{SinkExt, StreamExt};
-use tracing::{debug, info, Level, trace};
+use tracing::{debug, error, info, info_span, Level, trace, warn};
 use tracing_subscriber::FmtSubscriber;
 
 use vhl_cg::point::Point;
@@ -38,6 +42,7 @@ enum MyError {
     NibbleBufError(NibbleBufError),
     BufError(BufError),
     BitBufError(BitBufError),
+    XpiError(XpiError),
 }
 
 impl From<NibbleBufError> for MyError {
@@ -52,6 +57,12 @@ impl From<BufError> for MyError {
     }
 }
 
+impl From<XpiError> for MyError {
+    fn from(e: XpiError) -> Self {
+        MyError::XpiError(e)
+    }
+}
+
 impl From<BitBufError> for MyError {
     fn from(e: BitBufError) -> Self {
         MyError::BitBufError(e)
@@ -74,6 +85,72 @@ impl ECBridgeClient {
         self.node.connect_remote(addr, vec![NodeId(0)]).await
     }
 
+    /// Prove the knowledge of the pre-shared key, must be done first on every connection if
+    /// the bridge has one configured.
+    pub async fn authenticate(&mut self, psk: &[u8; auth::PSK_LEN]) -> Result<()> {
+        let nonce = self.call_raw(UriOwned::new(&[10, 0]), &[]).await
+            .context("/auth/challenge")?;
+        let mac = auth::response(psk, &nonce);
+        self.call_raw(UriOwned::new(&[10, 1]), &mac).await
+            .context("/auth/respond, wrong key?")?;
+        Ok(())
+    }
+
+    /// Call a method with bytes as arguments, returns result as bytes.
+    async fn call_raw(&mut self, uri: UriOwned, args: &[u8]) -> Result<Vec<u8>> {
+        let mut args_buf = Vec::new();
+        args_buf.resize(args.len(), 0);
+        let mut nwr = NibbleBufMut::new_all(&mut args_buf);
+        for b in args {
+            nwr.put(b)?;
+        }
+
+        let request_id = RequestId(4);
+        let dst_node_id = NodeId(0);
+        let ev = Event::new_with_default_ttl(
+            self.node.node_id(),
+            NodeSet::Unicast(dst_node_id),
+            ResourceSet::Uri(uri),
+            EventKind::Call {
+                args_set: vec![nwr.to_nibble_buf_owned()]
+            },
+            request_id,
+            Priority::Lossy(0)
+        );
+        self.node.submit_one(ev).await?;
+        let reply = self.node.filter_one(
+            EventFilter::new_with_timeout(Duration::from_millis(100))
+                .src(SourceFilter::NodeId(dst_node_id))
+                .dst(NodeSetFilter::NodeId(self.node.node_id()))
+                .kind(EventKindFilter::One(XpiEventDiscriminant::CallResults))
+                .request_id(request_id)
+        ).await?;
+        trace!("filter_one returned: {}", reply);
+        match reply.kind {
+            EventKind::CallResults(results) => {
+                if results.len() != 1 {
+                    return Err(NodeError::ExpectedDifferentAmountOf("CallComplete results".to_owned()).into());
+                }
+                match &results[0] {
+                    Ok(result) => {
+                        let mut nrd = result.to_nibble_buf_ref();
+                        let mut bytes = Vec::new();
+                        while nrd.nibbles_left() >= 2 {
+                            bytes.push(nrd.get_u8()?);
+                        }
+                        Ok(bytes)
+                    }
+                    Err(e) => {
+                        Err(e.clone().into())
+                    }
+                }
+            }
+            u => {
+                Err(NodeError::ExpectedReplyKind("CallComplete".to_owned(), format!("{:?}", u.discriminant())).into())
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub async fn call_sync(&mut self, p1: Point, p2: Point) -> Result<Point> {
         let mut args = Vec::new();
@@ -253,6 +330,107 @@ impl ECBridgeClient {
         Ok(rx)
     }
 
+    /// Bridge publishes /heartbeat every keepalive interval (5s by default), silence means it is gone.
+    pub async fn observe_heartbeat(&mut self) -> Result<Receiver<()>, NodeError> {
+        let request_id = RequestId(5);
+        let dst_node_id = NodeId(0);
+        let ev = Event::new_with_default_ttl(
+            self.node.node_id(),
+            NodeSet::Unicast(dst_node_id),
+            ResourceSet::Uri(UriOwned::new(&[11])),
+            EventKind::Subscribe {
+                rates: Vec::new()
+            },
+            request_id,
+            Priority::Lossy(0)
+        );
+        self.node.submit_one(ev).await?;
+        let mut updates = self.node.filter_many(
+            EventFilter::new()
+                .src(SourceFilter::NodeId(dst_node_id))
+                .dst(NodeSetFilter::NodeId(self.node.node_id()))
+                .kind(EventKindFilter::One(XpiEventDiscriminant::StreamUpdates))
+                .resource_set(ResourceSetFilter::ContainsUri(UriOwned::new(&[11])))
+                .drop_on_remote_disconnect(true)
+                .request_id(request_id)
+        ).await?;
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(event) = updates.next().await {
+                trace!("heartbeat: {:?}", event);
+                if tx.send(()).await.is_err() {
+                    break
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    /// Bridge publishes its log records as /log updates, long messages are split into several parts.
+    /// Records logged before subscribing are kept by the bridge and arrive first.
+    pub async fn observe_log(&mut self) -> Result<Receiver<LogRecord>, NodeError> {
+        let request_id = RequestId(6);
+        let dst_node_id = NodeId(0);
+        let ev = Event::new_with_default_ttl(
+            self.node.node_id(),
+            NodeSet::Unicast(dst_node_id),
+            ResourceSet::Uri(UriOwned::new(&[3])),
+            EventKind::Subscribe {
+                rates: Vec::new()
+            },
+            request_id,
+            Priority::Lossy(0)
+        );
+        self.node.submit_one(ev).await?;
+        let mut updates = self.node.filter_many(
+            EventFilter::new()
+                .src(SourceFilter::NodeId(dst_node_id))
+                .dst(NodeSetFilter::NodeId(self.node.node_id()))
+                .kind(EventKindFilter::One(XpiEventDiscriminant::StreamUpdates))
+                .resource_set(ResourceSetFilter::ContainsUri(UriOwned::new(&[3])))
+                .drop_on_remote_disconnect(true)
+                .request_id(request_id)
+        ).await?;
+        let (mut tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            let mut message = Vec::new();
+            while let Some(event) = updates.next().await {
+                let values = match event.kind {
+                    EventKind::StreamUpdates(values) => values,
+                    _ => continue,
+                };
+                for value in values {
+                    let part = match value.map_err(MyError::from).and_then(|value| {
+                        LogPart::des(&mut value.to_nibble_buf_ref())
+                    }) {
+                        Ok(part) => part,
+                        Err(e) => {
+                            warn!("bad /log update: {:?}", e);
+                            message.clear();
+                            continue;
+                        }
+                    };
+                    message.extend_from_slice(&part.message);
+                    if part.continued {
+                        continue;
+                    }
+                    let record = LogRecord {
+                        node_id: event.source,
+                        level: part.level,
+                        uptime_ms: part.uptime_ms,
+                        module: part.module,
+                        message: String::from_utf8_lossy(&message).into_owned(),
+                    };
+                    message.clear();
+                    if tx.send(record).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+
     #[allow(dead_code)]
     pub async fn write_digit(&mut self, digit: u8) -> Result<()> {
         let mut args = Vec::new();
@@ -346,6 +524,74 @@ impl ECBridgeClient {
     }
 }
 
+/// Record from /log of a bridge, reassembled from its parts
+#[derive(Debug)]
+pub struct LogRecord {
+    pub node_id: NodeId,
+    pub level: Level,
+    pub uptime_ms: u64,
+    pub module: String,
+    pub message: String,
+}
+
+impl LogRecord {
+    /// Print through tracing, inside a span with the id of the node it came from
+    pub fn print(&self) {
+        let span = info_span!("node", id = self.node_id.0);
+        let _enter = span.enter();
+        let module = self.module.as_str();
+        let uptime_s = self.uptime_ms as f64 / 1000.0;
+        match self.level {
+            Level::ERROR => error!(module, uptime_s, "{}", self.message),
+            Level::WARN => warn!(module, uptime_s, "{}", self.message),
+            Level::INFO => info!(module, uptime_s, "{}", self.message),
+            Level::DEBUG => debug!(module, uptime_s, "{}", self.message),
+            _ => trace!(module, uptime_s, "{}", self.message),
+        }
+    }
+}
+
+/// Set in the level byte of all the parts of a message except the last one
+const LOG_CONTINUED: u8 = 0x80;
+
+/// One /log update: level, uptime_ms, module length and module, part length and message part
+struct LogPart {
+    level: Level,
+    continued: bool,
+    uptime_ms: u64,
+    module: String,
+    message: Vec<u8>,
+}
+
+impl LogPart {
+    fn des(nrd: &mut NibbleBuf) -> Result<Self, MyError> {
+        let level = nrd.get_u8()?;
+        let continued = level & LOG_CONTINUED != 0;
+        let level = match level & !LOG_CONTINUED {
+            1 => Level::ERROR,
+            2 => Level::WARN,
+            3 => Level::INFO,
+            4 => Level::DEBUG,
+            _ => Level::TRACE,
+        };
+        let uptime_ms = ((nrd.get_u32_be()? as u64) << 32) | nrd.get_u32_be()? as u64;
+        let module_len = nrd.get_u8()?;
+        let module = (0..module_len).map(|_| nrd.get_u8()).collect::<Result<Vec<u8>, _>>()?;
+        let message_len = nrd.get_u8()?;
+        let message = (0..message_len).map(|_| nrd.get_u8()).collect::<Result<Vec<u8>, _>>()?;
+        Ok(LogPart {
+            level,
+            continued,
+            uptime_ms,
+            module: String::from_utf8_lossy(&module).into_owned(),
+            message,
+        })
+    }
+}
+
+/// Several missed heartbeats with the default 5s interval
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let subscriber = FmtSubscriber::builder()
@@ -355,9 +601,24 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
     // let addr = "tcp://192.168.0.199:7777";
-    let addr = "tcp://127.0.0.1:7777";
-    let addr = RemoteNodeAddr::parse(addr)
-        .context(format!("unable to parse socket address: '{}'", addr))?;
+    let addr = match env::args().nth(1) {
+        Some(addr) => addr,
+        None => {
+            let bridges = discovery::discover(Duration::from_secs(1)).await?;
+            for bridge in &bridges {
+                info!("discovered: {:?}", bridge);
+            }
+            bridges.first()
+                .map(|bridge| bridge.remote_addr())
+                .context("no xPI nodes found on the local network, pass address explicitly: tcp://ip:port or tcp://[ipv6]:port")?
+        }
+    };
+    let addr = parse_tcp_addr(&addr)?;
+    // bridge only understands framed events, VhNode is connected to it through a local proxy
+    let proxy_addr = framing::spawn_proxy(addr).await
+        .context(format!("unable to connect to {}", addr))?;
+    let proxy_addr = RemoteNodeAddr::parse(&format!("tcp://{}", proxy_addr))
+        .context(format!("unable to parse proxy address: '{}'", proxy_addr))?;
 
     // // Establish connection to another node with statically generated xPI
     // // SemVer compatibility checks must pass before any requests can be sent
@@ -370,12 +631,36 @@ async fn main() -> Result<()> {
     // let smth = local10.filter_one( () ).await;
     // println!("filter one: {:?}", smth);
 
-    ecbridge_client.connect_remote(addr).await?;
+    ecbridge_client.connect_remote(proxy_addr).await?;
     tokio::time::sleep(Duration::from_millis(50)).await;
 
+    if let Some(psk) = auth::psk_from_env()? {
+        ecbridge_client.authenticate(&psk).await?;
+        info!("authenticated");
+    }
+
     let mut updates = ecbridge_client.observe_one().await?;
-    while let Some(value) = updates.next().await {
-        info!("new value: {value}");
+    let mut heartbeats = ecbridge_client.observe_heartbeat().await?;
+    let mut logs = ecbridge_client.observe_log().await?;
+    loop {
+        tokio::select! {
+            record = logs.next() => match record {
+                Some(record) => record.print(),
+                None => break,
+            },
+            value = updates.next() => match value {
+                Some(value) => info!("new value: {value}"),
+                None => break,
+            },
+            heartbeat = tokio::time::timeout(HEARTBEAT_TIMEOUT, heartbeats.next()) => match heartbeat {
+                Ok(Some(())) => {}
+                Ok(None) => break,
+                Err(_) => {
+                    warn!("no heartbeat for {:?}, bridge is gone", HEARTBEAT_TIMEOUT);
+                    break;
+                }
+            },
+        }
     }
 
     // debug!("call_sync_unit: {:?}", ecbridge_client.call1_unit(UriOwned::new(&[0, 11, 2, 0])).await?);
@@ -440,3 +725,18 @@ async fn main() -> Result<()> {
     tokio::time::sleep(Duration::from_secs(2)).await;
     Ok(())
 }
+
+/// Parse `tcp://` addresses with std parser, so that IPv6 ones are accepted in the usual
+/// `tcp://[2001:db8::1]:7777` form, with an optional scope for link-local ones: `[fe80::1%2]:7777`.
+/// Scheme can be omitted, bridges are only reachable over TCP.
+fn parse_tcp_addr(addr: &str) -> Result<SocketAddr> {
+    let socket_addr = match addr.strip_prefix("tcp://") {
+        Some(socket_addr) => socket_addr,
+        None if !addr.contains("://") => addr,
+        None => anyhow::bail!("unsupported address: '{}', expected tcp://ip:port", addr),
+    };
+    socket_addr.parse().context(format!(
+        "unable to parse socket address: '{}', expected ip:port or [ipv6]:port",
+        addr
+    ))
+}
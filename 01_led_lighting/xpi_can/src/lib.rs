@@ -0,0 +1,735 @@
+#![no_std]
+
+//! xPI events over CAN as UAVCAN v1 (Cyphal/CAN) style transfers.
+//!
+//! A transfer is one serialized xPI event. It is sent in a single frame if it fits together with
+//! the tail byte, otherwise it is split into several frames and followed by CRC-16/CCITT-FALSE of
+//! the payload (big endian). The last byte of every frame is the tail byte:
+//! `start of transfer | end of transfer | toggle | 5 bit transfer id`, toggle is 1 in the first
+//! frame and alternates, so that lost or repeated frames are detected.
+//! CAN FD frames are padded with zeroes up to the next valid length, in multi-frame transfers the
+//! padding goes before the CRC and is covered by it.
+//!
+//! Extended CAN id layout follows Cyphal/CAN: priority, message or service, anonymous flag,
+//! subject id or service id with destination node id, source node id.
+//!
+//! Node ids are given out by the bridge with UAVCAN v1 plug-and-play allocation, see
+//! [AllocationData].
+//!
+//! Nodes without a debug probe attached publish their logs on separate subjects with a lower
+//! priority, see [log_header].
+//!
+//! Shared between ecbridge_fw and ecbridge_host, so must stay no_std and allocation free.
+
+/// Classic CAN frame payload
+pub const MTU_CLASSIC: usize = 8;
+/// CAN FD frame payload
+pub const MTU_FD: usize = 64;
+pub const CRC_LEN: usize = 2;
+pub const NODE_ID_MAX: u8 = 127;
+pub const SUBJECT_ID_MAX: u16 = 8191;
+pub const SERVICE_ID_MAX: u16 = 511;
+/// xPI events are published on this subject, destination node is inside the event
+pub const XPI_SUBJECT_ID: u16 = 1000;
+/// Partially received transfer is dropped if the next frame doesn't arrive in that time
+pub const TRANSFER_TIMEOUT_US: u64 = 1_000_000;
+pub const TRANSFER_ID_MODULO: u8 = 32;
+/// uavcan.pnp.NodeIDAllocationData.1.0
+pub const PNP_SUBJECT_ID: u16 = 8166;
+/// 48 bit unique id hash, array length, u16 node id
+pub const ALLOCATION_DATA_LEN_MAX: usize = 6 + 1 + 2;
+/// Text log lines
+pub const LOG_TEXT_SUBJECT_ID: u16 = 1001;
+/// defmt frames, decoded on the host with the firmware ELF
+pub const LOG_DEFMT_SUBJECT_ID: u16 = 1002;
+/// Logs must not delay xPI events, which are sent with priority 4
+pub const LOG_PRIORITY: u8 = 6;
+/// Level, u16 body length
+pub const LOG_HEADER_LEN: usize = 3;
+
+const TAIL_START: u8 = 0x80;
+const TAIL_END: u8 = 0x40;
+const TAIL_TOGGLE: u8 = 0x20;
+const TAIL_TRANSFER_ID: u8 = 0x1F;
+
+const ID_SERVICE: u32 = 1 << 25;
+const ID_ANONYMOUS: u32 = 1 << 24;
+const ID_REQUEST: u32 = 1 << 24;
+const ID_RESERVED_23: u32 = 1 << 23;
+/// Set in messages for compatibility with UAVCAN v0
+const ID_MESSAGE_COMPAT: u32 = 0b11 << 21;
+const ID_RESERVED_7: u32 = 1 << 7;
+const ID_PRIORITY: u32 = 0b111 << 26;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// Extended CAN id doesn't follow the layout
+    BadId,
+    /// Frame without a tail byte
+    Empty,
+    /// Frame is not the expected continuation of the transfer, e.g. it is lost or repeated
+    ToggleMismatch,
+    /// Continuation frame without a start frame
+    MissedStart,
+    /// Transfer did not fit into the reassembly buffer
+    TooLong,
+    CrcMismatch,
+    /// All the reassembly sessions are busy with other transfers
+    NoSession,
+    /// Anonymous transfers can only be single frame
+    AnonymousMultiFrame,
+    /// Transfer payload is not a valid message
+    Malformed,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Kind {
+    Message { subject_id: u16 },
+    Request { service_id: u16, destination: u8 },
+    Response { service_id: u16, destination: u8 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CanId {
+    /// 0 is the highest, 7 the lowest
+    pub priority: u8,
+    pub kind: Kind,
+    /// Node without an id yet, source is a pseudo id then, only single frame messages are allowed
+    pub anonymous: bool,
+    pub source: u8,
+}
+
+impl CanId {
+    pub fn message(priority: u8, subject_id: u16, source: u8) -> Self {
+        CanId {
+            priority,
+            kind: Kind::Message { subject_id },
+            anonymous: false,
+            source,
+        }
+    }
+
+    /// 29 bit extended id, out of range fields are truncated
+    pub fn to_raw(&self) -> u32 {
+        let mut raw = ((self.priority as u32) & 0b111) << 26 | (self.source & NODE_ID_MAX) as u32;
+        match self.kind {
+            Kind::Message { subject_id } => {
+                raw |= ID_MESSAGE_COMPAT | ((subject_id & SUBJECT_ID_MAX) as u32) << 8;
+                if self.anonymous {
+                    raw |= ID_ANONYMOUS;
+                }
+            }
+            Kind::Request { service_id, destination } | Kind::Response { service_id, destination } => {
+                raw |= ID_SERVICE
+                    | ((service_id & SERVICE_ID_MAX) as u32) << 14
+                    | ((destination & NODE_ID_MAX) as u32) << 7;
+                if matches!(self.kind, Kind::Request { .. }) {
+                    raw |= ID_REQUEST;
+                }
+            }
+        }
+        raw
+    }
+
+    pub fn from_raw(raw: u32) -> Result<Self, Error> {
+        if raw > 0x1FFF_FFFF || raw & ID_RESERVED_23 != 0 {
+            return Err(Error::BadId);
+        }
+        let priority = ((raw & ID_PRIORITY) >> 26) as u8;
+        let source = (raw & NODE_ID_MAX as u32) as u8;
+        if raw & ID_SERVICE == 0 {
+            if raw & ID_RESERVED_7 != 0 {
+                return Err(Error::BadId);
+            }
+            Ok(CanId {
+                priority,
+                kind: Kind::Message { subject_id: ((raw >> 8) as u16) & SUBJECT_ID_MAX },
+                anonymous: raw & ID_ANONYMOUS != 0,
+                source,
+            })
+        } else {
+            let service_id = ((raw >> 14) as u16) & SERVICE_ID_MAX;
+            let destination = ((raw >> 7) as u8) & NODE_ID_MAX;
+            let kind = if raw & ID_REQUEST != 0 {
+                Kind::Request { service_id, destination }
+            } else {
+                Kind::Response { service_id, destination }
+            };
+            Ok(CanId { priority, kind, anonymous: false, source })
+        }
+    }
+}
+
+/// Smallest CAN FD frame length that can hold `len` bytes, the same length for classic CAN
+pub fn frame_len(len: usize) -> usize {
+    match len {
+        0..=8 => len,
+        9..=12 => 12,
+        13..=16 => 16,
+        17..=20 => 20,
+        21..=24 => 24,
+        25..=32 => 32,
+        33..=48 => 48,
+        _ => 64,
+    }
+}
+
+/// CRC-16/CCITT-FALSE, computed frame by frame
+#[derive(Copy, Clone)]
+struct Crc16(u16);
+
+impl Crc16 {
+    const fn new() -> Self {
+        Crc16(0xFFFF)
+    }
+
+    fn add(&mut self, data: &[u8]) {
+        for b in data {
+            self.0 ^= (*b as u16) << 8;
+            for _ in 0..8 {
+                if self.0 & 0x8000 != 0 {
+                    self.0 = (self.0 << 1) ^ 0x1021;
+                } else {
+                    self.0 <<= 1;
+                }
+            }
+        }
+    }
+}
+
+/// Splits one transfer into frames.
+pub struct Segmenter<'a> {
+    payload: &'a [u8],
+    transfer_id: u8,
+    mtu: usize,
+    /// Zeroes after the payload, so that the last frame has a valid CAN FD length
+    padding: usize,
+    /// Big endian, multi-frame transfers only
+    crc: Option<[u8; CRC_LEN]>,
+    /// Payload, padding and CRC
+    stream_len: usize,
+    pos: usize,
+    toggle: bool,
+    done: bool,
+}
+
+impl<'a> Segmenter<'a> {
+    /// `mtu` is MTU_CLASSIC or MTU_FD, transfer id is taken modulo 32.
+    pub fn new(payload: &'a [u8], transfer_id: u8, mtu: usize) -> Self {
+        let chunk = mtu - 1;
+        let (padding, crc) = if payload.len() <= chunk {
+            (frame_len(payload.len() + 1) - (payload.len() + 1), None)
+        } else {
+            let len = payload.len() + CRC_LEN;
+            let last = len - chunk * ((len - 1) / chunk);
+            let padding = frame_len(last + 1) - (last + 1);
+            let mut crc = Crc16::new();
+            crc.add(payload);
+            crc.add(&[0; MTU_FD][..padding]);
+            (padding, Some(crc.0.to_be_bytes()))
+        };
+        let stream_len = payload.len() + padding + if crc.is_some() { CRC_LEN } else { 0 };
+        Segmenter {
+            payload,
+            transfer_id: transfer_id % TRANSFER_ID_MODULO,
+            mtu,
+            padding,
+            crc,
+            stream_len,
+            pos: 0,
+            toggle: true,
+            done: false,
+        }
+    }
+
+    /// Write the next frame into `frame`, which must hold `mtu` bytes.
+    /// Returns the frame length or None when all the frames were produced.
+    pub fn next_frame(&mut self, frame: &mut [u8]) -> Option<usize> {
+        if self.done {
+            return None;
+        }
+        let len = (self.mtu - 1).min(self.stream_len - self.pos);
+        for (i, b) in frame[..len].iter_mut().enumerate() {
+            *b = self.stream_byte(self.pos + i);
+        }
+        let start = self.pos == 0;
+        self.pos += len;
+        self.done = self.pos == self.stream_len;
+        let mut tail = self.transfer_id;
+        if start {
+            tail |= TAIL_START;
+        }
+        if self.done {
+            tail |= TAIL_END;
+        }
+        if self.toggle {
+            tail |= TAIL_TOGGLE;
+        }
+        self.toggle = !self.toggle;
+        frame[len] = tail;
+        Some(len + 1)
+    }
+
+    fn stream_byte(&self, pos: usize) -> u8 {
+        let payload_len = self.payload.len();
+        if pos < payload_len {
+            self.payload[pos]
+        } else if pos < payload_len + self.padding {
+            0
+        } else {
+            self.crc.map(|crc| crc[pos - payload_len - self.padding]).unwrap_or(0)
+        }
+    }
+}
+
+/// Complete transfer
+#[derive(Debug)]
+pub struct Received<'a> {
+    pub id: CanId,
+    pub transfer_id: u8,
+    /// Transfers from CAN FD nodes may have trailing zero padding
+    pub payload: &'a [u8],
+}
+
+struct Session<const N: usize> {
+    /// Raw CAN id without priority, None if the session is free
+    port: Option<u32>,
+    transfer_id: u8,
+    /// Expected in the next frame
+    toggle: bool,
+    last_frame_at_us: u64,
+    crc: Crc16,
+    len: usize,
+    buf: [u8; N],
+}
+
+impl<const N: usize> Session<N> {
+    const FREE: Self = Session {
+        port: None,
+        transfer_id: 0,
+        toggle: false,
+        last_frame_at_us: 0,
+        crc: Crc16::new(),
+        len: 0,
+        buf: [0; N],
+    };
+}
+
+/// Reassembles multi-frame transfers of up to N bytes (CRC included),
+/// from up to SESSIONS sources or ports at the same time.
+pub struct Reassembler<const N: usize, const SESSIONS: usize> {
+    sessions: [Session<N>; SESSIONS],
+}
+
+impl<const N: usize, const SESSIONS: usize> Reassembler<N, SESSIONS> {
+    pub const fn new() -> Self {
+        Reassembler {
+            sessions: [Session::<N>::FREE; SESSIONS],
+        }
+    }
+
+    /// Feed one received frame with extended `raw_id`, `now_us` is any monotonic time.
+    ///
+    /// Returns the transfer once its last frame is received, an error if the frame was dropped
+    /// (together with the rest of its transfer if it was broken) and None otherwise.
+    pub fn accept<'a>(&'a mut self, raw_id: u32, frame: &'a [u8], now_us: u64) -> Result<Option<Received<'a>>, Error> {
+        let id = CanId::from_raw(raw_id)?;
+        let (tail, data) = frame.split_last().ok_or(Error::Empty)?;
+        let start = tail & TAIL_START != 0;
+        let end = tail & TAIL_END != 0;
+        let toggle = tail & TAIL_TOGGLE != 0;
+        let transfer_id = tail & TAIL_TRANSFER_ID;
+        let port = raw_id & !ID_PRIORITY;
+        if start && !toggle {
+            return Err(Error::ToggleMismatch);
+        }
+        if start && end {
+            // supersedes an unfinished transfer on the same port
+            if let Some(session) = self.sessions.iter_mut().find(|s| s.port == Some(port)) {
+                session.port = None;
+            }
+            return Ok(Some(Received { id, transfer_id, payload: data }));
+        }
+        if id.anonymous {
+            return Err(Error::AnonymousMultiFrame);
+        }
+        if start {
+            let idx = self.sessions.iter().position(|s| s.port == Some(port))
+                .or_else(|| self.sessions.iter().position(|s| {
+                    s.port.is_none() || now_us.wrapping_sub(s.last_frame_at_us) > TRANSFER_TIMEOUT_US
+                }))
+                .ok_or(Error::NoSession)?;
+            let session = &mut self.sessions[idx];
+            if data.len() > N {
+                session.port = None;
+                return Err(Error::TooLong);
+            }
+            session.port = Some(port);
+            session.transfer_id = transfer_id;
+            session.toggle = false;
+            session.last_frame_at_us = now_us;
+            session.crc = Crc16::new();
+            session.crc.add(data);
+            session.buf[..data.len()].copy_from_slice(data);
+            session.len = data.len();
+            return Ok(None);
+        }
+        let session = self.sessions.iter_mut().find(|s| s.port == Some(port)).ok_or(Error::MissedStart)?;
+        if session.transfer_id != transfer_id
+            || now_us.wrapping_sub(session.last_frame_at_us) > TRANSFER_TIMEOUT_US
+        {
+            session.port = None;
+            return Err(Error::MissedStart);
+        }
+        if toggle != session.toggle {
+            // repeated frame, the transfer can still be completed
+            return Err(Error::ToggleMismatch);
+        }
+        let len = session.len + data.len();
+        if len > N {
+            session.port = None;
+            return Err(Error::TooLong);
+        }
+        session.buf[session.len..len].copy_from_slice(data);
+        session.len = len;
+        session.crc.add(data);
+        session.toggle = !session.toggle;
+        session.last_frame_at_us = now_us;
+        if !end {
+            return Ok(None);
+        }
+        session.port = None;
+        // CRC over the data followed by its CRC is 0
+        if session.len < CRC_LEN || session.crc.0 != 0 {
+            return Err(Error::CrcMismatch);
+        }
+        Ok(Some(Received {
+            id,
+            transfer_id,
+            payload: &session.buf[..session.len - CRC_LEN],
+        }))
+    }
+
+    /// Drop all unfinished transfers, e.g. after bus-off
+    pub fn reset(&mut self) {
+        for session in self.sessions.iter_mut() {
+            session.port = None;
+        }
+    }
+}
+
+impl<const N: usize, const SESSIONS: usize> Default for Reassembler<N, SESSIONS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// uavcan.pnp.NodeIDAllocationData.1.0: node without an id publishes it anonymously with its
+/// unique id hash and no node id, allocator publishes the same message with the id filled in.
+/// Nodes retry until they see a response with their hash.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AllocationData {
+    /// 48 bit hash of the node's unique hardware id, upper bits are ignored
+    pub unique_id_hash: u64,
+    pub node_id: Option<u16>,
+}
+
+impl AllocationData {
+    /// Serialize into `buf`, returns the length
+    pub fn encode(&self, buf: &mut [u8; ALLOCATION_DATA_LEN_MAX]) -> usize {
+        buf[..6].copy_from_slice(&self.unique_id_hash.to_le_bytes()[..6]);
+        match self.node_id {
+            Some(id) => {
+                buf[6] = 1;
+                buf[7..9].copy_from_slice(&id.to_le_bytes());
+                9
+            }
+            None => {
+                buf[6] = 0;
+                7
+            }
+        }
+    }
+
+    /// Trailing padding is ignored
+    pub fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let header = payload.get(..7).ok_or(Error::Malformed)?;
+        let mut hash = [0u8; 8];
+        hash[..6].copy_from_slice(&header[..6]);
+        let node_id = match header[6] {
+            0 => None,
+            1 => {
+                let id = payload.get(7..9).ok_or(Error::Malformed)?;
+                Some(u16::from_le_bytes([id[0], id[1]]))
+            }
+            _ => return Err(Error::Malformed),
+        };
+        Ok(AllocationData { unique_id_hash: u64::from_le_bytes(hash), node_id })
+    }
+}
+
+/// Transfer id counter for one subject or service, wraps at 32
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TransferIdCounter(u8);
+
+impl TransferIdCounter {
+    pub const fn new() -> Self {
+        TransferIdCounter(0)
+    }
+
+    /// Transfer id of the next transfer, while it is still being sent
+    pub fn current(&self) -> u8 {
+        self.0
+    }
+
+    /// Transfer id to use now, advances the counter
+    pub fn advance(&mut self) -> u8 {
+        let id = self.0;
+        self.0 = (self.0 + 1) % TRANSFER_ID_MODULO;
+        id
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn from_u8(level: u8) -> Option<Self> {
+        match level {
+            1 => Some(LogLevel::Error),
+            2 => Some(LogLevel::Warn),
+            3 => Some(LogLevel::Info),
+            4 => Some(LogLevel::Debug),
+            5 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+/// Header of a log transfer: level (0 for defmt frames, they carry their own) and body length,
+/// so that CAN FD padding can be told apart from the body. Text bodies are UTF-8, possibly cut
+/// in the middle of a character, defmt bodies are whole rzcobs encoded frames.
+pub fn log_header(level: Option<LogLevel>, body_len: usize) -> [u8; LOG_HEADER_LEN] {
+    let len = (body_len as u16).to_le_bytes();
+    [level.map(|l| l as u8).unwrap_or(0), len[0], len[1]]
+}
+
+/// Level and body of a log transfer, None level for defmt frames
+pub fn parse_log(payload: &[u8]) -> Result<(Option<LogLevel>, &[u8]), Error> {
+    let header = payload.get(..LOG_HEADER_LEN).ok_or(Error::Malformed)?;
+    let level = match header[0] {
+        0 => None,
+        l => Some(LogLevel::from_u8(l).ok_or(Error::Malformed)?),
+    };
+    let len = u16::from_le_bytes([header[1], header[2]]) as usize;
+    let body = payload.get(LOG_HEADER_LEN..LOG_HEADER_LEN + len).ok_or(Error::Malformed)?;
+    Ok((level, body))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    const SOURCE: u8 = 42;
+
+    fn raw_id() -> u32 {
+        CanId::message(4, XPI_SUBJECT_ID, SOURCE).to_raw()
+    }
+
+    fn segment(payload: &[u8], transfer_id: u8, mtu: usize) -> Vec<Vec<u8>> {
+        let mut segmenter = Segmenter::new(payload, transfer_id, mtu);
+        let mut frames = Vec::new();
+        let mut frame = [0u8; MTU_FD];
+        while let Some(len) = segmenter.next_frame(&mut frame) {
+            assert!(len <= mtu);
+            assert_eq!(len, frame_len(len));
+            frames.push(frame[..len].to_vec());
+        }
+        frames
+    }
+
+    /// Feeds all the frames, every one but the last must be accepted without a result
+    fn reassemble(reassembler: &mut Reassembler<256, 2>, frames: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+        let (last, first) = frames.split_last().unwrap();
+        for frame in first {
+            assert!(reassembler.accept(raw_id(), frame, 0)?.is_none());
+        }
+        let received = reassembler.accept(raw_id(), last, 0)?.unwrap();
+        assert_eq!(received.id, CanId::message(4, XPI_SUBJECT_ID, SOURCE));
+        Ok(received.payload.to_vec())
+    }
+
+    #[test]
+    fn round_trip_single_and_multi_frame() {
+        for mtu in [MTU_CLASSIC, MTU_FD] {
+            for len in 0..200 {
+                let payload: Vec<u8> = (0..len).map(|b| (b * 7 + 1) as u8).collect();
+                let frames = segment(&payload, len as u8, mtu);
+                assert_eq!(frames.len() == 1, len < mtu, "mtu {} len {}", mtu, len);
+                let mut reassembler = Reassembler::<256, 2>::new();
+                let received = reassemble(&mut reassembler, &frames).unwrap();
+                // CAN FD padding is kept
+                assert_eq!(&received[..len], &payload[..], "mtu {} len {}", mtu, len);
+                assert!(received[len..].iter().all(|b| *b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn tail_bytes() {
+        let frames = segment(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], 33, MTU_CLASSIC);
+        let tails: Vec<u8> = frames.iter().map(|f| *f.last().unwrap()).collect();
+        // transfer id 33 % 32, toggle starts at 1
+        assert_eq!(tails, vec![TAIL_START | TAIL_TOGGLE | 1, TAIL_END | 1]);
+    }
+
+    #[test]
+    fn repeated_frame_is_a_toggle_mismatch() {
+        let payload = [0x55; 20];
+        let frames = segment(&payload, 3, MTU_CLASSIC);
+        assert_eq!(frames.len(), 4);
+        let mut reassembler = Reassembler::<256, 2>::new();
+        assert!(matches!(reassembler.accept(raw_id(), &frames[0], 0), Ok(None)));
+        assert!(matches!(reassembler.accept(raw_id(), &frames[1], 0), Ok(None)));
+        assert_eq!(reassembler.accept(raw_id(), &frames[1], 0).unwrap_err(), Error::ToggleMismatch);
+        // transfer is completed anyway
+        let received = reassemble(&mut reassembler, &frames[2..]).unwrap();
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn start_frame_without_toggle_is_rejected() {
+        let mut frame = segment(&[1, 2, 3], 0, MTU_CLASSIC).remove(0);
+        *frame.last_mut().unwrap() &= !TAIL_TOGGLE;
+        let mut reassembler = Reassembler::<256, 2>::new();
+        assert_eq!(reassembler.accept(raw_id(), &frame, 0).unwrap_err(), Error::ToggleMismatch);
+    }
+
+    #[test]
+    fn other_transfer_id_drops_the_transfer() {
+        let frames = segment(&[0xAA; 20], 3, MTU_CLASSIC);
+        let other = segment(&[0xAA; 20], 4, MTU_CLASSIC);
+        let mut reassembler = Reassembler::<256, 2>::new();
+        assert!(matches!(reassembler.accept(raw_id(), &frames[0], 0), Ok(None)));
+        assert_eq!(reassembler.accept(raw_id(), &other[1], 0).unwrap_err(), Error::MissedStart);
+        // session is gone
+        assert_eq!(reassembler.accept(raw_id(), &frames[1], 0).unwrap_err(), Error::MissedStart);
+    }
+
+    #[test]
+    fn continuation_without_start() {
+        let frames = segment(&[0xAA; 20], 3, MTU_CLASSIC);
+        let mut reassembler = Reassembler::<256, 2>::new();
+        assert_eq!(reassembler.accept(raw_id(), &frames[1], 0).unwrap_err(), Error::MissedStart);
+    }
+
+    #[test]
+    fn corrupted_payload_is_a_crc_mismatch() {
+        for mtu in [MTU_CLASSIC, MTU_FD] {
+            let mut frames = segment(&[0x11; 100], 0, mtu);
+            frames[0][0] ^= 0x01;
+            let mut reassembler = Reassembler::<256, 2>::new();
+            assert_eq!(reassemble(&mut reassembler, &frames).unwrap_err(), Error::CrcMismatch);
+        }
+    }
+
+    #[test]
+    fn transfer_longer_than_buffer() {
+        let frames = segment(&[0x22; 30], 0, MTU_CLASSIC);
+        let mut reassembler = Reassembler::<16, 1>::new();
+        let mut result = Ok(None);
+        for frame in &frames {
+            result = reassembler.accept(raw_id(), frame, 0).map(|r| r.map(|r| r.payload.len()));
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, Err(Error::TooLong));
+        // start frame longer than the buffer
+        let frames = segment(&[0x22; 100], 0, MTU_FD);
+        let mut reassembler = Reassembler::<16, 1>::new();
+        assert_eq!(reassembler.accept(raw_id(), &frames[0], 0).unwrap_err(), Error::TooLong);
+    }
+
+    #[test]
+    fn session_times_out() {
+        let frames = segment(&[0x33; 20], 0, MTU_CLASSIC);
+        let mut reassembler = Reassembler::<256, 1>::new();
+        assert!(matches!(reassembler.accept(raw_id(), &frames[0], 0), Ok(None)));
+        assert_eq!(
+            reassembler.accept(raw_id(), &frames[1], TRANSFER_TIMEOUT_US + 1).unwrap_err(),
+            Error::MissedStart
+        );
+        // stale session of another source is reused instead of running out of sessions
+        let other = CanId::message(4, XPI_SUBJECT_ID, SOURCE + 1).to_raw();
+        assert!(matches!(reassembler.accept(raw_id(), &frames[0], 0), Ok(None)));
+        assert_eq!(reassembler.accept(other, &frames[0], 1).unwrap_err(), Error::NoSession);
+        assert!(matches!(reassembler.accept(other, &frames[0], TRANSFER_TIMEOUT_US + 1), Ok(None)));
+    }
+
+    #[test]
+    fn anonymous_transfers_are_single_frame() {
+        let id = CanId { anonymous: true, ..CanId::message(4, PNP_SUBJECT_ID, 3) }.to_raw();
+        let frames = segment(&[0x44; 20], 0, MTU_CLASSIC);
+        let mut reassembler = Reassembler::<256, 2>::new();
+        assert_eq!(reassembler.accept(id, &frames[0], 0).unwrap_err(), Error::AnonymousMultiFrame);
+        let frame = segment(&[0x44; 3], 0, MTU_CLASSIC).remove(0);
+        let received = reassembler.accept(id, &frame, 0).unwrap().unwrap();
+        assert!(received.id.anonymous);
+    }
+
+    #[test]
+    fn can_id_round_trip() {
+        let ids = [
+            CanId::message(0, 0, 0),
+            CanId::message(7, SUBJECT_ID_MAX, NODE_ID_MAX),
+            CanId::message(4, XPI_SUBJECT_ID, 1),
+            CanId { anonymous: true, ..CanId::message(2, PNP_SUBJECT_ID, 99) },
+            CanId {
+                priority: 3,
+                kind: Kind::Request { service_id: SERVICE_ID_MAX, destination: 5 },
+                anonymous: false,
+                source: 6,
+            },
+            CanId {
+                priority: 5,
+                kind: Kind::Response { service_id: 1, destination: NODE_ID_MAX },
+                anonymous: false,
+                source: 0,
+            },
+        ];
+        for id in ids {
+            let raw = id.to_raw();
+            assert!(raw <= 0x1FFF_FFFF);
+            assert_eq!(CanId::from_raw(raw), Ok(id), "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn can_id_reserved_bits() {
+        let raw = CanId::message(4, XPI_SUBJECT_ID, 1).to_raw();
+        assert_eq!(CanId::from_raw(raw | ID_RESERVED_23), Err(Error::BadId));
+        assert_eq!(CanId::from_raw(raw | ID_RESERVED_7), Err(Error::BadId));
+        assert_eq!(CanId::from_raw(0x2000_0000), Err(Error::BadId));
+    }
+}
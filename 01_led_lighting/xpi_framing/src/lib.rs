@@ -0,0 +1,312 @@
+#![no_std]
+
+//! Stream framing for xPI events carried over byte oriented links (TCP, UART).
+//!
+//! Every frame on the wire is `COBS(payload ++ crc16(payload)) ++ 0x00`.
+//! COBS guarantees that the delimiter never appears inside a frame, so after a corrupted or
+//! truncated frame the receiver simply drops everything up to the next delimiter and continues
+//! with the frame after it.
+//!
+//! Shared between ecbridge_fw and rustyclient, so must stay no_std and allocation free.
+
+pub const DELIMITER: u8 = 0x00;
+pub const CRC_LEN: usize = 2;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// Output buffer is too small to hold the encoded frame
+    OutOfSpace,
+    /// Frame did not fit into the reassembly buffer and was dropped
+    FrameTooLong,
+    /// Zero byte or truncated block inside a frame
+    BadEncoding,
+    /// Frame is shorter than the CRC itself
+    TooShort,
+    CrcMismatch,
+}
+
+/// Worst case amount of bytes required to encode a frame with `payload_len` bytes of payload,
+/// including the trailing delimiter.
+pub const fn max_encoded_len(payload_len: usize) -> usize {
+    let raw_len = payload_len + CRC_LEN;
+    raw_len + raw_len / 254 + 1 + 1
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Encode `payload` into `out`, returning the amount of bytes written (delimiter included).
+pub fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if out.len() < max_encoded_len(payload.len()) {
+        return Err(Error::OutOfSpace);
+    }
+    let crc = crc16(payload).to_le_bytes();
+    let mut code_idx = 0;
+    let mut code: u8 = 1;
+    let mut wr = 1;
+    for b in payload.iter().chain(crc.iter()) {
+        if *b == 0 {
+            out[code_idx] = code;
+            code_idx = wr;
+            wr += 1;
+            code = 1;
+        } else {
+            out[wr] = *b;
+            wr += 1;
+            code += 1;
+            if code == 0xFF {
+                out[code_idx] = code;
+                code_idx = wr;
+                wr += 1;
+                code = 1;
+            }
+        }
+    }
+    out[code_idx] = code;
+    out[wr] = DELIMITER;
+    Ok(wr + 1)
+}
+
+/// Decode one frame (without the delimiter) in place, check and strip the CRC.
+pub fn decode_in_place(buf: &mut [u8]) -> Result<&[u8], Error> {
+    let len = buf.len();
+    let mut rd = 0;
+    let mut wr = 0;
+    while rd < len {
+        let code = buf[rd];
+        if code == DELIMITER {
+            return Err(Error::BadEncoding);
+        }
+        rd += 1;
+        for _ in 1..code {
+            if rd >= len || buf[rd] == DELIMITER {
+                return Err(Error::BadEncoding);
+            }
+            buf[wr] = buf[rd];
+            wr += 1;
+            rd += 1;
+        }
+        if code != 0xFF && rd < len {
+            buf[wr] = 0;
+            wr += 1;
+        }
+    }
+    if wr < CRC_LEN {
+        return Err(Error::TooShort);
+    }
+    let payload_len = wr - CRC_LEN;
+    let received_crc = u16::from_le_bytes([buf[payload_len], buf[payload_len + 1]]);
+    if crc16(&buf[..payload_len]) != received_crc {
+        return Err(Error::CrcMismatch);
+    }
+    Ok(&buf[..payload_len])
+}
+
+/// Reassembly buffer for one byte stream, holds at most one encoded frame of up to N bytes.
+pub struct FrameDecoder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Push one byte from the stream.
+    ///
+    /// Returns the payload when the delimiter closing a valid frame is seen, an error when the
+    /// frame was corrupted (it is already dropped, decoder is ready for the next one)
+    /// and None otherwise.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<&[u8], Error>> {
+        if byte != DELIMITER {
+            if self.len < N {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overflow, false) {
+            return Some(Err(Error::FrameTooLong));
+        }
+        if len == 0 {
+            // back to back delimiters are allowed, senders may use them to force resync
+            return None;
+        }
+        Some(decode_in_place(&mut self.buf[..len]))
+    }
+
+    /// Upper bound of the payload length that the next delimiter will produce,
+    /// 0 if it won't produce a frame at all.
+    ///
+    /// Allows to reserve space for the frame before feeding the delimiter and to leave the
+    /// rest of the stream untouched if there is none.
+    pub fn pending_len(&self) -> usize {
+        if self.overflow {
+            0
+        } else {
+            self.len
+        }
+    }
+
+    /// No partial frame is held, the next byte starts a new frame.
+    ///
+    /// Then a complete frame found in the input can be decoded with decode_in_place() directly,
+    /// without going through the reassembly buffer.
+    pub fn is_idle(&self) -> bool {
+        self.len == 0 && !self.overflow
+    }
+
+    /// Drop partially received frame, e.g. when connection is closed.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn encode_vec(payload: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; 1024];
+        let len = encode(payload, &mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    /// Feed `chunks` one after another, collecting every frame and error produced
+    fn feed_all<const N: usize>(decoder: &mut FrameDecoder<N>, chunks: &[&[u8]]) -> Vec<Result<Vec<u8>, Error>> {
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            for b in chunk.iter() {
+                if let Some(r) = decoder.feed(*b) {
+                    frames.push(r.map(|payload| payload.to_vec()));
+                }
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn round_trip() {
+        let long: Vec<u8> = (0..600).map(|i| (i % 7) as u8).collect();
+        let payloads: [&[u8]; 6] = [&[], &[0], &[0, 0, 0], &[1, 2, 3], &[0xFF; 300], &long];
+        for payload in payloads {
+            let mut encoded = encode_vec(payload);
+            assert!(encoded.len() <= max_encoded_len(payload.len()));
+            assert_eq!(encoded.last(), Some(&DELIMITER));
+            assert_eq!(encoded.iter().filter(|b| **b == DELIMITER).count(), 1);
+            let len = encoded.len() - 1;
+            assert_eq!(decode_in_place(&mut encoded[..len]), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn encode_out_of_space() {
+        let mut out = [0u8; 5];
+        assert_eq!(encode(&[1, 2, 3], &mut out), Err(Error::OutOfSpace));
+    }
+
+    #[test]
+    fn frame_split_across_feeds() {
+        let encoded = encode_vec(&[0x10, 0x00, 0x20, 0x30]);
+        let mut decoder = FrameDecoder::<64>::new();
+        let (a, b) = encoded.split_at(2);
+        let (b, c) = b.split_at(1);
+        assert!(feed_all(&mut decoder, &[a, b]).is_empty());
+        assert!(!decoder.is_idle());
+        assert_eq!(feed_all(&mut decoder, &[c]), [Ok(vec![0x10, 0x00, 0x20, 0x30])]);
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn frames_coalesced_in_one_feed() {
+        let mut stream = encode_vec(&[1]);
+        stream.push(DELIMITER); // extra delimiters are skipped
+        stream.extend(encode_vec(&[2, 0]));
+        stream.extend(encode_vec(&[3, 4, 5]));
+        let mut decoder = FrameDecoder::<64>::new();
+        assert_eq!(
+            feed_all(&mut decoder, &[&stream]),
+            [Ok(vec![1]), Ok(vec![2, 0]), Ok(vec![3, 4, 5])]
+        );
+    }
+
+    #[test]
+    fn resync_after_corrupted_crc() {
+        let mut corrupted = encode_vec(&[1, 2, 3, 4]);
+        let crc_pos = corrupted.len() - 2;
+        corrupted[crc_pos] ^= 0x01;
+        let good = encode_vec(&[5, 6]);
+        let mut decoder = FrameDecoder::<64>::new();
+        assert_eq!(
+            feed_all(&mut decoder, &[&corrupted, &good]),
+            [Err(Error::CrcMismatch), Ok(vec![5, 6])]
+        );
+    }
+
+    #[test]
+    fn resync_after_truncated_frame() {
+        let truncated = encode_vec(&[0, 1, 2, 0, 3]);
+        let truncated = [&truncated[..3], &[DELIMITER]].concat();
+        let good = encode_vec(&[7]);
+        let mut decoder = FrameDecoder::<64>::new();
+        let frames = feed_all(&mut decoder, &[&truncated, &good]);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].is_err());
+        assert_eq!(frames[1], Ok(vec![7]));
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let long = encode_vec(&[0xAA; 40]);
+        let good = encode_vec(&[1, 2]);
+        let mut decoder = FrameDecoder::<16>::new();
+        assert!(feed_all(&mut decoder, &[&long[..20]]).is_empty());
+        assert_eq!(decoder.pending_len(), 0);
+        assert_eq!(
+            feed_all(&mut decoder, &[&long[20..], &good]),
+            [Err(Error::FrameTooLong), Ok(vec![1, 2])]
+        );
+    }
+
+    #[test]
+    fn reset_drops_partial_frame() {
+        let encoded = encode_vec(&[9, 9, 9]);
+        let mut decoder = FrameDecoder::<16>::new();
+        feed_all(&mut decoder, &[&encoded[..2]]);
+        decoder.reset();
+        assert!(decoder.is_idle());
+        assert_eq!(feed_all(&mut decoder, &[&encoded]), [Ok(vec![9, 9, 9])]);
+    }
+}
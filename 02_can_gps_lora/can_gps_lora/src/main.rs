@@ -6,11 +6,12 @@ mod oled_bw_ssd1306;
 mod util;
 mod lora;
 mod oled_color_ssd1331;
+mod supervisor;
 
 use cortex_m::asm::delay;
 // use panic_rtt_target as _;
 
-#[rtic::app(device = stm32l4xx_hal::stm32, peripherals = true, dispatchers = [SAI1, SAI2])]
+#[rtic::app(device = stm32l4xx_hal::stm32, peripherals = true, dispatchers = [SAI1, SAI2, LCD])]
 mod app {
     use cfg_if::cfg_if;
 
@@ -39,6 +40,7 @@ mod app {
 
     use crate::oled_bw_ssd1306::oled_ssd1306_task;
     use crate::oled_color_ssd1331::oled_ssd1331_task;
+    use crate::supervisor::supervise;
 
     #[shared]
     struct SharedResources {
@@ -68,6 +70,8 @@ mod app {
         // rx_cons: spsc::Consumer<'static, u8, U8>,
 
         led_green: stm32l4xx_hal::gpio::Pin<Output<PushPull>, H8, 'A', 10_u8>,
+
+        watchdog: crate::supervisor::Watchdog,
     }
 
     pub const SYSCLK: u32 = 24_000_000;
@@ -101,6 +105,7 @@ mod app {
 
         // let p = pac::Peripherals::take().unwrap();
         let mut p = cx.device;
+        crate::supervisor::print_reset_cause(&p.RCC);
 
         let mut dcb = cx.core.DCB;
         let dwt = cx.core.DWT;
@@ -313,6 +318,9 @@ mod app {
         oled_ssd1306_task::spawn();
         oled_ssd1331_task::spawn();
 
+        let watchdog = crate::supervisor::Watchdog::start(p.IWDG, &p.DBGMCU);
+        supervise::spawn().unwrap();
+
         (
             SharedResources {
                 local_heartbeat: HeartBeat { uptime: 0, remote_rssi: 0 },
@@ -337,7 +345,9 @@ mod app {
                 // rx_prod,
                 // rx_cons,
 
-                led_green
+                led_green,
+
+                watchdog,
             },
             init::Monotonics(mono)
         )
@@ -354,6 +364,7 @@ mod app {
         let mut buf = [0u8; 64];
         let mut counter = 0;
         loop {
+            crate::supervisor::check_in(crate::supervisor::Task::LoraTask);
             // led_green.toggle();
 
             rprintln!("idle");
@@ -468,6 +479,9 @@ mod app {
 
         #[task(priority = 2, local = [oled_ssd1331], shared = [local_heartbeat, remote_heartbeat])]
         fn oled_ssd1331_task(_: oled_ssd1331_task::Context);
+
+        #[task(priority = 3, local = [watchdog])]
+        fn supervise(_: supervise::Context);
     }
 
 }
@@ -21,6 +21,7 @@ pub type Display = Ssd1306<I2CInterface<DisplayI2c>, DisplaySize128x64, Buffered
 
 #[cfg(feature = "oled_bw_ssd1306")]
 pub fn oled_ssd1306_task(mut cx: crate::app::oled_ssd1306_task::Context) {
+    let _busy = crate::supervisor::busy(crate::supervisor::Task::Display);
     let display: &mut Display = cx.local.oled_ssd1306;
     let local_hb: HeartBeat = cx.shared.local_heartbeat.lock(|hb| hb.clone());
     let remote_hb: HeartBeat = cx.shared.remote_heartbeat.lock(|hb| hb.clone());
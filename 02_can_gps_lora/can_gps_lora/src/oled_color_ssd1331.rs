@@ -24,6 +24,7 @@ pub type Display = Ssd1331<DisplaySpi, Dc>;
 
 #[cfg(feature = "oled_color_ssd1331")]
 pub fn oled_ssd1331_task(mut cx: crate::app::oled_ssd1331_task::Context) {
+    let _busy = crate::supervisor::busy(crate::supervisor::Task::Display);
     let display: &mut Display = cx.local.oled_ssd1331;
     let local_hb: HeartBeat = cx.shared.local_heartbeat.lock(|hb| hb.clone());
     let remote_hb: HeartBeat = cx.shared.remote_heartbeat.lock(|hb| hb.clone());
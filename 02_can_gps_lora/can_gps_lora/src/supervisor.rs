@@ -0,0 +1,129 @@
+//! Feeds the independent watchdog only while every registered task is alive.
+//!
+//! idle (running lora_task) must check in on every iteration, display tasks hold a Busy guard while
+//! they run. supervise runs above them, when one misses its deadline the task is remembered in RAM
+//! that is not cleared on reset and the watchdog resets the chip, it is printed on the next boot.
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU32, Ordering};
+use dwt_systick_monotonic::ExtU64;
+use rtt_target::rprintln;
+use stm32l4xx_hal::stm32::{DBGMCU, IWDG, RCC};
+
+const PERIOD_MS: u64 = 250;
+/// LSI / 32 is 1kHz, so RLR is in ms
+const WATCHDOG_TIMEOUT_MS: u32 = 2000;
+const NOT_BUSY: u32 = u32::MAX;
+const MAGIC: u32 = 0x5D09_A11E;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Task {
+    LoraTask,
+    Display,
+}
+
+/// Name and deadline in ms, indexed by Task
+const TASKS: [(&str, u32); 2] = [
+    ("lora_task", 2000),
+    ("display", 1000),
+];
+
+/// Last check-in of lora_task or start of the current display update, ms of uptime
+static CHECK_INS: [AtomicU32; TASKS.len()] = [AtomicU32::new(0), AtomicU32::new(NOT_BUSY)];
+
+/// Missed task, kept across the watchdog reset
+#[repr(C)]
+struct Missed {
+    magic: u32,
+    task: u32,
+    late_ms: u32,
+}
+
+#[link_section = ".uninit.supervisor"]
+static mut MISSED: MaybeUninit<Missed> = MaybeUninit::uninit();
+
+fn now_ms() -> u32 {
+    crate::app::monotonics::now().duration_since_epoch().to_millis() as u32
+}
+
+pub fn check_in(task: Task) {
+    CHECK_INS[task as usize].store(now_ms(), Ordering::Relaxed);
+}
+
+/// `let _busy = supervisor::busy(Task::Display);` for the whole run
+pub fn busy(task: Task) -> Busy {
+    CHECK_INS[task as usize].store(now_ms().min(NOT_BUSY - 1), Ordering::Relaxed);
+    Busy { task }
+}
+
+pub struct Busy {
+    task: Task,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        CHECK_INS[self.task as usize].store(NOT_BUSY, Ordering::Relaxed);
+    }
+}
+
+/// Print why the previous run was reset, must be called before RCC is constrained
+pub fn print_reset_cause(rcc: &RCC) {
+    // safe: nothing else touches MISSED before supervise runs, any bit pattern is valid
+    let missed = unsafe { &mut *(*addr_of_mut!(MISSED)).as_mut_ptr() };
+    let watchdog = rcc.csr.read().iwdgrstf().bit_is_set();
+    match TASKS.get(missed.task as usize) {
+        Some((name, deadline)) if missed.magic == MAGIC => {
+            rprintln!("watchdog reset: {} missed its {}ms deadline by {}ms", name, deadline, missed.late_ms);
+        }
+        _ if watchdog => rprintln!("watchdog reset: supervisor didn't run"),
+        _ => {}
+    }
+    missed.magic = 0;
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+}
+
+pub struct Watchdog {
+    iwdg: IWDG,
+}
+
+impl Watchdog {
+    /// Can't be stopped anymore until reset, frozen while the core is halted by a debugger
+    pub fn start(iwdg: IWDG, dbgmcu: &DBGMCU) -> Self {
+        dbgmcu.apb1fzr1.modify(|_, w| w.dbg_iwdg_stop().set_bit());
+        iwdg.kr.write(|w| unsafe { w.bits(0xCCCC) }); // start
+        iwdg.kr.write(|w| unsafe { w.bits(0x5555) }); // unlock PR and RLR
+        iwdg.pr.write(|w| unsafe { w.bits(3) }); // LSI / 32
+        iwdg.rlr.write(|w| unsafe { w.bits(WATCHDOG_TIMEOUT_MS) });
+        while iwdg.sr.read().bits() != 0 {}
+        iwdg.kr.write(|w| unsafe { w.bits(0xAAAA) });
+        Watchdog { iwdg }
+    }
+}
+
+pub fn supervise(cx: crate::app::supervise::Context) {
+    let now = now_ms();
+    let missed = CHECK_INS.iter().zip(TASKS.iter()).enumerate().find_map(|(i, (check_in, (_, deadline)))| {
+        match check_in.load(Ordering::Relaxed) {
+            NOT_BUSY => None,
+            at if now.wrapping_sub(at) > *deadline => Some((i, now.wrapping_sub(at) - deadline)),
+            _ => None,
+        }
+    });
+    match missed {
+        None => cx.local.watchdog.iwdg.kr.write(|w| unsafe { w.bits(0xAAAA) }),
+        Some((task, late_ms)) => {
+            cortex_m::interrupt::disable();
+            let (name, deadline) = TASKS[task];
+            rprintln!("{} missed its {}ms deadline by {}ms", name, deadline, late_ms);
+            unsafe {
+                addr_of_mut!(MISSED).write(MaybeUninit::new(Missed { magic: MAGIC, task: task as u32, late_ms }));
+            }
+            // not fed anymore
+            loop {
+                core::sync::atomic::compiler_fence(Ordering::SeqCst);
+            }
+        }
+    }
+    crate::app::supervise::spawn_after(PERIOD_MS.millis()).unwrap();
+}